@@ -2,32 +2,51 @@
 //!
 //! This module contains logic for creating and managing channels.
 
+use super::{
+    factory_snapshot::{
+        ExtendedChannelSnapshot, ExtranoncesSnapshot, IdsSnapshot, PoolChannelFactorySnapshot,
+        StandardChannelSnapshot,
+    },
+    input_recorder::{InputRecorder, RecordedInput},
+    share_accounting::ShareAccounting,
+};
 use crate::{
+    errors::CoinbasePart,
     job_creator::{self, JobsCreators},
-    utils::{GroupId, Id, Mutex},
+    utils::{deserialize_template_outputs, witness_commitment, GroupId, Mutex},
     Error,
 };
 
 use codec_sv2::binary_sv2;
+use common_messages_sv2::{has_version_rolling, has_work_selection};
 use mining_sv2::{
-    ExtendedExtranonce, NewExtendedMiningJob, OpenExtendedMiningChannelSuccess,
-    OpenMiningChannelError, SetCustomMiningJob, SetCustomMiningJobSuccess, SetNewPrevHash,
-    SubmitSharesError, SubmitSharesExtended, SubmitSharesStandard, Target,
+    CloseChannel, ExtendedExtranonce, ExtendedExtranonceError, NewExtendedMiningJob, NewMiningJob,
+    OpenExtendedMiningChannelSuccess, OpenMiningChannelError, OpenStandardMiningChannelSuccess,
+    SetCustomMiningJob, SetCustomMiningJobError, SetCustomMiningJobSuccess, SetExtranoncePrefix,
+    SetNewPrevHash, SetTarget, SubmitSharesError, SubmitSharesExtended, SubmitSharesStandard,
+    Target, UpdateChannel, UpdateChannelError,
 };
 use parsers_sv2::Mining;
 
 use hex::DisplayHex;
 use nohash_hasher::BuildNoHashHasher;
-use std::{collections::HashMap, convert::TryInto, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryInto,
+    fmt::Write,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use template_distribution_sv2::{NewTemplate, SetNewPrevHash as SetNewPrevHashFromTp};
 
 use tracing::{debug, error, info, trace, warn};
 
 use bitcoin::{
     block::{Header, Version},
+    consensus::{self, encode::VarInt},
     hash_types,
-    hashes::sha256d::Hash,
-    CompactTarget, TxOut,
+    hashes::{sha256, sha256d::Hash, Hash as _, HashEngine as _},
+    CompactTarget, ScriptBuf, Transaction, TxOut, Witness,
 };
 
 /// A stripped type of `SetCustomMiningJob` without the (`channel_id, `request_id` and `token`)
@@ -48,17 +67,345 @@ pub struct PartialSetCustomMiningJob {
     pub future_job: bool,
 }
 
+// Check that coinbase prefix + extranonce + coinbase suffix is a valid transaction and that the
+// extranonce lands inside the script of the coinbase input. The extranonce is zeroed, any other
+// extranonce of the same len gives a transaction with the same layout.
+fn check_coinbase_split(
+    coinbase_tx_prefix: &[u8],
+    coinbase_tx_suffix: &[u8],
+    extranonce_len: usize,
+) -> Result<(), Error> {
+    let coinbase = [
+        coinbase_tx_prefix,
+        &vec![0; extranonce_len][..],
+        coinbase_tx_suffix,
+    ]
+    .concat();
+    let coinbase: Transaction =
+        consensus::deserialize(&coinbase).map_err(|_| Error::MalformedCoinbaseSplit)?;
+    let input = coinbase
+        .input
+        .first()
+        .ok_or(Error::MalformedCoinbaseSplit)?;
+    let script_len = input.script_sig.len();
+    // A serialized transaction with inputs has a 0 in place of the inputs count only when it has
+    // the segwit marker and flag
+    let segwit_bytes = match coinbase_tx_prefix.get(4) {
+        Some(0) => 2,
+        _ => 0,
+    };
+    let script_start = 4 // tx version
+        + segwit_bytes
+        + consensus::serialize(&VarInt(coinbase.input.len() as u64)).len()
+        + 32 // prev OutPoint
+        + 4 // index
+        + consensus::serialize(&VarInt(script_len as u64)).len();
+    if coinbase_tx_prefix.len() < script_start
+        || coinbase_tx_prefix.len() + extranonce_len > script_start + script_len
+    {
+        return Err(Error::MalformedCoinbaseSplit);
+    }
+    Ok(())
+}
+
+// The coinbase and the merkle path of a job prepared once, so that the merkle root of each share
+// of the job only hashes the coinbase with the share extranonce and folds the merkle path
+#[derive(Clone)]
+struct JobMerkleCache {
+    // Inputs that the cache has been built from
+    coinbase_tx_prefix: Vec<u8>,
+    coinbase_tx_suffix: Vec<u8>,
+    extranonce_len: usize,
+    merkle_path: Vec<Vec<u8>>,
+    // The sha256 engine fed with the coinbase serialization without witness that comes before
+    // the extranonce, and that serialization after the extranonce. None when the extranonce does
+    // not land inside the script of the coinbase input, then each share deserializes its coinbase
+    txid_parts: Option<(sha256::HashEngine, Vec<u8>)>,
+}
+
+impl std::fmt::Debug for JobMerkleCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JobMerkleCache")
+            .field("coinbase_tx_prefix", &self.coinbase_tx_prefix)
+            .field("coinbase_tx_suffix", &self.coinbase_tx_suffix)
+            .field("extranonce_len", &self.extranonce_len)
+            .field("merkle_path", &self.merkle_path)
+            .finish()
+    }
+}
+
+impl JobMerkleCache {
+    // Returns the malformed part of the job if the coinbase with a zeroed extranonce is not a
+    // valid transaction or a hash of the merkle path is not 32 bytes
+    fn new<T: AsRef<[u8]>>(
+        coinbase_tx_prefix: &[u8],
+        coinbase_tx_suffix: &[u8],
+        extranonce_len: usize,
+        merkle_path: &[T],
+    ) -> Result<Self, CoinbasePart> {
+        if merkle_path.iter().any(|h| h.as_ref().len() != 32) {
+            return Err(CoinbasePart::MerklePath);
+        }
+        let coinbase = [
+            coinbase_tx_prefix,
+            &vec![0; extranonce_len][..],
+            coinbase_tx_suffix,
+        ]
+        .concat();
+        let mut coinbase: Transaction = consensus::deserialize(&coinbase)
+            .map_err(|_| invalid_coinbase_part(coinbase_tx_prefix, extranonce_len))?;
+        // With the extranonce inside the script of the input every extranonce of the same len
+        // gives a coinbase with the same layout, so only the extranonce bytes of the serialization
+        // change from share to share
+        let txid_parts =
+            match check_coinbase_split(coinbase_tx_prefix, coinbase_tx_suffix, extranonce_len) {
+                Ok(()) => {
+                    for input in coinbase.input.iter_mut() {
+                        input.witness = Witness::default();
+                    }
+                    let serialized = consensus::serialize(&coinbase);
+                    // The segwit marker and flag are not part of the serialization without witness
+                    let extranonce_start = match coinbase_tx_prefix.get(4) {
+                        Some(0) => coinbase_tx_prefix.len() - 2,
+                        _ => coinbase_tx_prefix.len(),
+                    };
+                    let mut engine = Hash::engine();
+                    engine.input(&serialized[..extranonce_start]);
+                    Some((
+                        engine,
+                        serialized[extranonce_start + extranonce_len..].to_vec(),
+                    ))
+                }
+                Err(_) => None,
+            };
+        Ok(Self {
+            coinbase_tx_prefix: coinbase_tx_prefix.to_vec(),
+            coinbase_tx_suffix: coinbase_tx_suffix.to_vec(),
+            extranonce_len,
+            merkle_path: merkle_path.iter().map(|h| h.as_ref().to_vec()).collect(),
+            txid_parts,
+        })
+    }
+
+    // True if the cache has been built from these inputs
+    fn matches<T: AsRef<[u8]>>(
+        &self,
+        coinbase_tx_prefix: &[u8],
+        coinbase_tx_suffix: &[u8],
+        extranonce_len: usize,
+        merkle_path: &[T],
+    ) -> bool {
+        self.coinbase_tx_prefix == coinbase_tx_prefix
+            && self.coinbase_tx_suffix == coinbase_tx_suffix
+            && self.extranonce_len == extranonce_len
+            && self.merkle_path.len() == merkle_path.len()
+            && self
+                .merkle_path
+                .iter()
+                .zip(merkle_path)
+                .all(|(cached, h)| cached[..] == *h.as_ref())
+    }
+
+    // Same as `merkle_root_from_path` with the inputs of the cache, returns the malformed part of
+    // the job if the coinbase with `extranonce` is not a valid transaction
+    fn merkle_root(&self, extranonce: &[u8]) -> Result<[u8; 32], CoinbasePart> {
+        match &self.txid_parts {
+            Some((engine, suffix)) if extranonce.len() == self.extranonce_len => {
+                let mut engine = engine.clone();
+                engine.input(extranonce);
+                engine.input(suffix);
+                let coinbase_id = Hash::from_engine(engine).to_byte_array();
+                Ok(crate::utils::merkle_root_from_path_(
+                    coinbase_id,
+                    &self.merkle_path[..],
+                ))
+            }
+            // Safe unwrap a sha256 can always be converted into [u8;32]
+            _ => crate::utils::merkle_root_from_path(
+                &self.coinbase_tx_prefix,
+                &self.coinbase_tx_suffix,
+                extranonce,
+                &self.merkle_path[..],
+            )
+            .map(|root| root.try_into().unwrap())
+            .ok_or_else(|| invalid_coinbase_part(&self.coinbase_tx_prefix, extranonce.len())),
+        }
+    }
+}
+
+// The part of a job that makes coinbase prefix + extranonce + coinbase suffix an invalid
+// transaction: the prefix if it does not parse up to the script len of the coinbase input, the
+// extranonce len if the extranonce overflows that script and otherwise the suffix
+fn invalid_coinbase_part(coinbase_tx_prefix: &[u8], extranonce_len: usize) -> CoinbasePart {
+    // Start and len of the script of the coinbase input
+    let script = || -> Option<(usize, usize)> {
+        // tx version
+        let mut offset = 4;
+        // The segwit marker must be followed by the flag
+        if coinbase_tx_prefix.get(offset) == Some(&0) {
+            if coinbase_tx_prefix.get(offset + 1) != Some(&1) {
+                return None;
+            }
+            offset += 2;
+        }
+        let (inputs, len) =
+            consensus::deserialize_partial::<VarInt>(coinbase_tx_prefix.get(offset..)?).ok()?;
+        if inputs.0 == 0 {
+            return None;
+        }
+        // prev OutPoint and index
+        offset += len + 32 + 4;
+        let (script_len, len) =
+            consensus::deserialize_partial::<VarInt>(coinbase_tx_prefix.get(offset..)?).ok()?;
+        Some((offset + len, script_len.0 as usize))
+    };
+    match script() {
+        None => CoinbasePart::Prefix,
+        Some((script_start, script_len))
+            if coinbase_tx_prefix.len() + extranonce_len > script_start + script_len =>
+        {
+            CoinbasePart::ExtranonceLen
+        }
+        Some(_) => CoinbasePart::Suffix,
+    }
+}
+
+// Checks that `nbits` encodes a positive target that fits in 256 bits, with the same overflow
+// rules as bitcoin core. With nbits 0 the target is 0 and with a negative or overflowing encoding
+// it is meaningless.
+fn check_nbits(nbits: u32) -> Result<(), Error> {
+    let exponent = nbits >> 24;
+    let mantissa = nbits & 0x007f_ffff;
+    let negative = nbits & 0x0080_0000 != 0;
+    let target_is_zero = match exponent {
+        0..=3 => mantissa >> (8 * (3 - exponent)) == 0,
+        _ => mantissa == 0,
+    };
+    let overflow =
+        exponent > 34 || (mantissa > 0xff && exponent > 33) || (mantissa > 0xffff && exponent > 32);
+    if target_is_zero || negative || overflow {
+        error!("Invalid nbits {:#010x}", nbits);
+        return Err(Error::InvalidNbits(nbits));
+    }
+    Ok(())
+}
+
+// Start of the script of a BIP141 witness commitment output: OP_RETURN, push of 36 bytes and the
+// commitment header
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+// Checks that the witness commitment output of the template coinbase commits to `wtxids`, the
+// witness hashes of the template transactions. A template without a commitment output has no
+// segwit data to check.
+fn check_witness_commitment(m: &NewTemplate, wtxids: &[[u8; 32]]) -> Result<(), Error> {
+    let outputs =
+        deserialize_template_outputs(m.coinbase_tx_outputs.to_vec(), m.coinbase_tx_outputs_count)?;
+    // As in bitcoin, when more than one output matches the last one is the commitment
+    let commitment = outputs.iter().rev().find_map(|output| {
+        let script = output.script_pubkey.as_bytes();
+        match script.len() >= 38 && script[..6] == WITNESS_COMMITMENT_HEADER {
+            true => Some(&script[6..38]),
+            false => None,
+        }
+    });
+    match commitment {
+        Some(commitment) if commitment != witness_commitment(wtxids) => {
+            error!(
+                "Template {} witness commitment {} does not commit to its transactions",
+                m.template_id,
+                commitment.as_hex()
+            );
+            Err(Error::InvalidWitnessCommitment(m.template_id))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Subsidy in satoshis of the first blocks, before any halving
+const INITIAL_BLOCK_SUBSIDY: u64 = 50 * 100_000_000;
+
+// Number of blocks after which the subsidy halves
+const SUBSIDY_HALVING_INTERVAL: u64 = 210_000;
+
+// Subsidy in satoshis of a block at `height`
+fn block_subsidy(height: u64) -> u64 {
+    match height / SUBSIDY_HALVING_INTERVAL {
+        halvings if halvings >= 64 => 0,
+        halvings => INITIAL_BLOCK_SUBSIDY >> halvings,
+    }
+}
+
+// Height pushed at the start of the coinbase script as required by BIP34, None if the script does
+// not start with a height
+fn coinbase_prefix_height(coinbase_prefix: &[u8]) -> Option<u64> {
+    match *coinbase_prefix.first()? {
+        0x00 => Some(0),
+        // OP_1 to OP_16
+        opcode @ 0x51..=0x60 => Some((opcode - 0x50) as u64),
+        len @ 1..=8 => {
+            let bytes = coinbase_prefix.get(1..1 + len as usize)?;
+            // Script numbers are signed little endian, a negative number is not a height
+            if bytes[bytes.len() - 1] & 0x80 != 0 {
+                return None;
+            }
+            Some(
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |height, byte| height << 8 | *byte as u64),
+            )
+        }
+        _ => None,
+    }
+}
+
+// Returns the first output of `required` that is not paid by `outputs`, an output is paid by an
+// output with the same script and at least the same value. Each output pays a single one.
+fn missing_output<'a>(outputs: &[TxOut], required: &'a [TxOut]) -> Option<&'a TxOut> {
+    let mut outputs = outputs.to_vec();
+    for required_output in required {
+        match outputs.iter().position(|output| {
+            output.script_pubkey == required_output.script_pubkey
+                && output.value >= required_output.value
+        }) {
+            Some(position) => {
+                outputs.swap_remove(position);
+            }
+            None => return Some(required_output),
+        }
+    }
+    None
+}
+
+/// How the [`PoolChannelFactory`] checks the coinbase outputs of the custom jobs declared by the
+/// downstreams, see [`PoolChannelFactory::set_coinbase_output_policy`]. Custom jobs that do not
+/// comply are rejected with `invalid-coinbase-tx-outputs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoinbaseOutputPolicy {
+    /// The outputs are the pool coinbase outputs, in the same order and without other outputs,
+    /// each one paying at least the value of the pool output
+    ExactMatch,
+    /// Each output of the list is paid by an output with the same script and at least the same
+    /// value, the downstream can add its own outputs
+    MustContain(Vec<TxOut>),
+    /// The outputs to `script` pay at least `min_sats` in total, the downstream can add its own
+    /// outputs
+    MinValueToScript { script: ScriptBuf, min_sats: u64 },
+}
+
 /// Represents the action that needs to be done when a new share is received.
 #[derive(Debug, Clone)]
 pub enum OnNewShare {
     /// Used when the received is malformed, is for an inexistent channel or do not meet downstream
-    /// target.
-    SendErrorDownstream(SubmitSharesError<'static>),
+    /// target. The [`ShareRejectionDetail`] is there only when rejection details are enabled, it
+    /// is for the role to log and it is not sent downstream.
+    SendErrorDownstream((SubmitSharesError<'static>, Option<ShareRejectionDetail>)),
     /// Used when an extended channel in a proxy receive a share, and the share meet upstream
     /// target, in this case a new share must be sent upstream. Also an optional template id is
     /// returned, when a job declarator want to send a valid share upstream could use the
-    /// template for get the up job id.
-    SendSubmitShareUpstream((Share, Option<u64>)),
+    /// template for get the up job id. The [`ForwardReason`] tells if the share is also a block
+    /// that the upstream should propagate.
+    SendSubmitShareUpstream((Share, Option<u64>, ForwardReason)),
     /// Used when a group channel in a proxy receive a share that is not malformed and is for a
     /// valid channel in that case we relay the same exact share upstream with a new request id.
     RelaySubmitShareUpstream,
@@ -66,19 +413,20 @@ pub enum OnNewShare {
     /// the share upstream, whenever possible we should also notify the TP about it.
     /// When a pool negotiate a job with downstream we do not have the template_id so we set it to
     /// None
-    /// (share, template id, coinbase,complete extranonce)
-    ShareMeetBitcoinTarget((Share, Option<u64>, Vec<u8>, Vec<u8>)),
+    /// (share, template id, coinbase,complete extranonce, header that produced the block hash)
+    ShareMeetBitcoinTarget((Share, Option<u64>, Vec<u8>, Vec<u8>, FoundBlockHeader)),
     /// Indicate that the share meet downstream target, in the case we could send a success
     /// response downstream.
     ShareMeetDownstreamTarget,
 }
 
 impl OnNewShare {
-    /// Converts standard share into extended share
+    /// Converts standard share into extended share, variants without a share to relay upstream
+    /// are left unchanged
     pub fn into_extended(&mut self, extranonce: Vec<u8>, up_id: u32) {
         match self {
             OnNewShare::SendErrorDownstream(_) => (),
-            OnNewShare::SendSubmitShareUpstream((share, template_id)) => match share {
+            OnNewShare::SendSubmitShareUpstream((share, template_id, reason)) => match share {
                 Share::Extended(_) => (),
                 Share::Standard((share, _)) => {
                     let share = SubmitSharesExtended {
@@ -90,11 +438,16 @@ impl OnNewShare {
                         version: share.version,
                         extranonce: extranonce.try_into().unwrap(),
                     };
-                    *self = Self::SendSubmitShareUpstream((Share::Extended(share), *template_id));
+                    *self = Self::SendSubmitShareUpstream((
+                        Share::Extended(share),
+                        *template_id,
+                        *reason,
+                    ));
                 }
             },
             OnNewShare::RelaySubmitShareUpstream => (),
-            OnNewShare::ShareMeetBitcoinTarget((share, t_id, coinbase, ext)) => match share {
+            OnNewShare::ShareMeetBitcoinTarget((share, t_id, coinbase, ext, header)) => match share
+            {
                 Share::Extended(_) => (),
                 Share::Standard((share, _)) => {
                     let share = SubmitSharesExtended {
@@ -111,14 +464,216 @@ impl OnNewShare {
                         *t_id,
                         coinbase.clone(),
                         ext.to_vec(),
+                        header.clone(),
                     ));
                 }
             },
-            OnNewShare::ShareMeetDownstreamTarget => todo!(),
+            // The share is not relayed upstream, so there is nothing to convert
+            OnNewShare::ShareMeetDownstreamTarget => (),
+        }
+    }
+}
+
+/// Context of a share rejected with [`OnNewShare::SendErrorDownstream`], for operators debugging
+/// miners
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShareRejectionDetail {
+    /// Difficulty of the share hash, None if the share has been rejected before hashing it
+    pub achieved_difficulty: Option<f64>,
+    /// Difficulty of the channel target, None if the channel is not open
+    pub required_difficulty: Option<f64>,
+    /// Why the share has been rejected
+    pub reason: ShareRejectionReason,
+}
+
+/// Why a share has been rejected, see [`ShareRejectionDetail`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShareRejectionReason {
+    /// The share hash does not meet the channel target
+    DifficultyTooLow,
+    /// The share references a job that has been superseded by a new prev hash
+    StaleJob,
+    /// The share references a job unknown to the channel
+    InvalidJobId,
+    /// The share is for a channel that is not open
+    UnknownChannel,
+    /// The share has already been submitted
+    DuplicateShare,
+    /// The share version differs from the job version outside of the version rolling mask
+    InvalidVersion,
+    /// The share extranonce is shorter or longer than the extranonce size granted to the channel
+    InvalidExtranonceSize,
+    /// The share ntime is before the job min_ntime or too far in the future
+    InvalidNtime,
+    /// The standard share is for a group that is not the group of its channel
+    ChannelGroupMismatch,
+}
+
+/// Why a share is sent upstream with [`OnNewShare::SendSubmitShareUpstream`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardReason {
+    /// The share meets the upstream target
+    MetUpstreamTarget,
+    /// The share meets the network target (from the prev hash nbits) but this factory can not
+    /// propagate blocks, so it is relayed to the upstream that will do it
+    BlockRelay,
+}
+
+/// The `SetupConnection` flags negotiated by a downstream that change how its channels behave
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionFlags {
+    /// When false the shares of the channel can not change the job version
+    pub version_rolling: bool,
+    /// When false the channel can not set custom mining jobs
+    pub work_selection: bool,
+}
+
+impl ConnectionFlags {
+    /// Version rolling and work selection both allowed
+    pub fn all() -> Self {
+        Self {
+            version_rolling: true,
+            work_selection: true,
+        }
+    }
+}
+
+impl From<u32> for ConnectionFlags {
+    /// Builds the flags from the `flags` field of a mining protocol `SetupConnection`
+    fn from(flags: u32) -> Self {
+        Self {
+            version_rolling: has_version_rolling(flags),
+            work_selection: has_work_selection(flags),
+        }
+    }
+}
+
+/// Extranonce size requested by an `OpenExtendedMiningChannel` and what the factory granted. The
+/// requested size is a minimum, so the factory never grants less than requested: a request that
+/// exceeds the available extranonce size is refused and nothing is granted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtranonceGrant {
+    /// The `request_id` of the `OpenExtendedMiningChannel`
+    pub request_id: u32,
+    /// The `min_extranonce_size` of the request
+    pub requested: u16,
+    /// The extranonce size that the factory can give to a channel
+    pub available: u16,
+    /// The extranonce size of the opened channel, that is the requested one, or 0 if the channel
+    /// has been refused
+    pub granted: u16,
+}
+
+impl ExtranonceGrant {
+    /// By how many bytes the requested extranonce size exceeds the available one, 0 when the
+    /// request has been granted
+    pub fn shortfall(&self) -> u16 {
+        self.requested.saturating_sub(self.available)
+    }
+}
+
+/// The exact header inputs that produced the hash of a share that meets the bitcoin target.
+/// Useful to reproduce the block hash when a submitted block is rejected by the node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoundBlockHeader {
+    pub version: u32,
+    pub prev_blockhash: hash_types::BlockHash,
+    pub merkle_root: hash_types::TxMerkleNode,
+    pub time: u32,
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl FoundBlockHeader {
+    /// Returns the bitcoin block header
+    pub fn header(&self) -> Header {
+        Header {
+            version: Version::from_consensus(self.version as i32),
+            prev_blockhash: self.prev_blockhash,
+            merkle_root: self.merkle_root,
+            time: self.time,
+            bits: CompactTarget::from_consensus(self.bits),
+            nonce: self.nonce,
+        }
+    }
+
+    /// Returns the 80 bytes consensus serialized header
+    pub fn serialize(&self) -> Vec<u8> {
+        bitcoin::consensus::serialize(&self.header())
+    }
+
+    /// Returns the hash of the header
+    pub fn block_hash(&self) -> hash_types::BlockHash {
+        self.header().block_hash()
+    }
+}
+
+impl From<&Header> for FoundBlockHeader {
+    fn from(header: &Header) -> Self {
+        Self {
+            version: header.version.to_consensus() as u32,
+            prev_blockhash: header.prev_blockhash,
+            merkle_root: header.merkle_root,
+            time: header.time,
+            bits: header.bits.to_consensus(),
+            nonce: header.nonce,
         }
     }
 }
 
+/// Snapshot of the state of a channel, see [`PoolChannelFactory::channel_overview`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelOverview {
+    pub channel_id: u32,
+    pub group_id: u32,
+    /// The downstream target of the channel
+    pub target: Target,
+    /// The difficulty of `target`
+    pub difficulty: f64,
+    pub shares_accepted: u64,
+    pub shares_rejected: u64,
+    /// Time elapsed since the last accepted share, None if the channel has no accepted share
+    pub last_share_age: Option<Duration>,
+}
+
+/// Metadata of an open channel, see [`PoolChannelFactory::channels_snapshot`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelInfo {
+    pub channel_id: u32,
+    pub group_id: u32,
+    /// The downstream target of the channel
+    pub target: Target,
+    /// The extranonce prefix of an extended channel or the full extranonce of a standard channel
+    pub extranonce_prefix: Vec<u8>,
+    /// The extranonce size that the downstream can roll, 0 for standard channels
+    pub extranonce_size: u16,
+}
+
+/// Share counters of a channel, see [`PoolChannelFactory::get_channel_stats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChannelStats {
+    /// Shares that met at least the channel target
+    pub accepted: u64,
+    /// Shares rejected for any reason other than being stale
+    pub rejected: u64,
+    /// Shares rejected because they reference a superseded job or prev hash
+    pub stale: u64,
+    /// Highest difficulty achieved by the hash of a share of the channel
+    pub best_diff: f64,
+    /// Unix time in seconds of the last share of the channel, 0 if no share has been checked
+    pub last_share_time: u32,
+}
+
+// Share counters of a channel that its ShareAccounting does not keep
+#[derive(Debug, Clone, Copy, Default)]
+struct ShareStats {
+    stale: u64,
+    best_diff: f64,
+    // Unix time in seconds
+    last_share_time: u32,
+}
+
 /// A share can be either extended or standard
 #[derive(Clone, Debug)]
 pub enum Share {
@@ -203,6 +758,309 @@ impl Share {
     }
 }
 
+// Number of found blocks whose coinbase is kept for payout reconciliation
+const FOUND_BLOCKS_HISTORY_LEN: usize = 32;
+
+// Min time between two vardiff adjustments of a channel
+const VARDIFF_MIN_INTERVAL: Duration = Duration::from_secs(60);
+// Max factor by which a single vardiff adjustment changes the difficulty
+const VARDIFF_MAX_STEP: f64 = 4.0;
+// Exponent applied to the observed/expected share rate ratio, with 0.5 the difficulty only moves
+// halfway (geometrically) toward the observed hashrate at every adjustment
+const VARDIFF_SMOOTHING: f64 = 0.5;
+// The target is not changed when the difficulty would change by less than this fraction
+const VARDIFF_TOLERANCE: f64 = 0.2;
+// Max number of share timestamps kept per channel between two vardiff adjustments
+const VARDIFF_MAX_RECORDED_SHARES: usize = 4096;
+// Min number of recent shares of a channel to estimate its hashrate
+const HASHRATE_ESTIMATE_MIN_SHARES: usize = 5;
+// Max number of recent shares of a channel used to estimate its hashrate
+const HASHRATE_ESTIMATE_MAX_SHARES: usize = 512;
+
+// Accepted shares of a channel since the last vardiff adjustment, and the most recent shares of
+// the channel whatever its adjustments, for the hashrate estimate
+#[derive(Debug)]
+struct VardiffState {
+    // Start of the current observation window, the channel open time or the last adjustment
+    window_start: Instant,
+    share_times: VecDeque<Instant>,
+    // Time and channel difficulty of the last `HASHRATE_ESTIMATE_MAX_SHARES` shares, not cleared
+    // by `VardiffState::reset`
+    hashrate_samples: VecDeque<(Instant, f64)>,
+}
+
+impl VardiffState {
+    fn new(window_start: Instant) -> Self {
+        Self {
+            window_start,
+            share_times: VecDeque::new(),
+            hashrate_samples: VecDeque::new(),
+        }
+    }
+
+    fn on_share(&mut self, now: Instant, difficulty: f64) {
+        if self.share_times.len() == VARDIFF_MAX_RECORDED_SHARES {
+            self.share_times.pop_front();
+        }
+        self.share_times.push_back(now);
+        if self.hashrate_samples.len() == HASHRATE_ESTIMATE_MAX_SHARES {
+            self.hashrate_samples.pop_front();
+        }
+        self.hashrate_samples.push_back((now, difficulty));
+    }
+
+    fn reset(&mut self, now: Instant) {
+        self.window_start = now;
+        self.share_times.clear();
+    }
+}
+
+// Number of superseded job ids remembered to tell stale shares from shares for unknown jobs
+const STALE_JOB_IDS_HISTORY_LEN: usize = 16;
+
+// Number of previous tips whose jobs still accept shares, so that shares submitted just before a
+// short reorg are not rejected
+const PREVIOUS_TIPS_HISTORY_LEN: usize = 3;
+
+// Bits of the block version that miners are allowed to roll by default, as defined in BIP320
+const VERSION_ROLLING_MASK: u32 = 0x1fffe000;
+
+// Error code sent downstream when a share rolls version bits outside of the rolling mask
+const INVALID_VERSION_ERROR_CODE: &str = "invalid-version";
+
+// Error code sent downstream when the extranonce of a share does not fill the extranonce space of
+// the channel
+const INVALID_EXTRANONCE_SIZE_ERROR_CODE: &str = "invalid-extranonce-size";
+
+// Error code sent downstream when the ntime of a share is before the min_ntime of its job
+const NTIME_BELOW_MIN_NTIME_ERROR_CODE: &str = "ntime-below-min-ntime";
+
+// Error code sent downstream when the ntime of a share is further than the max drift in the future
+const NTIME_TOO_FAR_IN_FUTURE_ERROR_CODE: &str = "ntime-too-far-in-future";
+
+// Default max drift of the share ntime in the future, the max future block time of bitcoin
+const DEFAULT_MAX_NTIME_DRIFT: Duration = Duration::from_secs(2 * 60 * 60);
+
+// Error code sent downstream when a share is for a recently closed channel
+const CHANNEL_CLOSED_ERROR_CODE: &str = "channel-closed";
+
+// Error code sent downstream when a share has already been submitted on the channel
+const DUPLICATE_SHARE_ERROR_CODE: &str = "duplicate-share";
+
+// Number of shares remembered per channel to detect duplicates
+const RECENT_SHARES_PER_CHANNEL: usize = 4096;
+
+// Fields that identify a share, shares that differ only in sequence number are duplicates
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ShareFingerprint {
+    job_id: u32,
+    nonce: u32,
+    ntime: u32,
+    version: u32,
+    // Full extranonce, extranonce prefix + extranonce2
+    extranonce: Vec<u8>,
+}
+
+// Fingerprints of the last `RECENT_SHARES_PER_CHANNEL` shares of a channel
+#[derive(Debug, Default)]
+struct RecentShares {
+    // Oldest first
+    order: VecDeque<ShareFingerprint>,
+    fingerprints: HashSet<ShareFingerprint>,
+}
+
+impl RecentShares {
+    // Returns false if the share has already been seen
+    fn insert(&mut self, fingerprint: ShareFingerprint) -> bool {
+        if self.fingerprints.contains(&fingerprint) {
+            return false;
+        }
+        if self.order.len() == RECENT_SHARES_PER_CHANNEL {
+            if let Some(oldest) = self.order.pop_front() {
+                self.fingerprints.remove(&oldest);
+            }
+        }
+        self.order.push_back(fingerprint.clone());
+        self.fingerprints.insert(fingerprint);
+        true
+    }
+
+    // Forgets the shares of the jobs not in `live_job_ids`
+    fn retain_jobs(&mut self, live_job_ids: &[u32]) {
+        self.order
+            .retain(|fingerprint| live_job_ids.contains(&fingerprint.job_id));
+        self.fingerprints
+            .retain(|fingerprint| live_job_ids.contains(&fingerprint.job_id));
+    }
+}
+
+// Builds a `SubmitSharesError` for `share` with the given error code
+fn submit_shares_error(share: &Share, error_code: &str) -> SubmitSharesError<'static> {
+    SubmitSharesError {
+        channel_id: share.get_channel_id(),
+        sequence_number: share.get_sequence_number(),
+        // Infallible unwrap error codes are static strings shorter than 255 bytes
+        error_code: error_code.to_string().try_into().unwrap(),
+    }
+}
+
+// Converts an extended job in the `NewMiningJob` of the standard channel `channel_id`, whose full
+// extranonce is `extranonce`
+fn standard_job(
+    job: &NewExtendedMiningJob,
+    extranonce: &[u8],
+    channel_id: u32,
+) -> Result<Mining<'static>, Error> {
+    super::extended_to_standard_job(job, extranonce, channel_id, None)
+        .map(Mining::NewMiningJob)
+        .ok_or(Error::ImpossibleToCalculateMerkleRoot)
+}
+
+/// The messages to send downstream when an extended channel is opened, see
+/// [`PoolChannelFactory::new_extended_channel_structured`].
+#[derive(Debug, Clone)]
+pub struct NewExtendedChannel {
+    /// The response to the `OpenExtendedMiningChannel`.
+    pub success: OpenExtendedMiningChannelSuccess<'static>,
+    /// The last valid job, sent as a future job that `prev_hash` activates.
+    pub job: Option<NewExtendedMiningJob<'static>>,
+    /// The current prev hash, it references `job` when there is one.
+    pub prev_hash: Option<SetNewPrevHash<'static>>,
+    /// The jobs waiting for a prev hash.
+    pub future_jobs: Vec<NewExtendedMiningJob<'static>>,
+}
+
+impl NewExtendedChannel {
+    /// Returns the messages in the order they must be sent downstream: the success, the job, the
+    /// prev hash and then the future jobs.
+    pub fn into_messages(self) -> Vec<Mining<'static>> {
+        let mut messages = vec![Mining::OpenExtendedMiningChannelSuccess(self.success)];
+        messages.extend(self.job.map(Mining::NewExtendedMiningJob));
+        messages.extend(self.prev_hash.map(Mining::SetNewPrevHash));
+        messages.extend(
+            self.future_jobs
+                .into_iter()
+                .map(Mining::NewExtendedMiningJob),
+        );
+        messages
+    }
+}
+
+// Jobs that a new channel must receive, `J` is the job type of the channel
+struct NewChannelJobs<J> {
+    job: Option<J>,
+    prev_hash: Option<SetNewPrevHash<'static>>,
+    future_jobs: Vec<J>,
+}
+
+impl NewChannelJobs<Mining<'static>> {
+    // Same order as `NewExtendedChannel::into_messages`
+    fn into_messages(self) -> Vec<Mining<'static>> {
+        let mut messages: Vec<Mining<'static>> = self.job.into_iter().collect();
+        messages.extend(self.prev_hash.map(Mining::SetNewPrevHash));
+        messages.extend(self.future_jobs);
+        messages
+    }
+}
+
+/// Represents the response to a `SetCustomMiningJob` received by a pool.
+#[derive(Debug, Clone)]
+pub enum OnNewSetCustomMiningJob {
+    /// The job is accepted, the success must be sent downstream and the job can be mined.
+    Success(SetCustomMiningJobSuccess),
+    /// The job is rejected, the error code tells which job field is invalid.
+    SendErrorDownstream(SetCustomMiningJobError<'static>),
+}
+
+/// Credits the shares accepted by a channel factory, so that the embedder can implement its own
+/// payout scheme (PPS, PPLNS, score, ...).
+pub trait ShareCredit: std::fmt::Debug + Send {
+    /// Called for every share that meets at least the downstream target, with the channel that
+    /// submitted the share and the difficulty achieved by the share hash.
+    fn on_accepted_share(&mut self, channel_id: u32, difficulty: f64);
+
+    /// Returns the credit accumulated by the channel.
+    fn credit(&self, channel_id: u32) -> f64;
+}
+
+/// Default [`ShareCredit`], it does not credit shares.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoShareCredit;
+
+impl ShareCredit for NoShareCredit {
+    fn on_accepted_share(&mut self, _channel_id: u32, _difficulty: f64) {}
+
+    fn credit(&self, _channel_id: u32) -> f64 {
+        0.0
+    }
+}
+
+/// Source of the current time used by the channel factories, it can be replaced to control the
+/// time in tests.
+pub trait Clock: std::fmt::Debug + Send {
+    /// Returns the current instant
+    fn now(&self) -> Instant;
+
+    /// Returns the current unix time in seconds, by default the system time
+    fn unix_time(&self) -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs())
+    }
+}
+
+/// Default [`Clock`], it returns [`Instant::now`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// Callback invoked with every job received by the factory before it is distributed downstream
+struct OnNewJobHook(Box<dyn Fn(&NewExtendedMiningJob<'static>) + Send>);
+
+impl std::fmt::Debug for OnNewJobHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("OnNewJobHook")
+    }
+}
+
+/// Tracks the distinct extranonce2 used by a channel in the shares that it submitted
+#[derive(Debug, Default)]
+struct Extranonce2Diversity {
+    seen: HashSet<Vec<u8>>,
+    shares: u64,
+}
+
+impl Extranonce2Diversity {
+    fn on_share(&mut self, extranonce2: &[u8]) {
+        self.shares += 1;
+        if !self.seen.contains(extranonce2) {
+            self.seen.insert(extranonce2.to_vec());
+        }
+    }
+
+    // Ratio of distinct extranonce2 to shares, None if no share has been received
+    fn ratio(&self) -> Option<f32> {
+        match self.shares {
+            0 => None,
+            shares => Some(self.seen.len() as f32 / shares as f32),
+        }
+    }
+}
+
+// Downstream target and extranonce assigned to a standard channel
+#[derive(Debug, Clone)]
+struct StandardChannel {
+    group_id: u32,
+    target: Target,
+    // Full extranonce, standard channels can not roll it
+    extranonce: Vec<u8>,
+}
+
 #[derive(Debug)]
 /// Basic logic shared between all the channel factories
 struct ChannelFactory {
@@ -211,17 +1069,80 @@ struct ChannelFactory {
         HashMap<u32, OpenExtendedMiningChannelSuccess<'static>, BuildNoHashHasher<u32>>,
     extranonces: ExtendedExtranonce,
     share_per_min: f32,
-    // (NewExtendedMiningJob,group ids that already received the future job)
+    // (NewExtendedMiningJob,group or channel ids that already received the future job)
     future_jobs: Vec<(NewExtendedMiningJob<'static>, Vec<u32>)>,
-    // (SetNewPrevHash,group ids that already received the set prev_hash)
+    // Max number of future jobs queued until the next prev hash, the oldest are evicted
+    max_future_jobs: usize,
+    // (SetNewPrevHash,group or channel ids that already received the set prev_hash)
+    // Single source of truth for the current prev hash, the block hash used to check shares is
+    // derived from it with `last_prev_blockhash`
     last_prev_hash: Option<(StagedPhash, Vec<u32>)>,
-    last_prev_hash_: Option<hash_types::BlockHash>,
-    // (NewExtendedMiningJob,group ids that already received the job)
+    // (NewExtendedMiningJob,group or channel ids that already received the job)
     last_valid_job: Option<(NewExtendedMiningJob<'static>, Vec<u32>)>,
+    // Ids of the jobs that were valid before `last_valid_job`, oldest first
+    stale_job_ids: VecDeque<u32>,
     kind: ExtendedChannelKind,
-    job_ids: Id,
     channel_to_group_id: HashMap<u32, u32, BuildNoHashHasher<u32>>,
+    standard_channels: HashMap<u32, StandardChannel, BuildNoHashHasher<u32>>,
     future_templates: HashMap<u32, NewTemplate<'static>, BuildNoHashHasher<u32>>,
+    // When false new channels are refused, already opened channels keep working
+    accepting_new_channels: bool,
+    // When true shares that do not reference the last valid job are rejected, when false they
+    // are checked against the last valid job
+    strict_job_id: bool,
+    // channel_id -> extranonce2 used in the channel shares, None when tracking is disabled
+    extranonce2_diversity: Option<HashMap<u32, Extranonce2Diversity, BuildNoHashHasher<u32>>>,
+    share_credit: Box<dyn ShareCredit>,
+    on_new_job: Option<OnNewJobHook>,
+    clock: Box<dyn Clock>,
+    // channel_id -> instant at which the channel has been opened
+    channel_open_time: HashMap<u32, Instant, BuildNoHashHasher<u32>>,
+    // Extranonce sizes of the last extended channel request
+    last_extranonce_grant: Option<ExtranonceGrant>,
+    // extranonce2 size smaller than range_2 -> last prefix handed out to a channel of that size
+    reduced_extranonce_prefixes: HashMap<u16, Vec<u8>, BuildNoHashHasher<u16>>,
+    // Last full extranonce handed out to a standard channel
+    last_standard_extranonce: Option<Vec<u8>>,
+    // Channels whose shares are not checked against the bitcoin target
+    block_detection_disabled: HashSet<u32, BuildNoHashHasher<u32>>,
+    // channel_id -> last shares submitted on the channel, cleared on every new prev hash
+    recent_shares: HashMap<u32, RecentShares, BuildNoHashHasher<u32>>,
+    // channel_id -> counters of the shares checked by the factory
+    channel_stats: HashMap<u32, ShareStats, BuildNoHashHasher<u32>>,
+    // channel_id -> time of the last accepted share
+    last_share_time: HashMap<u32, Instant, BuildNoHashHasher<u32>>,
+    // channel_id -> shares accepted since the last vardiff adjustment
+    vardiff: HashMap<u32, VardiffState, BuildNoHashHasher<u32>>,
+    // (job id, coinbase, header) of the most recent blocks found, oldest first
+    found_blocks: VecDeque<(u32, Vec<u8>, FoundBlockHeader)>,
+    // Window during which further block meeting shares for the job of the last found block are
+    // not flagged as blocks, None if no share is debounced
+    block_debounce: Option<Duration>,
+    // job_id and instant of the last share that has been flagged as a block
+    last_found_block: Option<(u32, Instant)>,
+    // For how long closed channels are remembered, None if they are not
+    closed_channel_ttl: Option<Duration>,
+    // channel_id -> instant at which the channel has been closed
+    recently_closed_channels: HashMap<u32, Instant, BuildNoHashHasher<u32>>,
+    share_accounting: HashMap<u32, ShareAccounting, BuildNoHashHasher<u32>>,
+    // Flags that the downstream negotiated for each extended channel
+    channel_flags: HashMap<u32, ConnectionFlags, BuildNoHashHasher<u32>>,
+    // When true rejected shares come with a `ShareRejectionDetail`
+    rejection_details: bool,
+    // How far in the future the ntime of a share can be, None if it is not checked
+    max_ntime_drift: Option<Duration>,
+    // Coinbase and merkle path of the jobs that got shares since the last prev hash, or that were
+    // sent to the standard channels with `lazy_merkle_roots`, by job id
+    merkle_caches: HashMap<u32, JobMerkleCache, BuildNoHashHasher<u32>>,
+    // When true the merkle roots of the `NewMiningJob` of the standard channels are computed from
+    // the merkle cache of the job, prepared once per job, in place of deserializing the coinbase
+    // of every channel
+    lazy_merkle_roots: bool,
+    // Bits of the block version that the shares of channels with version rolling can roll
+    version_rolling_mask: u32,
+    // (downstream target, upstream target, bitcoin target) used in place of the real ones
+    #[cfg(any(test, feature = "test_targets"))]
+    test_targets: Option<(Target, Target, Target)>,
 }
 
 impl ChannelFactory {
@@ -231,24 +1152,50 @@ impl ChannelFactory {
     /// to be sent downstream. For the mining messages, we will first return an
     /// `OpenExtendedMiningChannelSuccess` if the channel is successfully opened. Then we add
     /// the `NewExtendedMiningJob` and `SetNewPrevHash` messages if the relevant data is
-    /// available. If the channel opening fails, we return `OpenExtendedMiningChannelError`.
+    /// available. If the channel opening fails, we return `OpenExtendedMiningChannelError`, with
+    /// the `extranonce-prefixes-exhausted` error code when every extranonce prefix has been
+    /// handed out. `flags` are the flags negotiated by the downstream, they are enforced for the
+    /// channel.
     pub fn new_extended_channel(
         &mut self,
         request_id: u32,
         hash_rate: f32,
         min_extranonce_size: u16,
+        flags: ConnectionFlags,
     ) -> Result<Vec<Mining<'static>>, Error> {
+        Ok(
+            match self.new_extended_channel_structured(
+                request_id,
+                hash_rate,
+                min_extranonce_size,
+                flags,
+            )? {
+                Ok(channel) => channel.into_messages(),
+                Err(error) => vec![Mining::OpenMiningChannelError(error)],
+            },
+        )
+    }
+
+    /// Like [`ChannelFactory::new_extended_channel`] but the messages are returned as a
+    /// [`NewExtendedChannel`], or as the `OpenMiningChannelError` if the channel is refused.
+    pub fn new_extended_channel_structured(
+        &mut self,
+        request_id: u32,
+        hash_rate: f32,
+        min_extranonce_size: u16,
+        flags: ConnectionFlags,
+    ) -> Result<Result<NewExtendedChannel, OpenMiningChannelError<'static>>, Error> {
+        if !self.accepting_new_channels {
+            info!(
+                "Not accepting new channels, refusing extended channel. Request id: {:?}",
+                request_id
+            );
+            return Ok(Err(maintenance_open_channel_error(request_id)));
+        }
         let extended_channels_group = 0;
         let max_extranonce_size = self.extranonces.get_range2_len() as u16;
         if min_extranonce_size <= max_extranonce_size {
-            // SECURITY is very unlikely to finish the ids btw this unwrap could be used by an
-            // attacker that want to disrupt the service maybe we should have a method
-            // to reuse ids that are no longer connected?
-            let channel_id = self
-                .ids
-                .safe_lock(|ids| ids.new_channel_id(extended_channels_group))
-                .unwrap();
-            self.channel_to_group_id.insert(channel_id, 0);
+            // The target is computed first, so that a failed channel takes no prefix and no id
             let target = match crate::utils::hash_rate_to_target(
                 hash_rate.into(),
                 self.share_per_min.into(),
@@ -262,58 +1209,257 @@ impl ChannelFactory {
                     return Err(e);
                 }
             };
-            let extranonce_prefix = self
-                .extranonces
-                .next_prefix_extended(max_extranonce_size as usize)
-                .unwrap()
-                .into_b032();
+            let extranonce_prefix: binary_sv2::B032<'static> =
+                match self.next_extended_prefix(min_extranonce_size) {
+                    Ok(extranonce_prefix) => extranonce_prefix.try_into()?,
+                    Err(e) => {
+                        error!(
+                            "No extranonce prefix available for a new extended channel: {:?}. \
+                             Request id: {:?}",
+                            e, request_id
+                        );
+                        return Ok(Err(prefixes_exhausted_open_channel_error(request_id)));
+                    }
+                };
+            // SECURITY is very unlikely to finish the ids btw this could be used by an attacker
+            // that want to disrupt the service. Ids of closed channels are reused when the ids
+            // run out, see `close_channel`. A poisoned ids lock is returned as `Error::PoisonLock`
+            let channel_id = self
+                .ids
+                .safe_lock(|ids| ids.new_channel_id(extended_channels_group))?;
+            self.channel_to_group_id.insert(channel_id, 0);
+            self.recently_closed_channels.remove(&channel_id);
             let success = OpenExtendedMiningChannelSuccess {
                 request_id,
                 channel_id,
                 target,
-                extranonce_size: max_extranonce_size,
+                extranonce_size: min_extranonce_size,
                 extranonce_prefix,
             };
+            self.last_extranonce_grant = Some(ExtranonceGrant {
+                request_id,
+                requested: min_extranonce_size,
+                available: max_extranonce_size,
+                granted: min_extranonce_size,
+            });
             self.extended_channels.insert(channel_id, success.clone());
-            let mut result = vec![Mining::OpenExtendedMiningChannelSuccess(success)];
-            if let Some((job, _)) = &self.last_valid_job {
-                let mut job = job.clone();
-                job.set_future();
-                let j_id = job.job_id;
-                result.push(Mining::NewExtendedMiningJob(job));
-                if let Some((new_prev_hash, _)) = &self.last_prev_hash {
-                    let mut new_prev_hash = new_prev_hash.into_set_p_hash(channel_id, None);
-                    new_prev_hash.job_id = j_id;
-                    result.push(Mining::SetNewPrevHash(new_prev_hash.clone()))
-                };
-            } else if let Some((new_prev_hash, _)) = &self.last_prev_hash {
-                let new_prev_hash = new_prev_hash.into_set_p_hash(channel_id, None);
-                result.push(Mining::SetNewPrevHash(new_prev_hash.clone()))
+            self.share_accounting
+                .insert(channel_id, ShareAccounting::new());
+            self.channel_flags.insert(channel_id, flags);
+            self.channel_open_time.insert(channel_id, self.clock.now());
+            let jobs = self.jobs_for_new_channel(channel_id, |mut job| {
+                job.version_rolling_allowed &= flags.version_rolling;
+                Ok(job)
+            })?;
+            Ok(Ok(NewExtendedChannel {
+                success,
+                job: jobs.job,
+                prev_hash: jobs.prev_hash,
+                future_jobs: jobs.future_jobs,
+            }))
+        } else {
+            let grant = ExtranonceGrant {
+                request_id,
+                requested: min_extranonce_size,
+                available: max_extranonce_size,
+                granted: 0,
             };
-            for (job, _) in &self.future_jobs {
-                result.push(Mining::NewExtendedMiningJob(job.clone()))
+            warn!(
+                "Requested extranonce size {} exceeds the available {} by {}. Request id: {:?}",
+                grant.requested,
+                grant.available,
+                grant.shortfall(),
+                request_id
+            );
+            self.last_extranonce_grant = Some(grant);
+            Ok(Err(OpenMiningChannelError::unsupported_extranonce_size(
+                request_id,
+            )))
+        }
+    }
+
+    // Returns the extranonce prefix of a new extended channel granted `extranonce2_size` bytes.
+    // A channel granted the whole range_2 takes the next prefix of `extranonces`. Smaller channels
+    // share a prefix of `extranonces` per size: it is extended with the range_2 bytes that they
+    // are not granted, incremented for each channel, so that their extranonces never overlap.
+    fn next_extended_prefix(
+        &mut self,
+        extranonce2_size: u16,
+    ) -> Result<Vec<u8>, ExtendedExtranonceError> {
+        let range_2_len = self.extranonces.get_range2_len();
+        let reserved_len = range_2_len - extranonce2_size as usize;
+        let shared_prefix = self
+            .reduced_extranonce_prefixes
+            .get(&extranonce2_size)
+            .cloned()
+            .and_then(|mut prefix| {
+                let reserved_start = prefix.len() - reserved_len;
+                match increment_bytes_be(&mut prefix[reserved_start..]) {
+                    true => Some(prefix),
+                    false => None,
+                }
+            });
+        let prefix = match shared_prefix {
+            Some(prefix) => prefix,
+            None => {
+                let mut prefix = self.extranonces.next_prefix_extended(range_2_len)?.to_vec();
+                prefix.resize(prefix.len() + reserved_len, 0);
+                prefix
             }
-            Ok(result)
-        } else {
-            Ok(vec![Mining::OpenMiningChannelError(
-                OpenMiningChannelError::unsupported_extranonce_size(request_id),
-            )])
+        };
+        if reserved_len > 0 {
+            self.reduced_extranonce_prefixes
+                .insert(extranonce2_size, prefix.clone());
         }
+        Ok(prefix)
     }
 
-    /// Called when we want to replicate a channel already opened by another actor.
-    /// It is used only in the jd client from the template provider module to mock a pool.
-    /// Anything else should open channel with the new_extended_channel function
-    pub fn replicate_upstream_extended_channel_only_jd(
+    // Returns the full extranonce of a new standard channel. Standard channels take a range_1 value
+    // of their own with `next_prefix_extended`, never one handed out to an extended channel, and
+    // increment the range_2 bytes under it. A new range_1 value is taken when range_2 wraps.
+    fn next_standard_extranonce(
         &mut self,
-        target: binary_sv2::U256<'static>,
-        extranonce: mining_sv2::Extranonce,
-        channel_id: u32,
-        extranonce_size: u16,
-    ) -> Option<()> {
-        self.channel_to_group_id.insert(channel_id, 0);
-        let extranonce_prefix = extranonce.into();
-        let success = OpenExtendedMiningChannelSuccess {
+    ) -> Result<mining_sv2::Extranonce, ExtendedExtranonceError> {
+        let range_2_start = self.extranonces.get_prefix_len();
+        let next = self
+            .last_standard_extranonce
+            .clone()
+            .and_then(|mut extranonce| {
+                increment_bytes_be(&mut extranonce[range_2_start..]).then_some(extranonce)
+            });
+        let extranonce = match next {
+            Some(extranonce) => extranonce,
+            None => {
+                let range_2_len = self.extranonces.get_range2_len();
+                let mut extranonce = self.extranonces.next_prefix_extended(range_2_len)?.to_vec();
+                extranonce.resize(range_2_start + range_2_len, 0);
+                extranonce
+            }
+        };
+        self.last_standard_extranonce = Some(extranonce.clone());
+        extranonce
+            .try_into()
+            .map_err(|_| ExtendedExtranonceError::ExceedsMaxLength)
+    }
+
+    /// Called when a `OpenStandardMiningChannel` message is received. The channel is registered
+    /// in `group_id` with a target based on `hash_rate` and the next standard extranonce. We
+    /// return an `OpenStandardMiningChannelSuccess` followed by the same jobs and prev hash that
+    /// [`ChannelFactory::new_extended_channel`] returns, with `NewMiningJob`s in place of the
+    /// extended jobs.
+    fn new_standard_channel(
+        &mut self,
+        request_id: u32,
+        hash_rate: f32,
+        group_id: u32,
+    ) -> Result<Vec<Mining<'static>>, Error> {
+        if !self.accepting_new_channels {
+            info!(
+                "Not accepting new channels, refusing standard channel. Request id: {:?}",
+                request_id
+            );
+            return Ok(vec![Mining::OpenMiningChannelError(
+                maintenance_open_channel_error(request_id),
+            )]);
+        }
+        let target =
+            match crate::utils::hash_rate_to_target(hash_rate.into(), self.share_per_min.into()) {
+                Ok(target) => target,
+                Err(e) => {
+                    error!(
+                        "Impossible to get target: {:?}. Request id: {:?}",
+                        e, request_id
+                    );
+                    return Err(e);
+                }
+            };
+        // The extranonce is taken before the id, so that no id is taken when the standard
+        // extranonces are exhausted
+        let extranonce = self
+            .next_standard_extranonce()
+            .map_err(Error::ExtranoncePrefixFactoryError)?;
+        let channel_id = self.ids.safe_lock(|ids| ids.new_channel_id(group_id))?;
+        self.insert_standard_channel(channel_id, group_id, target.clone().into(), &extranonce);
+        self.channel_open_time.insert(channel_id, self.clock.now());
+        let success = OpenStandardMiningChannelSuccess {
+            request_id: request_id.into(),
+            channel_id,
+            target,
+            extranonce_prefix: extranonce.clone().into(),
+            group_channel_id: group_id,
+        };
+        let extranonce = extranonce.to_vec();
+        let mut result = vec![Mining::OpenStandardMiningChannelSuccess(success)];
+        result.extend(
+            self.jobs_for_new_channel(channel_id, |job| {
+                self.standard_channel_job(&job, &extranonce, channel_id)
+            })?
+            .into_messages(),
+        );
+        Ok(result)
+    }
+
+    // Jobs that a new channel must receive: the last valid job, as a future job, followed by the
+    // prev hash that activates it (or only the prev hash if there is no valid job), then the
+    // queued future jobs. `channel_job` converts an extended job in the job sent to the channel.
+    fn jobs_for_new_channel<J>(
+        &self,
+        channel_id: u32,
+        channel_job: impl Fn(NewExtendedMiningJob<'static>) -> Result<J, Error>,
+    ) -> Result<NewChannelJobs<J>, Error> {
+        let mut jobs = NewChannelJobs {
+            job: None,
+            prev_hash: None,
+            future_jobs: vec![],
+        };
+        if let Some((job, _)) = &self.last_valid_job {
+            let mut job = job.clone();
+            job.set_future();
+            let j_id = job.job_id;
+            jobs.job = Some(channel_job(job)?);
+            if let Some((new_prev_hash, _)) = &self.last_prev_hash {
+                let mut new_prev_hash = new_prev_hash.into_set_p_hash(channel_id, None);
+                new_prev_hash.job_id = j_id;
+                jobs.prev_hash = Some(new_prev_hash);
+            };
+        } else if let Some((new_prev_hash, _)) = &self.last_prev_hash {
+            jobs.prev_hash = Some(new_prev_hash.into_set_p_hash(channel_id, None));
+        };
+        for (job, _) in &self.future_jobs {
+            jobs.future_jobs.push(channel_job(job.clone())?);
+        }
+        Ok(jobs)
+    }
+
+    /// Called when we want to replicate a channel already opened by another actor.
+    /// It is used only in the jd client from the template provider module to mock a pool.
+    /// Anything else should open channel with the new_extended_channel function.
+    /// Returns None if the extranonce prefix does not contain the factory range0 or if the prefix
+    /// and `extranonce_size` do not add up to the factory extranonce len.
+    pub fn replicate_upstream_extended_channel_only_jd(
+        &mut self,
+        target: binary_sv2::U256<'static>,
+        extranonce: mining_sv2::Extranonce,
+        channel_id: u32,
+        extranonce_size: u16,
+    ) -> Option<()> {
+        let extranonce_prefix: binary_sv2::B032<'static> = extranonce.into();
+        let extranonce_prefix_len = extranonce_prefix.inner_as_ref().len();
+        if extranonce_prefix_len < self.extranonces.get_range0_len()
+            || extranonce_prefix_len + extranonce_size as usize != self.extranonces.get_len()
+        {
+            error!(
+                "Can not replicate channel {}: extranonce prefix len {} and extranonce size {} do \
+                 not match the factory extranonce len {}",
+                channel_id,
+                extranonce_prefix_len,
+                extranonce_size,
+                self.extranonces.get_len()
+            );
+            return None;
+        }
+        self.channel_to_group_id.insert(channel_id, 0);
+        let success = OpenExtendedMiningChannelSuccess {
             request_id: 0,
             channel_id,
             target,
@@ -321,29 +1467,156 @@ impl ChannelFactory {
             extranonce_prefix,
         };
         self.extended_channels.insert(channel_id, success.clone());
+        self.share_accounting
+            .insert(channel_id, ShareAccounting::new());
+        self.channel_flags
+            .insert(channel_id, ConnectionFlags::all());
+        self.channel_open_time.insert(channel_id, self.clock.now());
         Some(())
     }
 
     /// Called when a new prev hash is received. If the respective job is available in the future
-    /// job queue, we move the future job into the valid job slot and store the prev hash as the
-    /// current prev hash to be referenced.
-    fn on_new_prev_hash(&mut self, m: StagedPhash) -> Result<(), Error> {
-        while let Some(mut job) = self.future_jobs.pop() {
-            if job.0.job_id == m.job_id {
-                let now = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs() as u32;
-                job.0.set_no_future(now);
-                self.last_valid_job = Some(job);
-                break;
-            }
-            self.last_valid_job = None;
+    /// job queue, we move the future job into the valid job slot, superseding the active job, and
+    /// store the prev hash as the current prev hash to be referenced. If more future jobs have the
+    /// same job id, the most recently received one is activated. If the prev hash references the
+    /// active job, the active job is kept. If it references a job that is not queued there is no
+    /// active job until a job for the prev hash is received, the old job is only remembered as
+    /// stale. Returns the `SetNewPrevHash` to send to every extended and standard channel,
+    /// referencing the active job or the job of the prev hash if there is no active job.
+    fn on_new_prev_hash(
+        &mut self,
+        m: StagedPhash,
+    ) -> Result<HashMap<u32, Mining<'static>, BuildNoHashHasher<u32>>, Error> {
+        check_nbits(m.nbits)?;
+        self.merkle_caches.clear();
+        let matching_jobs = self
+            .future_jobs
+            .iter()
+            .filter(|(job, _)| job.job_id == m.job_id)
+            .count();
+        if matching_jobs > 1 {
+            warn!(
+                "Found {} future jobs with job id {}, activating the most recent one",
+                matching_jobs, m.job_id
+            );
+        }
+        if !self.future_jobs.is_empty() {
+            let position = self
+                .future_jobs
+                .iter()
+                .rposition(|(job, _)| job.job_id == m.job_id);
+            let references_active_job = self
+                .last_valid_job
+                .as_ref()
+                .is_some_and(|(job, _)| job.job_id == m.job_id);
+            match position {
+                Some(position) => {
+                    let mut job = self.future_jobs.swap_remove(position);
+                    job.0.set_no_future(self.clock.unix_time() as u32);
+                    if let Some((active, _)) = &self.last_valid_job {
+                        debug!(
+                            "Future job {} supersedes active job {}",
+                            job.0.job_id, active.job_id
+                        );
+                    }
+                    self.set_last_valid_job(Some(job));
+                }
+                // The active job is not dropped when the prev hash is for it, the queued future
+                // jobs are for an other prev hash
+                None if references_active_job => (),
+                // The active job is for the old prev hash, mining it on the new one would build
+                // blocks that do not exist
+                None => {
+                    warn!(
+                        "Prev hash references job {} that is not a future job, no job is active \
+                         until it is received",
+                        m.job_id
+                    );
+                    self.set_last_valid_job(None);
+                }
+            };
+        } else if self
+            .last_valid_job
+            .as_ref()
+            .is_some_and(|(job, _)| job.job_id != m.job_id)
+        {
+            warn!(
+                "Prev hash references job {} but there are no future jobs, no job is active until \
+                 it is received",
+                m.job_id
+            );
+            self.set_last_valid_job(None);
         }
         self.future_jobs = vec![];
-        self.last_prev_hash_ = Some(crate::utils::u256_to_block_hash(m.prev_hash.clone()));
         self.last_prev_hash = Some((m, vec![]));
-        Ok(())
+        Ok(self.prev_hash_messages())
+    }
+
+    // The current prev hash addressed to every extended and standard channel, it references the
+    // active job if there is one
+    fn prev_hash_messages(&self) -> HashMap<u32, Mining<'static>, BuildNoHashHasher<u32>> {
+        let mut result = HashMap::with_hasher(BuildNoHashHasher::default());
+        if let Some((prev_hash, _)) = &self.last_prev_hash {
+            let job_id = self.last_valid_job.as_ref().map(|(job, _)| job.job_id);
+            for id in self
+                .extended_channels
+                .keys()
+                .chain(self.standard_channels.keys())
+            {
+                result.insert(
+                    *id,
+                    Mining::SetNewPrevHash(prev_hash.into_set_p_hash(*id, job_id)),
+                );
+            }
+        }
+        result
+    }
+
+    /// Replaces the last valid job, the id of the replaced job is remembered as stale
+    fn set_last_valid_job(&mut self, job: Option<(NewExtendedMiningJob<'static>, Vec<u32>)>) {
+        if let Some((old_job, _)) = self.last_valid_job.take() {
+            let is_replaced = job
+                .as_ref()
+                .is_none_or(|(new_job, _)| new_job.job_id != old_job.job_id);
+            if is_replaced && !self.stale_job_ids.contains(&old_job.job_id) {
+                if self.stale_job_ids.len() == STALE_JOB_IDS_HISTORY_LEN {
+                    self.stale_job_ids.pop_front();
+                }
+                self.stale_job_ids.push_back(old_job.job_id);
+            }
+        }
+        if let Some((new_job, _)) = &job {
+            self.stale_job_ids.retain(|id| *id != new_job.job_id);
+        }
+        self.last_valid_job = job;
+    }
+
+    // Returns the ids of the jobs that shares can still reference: the last valid job, the future
+    // jobs, the stale jobs and the jobs of the future templates
+    fn live_job_ids(&self) -> Vec<u32> {
+        self.last_valid_job
+            .iter()
+            .map(|(job, _)| job.job_id)
+            .chain(self.future_jobs.iter().map(|(job, _)| job.job_id))
+            .chain(self.stale_job_ids.iter().copied())
+            .chain(self.future_templates.keys().copied())
+            .collect()
+    }
+
+    // Forgets the shares of the jobs that are not in `live_job_ids`, shares for them are rejected
+    // as stale before being checked for duplicates. Called by the wrappers after a new prev hash
+    // with the jobs that still accept shares.
+    fn retain_recent_shares(&mut self, live_job_ids: &[u32]) {
+        for recent_shares in self.recent_shares.values_mut() {
+            recent_shares.retain_jobs(live_job_ids);
+        }
+    }
+
+    /// Returns the current prev hash as a `BlockHash`, derived from `last_prev_hash`
+    fn last_prev_blockhash(&self) -> Option<hash_types::BlockHash> {
+        self.last_prev_hash
+            .as_ref()
+            .map(|(prev_hash, _)| crate::utils::u256_to_block_hash(prev_hash.prev_hash.clone()))
     }
 
     /// Called when a `NewExtendedMiningJob` arrives. If the job is future, we add it to the future
@@ -352,23 +1625,32 @@ impl ChannelFactory {
         &mut self,
         m: NewExtendedMiningJob<'static>,
     ) -> Result<HashMap<u32, Mining<'static>, BuildNoHashHasher<u32>>, Error> {
+        check_coinbase_split(
+            m.coinbase_tx_prefix.as_ref(),
+            m.coinbase_tx_suffix.as_ref(),
+            self.extranonces.get_len(),
+        )?;
+        if let Some(on_new_job) = &self.on_new_job {
+            (on_new_job.0)(&m);
+        }
         match (m.is_future(), &self.last_prev_hash) {
             (true, _) => {
                 let mut result = HashMap::with_hasher(BuildNoHashHasher::default());
                 self.prepare_jobs_for_downstream_on_new_extended(&mut result, &m)?;
                 self.future_jobs.push((m, vec![]));
+                self.evict_future_jobs();
                 Ok(result)
             }
             (false, Some(_)) => {
                 let mut result = HashMap::with_hasher(BuildNoHashHasher::default());
                 self.prepare_jobs_for_downstream_on_new_extended(&mut result, &m)?;
-                // If job is not future it must always be paired with the last received prev hash
-                self.last_valid_job = Some((m, vec![]));
-                if let Some((_p_hash, _)) = &self.last_prev_hash {
-                    Ok(result)
-                } else {
-                    Err(Error::JobIsNotFutureButPrevHashNotPresent)
+                // If job is not future it must always be paired with the last received prev hash,
+                // from now on the prev hash references it
+                if let Some((prev_hash, _)) = self.last_prev_hash.as_mut() {
+                    prev_hash.job_id = m.job_id;
                 }
+                self.set_last_valid_job(Some((m, vec![])));
+                Ok(result)
             }
             // This should not happen when a non future job is received we always need to have a
             // prev hash
@@ -376,8 +1658,20 @@ impl ChannelFactory {
         }
     }
 
+    // Drops the oldest future jobs until there are at most `max_future_jobs`
+    fn evict_future_jobs(&mut self) {
+        while self.future_jobs.len() > self.max_future_jobs {
+            let (evicted, _) = self.future_jobs.remove(0);
+            warn!(
+                "More than {} future jobs, evicting future job {}",
+                self.max_future_jobs, evicted.job_id
+            );
+        }
+    }
+
     // When a new extended job is received we use this function to prepare the jobs to be sent
-    // downstream (standard for hom and this job for non hom)
+    // downstream: this job for extended channels and a `NewMiningJob` with the channel merkle
+    // root for standard (hom) channels
     fn prepare_jobs_for_downstream_on_new_extended(
         &mut self,
         result: &mut HashMap<u32, Mining, BuildNoHashHasher<u32>>,
@@ -386,12 +1680,157 @@ impl ChannelFactory {
         for id in self.extended_channels.keys() {
             let mut extended = m.clone();
             extended.channel_id = *id;
+            extended.version_rolling_allowed &= self.channel_version_rolling(*id);
             let extended_job = Mining::NewExtendedMiningJob(extended);
             result.insert(*id, extended_job);
         }
+        if self.lazy_merkle_roots {
+            self.cache_job_merkle(m);
+        }
+        // A channel whose job can not be built is skipped, the other channels still get the job
+        for (id, channel) in &self.standard_channels {
+            match self.standard_channel_job(m, &channel.extranonce, *id) {
+                Ok(job) => {
+                    result.insert(*id, job);
+                }
+                Err(e) => error!(
+                    "Impossible to build job {} for standard channel {}: {:?}",
+                    m.job_id, id, e
+                ),
+            }
+        }
         Ok(())
     }
 
+    // Prepares the merkle cache of the job for the standard channels, their extranonces all have
+    // the same len. A job whose cache can not be built is not cached, each channel then fails to
+    // build its job as without `lazy_merkle_roots`.
+    fn cache_job_merkle(&mut self, m: &NewExtendedMiningJob<'static>) {
+        let extranonce_len = match self.standard_channels.values().next() {
+            Some(channel) => channel.extranonce.len(),
+            None => return,
+        };
+        let merkle_path = m.merkle_path.inner_as_ref();
+        match JobMerkleCache::new(
+            m.coinbase_tx_prefix.inner_as_ref(),
+            m.coinbase_tx_suffix.inner_as_ref(),
+            extranonce_len,
+            &merkle_path[..],
+        ) {
+            Ok(cache) => {
+                self.merkle_caches.insert(m.job_id, cache);
+            }
+            Err(part) => debug!("Job {} not cached, invalid {:?}", m.job_id, part),
+        }
+    }
+
+    // The job sent to a standard channel: a `NewMiningJob` with the merkle root of the channel.
+    // With `lazy_merkle_roots` the merkle root comes from the merkle cache of the job, if any.
+    fn standard_channel_job(
+        &self,
+        m: &NewExtendedMiningJob<'static>,
+        extranonce: &[u8],
+        channel_id: u32,
+    ) -> Result<Mining<'static>, Error> {
+        let merkle_path = m.merkle_path.inner_as_ref();
+        let cache = self.merkle_caches.get(&m.job_id).filter(|cache| {
+            self.lazy_merkle_roots
+                && cache.matches(
+                    m.coinbase_tx_prefix.inner_as_ref(),
+                    m.coinbase_tx_suffix.inner_as_ref(),
+                    extranonce.len(),
+                    &merkle_path[..],
+                )
+        });
+        match cache {
+            Some(cache) => {
+                let merkle_root = cache
+                    .merkle_root(extranonce)
+                    .map_err(|_| Error::ImpossibleToCalculateMerkleRoot)?;
+                Ok(Mining::NewMiningJob(NewMiningJob {
+                    channel_id,
+                    job_id: m.job_id,
+                    min_ntime: m.min_ntime.clone().into_static(),
+                    version: m.version,
+                    merkle_root: merkle_root.into(),
+                }))
+            }
+            None => standard_job(m, extranonce, channel_id),
+        }
+    }
+
+    // Merkle root of the job with `extranonce` in the coinbase, `InvalidShareCoinbase` with the
+    // malformed part of the job if it can not be computed. The coinbase and the merkle path are
+    // prepared with the first share of the job and reused for the next ones.
+    fn merkle_root<T: AsRef<[u8]>>(
+        &mut self,
+        job_id: u32,
+        coinbase_tx_prefix: &[u8],
+        coinbase_tx_suffix: &[u8],
+        extranonce: &[u8],
+        merkle_path: &[T],
+    ) -> Result<[u8; 32], Error> {
+        match self.merkle_caches.get(&job_id) {
+            Some(cache)
+                if cache.matches(
+                    coinbase_tx_prefix,
+                    coinbase_tx_suffix,
+                    extranonce.len(),
+                    merkle_path,
+                ) =>
+            {
+                cache
+                    .merkle_root(extranonce)
+                    .map_err(Error::InvalidShareCoinbase)
+            }
+            _ => {
+                let cache = JobMerkleCache::new(
+                    coinbase_tx_prefix,
+                    coinbase_tx_suffix,
+                    extranonce.len(),
+                    merkle_path,
+                )
+                .map_err(Error::InvalidShareCoinbase)?;
+                let merkle_root = cache.merkle_root(extranonce);
+                self.merkle_caches.insert(job_id, cache);
+                merkle_root.map_err(Error::InvalidShareCoinbase)
+            }
+        }
+    }
+
+    // Returns the error code of a share with an ntime before `min_ntime` or further than the max
+    // drift in the future, None if the ntime is valid
+    fn check_ntime(&self, ntime: u32, min_ntime: u32) -> Option<&'static str> {
+        if ntime < min_ntime {
+            error!(
+                "Share ntime {} is before the job min_ntime {}",
+                ntime, min_ntime
+            );
+            return Some(NTIME_BELOW_MIN_NTIME_ERROR_CODE);
+        }
+        let max_ntime = self
+            .max_ntime_drift
+            .map(|drift| self.clock.unix_time().saturating_add(drift.as_secs()));
+        match max_ntime {
+            Some(max_ntime) if ntime as u64 > max_ntime => {
+                error!("Share ntime {} is after the max ntime {}", ntime, max_ntime);
+                Some(NTIME_TOO_FAR_IN_FUTURE_ERROR_CODE)
+            }
+            _ => None,
+        }
+    }
+
+    // True if a block for `job_id` has been found less than `block_debounce` ago
+    fn is_block_debounced(&self, job_id: u32) -> bool {
+        match (self.block_debounce, self.last_found_block) {
+            (Some(window), Some((last_job_id, found_at))) => {
+                last_job_id == job_id
+                    && self.clock.now().saturating_duration_since(found_at) < window
+            }
+            _ => false,
+        }
+    }
+
     // If there is job creator, bitcoin_target is retrieved from there. If not, it is set to 0.
     // If there is a job creator we pass the correct template id. If not, we pass `None`
     // allow comparison chain because clippy wants to make job management assertion into a match
@@ -409,6 +1848,8 @@ impl ChannelFactory {
         coinbase_tx_suffix: &[u8],
         prev_blockhash: hash_types::BlockHash,
         bits: u32,
+        min_ntime: u32,
+        job_version: u32,
     ) -> Result<OnNewShare, Error> {
         debug!("Checking target for share {:?}", m);
         let upstream_target = match &self.kind {
@@ -421,9 +1862,114 @@ impl ChannelFactory {
             } => upstream_target.clone(),
         };
 
+        let channel_exists = match &m {
+            Share::Extended(share) => self.extended_channels.contains_key(&share.channel_id),
+            Share::Standard((share, _)) => self.standard_channels.contains_key(&share.channel_id),
+        };
+        if !channel_exists {
+            let error = self.unknown_channel_error(m.get_channel_id(), m.get_sequence_number());
+            return Ok(self.reject_share(error, ShareRejectionReason::UnknownChannel, None));
+        }
+        if let Share::Standard((share, group_id)) = &m {
+            let channel_group_id = self.standard_channels[&share.channel_id].group_id;
+            if channel_group_id != *group_id {
+                error!(
+                    "Standard share for group {} but channel {} is in group {}",
+                    group_id, share.channel_id, channel_group_id
+                );
+                self.count_rejected_share(share.channel_id, false);
+                return Ok(self.reject_share(
+                    submit_shares_error(&m, SubmitSharesError::invalid_channel_error_code()),
+                    ShareRejectionReason::ChannelGroupMismatch,
+                    None,
+                ));
+            }
+        }
         let (downstream_target, extranonce) = self
             .get_channel_specific_mining_info(&m)
             .ok_or(Error::ShareDoNotMatchAnyChannel)?;
+        // The coinbase of a share with an extranonce of the wrong len is not the one of the job
+        if extranonce.len() != self.extranonces.get_len() {
+            error!(
+                "Extranonce is not of the right len expected {} actual {}",
+                self.extranonces.get_len(),
+                extranonce.len()
+            );
+            self.count_rejected_share(m.get_channel_id(), false);
+            return Ok(self.reject_share(
+                submit_shares_error(&m, INVALID_EXTRANONCE_SIZE_ERROR_CODE),
+                ShareRejectionReason::InvalidExtranonceSize,
+                None,
+            ));
+        }
+        #[cfg(any(test, feature = "test_targets"))]
+        let (downstream_target, upstream_target, bitcoin_target) = match &self.test_targets {
+            Some((downstream, upstream, bitcoin)) => {
+                (downstream.clone(), upstream.clone(), bitcoin.clone())
+            }
+            None => (downstream_target, upstream_target, bitcoin_target),
+        };
+        // Only the bits in the version rolling mask can differ from the job version, none if the
+        // channel did not negotiate version rolling
+        let version_rolling_mask = match self.channel_version_rolling(m.get_channel_id()) {
+            true => self.version_rolling_mask,
+            false => 0,
+        };
+        if (m.get_version() & !version_rolling_mask) != (job_version & !version_rolling_mask) {
+            error!(
+                "Share version {:#x} does not match job version {:#x} outside of the rolling mask",
+                m.get_version(),
+                job_version
+            );
+            self.count_rejected_share(m.get_channel_id(), false);
+            return Ok(self.reject_share(
+                submit_shares_error(&m, INVALID_VERSION_ERROR_CODE),
+                ShareRejectionReason::InvalidVersion,
+                None,
+            ));
+        }
+        if let Some(error_code) = self.check_ntime(m.get_n_time(), min_ntime) {
+            self.count_rejected_share(m.get_channel_id(), false);
+            return Ok(self.reject_share(
+                submit_shares_error(&m, error_code),
+                ShareRejectionReason::InvalidNtime,
+                None,
+            ));
+        }
+        // Only shares that pass every check are remembered, so that an invalid share resubmitted
+        // gets its own error and does not evict the fingerprint of a valid share
+        let fingerprint = ShareFingerprint {
+            job_id: m.get_job_id(),
+            nonce: m.get_nonce(),
+            ntime: m.get_n_time(),
+            version: m.get_version(),
+            extranonce: extranonce.clone(),
+        };
+        let is_new_share = self
+            .recent_shares
+            .entry(m.get_channel_id())
+            .or_default()
+            .insert(fingerprint);
+        if !is_new_share {
+            warn!(
+                "Duplicate share {} on channel {}",
+                m.get_sequence_number(),
+                m.get_channel_id()
+            );
+            self.count_rejected_share(m.get_channel_id(), false);
+            return Ok(self.reject_share(
+                submit_shares_error(&m, DUPLICATE_SHARE_ERROR_CODE),
+                ShareRejectionReason::DuplicateShare,
+                None,
+            ));
+        }
+        if let (Share::Extended(share), Some(diversity)) = (&m, self.extranonce2_diversity.as_mut())
+        {
+            diversity
+                .entry(share.channel_id)
+                .or_default()
+                .on_share(share.extranonce.inner_as_ref());
+        }
         let extranonce_1_len = self.extranonces.get_range0_len();
         let extranonce_2 = extranonce[extranonce_1_len..].to_vec();
         match &mut m {
@@ -440,16 +1986,13 @@ impl ChannelFactory {
             "On checking target coinbase suffix is: {:?}",
             coinbase_tx_suffix
         );
-        // Safe unwrap a sha256 can always be converted into [u8;32]
-        let merkle_root: [u8; 32] = crate::utils::merkle_root_from_path(
+        let merkle_root = self.merkle_root(
+            m.get_job_id(),
             coinbase_tx_prefix,
             coinbase_tx_suffix,
             &extranonce[..],
             &merkle_path[..],
-        )
-        .ok_or(Error::InvalidCoinbase)?
-        .try_into()
-        .unwrap();
+        )?;
         let version = match &m {
             Share::Extended(share) => share.version as i32,
             Share::Standard(share) => share.0.version as i32,
@@ -484,8 +2027,46 @@ impl ChannelFactory {
             debug!("Hash           : {:?}", hash.to_vec().as_hex());
         }
         let hash: Target = hash.into();
+        let share_difficulty = crate::utils::target_to_difficulty(&hash);
+        let stats = self.share_stats(m.get_channel_id());
+        stats.best_diff = stats.best_diff.max(share_difficulty);
+        // Shares of channels with block detection disabled are only checked against the upstream
+        // and downstream targets
+        let detect_blocks = !self.block_detection_disabled.contains(&m.get_channel_id());
+        // A share that meets the bitcoin target for a job that already found a block within the
+        // debounce window is only checked against the upstream and downstream targets
+        let debounced =
+            detect_blocks && hash <= bitcoin_target && self.is_block_debounced(m.get_job_id());
+        if debounced {
+            warn!(
+                "Share of channel {} meets the bitcoin target of job {} already found, not flagged as a block",
+                m.get_channel_id(),
+                m.get_job_id()
+            );
+        }
+        let detect_blocks = detect_blocks && !debounced;
+        let meets_bitcoin_target = detect_blocks && hash <= bitcoin_target;
+
+        let accounting = self.share_accounting.entry(m.get_channel_id()).or_default();
+        if meets_bitcoin_target || hash <= upstream_target || hash <= downstream_target {
+            accounting.on_accepted_share(crate::utils::target_to_difficulty(&downstream_target));
+            if meets_bitcoin_target {
+                accounting.on_block_found();
+            }
+            self.share_credit
+                .on_accepted_share(m.get_channel_id(), share_difficulty);
+            let now = self.clock.now();
+            self.last_share_time.insert(m.get_channel_id(), now);
+            let difficulty = self
+                .channel_difficulty(m.get_channel_id())
+                .unwrap_or_default();
+            self.vardiff_state(m.get_channel_id())
+                .on_share(now, difficulty);
+        } else {
+            self.count_rejected_share(m.get_channel_id(), false);
+        }
 
-        if hash <= bitcoin_target {
+        if meets_bitcoin_target {
             let mut print_hash: [u8; 32] = *hash_.to_raw_hash().as_ref();
             print_hash.reverse();
 
@@ -497,6 +2078,13 @@ impl ChannelFactory {
             let coinbase = [coinbase_tx_prefix, &extranonce[..], coinbase_tx_suffix]
                 .concat()
                 .to_vec();
+            if self.found_blocks.len() == FOUND_BLOCKS_HISTORY_LEN {
+                self.found_blocks.pop_front();
+            }
+            let found_header = FoundBlockHeader::from(&header);
+            self.found_blocks
+                .push_back((m.get_job_id(), coinbase.clone(), found_header.clone()));
+            self.last_found_block = Some((m.get_job_id(), self.clock.now()));
             match self.kind {
                 ExtendedChannelKind::Proxy { .. } | ExtendedChannelKind::ProxyJd { .. } => {
                     let upstream_extranonce_space = self.extranonces.get_range0_len();
@@ -506,6 +2094,7 @@ impl ChannelFactory {
                         template_id,
                         coinbase,
                         extranonce.to_vec(),
+                        found_header,
                     ));
                     res.into_extended(extranonce_, up_id);
                     Ok(res)
@@ -515,20 +2104,34 @@ impl ChannelFactory {
                     template_id,
                     coinbase,
                     extranonce.to_vec(),
+                    found_header,
                 ))),
             }
         } else if hash <= upstream_target {
+            // When the bitcoin target is not known (proxy without job declaration) blocks are
+            // detected with the network target derived from nbits
+            let network_target: Target =
+                bitcoin::Target::from_compact(CompactTarget::from_consensus(bits))
+                    .to_le_bytes()
+                    .into();
+            let reason = if detect_blocks && hash <= network_target {
+                ForwardReason::BlockRelay
+            } else {
+                ForwardReason::MetUpstreamTarget
+            };
             match self.kind {
                 ExtendedChannelKind::Proxy { .. } | ExtendedChannelKind::ProxyJd { .. } => {
                     let upstream_extranonce_space = self.extranonces.get_range0_len();
                     let extranonce = extranonce[upstream_extranonce_space..].to_vec();
-                    let mut res = OnNewShare::SendSubmitShareUpstream((m, template_id));
+                    let mut res = OnNewShare::SendSubmitShareUpstream((m, template_id, reason));
                     res.into_extended(extranonce, up_id);
                     Ok(res)
                 }
-                ExtendedChannelKind::Pool => {
-                    Ok(OnNewShare::SendSubmitShareUpstream((m, template_id)))
-                }
+                ExtendedChannelKind::Pool => Ok(OnNewShare::SendSubmitShareUpstream((
+                    m,
+                    template_id,
+                    reason,
+                ))),
             }
         } else if hash <= downstream_target {
             Ok(OnNewShare::ShareMeetDownstreamTarget)
@@ -544,8 +2147,57 @@ impl ChannelFactory {
                     .try_into()
                     .unwrap(),
             };
-            Ok(OnNewShare::SendErrorDownstream(error))
+            Ok(self.reject_share(
+                error,
+                ShareRejectionReason::DifficultyTooLow,
+                Some(share_difficulty),
+            ))
+        }
+    }
+
+    // The answer to a rejected share. When rejection details are enabled the error comes with a
+    // `ShareRejectionDetail`, `achieved_difficulty` is known only if the share has been hashed
+    fn reject_share(
+        &self,
+        error: SubmitSharesError<'static>,
+        reason: ShareRejectionReason,
+        achieved_difficulty: Option<f64>,
+    ) -> OnNewShare {
+        let detail = self.rejection_details.then(|| ShareRejectionDetail {
+            achieved_difficulty,
+            required_difficulty: self.channel_difficulty(error.channel_id),
+            reason,
+        });
+        OnNewShare::SendErrorDownstream((error, detail))
+    }
+
+    // Difficulty of the target of an extended or standard channel
+    fn channel_difficulty(&self, channel_id: u32) -> Option<f64> {
+        let target = match self.extended_channels.get(&channel_id) {
+            Some(channel) => Target::from(channel.target.clone()),
+            None => self.standard_channels.get(&channel_id)?.target.clone(),
+        };
+        Some(crate::utils::target_to_difficulty(&target))
+    }
+
+    /// Returns the full extranonce, extranonce1 (static for channel) + extranonce2 (miner nonce
+    /// space). Fails with [`ExtendedExtranonceError::ExceedsMaxLength`] if the full extranonce
+    /// would be out of the max extranonce len and with
+    /// [`ExtendedExtranonceError::InvalidDownstreamLength`] if `ext` is not as long as the
+    /// extranonce2 space.
+    fn extranonce_from_downstream_extranonce(
+        &self,
+        ext: mining_sv2::Extranonce,
+    ) -> Result<mining_sv2::Extranonce, Error> {
+        let len = self.extranonces.get_prefix_len() + ext.clone().to_vec().len();
+        if len > mining_sv2::MAX_EXTRANONCE_LEN {
+            return Err(Error::ExtranoncePrefixFactoryError(
+                ExtendedExtranonceError::ExceedsMaxLength,
+            ));
         }
+        self.extranonces
+            .extranonce_from_downstream_extranonce(ext)
+            .map_err(Error::ExtranoncePrefixFactoryError)
     }
 
     /// Returns the downstream target and extranonce for the channel
@@ -558,341 +2210,2547 @@ impl ChannelFactory {
                 let extranonce = [&extranonce_prefix[..], &share.extranonce.to_vec()[..]]
                     .concat()
                     .to_vec();
-                if extranonce.len() != self.extranonces.get_len() {
-                    error!(
-                        "Extranonce is not of the right len expected {} actual {}",
-                        self.extranonces.get_len(),
-                        extranonce.len()
-                    );
-                }
                 Some((dowstream_target, extranonce))
             }
-            Share::Standard((_share, _group_id)) => {
-                unimplemented!()
+            Share::Standard((share, _group_id)) => {
+                let channel = self.standard_channels.get(&share.channel_id)?;
+                Some((channel.target.clone(), channel.extranonce.clone()))
             }
         }
     }
-    /// Updates the downstream target for the given channel_id
-    fn update_target_for_channel(&mut self, channel_id: u32, new_target: Target) -> Option<bool> {
-        let channel = self.extended_channels.get_mut(&channel_id)?;
-        channel.target = new_target.into();
-        Some(true)
-    }
-}
-
-/// Used by a pool to in order to manage all downstream channel. It adds job creation capabilities
-/// to ChannelFactory.
-#[derive(Debug)]
-pub struct PoolChannelFactory {
-    inner: ChannelFactory,
-    job_creator: JobsCreators,
-    pool_coinbase_outputs: Vec<TxOut>,
-    // extended_channel_id -> SetCustomMiningJob
-    negotiated_jobs: HashMap<u32, SetCustomMiningJob<'static>, BuildNoHashHasher<u32>>,
-}
-
-impl PoolChannelFactory {
-    /// constructor
-    pub fn new(
-        ids: Arc<Mutex<GroupId>>,
-        extranonces: ExtendedExtranonce,
-        job_creator: JobsCreators,
-        share_per_min: f32,
-        kind: ExtendedChannelKind,
-        pool_coinbase_outputs: Vec<TxOut>,
-    ) -> Self {
-        let inner = ChannelFactory {
-            ids,
-            extended_channels: HashMap::with_hasher(BuildNoHashHasher::default()),
-            extranonces,
-            share_per_min,
-            future_jobs: Vec::new(),
-            last_prev_hash: None,
-            last_prev_hash_: None,
-            last_valid_job: None,
-            kind,
-            job_ids: Id::new(),
-            channel_to_group_id: HashMap::with_hasher(BuildNoHashHasher::default()),
-            future_templates: HashMap::with_hasher(BuildNoHashHasher::default()),
-        };
-
-        Self {
-            inner,
-            job_creator,
-            pool_coinbase_outputs,
-            negotiated_jobs: HashMap::with_hasher(BuildNoHashHasher::default()),
-        }
-    }
 
-    /// Calls [`ChannelFactory::new_extended_channel`]
-    pub fn new_extended_channel(
+    // Registers a standard channel in `group_id` and assigns it the next standard extranonce.
+    // Returns the full extranonce of the channel.
+    fn add_standard_channel(
         &mut self,
-        request_id: u32,
-        hash_rate: f32,
-        min_extranonce_size: u16,
-    ) -> Result<Vec<Mining<'static>>, Error> {
-        self.inner
-            .new_extended_channel(request_id, hash_rate, min_extranonce_size)
+        channel_id: u32,
+        group_id: u32,
+        target: Target,
+    ) -> Result<mining_sv2::Extranonce, Error> {
+        let extranonce = self
+            .next_standard_extranonce()
+            .map_err(Error::ExtranoncePrefixFactoryError)?;
+        self.insert_standard_channel(channel_id, group_id, target, &extranonce);
+        Ok(extranonce)
     }
 
-    /// Called when we want to replicate a channel already opened by another actor.
-    /// is used only in the jd client from the template provider module to mock a pool.
-    /// Anything else should open channel with the new_extended_channel function
-    pub fn replicate_upstream_extended_channel_only_jd(
+    // Registers a standard channel in `group_id` with the full extranonce `extranonce`
+    fn insert_standard_channel(
         &mut self,
-        target: binary_sv2::U256<'static>,
-        extranonce: mining_sv2::Extranonce,
         channel_id: u32,
-        extranonce_size: u16,
-    ) -> Option<()> {
-        self.inner.replicate_upstream_extended_channel_only_jd(
-            target,
-            extranonce,
+        group_id: u32,
+        target: Target,
+        extranonce: &mining_sv2::Extranonce,
+    ) {
+        self.channel_to_group_id.insert(channel_id, group_id);
+        self.recently_closed_channels.remove(&channel_id);
+        self.standard_channels.insert(
             channel_id,
-            extranonce_size,
-        )
+            StandardChannel {
+                group_id,
+                target,
+                extranonce: extranonce.clone().to_vec(),
+            },
+        );
+        self.share_accounting
+            .insert(channel_id, ShareAccounting::new());
+    }
+    /// Overrides the downstream, upstream and bitcoin targets used by `check_target`, so that
+    /// each outcome can be exercised without mining.
+    #[cfg(any(test, feature = "test_targets"))]
+    fn set_test_targets(&mut self, downstream: Target, upstream: Target, bitcoin: Target) {
+        self.test_targets = Some((downstream, upstream, bitcoin));
     }
 
-    /// Called only when a new prev hash is received by a Template Provider. It matches the
-    /// message with a `job_id` and calls [`ChannelFactory::on_new_prev_hash`]
-    /// it return the job_id
-    pub fn on_new_prev_hash_from_tp(
-        &mut self,
+    /// Returns the extranonce2 size granted to the channel in `OpenExtendedMiningChannelSuccess`
+    fn channel_extranonce2_size(&self, channel_id: u32) -> Option<usize> {
+        self.extended_channels
+            .get(&channel_id)
+            .map(|channel| channel.extranonce_size as usize)
+    }
+
+    /// Returns a fingerprint of the extended channel that does not depend on its id: the first 16
+    /// bytes of the sha256 of its extranonce prefix followed by its extranonce size (u16 little
+    /// endian). None if the channel does not exist.
+    fn channel_fingerprint(&self, channel_id: u32) -> Option<[u8; 16]> {
+        let channel = self.extended_channels.get(&channel_id)?;
+        let data = [
+            channel.extranonce_prefix.inner_as_ref(),
+            &channel.extranonce_size.to_le_bytes()[..],
+        ]
+        .concat();
+        let hash = sha256::Hash::hash(&data).to_byte_array();
+        let mut fingerprint = [0; 16];
+        fingerprint.copy_from_slice(&hash[..16]);
+        Some(fingerprint)
+    }
+
+    /// Assigns `new_prefix` to the extended channel and returns the `SetExtranoncePrefix` that
+    /// must be sent to the downstream, the shares of the channel are then checked with the new
+    /// prefix. The prefix must leave to the channel its extranonce2 size, start with the upstream
+    /// part of the extranonce, not overlap the prefix of another extended channel and not be
+    /// above the last prefix handed out to a new channel.
+    fn set_extranonce_prefix(
+        &mut self,
+        channel_id: u32,
+        new_prefix: Vec<u8>,
+    ) -> Result<Mining<'static>, Error> {
+        let prefix_len = match self.extended_channels.get(&channel_id) {
+            Some(channel) => self.extranonces.get_len() - channel.extranonce_size as usize,
+            None => return Err(Error::NotFoundChannelId),
+        };
+        let range_0_len = self.extranonces.get_range0_len();
+        // Last prefix handed out by the allocator, range_0 followed by range_1
+        let last_prefix = self.extranonces.upstream_part().to_vec();
+        if new_prefix.len() != prefix_len {
+            error!(
+                "Extranonce prefix for channel {} has len {}, expected {}",
+                channel_id,
+                new_prefix.len(),
+                prefix_len
+            );
+            return Err(Error::InvalidExtranoncePrefix(channel_id));
+        }
+        if new_prefix[..range_0_len] != last_prefix[..range_0_len] {
+            error!(
+                "Extranonce prefix for channel {} is outside of the upstream range",
+                channel_id
+            );
+            return Err(Error::InvalidExtranoncePrefix(channel_id));
+        }
+        // The allocator hands out increasing prefixes, one that it has not handed out yet would be
+        // handed out again to a new channel
+        if new_prefix[range_0_len..last_prefix.len()] > last_prefix[range_0_len..] {
+            error!(
+                "Extranonce prefix for channel {} has not been handed out yet",
+                channel_id
+            );
+            return Err(Error::InvalidExtranoncePrefix(channel_id));
+        }
+        if self.extended_channels.iter().any(|(id, channel)| {
+            let prefix = channel.extranonce_prefix.inner_as_ref();
+            *id != channel_id && (prefix.starts_with(&new_prefix) || new_prefix.starts_with(prefix))
+        }) || self
+            .standard_channels
+            .values()
+            .any(|channel| channel.extranonce.starts_with(&new_prefix))
+        {
+            error!(
+                "Extranonce prefix for channel {} is used by another channel",
+                channel_id
+            );
+            return Err(Error::InvalidExtranoncePrefix(channel_id));
+        }
+        let extranonce_prefix: binary_sv2::B032<'static> = new_prefix.try_into()?;
+        // Infallible unwrap we already checked that the channel exists
+        let channel = self.extended_channels.get_mut(&channel_id).unwrap();
+        channel.extranonce_prefix = extranonce_prefix.clone();
+        Ok(Mining::SetExtranoncePrefix(SetExtranoncePrefix {
+            channel_id,
+            extranonce_prefix,
+        }))
+    }
+
+    /// Returns the job with the given id, looking both at the valid job and at the future jobs
+    fn job_by_id(&self, job_id: u32) -> Option<&NewExtendedMiningJob<'static>> {
+        self.last_valid_job
+            .iter()
+            .chain(self.future_jobs.iter())
+            .map(|(job, _)| job)
+            .find(|job| job.job_id == job_id)
+    }
+
+    /// When strict job id enforcement is on and the share does not reference `job_id` returns
+    /// the error that must be sent downstream: `stale-share` if the share references a job that
+    /// has been superseded, `invalid-job-id` if it references an unknown job
+    fn check_job_id(&mut self, m: &Share, job_id: u32) -> Option<OnNewShare> {
+        if !self.strict_job_id || m.get_job_id() == job_id {
+            return None;
+        }
+        error!(
+            "Share for job {} but the last valid job is {}",
+            m.get_job_id(),
+            job_id
+        );
+        Some(self.reject_job_id(m))
+    }
+
+    /// Returns the error for a share that references a job that can not be mined on the
+    /// channel: `stale-share` if the job has been superseded, `invalid-job-id` otherwise
+    fn reject_job_id(&mut self, m: &Share) -> OnNewShare {
+        let stale = self.stale_job_ids.contains(&m.get_job_id());
+        let (error_code, reason) = match stale {
+            true => (
+                SubmitSharesError::stale_share_error_code(),
+                ShareRejectionReason::StaleJob,
+            ),
+            false => (
+                SubmitSharesError::invalid_job_id_error_code(),
+                ShareRejectionReason::InvalidJobId,
+            ),
+        };
+        self.count_rejected_share(m.get_channel_id(), stale);
+        self.reject_share(submit_shares_error(m, error_code), reason, None)
+    }
+
+    /// Removes the channel and every data kept for it and frees its id, so that it can be given to
+    /// a new channel once every other channel id has been handed out. If closed channels are
+    /// remembered the channel is marked as recently closed. Returns false if the channel does not
+    /// exist.
+    fn close_channel(&mut self, channel_id: u32) -> bool {
+        let existed = self.channel_to_group_id.remove(&channel_id).is_some()
+            | self.extended_channels.remove(&channel_id).is_some()
+            | self.standard_channels.remove(&channel_id).is_some();
+        self.share_accounting.remove(&channel_id);
+        self.channel_flags.remove(&channel_id);
+        self.channel_open_time.remove(&channel_id);
+        self.block_detection_disabled.remove(&channel_id);
+        self.recent_shares.remove(&channel_id);
+        self.channel_stats.remove(&channel_id);
+        self.last_share_time.remove(&channel_id);
+        self.vardiff.remove(&channel_id);
+        if let Some(diversity) = self.extranonce2_diversity.as_mut() {
+            diversity.remove(&channel_id);
+        }
+        if existed {
+            if let Err(e) = self.ids.safe_lock(|ids| ids.free_channel_id(channel_id)) {
+                error!("Impossible to free the id of channel {}: {}", channel_id, e);
+            }
+        }
+        if existed && self.closed_channel_ttl.is_some() {
+            let now = self.clock.now();
+            self.recently_closed_channels.insert(channel_id, now);
+        }
+        existed
+    }
+
+    // Error for a share of a channel that is not open: `channel-closed` if the channel has been
+    // closed less than `closed_channel_ttl` ago, `invalid-channel-id` otherwise
+    fn unknown_channel_error(
+        &mut self,
+        channel_id: u32,
+        sequence_number: u32,
+    ) -> SubmitSharesError<'static> {
+        let now = self.clock.now();
+        let error_code = match self.closed_channel_ttl {
+            Some(ttl) => {
+                self.recently_closed_channels
+                    .retain(|_, closed_at| now.saturating_duration_since(*closed_at) < ttl);
+                match self.recently_closed_channels.contains_key(&channel_id) {
+                    true => CHANNEL_CLOSED_ERROR_CODE,
+                    false => SubmitSharesError::invalid_channel_error_code(),
+                }
+            }
+            None => SubmitSharesError::invalid_channel_error_code(),
+        };
+        SubmitSharesError {
+            channel_id,
+            sequence_number,
+            // Infallible unwrap error codes are static strings shorter than 255 bytes
+            error_code: error_code.to_string().try_into().unwrap(),
+        }
+    }
+
+    // Records that the jobs with `job_id`, the active one or the future ones, have been delivered
+    // to the channel. Returns false if the channel or the job does not exist.
+    fn mark_job_delivered(&mut self, channel_id: u32, job_id: u32) -> bool {
+        if !self.extended_channels.contains_key(&channel_id) {
+            return false;
+        }
+        let mut found = false;
+        for (job, delivered) in self
+            .last_valid_job
+            .iter_mut()
+            .chain(self.future_jobs.iter_mut())
+        {
+            if job.job_id == job_id {
+                found = true;
+                if !delivered.contains(&channel_id) {
+                    delivered.push(channel_id);
+                }
+            }
+        }
+        found
+    }
+
+    // Records that the current prev hash has been delivered to the channel. Returns false if the
+    // channel does not exist or there is no prev hash.
+    fn mark_prev_hash_delivered(&mut self, channel_id: u32) -> bool {
+        if !self.extended_channels.contains_key(&channel_id) {
+            return false;
+        }
+        match self.last_prev_hash.as_mut() {
+            Some((_, delivered)) => {
+                if !delivered.contains(&channel_id) {
+                    delivered.push(channel_id);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Messages that bring an extended channel up to date, skipping the jobs and the prev hash
+    // already marked as delivered to it. The active job is sent as future when the prev hash that
+    // activates it is sent too.
+    fn undelivered_messages(&self, channel_id: u32) -> Vec<Mining<'static>> {
+        let mut messages = vec![];
+        if !self.extended_channels.contains_key(&channel_id) {
+            return messages;
+        }
+        let version_rolling = self.channel_version_rolling(channel_id);
+        let prev_hash_undelivered = self
+            .last_prev_hash
+            .as_ref()
+            .is_some_and(|(_, delivered)| !delivered.contains(&channel_id));
+        if let Some((job, delivered)) = &self.last_valid_job {
+            if !delivered.contains(&channel_id) {
+                let mut job = job.clone();
+                job.channel_id = channel_id;
+                job.version_rolling_allowed &= version_rolling;
+                if prev_hash_undelivered {
+                    job.set_future();
+                }
+                messages.push(Mining::NewExtendedMiningJob(job));
+            }
+        }
+        if let (true, Some((prev_hash, _))) = (prev_hash_undelivered, &self.last_prev_hash) {
+            let job_id = self.last_valid_job.as_ref().map(|(job, _)| job.job_id);
+            messages.push(Mining::SetNewPrevHash(
+                prev_hash.into_set_p_hash(channel_id, job_id),
+            ));
+        }
+        for (job, delivered) in &self.future_jobs {
+            if !delivered.contains(&channel_id) {
+                let mut job = job.clone();
+                job.channel_id = channel_id;
+                job.version_rolling_allowed &= version_rolling;
+                messages.push(Mining::NewExtendedMiningJob(job));
+            }
+        }
+        messages
+    }
+
+    // Channels without flags (standard channels) can roll the version
+    fn channel_version_rolling(&self, channel_id: u32) -> bool {
+        self.channel_flags
+            .get(&channel_id)
+            .is_none_or(|flags| flags.version_rolling)
+    }
+
+    /// Updates the downstream target for the given extended or standard channel_id
+    fn update_target_for_channel(&mut self, channel_id: u32, new_target: Target) -> Option<bool> {
+        match self.extended_channels.get_mut(&channel_id) {
+            Some(channel) => channel.target = new_target.into(),
+            None => self.standard_channels.get_mut(&channel_id)?.target = new_target,
+        }
+        // The share rate observed with the old target is meaningless for the new one
+        let now = self.clock.now();
+        if let Some(vardiff) = self.vardiff.get_mut(&channel_id) {
+            vardiff.reset(now);
+        }
+        Some(true)
+    }
+
+    /// Updates the downstream target for the given channel_id and returns the `SetTarget` that
+    /// tells the downstream its new target
+    fn update_target_for_channel_with_message(
+        &mut self,
+        channel_id: u32,
+        new_target: Target,
+    ) -> Option<Mining<'static>> {
+        self.update_target_for_channel(channel_id, new_target.clone())?;
+        Some(Mining::SetTarget(SetTarget {
+            channel_id,
+            maximum_target: new_target.into(),
+        }))
+    }
+
+    // Returns the stats of the channel of a share that is being checked, updating the time of its
+    // last share
+    fn share_stats(&mut self, channel_id: u32) -> &mut ShareStats {
+        let now = self.clock.unix_time() as u32;
+        let stats = self.channel_stats.entry(channel_id).or_default();
+        stats.last_share_time = now;
+        stats
+    }
+
+    // Counts a rejected share in the accounting of the channel, `stale` if the share references a
+    // superseded job or prev hash
+    fn count_rejected_share(&mut self, channel_id: u32, stale: bool) {
+        self.share_accounting
+            .entry(channel_id)
+            .or_default()
+            .on_rejected_share();
+        let stats = self.share_stats(channel_id);
+        if stale {
+            stats.stale += 1;
+        }
+    }
+
+    /// True if an extended or standard channel with this id is open
+    fn has_channel(&self, channel_id: u32) -> bool {
+        self.extended_channels.contains_key(&channel_id)
+            || self.standard_channels.contains_key(&channel_id)
+    }
+
+    /// Returns the group of an open channel, 0 for the extended channels, None if the channel is
+    /// not open
+    fn channel_group(&self, channel_id: u32) -> Option<u32> {
+        self.channel_to_group_id.get(&channel_id).copied()
+    }
+
+    /// Returns the metadata of every extended and standard channel, ordered by channel id
+    fn channels_snapshot(&self) -> Vec<ChannelInfo> {
+        let group_id = |channel_id: u32| *self.channel_to_group_id.get(&channel_id).unwrap_or(&0);
+        let extended_channels =
+            self.extended_channels
+                .iter()
+                .map(|(channel_id, channel)| ChannelInfo {
+                    channel_id: *channel_id,
+                    group_id: group_id(*channel_id),
+                    target: channel.target.clone().into(),
+                    extranonce_prefix: channel.extranonce_prefix.to_vec(),
+                    extranonce_size: channel.extranonce_size,
+                });
+        let standard_channels =
+            self.standard_channels
+                .iter()
+                .map(|(channel_id, channel)| ChannelInfo {
+                    channel_id: *channel_id,
+                    group_id: channel.group_id,
+                    target: channel.target.clone(),
+                    extranonce_prefix: channel.extranonce.clone(),
+                    extranonce_size: 0,
+                });
+        let mut channels: Vec<ChannelInfo> = extended_channels.chain(standard_channels).collect();
+        channels.sort_unstable_by_key(|channel| channel.channel_id);
+        channels
+    }
+
+    /// Returns the share counters of the channel, None if the channel does not exist. Accepted
+    /// and rejected shares come from the [`ShareAccounting`] of the channel.
+    fn get_channel_stats(&self, channel_id: u32) -> Option<ChannelStats> {
+        if !self.channel_to_group_id.contains_key(&channel_id) {
+            return None;
+        }
+        let accounting = self.share_accounting.get(&channel_id);
+        let stats = self
+            .channel_stats
+            .get(&channel_id)
+            .copied()
+            .unwrap_or_default();
+        Some(ChannelStats {
+            accepted: accounting.map_or(0, |accounting| accounting.shares_accepted()),
+            rejected: accounting
+                .map_or(0, |accounting| accounting.shares_rejected())
+                .saturating_sub(stats.stale),
+            stale: stats.stale,
+            best_diff: stats.best_diff,
+            last_share_time: stats.last_share_time,
+        })
+    }
+
+    // Returns the vardiff state of the channel, the first observation window starts when the
+    // channel is opened
+    fn vardiff_state(&mut self, channel_id: u32) -> &mut VardiffState {
+        let window_start = self
+            .channel_open_time
+            .get(&channel_id)
+            .copied()
+            .unwrap_or_else(|| self.clock.now());
+        self.vardiff
+            .entry(channel_id)
+            .or_insert_with(|| VardiffState::new(window_start))
+    }
+
+    /// Compares the rate of the shares accepted on the extended channel since the last adjustment
+    /// with `share_per_min` and, if they differ enough, retargets the channel to the hashrate
+    /// implied by the observed rate. Adjustments are at least `VARDIFF_MIN_INTERVAL` apart and
+    /// change the difficulty by at most `VARDIFF_MAX_STEP` times, smoothed by
+    /// `VARDIFF_SMOOTHING`. Returns the new target, None if the channel does not exist or the
+    /// target is not changed.
+    fn try_vardiff(&mut self, channel_id: u32) -> Option<Target> {
+        let current_target = self.extended_channels.get(&channel_id)?.target.clone();
+        let share_per_min = self.share_per_min as f64;
+        let now = self.clock.now();
+        let vardiff = self.vardiff_state(channel_id);
+        let elapsed = now.saturating_duration_since(vardiff.window_start);
+        if elapsed < VARDIFF_MIN_INTERVAL {
+            return None;
+        }
+        let observed_share_per_min =
+            vardiff.share_times.len() as f64 * 60.0 / elapsed.as_secs_f64();
+        vardiff.reset(now);
+        let change = (observed_share_per_min / share_per_min)
+            .clamp(1.0 / VARDIFF_MAX_STEP, VARDIFF_MAX_STEP)
+            .powf(VARDIFF_SMOOTHING);
+        if (change - 1.0).abs() < VARDIFF_TOLERANCE {
+            return None;
+        }
+        // The hashrate implied by the target is proportional to its difficulty
+        let difficulty = crate::utils::target_to_difficulty(&current_target.into());
+        let new_target = crate::utils::difficulty_to_target(difficulty * change);
+        debug!(
+            "Vardiff: channel {} observed {} shares per minute, difficulty {} -> {}",
+            channel_id,
+            observed_share_per_min,
+            difficulty,
+            difficulty * change
+        );
+        self.update_target_for_channel(channel_id, new_target.clone())?;
+        Some(new_target)
+    }
+
+    /// Estimates the hashrate of the channel, in hashes per second, from its last
+    /// `HASHRATE_ESTIMATE_MAX_SHARES` accepted shares: `work per second * 2^32`, with the work of
+    /// a share being the channel difficulty when it was accepted and the rate measured between the
+    /// first and the last share. The shares are kept across target changes, so the estimate does
+    /// not restart with vardiff. None if the channel does not exist or has less than
+    /// `HASHRATE_ESTIMATE_MIN_SHARES` shares.
+    fn estimated_hashrate(&self, channel_id: u32) -> Option<f64> {
+        if !self.extended_channels.contains_key(&channel_id)
+            && !self.standard_channels.contains_key(&channel_id)
+        {
+            return None;
+        }
+        let samples = &self.vardiff.get(&channel_id)?.hashrate_samples;
+        if samples.len() < HASHRATE_ESTIMATE_MIN_SHARES {
+            return None;
+        }
+        let elapsed = samples
+            .back()?
+            .0
+            .saturating_duration_since(samples.front()?.0)
+            .as_secs_f64();
+        if elapsed == 0.0 {
+            return None;
+        }
+        // The first share only starts the measurement
+        let work: f64 = samples
+            .iter()
+            .skip(1)
+            .map(|(_, difficulty)| difficulty)
+            .sum();
+        Some(work / elapsed * 2_f64.powi(32))
+    }
+}
+
+// A job that has been superseded by a new prev hash, with the prev hash, nbits and bitcoin target
+// it was mined on
+#[derive(Debug, Clone)]
+struct PreviousTip {
+    job: NewExtendedMiningJob<'static>,
+    prev_hash: StagedPhash,
+    bitcoin_target: Target,
+}
+
+/// Used by a pool to in order to manage all downstream channel. It adds job creation capabilities
+/// to ChannelFactory.
+#[derive(Debug)]
+pub struct PoolChannelFactory {
+    inner: ChannelFactory,
+    job_creator: JobsCreators,
+    pool_coinbase_outputs: Vec<TxOut>,
+    // extended_channel_id -> (job id given to the job in SetCustomMiningJobSuccess,
+    // SetCustomMiningJob)
+    negotiated_jobs: HashMap<u32, (u32, SetCustomMiningJob<'static>), BuildNoHashHasher<u32>>,
+    // extended_channel_id -> job id of the negotiated job dropped because it was built on a prev
+    // hash that is not the current one anymore, shares for it are stale
+    stale_negotiated_jobs: HashMap<u32, u32, BuildNoHashHasher<u32>>,
+    // Max number of outputs accepted in templates and custom jobs coinbases
+    max_coinbase_outputs: usize,
+    // Max fees that a template can claim on top of the subsidy, None if not checked
+    max_template_fees: Option<u64>,
+    // Policy for the custom jobs coinbase outputs, None if they must contain every pool output
+    coinbase_output_policy: Option<CoinbaseOutputPolicy>,
+    // Jobs of the last tips, oldest first, shares for them are checked against the tip they were
+    // mined on
+    previous_tips: VecDeque<PreviousTip>,
+    // Template id of the last prev hash when it has been received before its template, the
+    // template is mined on the prev hash as soon as it arrives
+    template_after_prev_hash: Option<u64>,
+    // Records the inputs of the factory when recording is enabled
+    input_recorder: Option<InputRecorder>,
+    // Hardest max target that downstreams can request with an UpdateChannel, None if any
+    min_downstream_target: Option<Target>,
+}
+
+impl PoolChannelFactory {
+    /// constructor
+    pub fn new(
+        ids: Arc<Mutex<GroupId>>,
+        extranonces: ExtendedExtranonce,
+        job_creator: JobsCreators,
+        share_per_min: f32,
+        kind: ExtendedChannelKind,
+        pool_coinbase_outputs: Vec<TxOut>,
+    ) -> Self {
+        let inner = ChannelFactory {
+            ids,
+            extended_channels: HashMap::with_hasher(BuildNoHashHasher::default()),
+            extranonces,
+            share_per_min,
+            future_jobs: Vec::new(),
+            max_future_jobs: usize::MAX,
+            last_prev_hash: None,
+            last_valid_job: None,
+            stale_job_ids: VecDeque::with_capacity(STALE_JOB_IDS_HISTORY_LEN),
+            kind,
+            channel_to_group_id: HashMap::with_hasher(BuildNoHashHasher::default()),
+            standard_channels: HashMap::with_hasher(BuildNoHashHasher::default()),
+            future_templates: HashMap::with_hasher(BuildNoHashHasher::default()),
+            accepting_new_channels: true,
+            strict_job_id: true,
+            extranonce2_diversity: None,
+            share_credit: Box::new(NoShareCredit),
+            on_new_job: None,
+            clock: Box::new(SystemClock),
+            channel_open_time: HashMap::with_hasher(BuildNoHashHasher::default()),
+            last_extranonce_grant: None,
+            reduced_extranonce_prefixes: HashMap::with_hasher(BuildNoHashHasher::default()),
+            last_standard_extranonce: None,
+            block_detection_disabled: HashSet::with_hasher(BuildNoHashHasher::default()),
+            recent_shares: HashMap::with_hasher(BuildNoHashHasher::default()),
+            channel_stats: HashMap::with_hasher(BuildNoHashHasher::default()),
+            last_share_time: HashMap::with_hasher(BuildNoHashHasher::default()),
+            vardiff: HashMap::with_hasher(BuildNoHashHasher::default()),
+            found_blocks: VecDeque::with_capacity(FOUND_BLOCKS_HISTORY_LEN),
+            block_debounce: None,
+            last_found_block: None,
+            closed_channel_ttl: None,
+            recently_closed_channels: HashMap::with_hasher(BuildNoHashHasher::default()),
+            share_accounting: HashMap::with_hasher(BuildNoHashHasher::default()),
+            channel_flags: HashMap::with_hasher(BuildNoHashHasher::default()),
+            rejection_details: false,
+            max_ntime_drift: Some(DEFAULT_MAX_NTIME_DRIFT),
+            merkle_caches: HashMap::with_hasher(BuildNoHashHasher::default()),
+            lazy_merkle_roots: false,
+            version_rolling_mask: VERSION_ROLLING_MASK,
+            #[cfg(any(test, feature = "test_targets"))]
+            test_targets: None,
+        };
+
+        Self {
+            inner,
+            job_creator,
+            pool_coinbase_outputs,
+            negotiated_jobs: HashMap::with_hasher(BuildNoHashHasher::default()),
+            stale_negotiated_jobs: HashMap::with_hasher(BuildNoHashHasher::default()),
+            max_coinbase_outputs: usize::MAX,
+            max_template_fees: None,
+            coinbase_output_policy: None,
+            previous_tips: VecDeque::with_capacity(PREVIOUS_TIPS_HISTORY_LEN),
+            template_after_prev_hash: None,
+            input_recorder: None,
+            min_downstream_target: None,
+        }
+    }
+
+    fn record_input(&mut self, input: impl FnOnce() -> RecordedInput) {
+        if let Some(recorder) = self.input_recorder.as_mut() {
+            recorder.record(
+                input(),
+                self.inner.clock.now(),
+                self.inner.clock.unix_time(),
+            );
+        }
+    }
+
+    /// Calls [`ChannelFactory::new_extended_channel`]
+    pub fn new_extended_channel(
+        &mut self,
+        request_id: u32,
+        hash_rate: f32,
+        min_extranonce_size: u16,
+        flags: ConnectionFlags,
+    ) -> Result<Vec<Mining<'static>>, Error> {
+        self.record_input(|| RecordedInput::OpenExtendedChannel {
+            request_id,
+            hash_rate,
+            min_extranonce_size,
+            flags,
+        });
+        self.inner
+            .new_extended_channel(request_id, hash_rate, min_extranonce_size, flags)
+    }
+
+    /// Calls [`ChannelFactory::new_extended_channel_structured`]
+    pub fn new_extended_channel_structured(
+        &mut self,
+        request_id: u32,
+        hash_rate: f32,
+        min_extranonce_size: u16,
+        flags: ConnectionFlags,
+    ) -> Result<Result<NewExtendedChannel, OpenMiningChannelError<'static>>, Error> {
+        self.record_input(|| RecordedInput::OpenExtendedChannel {
+            request_id,
+            hash_rate,
+            min_extranonce_size,
+            flags,
+        });
+        self.inner.new_extended_channel_structured(
+            request_id,
+            hash_rate,
+            min_extranonce_size,
+            flags,
+        )
+    }
+
+    /// Calls [`ChannelFactory::new_standard_channel`]
+    pub fn new_standard_channel(
+        &mut self,
+        request_id: u32,
+        hash_rate: f32,
+        group_id: u32,
+    ) -> Result<Vec<Mining<'static>>, Error> {
+        self.record_input(|| RecordedInput::OpenStandardChannel {
+            request_id,
+            hash_rate,
+            group_id,
+        });
+        self.inner
+            .new_standard_channel(request_id, hash_rate, group_id)
+    }
+
+    /// Calls [`ChannelFactory::close_channel`] and drops the custom job negotiated on the channel
+    pub fn close_channel(&mut self, channel_id: u32) -> bool {
+        self.record_input(|| RecordedInput::CloseChannel(channel_id));
+        let negotiated_job = self.negotiated_jobs.remove(&channel_id).is_some();
+        self.stale_negotiated_jobs.remove(&channel_id);
+        self.inner.close_channel(channel_id) || negotiated_job
+    }
+
+    /// Called when a `CloseChannel` message is received from the downstream, the reason is logged
+    /// and the channel is closed with [`PoolChannelFactory::close_channel`]. Fails with
+    /// [`Error::NotFoundChannelId`] if the channel is not open.
+    pub fn on_close_channel(&mut self, m: CloseChannel) -> Result<(), Error> {
+        info!(
+            "Downstream closed channel {}: {}",
+            m.channel_id,
+            m.reason_code.as_utf8_or_hex()
+        );
+        match self.close_channel(m.channel_id) {
+            true => Ok(()),
+            false => Err(Error::NotFoundChannelId),
+        }
+    }
+
+    /// Set for how long closed channels are remembered, shares for a channel closed less than
+    /// `ttl` ago are rejected with `channel-closed` in place of `invalid-channel-id`. None, the
+    /// default, disables it.
+    pub fn set_closed_channel_ttl(&mut self, ttl: Option<Duration>) {
+        self.inner.closed_channel_ttl = ttl;
+        if ttl.is_none() {
+            self.inner.recently_closed_channels.clear();
+        }
+    }
+
+    /// Sets the window during which, after a share has been flagged as a block, further shares
+    /// meeting the bitcoin target for the same job are accepted without being flagged as
+    /// blocks. With `None` (the default) every share meeting the bitcoin target is a block.
+    pub fn set_block_submission_debounce(&mut self, window: Option<Duration>) {
+        self.inner.block_debounce = window;
+    }
+
+    /// Enable or disable, enabled by default, the check of the channel shares against the bitcoin
+    /// target. Shares of a channel with block detection disabled never meet the bitcoin target,
+    /// they are only checked against the upstream and downstream targets.
+    pub fn set_block_detection(&mut self, channel_id: u32, enabled: bool) {
+        match enabled {
+            true => self.inner.block_detection_disabled.remove(&channel_id),
+            false => self.inner.block_detection_disabled.insert(channel_id),
+        };
+    }
+
+    /// Called when we want to replicate a channel already opened by another actor.
+    /// is used only in the jd client from the template provider module to mock a pool.
+    /// Anything else should open channel with the new_extended_channel function.
+    /// Returns None if the extranonce does not fit the factory extranonce ranges, see
+    /// [`ChannelFactory::replicate_upstream_extended_channel_only_jd`].
+    pub fn replicate_upstream_extended_channel_only_jd(
+        &mut self,
+        target: binary_sv2::U256<'static>,
+        extranonce: mining_sv2::Extranonce,
+        channel_id: u32,
+        extranonce_size: u16,
+    ) -> Option<()> {
+        self.inner.replicate_upstream_extended_channel_only_jd(
+            target,
+            extranonce,
+            channel_id,
+            extranonce_size,
+        )
+    }
+
+    /// Called only when a new prev hash is received by a Template Provider. It matches the
+    /// message with a `job_id` and calls [`ChannelFactory::on_new_prev_hash`], it returns the
+    /// `SetNewPrevHash` to send to each channel.
+    pub fn on_new_prev_hash_from_tp(
+        &mut self,
         m: &SetNewPrevHashFromTp<'static>,
-    ) -> Result<u32, Error> {
-        let job_id = self.job_creator.on_new_prev_hash(m).unwrap_or(0);
+    ) -> Result<HashMap<u32, Mining<'static>, BuildNoHashHasher<u32>>, Error> {
+        self.record_input(|| RecordedInput::NewPrevHash(m.clone()));
+        check_nbits(m.n_bits)?;
+        let previous_tip = self.current_tip();
+        let job_id = self.job_creator.on_new_prev_hash(m);
+        self.template_after_prev_hash = match job_id {
+            Some(_) => None,
+            None => {
+                warn!(
+                    "Prev hash received before template {}, the template will be mined on it",
+                    m.template_id
+                );
+                Some(m.template_id)
+            }
+        };
+        let job_id = job_id.unwrap_or(0);
         let new_prev_hash = StagedPhash {
             job_id,
             prev_hash: m.prev_hash.clone(),
             min_ntime: m.header_timestamp,
             nbits: m.n_bits,
         };
-        self.inner.on_new_prev_hash(new_prev_hash)?;
-        Ok(job_id)
+        let messages = self.inner.on_new_prev_hash(new_prev_hash)?;
+        self.remember_previous_tip(previous_tip);
+        // The shares of the previous tips are kept until the tips are evicted
+        let live_job_ids = self.live_job_ids();
+        self.inner.retain_recent_shares(&live_job_ids);
+        self.drop_expired_template_mappings();
+        self.drop_stale_negotiated_jobs(&m.prev_hash);
+        Ok(messages)
+    }
+
+    // Returns the ids of the jobs that shares can still reference, the ones of the factory and the
+    // negotiated jobs and the jobs of the previous tips
+    fn live_job_ids(&self) -> Vec<u32> {
+        let mut live_job_ids = self.inner.live_job_ids();
+        live_job_ids.extend(self.negotiated_jobs.values().map(|(job_id, _)| *job_id));
+        live_job_ids.extend(self.stale_negotiated_jobs.values().copied());
+        live_job_ids.extend(self.previous_tips.iter().map(|tip| tip.job.job_id));
+        live_job_ids
+    }
+
+    // Drops the template mappings of the jobs outside of the retained prev hash window, only the
+    // jobs of the previous tips, the active job and the future jobs are kept
+    fn drop_expired_template_mappings(&mut self) {
+        let retained_job_ids: Vec<u32> = self
+            .previous_tips
+            .iter()
+            .map(|tip| tip.job.job_id)
+            .chain(self.inner.last_valid_job.iter().map(|(job, _)| job.job_id))
+            .chain(self.inner.future_jobs.iter().map(|(job, _)| job.job_id))
+            .collect();
+        self.job_creator.retain_mappings(&retained_job_ids);
+    }
+
+    /// Returns the number of jobs mapped to their template by the job creator, old mappings are
+    /// dropped on each new prev hash.
+    pub fn template_mappings_len(&self) -> usize {
+        self.job_creator.template_mappings_len()
+    }
+
+    // Drops the negotiated jobs that are not built on `prev_hash`, the new chain tip
+    fn drop_stale_negotiated_jobs(&mut self, prev_hash: &binary_sv2::U256<'static>) {
+        let stale_channels: Vec<u32> = self
+            .negotiated_jobs
+            .iter()
+            .filter(|(_, (_, job))| job.prev_hash.inner_as_ref() != prev_hash.inner_as_ref())
+            .map(|(channel_id, _)| *channel_id)
+            .collect();
+        for channel_id in &stale_channels {
+            if let Some((job_id, _)) = self.negotiated_jobs.remove(channel_id) {
+                self.stale_negotiated_jobs.insert(*channel_id, job_id);
+            }
+        }
+        if !stale_channels.is_empty() {
+            info!(
+                "Dropped {} negotiated jobs built on the previous prev hash",
+                stale_channels.len()
+            );
+        }
+    }
+
+    // Returns the active job with the prev hash and bitcoin target it is mined on
+    fn current_tip(&self) -> Option<PreviousTip> {
+        let (job, _) = self.inner.last_valid_job.as_ref()?;
+        let (prev_hash, _) = self.inner.last_prev_hash.as_ref()?;
+        Some(PreviousTip {
+            job: job.clone(),
+            prev_hash: prev_hash.clone(),
+            bitcoin_target: self.job_creator.last_target(),
+        })
+    }
+
+    // Called after a new prev hash with the tip that was current before it, the tip is remembered
+    // if its job has been superseded
+    fn remember_previous_tip(&mut self, previous_tip: Option<PreviousTip>) {
+        let active_job_id = self
+            .inner
+            .last_valid_job
+            .as_ref()
+            .map(|(job, _)| job.job_id);
+        self.previous_tips
+            .retain(|tip| Some(tip.job.job_id) != active_job_id);
+        match previous_tip {
+            Some(tip) if Some(tip.job.job_id) != active_job_id => {
+                if self.previous_tips.len() == PREVIOUS_TIPS_HISTORY_LEN {
+                    self.previous_tips.pop_front();
+                }
+                self.previous_tips.push_back(tip);
+            }
+            _ => (),
+        }
+    }
+
+    // Returns the previous tip of the job `job_id`, if any
+    fn previous_tip(&self, job_id: u32) -> Option<PreviousTip> {
+        self.previous_tips
+            .iter()
+            .rev()
+            .find(|tip| tip.job.job_id == job_id)
+            .cloned()
+    }
+
+    // Checks a share for the job of a previous tip against the prev hash, nbits and bitcoin target
+    // of that tip, the share was valid when it has been submitted
+    fn check_previous_tip_share(
+        &mut self,
+        share: Share,
+        tip: PreviousTip,
+    ) -> Result<OnNewShare, Error> {
+        debug!(
+            "Share for job {} of channel {} checked against a previous tip",
+            share.get_job_id(),
+            share.get_channel_id()
+        );
+        let template_id = self.job_creator.get_template_id_from_job(tip.job.job_id);
+        self.inner.check_target(
+            share,
+            tip.bitcoin_target,
+            template_id,
+            0,
+            tip.job.merkle_path.to_vec(),
+            tip.job.coinbase_tx_prefix.as_ref(),
+            tip.job.coinbase_tx_suffix.as_ref(),
+            crate::utils::u256_to_block_hash(tip.prev_hash.prev_hash.clone()),
+            tip.prev_hash.nbits,
+            tip.prev_hash.min_ntime,
+            tip.job.version,
+        )
+    }
+
+    /// Called only when a new template is received by a Template Provider. Returns
+    /// [`Error::NoCoinbaseOutputsConfigured`] if the pool coinbase outputs are not set.
+    ///
+    /// Templates are expected in any order with respect to the prev hashes:
+    /// - a future template is queued until the prev hash that references it
+    /// - a future template whose prev hash has already been received is mined on that prev hash
+    ///   as a non future template
+    /// - a non future template is mined on the last prev hash, that from now on references it,
+    ///   [`Error::JobIsNotFutureButPrevHashNotPresent`] is returned if no prev hash has been
+    ///   received
+    pub fn on_new_template(
+        &mut self,
+        m: &mut NewTemplate<'static>,
+    ) -> Result<HashMap<u32, Mining<'static>, BuildNoHashHasher<u32>>, Error> {
+        self.record_input(|| RecordedInput::NewTemplate(m.clone()));
+        let mut late_template;
+        let m = match self.template_after_prev_hash {
+            Some(template_id) if m.future_template && m.template_id == template_id => {
+                info!(
+                    "Future template {} received after its prev hash, mining it on the prev hash",
+                    template_id
+                );
+                late_template = m.clone();
+                late_template.future_template = false;
+                &mut late_template
+            }
+            _ => m,
+        };
+        if !m.future_template {
+            self.template_after_prev_hash = None;
+        }
+        if self.pool_coinbase_outputs.is_empty() {
+            error!("Received a new template but no pool coinbase outputs are configured");
+            return Err(Error::NoCoinbaseOutputsConfigured);
+        }
+        self.check_coinbase_outputs_count(
+            self.pool_coinbase_outputs.len() + m.coinbase_tx_outputs_count as usize,
+        )?;
+        self.check_template_subsidy(m)?;
+        let live_job_ids = self.live_job_ids();
+        self.job_creator.skip_live_job_ids(&live_job_ids);
+        let new_job =
+            self.job_creator
+                .on_new_template(m, true, self.pool_coinbase_outputs.clone())?;
+        self.inner.on_new_extended_mining_job(new_job)
+    }
+
+    /// Like [`PoolChannelFactory::on_new_template`] but when the template coinbase has a witness
+    /// commitment output it is first checked against `wtxids`, the witness hashes of the template
+    /// transactions in block order. `NewTemplate` does not carry the transactions, so they must
+    /// come from the Template Provider transaction data.
+    pub fn on_new_template_with_wtxids(
+        &mut self,
+        m: &mut NewTemplate<'static>,
+        wtxids: &[[u8; 32]],
+    ) -> Result<HashMap<u32, Mining<'static>, BuildNoHashHasher<u32>>, Error> {
+        check_witness_commitment(m, wtxids)?;
+        self.on_new_template(m)
+    }
+
+    /// Called when a `SubmitSharesStandard` message is received from the downstream. We check the
+    /// shares against the channel's respective target and return `OnNewShare` to let us know if
+    /// and where the shares should be relayed
+    pub fn on_submit_shares_standard(
+        &mut self,
+        m: SubmitSharesStandard,
+    ) -> Result<OnNewShare, Error> {
+        self.record_input(|| RecordedInput::SubmitSharesStandard(m.clone()));
+        match self.inner.channel_to_group_id.get(&m.channel_id) {
+            Some(g_id) => {
+                let share = Share::Standard((m, *g_id));
+                let referenced_job = match self.inner.last_valid_job.clone() {
+                    Some((job, _)) => job,
+                    None => return Ok(self.reject_stale_share(share)),
+                };
+                if share.get_job_id() != referenced_job.job_id {
+                    if let Some(tip) = self.previous_tip(share.get_job_id()) {
+                        return self.check_previous_tip_share(share, tip);
+                    }
+                }
+                if let Some(error) = self.inner.check_job_id(&share, referenced_job.job_id) {
+                    return Ok(error);
+                }
+                let merkle_path = referenced_job.merkle_path.to_vec();
+                let template_id = self
+                    .job_creator
+                    .get_template_id_from_job(referenced_job.job_id)
+                    .ok_or(Error::NoTemplateForId)?;
+                let target = self.job_creator.last_target();
+                let prev_blockhash = self
+                    .inner
+                    .last_prev_blockhash()
+                    .ok_or(Error::ShareDoNotMatchAnyJob)?;
+                let (bits, min_ntime) = self
+                    .inner
+                    .last_prev_hash
+                    .as_ref()
+                    .map(|(prev_hash, _)| (prev_hash.nbits, prev_hash.min_ntime))
+                    .ok_or(Error::ShareDoNotMatchAnyJob)?;
+                self.inner.check_target(
+                    share,
+                    target,
+                    Some(template_id),
+                    0,
+                    merkle_path,
+                    referenced_job.coinbase_tx_prefix.as_ref(),
+                    referenced_job.coinbase_tx_suffix.as_ref(),
+                    prev_blockhash,
+                    bits,
+                    min_ntime,
+                    referenced_job.version,
+                )
+            }
+            None => {
+                let error = self
+                    .inner
+                    .unknown_channel_error(m.channel_id, m.sequence_number);
+                Ok(self
+                    .inner
+                    .reject_share(error, ShareRejectionReason::UnknownChannel, None))
+            }
+        }
+    }
+
+    /// Called when a `SubmitSharesExtended` message is received from the downstream. We check the
+    /// shares against the channel's respective target and return `OnNewShare` to let us know if
+    /// and where the shares should be relayed
+    pub fn on_submit_shares_extended(
+        &mut self,
+        m: SubmitSharesExtended,
+    ) -> Result<OnNewShare, Error> {
+        self.record_input(|| RecordedInput::SubmitSharesExtended(m.clone().into_static()));
+        let target = self.job_creator.last_target();
+        // When downstream set a custom mining job we add the job to the negotiated job
+        // hashmap, with the extended channel id as a key. Whenever the pool receive a share for
+        // the negotiated job of the channel we can not retrieve the template via the job creator
+        // but we create a new one from the set custom job. A channel with a negotiated job can
+        // still submit shares for the pool job, if a share job id matches both jobs it is
+        // checked against the negotiated job.
+        let negotiated_job_id = self
+            .negotiated_jobs
+            .get(&m.channel_id)
+            .map(|(job_id, _)| *job_id);
+        if self.stale_negotiated_jobs.get(&m.channel_id) == Some(&m.job_id) {
+            error!(
+                "Share for custom job of channel {} dropped on a new prev hash",
+                m.channel_id
+            );
+            return Ok(self.reject_stale_share(Share::Extended(m.into_static())));
+        }
+        if negotiated_job_id == Some(m.job_id) {
+            let (_, referenced_job) = self.negotiated_jobs.get(&m.channel_id).unwrap();
+            // A share for a custom job built on an old tip would be a stale block
+            let is_current_tip =
+                self.inner
+                    .last_prev_hash
+                    .as_ref()
+                    .is_some_and(|(prev_hash, _)| {
+                        prev_hash.prev_hash.inner_as_ref()
+                            == referenced_job.prev_hash.inner_as_ref()
+                    });
+            if !is_current_tip {
+                error!(
+                    "Share for custom job of channel {} built on a stale prev hash",
+                    m.channel_id
+                );
+                return Ok(self.reject_stale_share(Share::Extended(m.into_static())));
+            }
+            let merkle_path = referenced_job.merkle_path.to_vec();
+            let extended_job = job_creator::extended_job_from_custom_job(
+                referenced_job,
+                self.inner.extranonces.get_len() as u8,
+            )
+            .unwrap();
+            let prev_blockhash = crate::utils::u256_to_block_hash(referenced_job.prev_hash.clone());
+            let bits = referenced_job.nbits;
+            let min_ntime = referenced_job.min_ntime;
+            self.inner.check_target(
+                Share::Extended(m.into_static()),
+                target,
+                None,
+                0,
+                merkle_path,
+                extended_job.coinbase_tx_prefix.as_ref(),
+                extended_job.coinbase_tx_suffix.as_ref(),
+                prev_blockhash,
+                bits,
+                min_ntime,
+                referenced_job.version,
+            )
+        } else {
+            let share = Share::Extended(m.into_static());
+            let pool_job_id = self
+                .inner
+                .last_valid_job
+                .as_ref()
+                .map(|(job, _)| job.job_id);
+            if pool_job_id != Some(share.get_job_id()) {
+                if let Some(tip) = self.previous_tip(share.get_job_id()) {
+                    return self.check_previous_tip_share(share, tip);
+                }
+            }
+            if negotiated_job_id.is_some() && pool_job_id != Some(share.get_job_id()) {
+                error!(
+                    "Share for job {} on channel {} matches neither the negotiated job nor the \
+                     pool job",
+                    share.get_job_id(),
+                    share.get_channel_id()
+                );
+                return Ok(self.inner.reject_job_id(&share));
+            }
+            let referenced_job = match self.inner.last_valid_job.clone() {
+                Some((job, _)) => job,
+                None => return Ok(self.reject_stale_share(share)),
+            };
+            if let Some(error) = self.inner.check_job_id(&share, referenced_job.job_id) {
+                return Ok(error);
+            }
+            let merkle_path = referenced_job.merkle_path.to_vec();
+            let template_id = self
+                .job_creator
+                .get_template_id_from_job(referenced_job.job_id)
+                .ok_or(Error::NoTemplateForId)?;
+            let prev_blockhash = self
+                .inner
+                .last_prev_blockhash()
+                .ok_or(Error::ShareDoNotMatchAnyJob)?;
+            let (bits, min_ntime) = self
+                .inner
+                .last_prev_hash
+                .as_ref()
+                .map(|(prev_hash, _)| (prev_hash.nbits, prev_hash.min_ntime))
+                .ok_or(Error::ShareDoNotMatchAnyJob)?;
+            self.inner.check_target(
+                share,
+                target,
+                Some(template_id),
+                0,
+                merkle_path,
+                referenced_job.coinbase_tx_prefix.as_ref(),
+                referenced_job.coinbase_tx_suffix.as_ref(),
+                prev_blockhash,
+                bits,
+                min_ntime,
+                referenced_job.version,
+            )
+        }
+    }
+
+    // Rejects with `stale-share` a share that can not be checked against the current tip: a share
+    // for a custom job built on an old tip, it would be a stale block, or any share received while
+    // there is no active job, e.g. after the prev hash expired
+    fn reject_stale_share(&mut self, share: Share) -> OnNewShare {
+        self.inner
+            .count_rejected_share(share.get_channel_id(), true);
+        self.inner.reject_share(
+            submit_shares_error(&share, SubmitSharesError::stale_share_error_code()),
+            ShareRejectionReason::StaleJob,
+            None,
+        )
+    }
+
+    /// Utility function to return a new group id, fails with [`Error::PoisonLock`] if the ids
+    /// lock is poisoned
+    pub fn new_group_id(&mut self) -> Result<u32, Error> {
+        self.record_input(|| RecordedInput::NewGroupId);
+        let new_id = self.inner.ids.safe_lock(|ids| ids.new_group_id())?;
+        Ok(new_id)
+    }
+
+    /// Registers the standard channel `channel_id`, an id returned by
+    /// [`PoolChannelFactory::new_standard_id_for_hom`], in `group_id` with the given target.
+    /// Returns the extranonce assigned to the channel, to be sent in
+    /// `OpenStandardMiningChannelSuccess`.
+    pub fn add_standard_channel(
+        &mut self,
+        channel_id: u32,
+        group_id: u32,
+        target: Target,
+    ) -> Result<mining_sv2::Extranonce, Error> {
+        self.inner
+            .add_standard_channel(channel_id, group_id, target)
+    }
+
+    /// Utility function to return a new standard channel id, fails with [`Error::PoisonLock`] if
+    /// the ids lock is poisoned
+    pub fn new_standard_id_for_hom(&mut self) -> Result<u32, Error> {
+        self.record_input(|| RecordedInput::NewStandardIdForHom);
+        let hom_group_id = 0;
+        let new_id = self
+            .inner
+            .ids
+            .safe_lock(|ids| ids.new_channel_id(hom_group_id))?;
+        Ok(new_id)
+    }
+
+    /// Calls [`ChannelFactory::extranonce_from_downstream_extranonce`]
+    pub fn extranonce_from_downstream_extranonce(
+        &self,
+        ext: mining_sv2::Extranonce,
+    ) -> Result<mining_sv2::Extranonce, Error> {
+        self.inner.extranonce_from_downstream_extranonce(ext)
+    }
+
+    /// Same as [`Self::extranonce_from_downstream_extranonce`] but returns None on failure
+    pub fn extranonce_from_downstream_extranonce_opt(
+        &self,
+        ext: mining_sv2::Extranonce,
+    ) -> Option<mining_sv2::Extranonce> {
+        self.extranonce_from_downstream_extranonce(ext).ok()
+    }
+
+    /// Returns the scriptSig of the coinbase input of the job `job_id`, that is the coinbase
+    /// prefix provided by the template (height and any tag) followed by `extranonce`.
+    /// `extranonce` must be the full extranonce (extranonce prefix + extranonce2). Returns `None`
+    /// if the job is unknown, `extranonce` has the wrong len or the coinbase can not be decoded.
+    pub fn coinbase_script_sig(&self, job_id: u32, extranonce: &[u8]) -> Option<Vec<u8>> {
+        if extranonce.len() != self.inner.extranonces.get_len() {
+            return None;
+        }
+        let job = self.inner.job_by_id(job_id)?;
+        let coinbase = [
+            job.coinbase_tx_prefix.inner_as_ref(),
+            extranonce,
+            job.coinbase_tx_suffix.inner_as_ref(),
+        ]
+        .concat();
+        let coinbase: bitcoin::Transaction = bitcoin::consensus::deserialize(&coinbase).ok()?;
+        Some(coinbase.input.first()?.script_sig.to_bytes())
+    }
+
+    /// Returns the full extranonce of the channel `channel_id`, that is the channel extranonce
+    /// prefix followed by `extranonce2`. Returns `None` if the channel do not exist or if
+    /// `extranonce2` len is not the extranonce size granted to the channel.
+    pub fn full_extranonce(&self, channel_id: u32, extranonce2: &[u8]) -> Option<Vec<u8>> {
+        let channel = self.inner.extended_channels.get(&channel_id)?;
+        if extranonce2.len() != channel.extranonce_size as usize {
+            warn!(
+                "Extranonce2 is not of the right len for channel {}: expected {} actual {}",
+                channel_id,
+                channel.extranonce_size,
+                extranonce2.len()
+            );
+            return None;
+        }
+        Some([channel.extranonce_prefix.inner_as_ref(), extranonce2].concat())
+    }
+
+    /// Checks that the full extranonce spaces (extranonce prefix followed by any extranonce2) of
+    /// the extended channels and the full extranonces of the standard channels do not overlap.
+    /// A standard extranonce is a space of a single full length extranonce, two spaces overlap
+    /// when the prefix of a channel is a prefix of the other channel one. Returns the overlapping
+    /// pairs of channel ids, ordered by id.
+    pub fn audit_extranonce_disjointness(&self) -> Result<(), Vec<(u32, u32)>> {
+        let mut prefixes: Vec<(u32, &[u8])> = self
+            .inner
+            .extended_channels
+            .iter()
+            .map(|(id, channel)| (*id, channel.extranonce_prefix.inner_as_ref()))
+            .chain(
+                self.inner
+                    .standard_channels
+                    .iter()
+                    .map(|(id, channel)| (*id, &channel.extranonce[..])),
+            )
+            .collect();
+        prefixes.sort_unstable_by_key(|(id, _)| *id);
+        let mut overlapping = vec![];
+        for (i, (id, prefix)) in prefixes.iter().enumerate() {
+            for (other_id, other_prefix) in &prefixes[i + 1..] {
+                if prefix.starts_with(other_prefix) || other_prefix.starts_with(prefix) {
+                    error!(
+                        "Channels {} and {} have overlapping extranonce spaces",
+                        id, other_id
+                    );
+                    overlapping.push((*id, *other_id));
+                }
+            }
+        }
+        if overlapping.is_empty() {
+            Ok(())
+        } else {
+            Err(overlapping)
+        }
+    }
+
+    /// Returns true if a miner with the given `hashrate` (H/s) can not exhaust the search space of
+    /// the channel (nonce + extranonce2) in less than one second, when it would the miner has to
+    /// roll ntime faster than allowed and needs a bigger extranonce2. Returns false if the
+    /// channel do not exist.
+    pub fn nonce_space_adequate(&self, channel_id: u32, hashrate: f64) -> bool {
+        match self.inner.channel_extranonce2_size(channel_id) {
+            Some(extranonce2_size) => {
+                let search_space = 2_f64.powi(32 + 8 * extranonce2_size as i32);
+                hashrate <= search_space
+            }
+            None => false,
+        }
+    }
+
+    /// Enable or disable the tracking of the extranonce2 used by each extended channel in the
+    /// submitted shares. Disabling it drops the data collected so far.
+    pub fn set_extranonce2_tracking(&mut self, enabled: bool) {
+        match (enabled, &self.inner.extranonce2_diversity) {
+            (true, None) => {
+                self.inner.extranonce2_diversity =
+                    Some(HashMap::with_hasher(BuildNoHashHasher::default()))
+            }
+            (false, _) => self.inner.extranonce2_diversity = None,
+            (true, Some(_)) => (),
+        }
+    }
+
+    /// Returns the ratio of distinct extranonce2 to shares received on the channel. A value
+    /// close to 0 means that the miner keeps reusing the same extranonce2, which suggests a tiny
+    /// search space or replayed shares. Returns `None` if tracking is disabled or no share has
+    /// been received on the channel.
+    pub fn extranonce2_diversity(&self, channel_id: u32) -> Option<f32> {
+        self.inner
+            .extranonce2_diversity
+            .as_ref()?
+            .get(&channel_id)?
+            .ratio()
+    }
+
+    /// Called when a new custom mining job arrives. Returns an error if the job coinbase has more
+    /// outputs than allowed, and a `SetCustomMiningJobError` for the downstream if the job does
+    /// not pass [`PoolChannelFactory::check_set_custom_mining_job`].
+    pub fn on_new_set_custom_mining_job(
+        &mut self,
+        set_custom_mining_job: SetCustomMiningJob<'static>,
+    ) -> Result<OnNewSetCustomMiningJob, Error> {
+        let channel_id = set_custom_mining_job.channel_id;
+        // Unknown channels are rejected by `check_set_custom_mining_job`
+        if self
+            .inner
+            .channel_flags
+            .get(&channel_id)
+            .is_some_and(|flags| !flags.work_selection)
+        {
+            error!(
+                "Channel {} set a custom job but did not negotiate work selection",
+                channel_id
+            );
+            return Err(Error::WorkSelectionNotNegotiated(channel_id));
+        }
+        self.check_coinbase_outputs_count(
+            job_creator::tx_outputs_to_costum_scripts(
+                set_custom_mining_job.coinbase_tx_outputs.inner_as_ref(),
+            )
+            .len(),
+        )?;
+        if let Err(error_code) = self.check_set_custom_mining_job(&set_custom_mining_job) {
+            return Ok(OnNewSetCustomMiningJob::SendErrorDownstream(
+                SetCustomMiningJobError {
+                    channel_id,
+                    request_id: set_custom_mining_job.request_id,
+                    // Infallible unwrap error codes are static strings shorter than 255 bytes
+                    error_code: error_code.to_string().try_into().unwrap(),
+                },
+            ));
+        }
+        // Taken from the job creator, so that it can not be the id of a pool job
+        let live_job_ids = self.live_job_ids();
+        self.job_creator.skip_live_job_ids(&live_job_ids);
+        let job_id = self.job_creator.new_job_id();
+        self.stale_negotiated_jobs
+            .remove(&set_custom_mining_job.channel_id);
+        self.negotiated_jobs.insert(
+            set_custom_mining_job.channel_id,
+            (job_id, set_custom_mining_job.clone()),
+        );
+        Ok(OnNewSetCustomMiningJob::Success(
+            SetCustomMiningJobSuccess {
+                channel_id: set_custom_mining_job.channel_id,
+                request_id: set_custom_mining_job.request_id,
+                job_id,
+            },
+        ))
+    }
+
+    // Checks that a coinbase with `outputs_count` outputs do not exceed the max allowed outputs
+    fn check_coinbase_outputs_count(&self, outputs_count: usize) -> Result<(), Error> {
+        if outputs_count > self.max_coinbase_outputs {
+            error!(
+                "Coinbase has {} outputs, max allowed is {}",
+                outputs_count, self.max_coinbase_outputs
+            );
+            return Err(Error::TooManyCoinbaseOutputs(
+                outputs_count,
+                self.max_coinbase_outputs,
+            ));
+        }
+        Ok(())
+    }
+
+    // Checks that the value claimed by a template is plausible for its height, when the height
+    // can be read from the coinbase prefix. A value below the subsidy is only logged, a miner can
+    // claim less than allowed. A value above the subsidy plus `max_template_fees` is rejected.
+    fn check_template_subsidy(&self, m: &NewTemplate) -> Result<(), Error> {
+        let height = match coinbase_prefix_height(m.coinbase_prefix.inner_as_ref()) {
+            Some(height) => height,
+            None => return Ok(()),
+        };
+        let subsidy = block_subsidy(height);
+        let value_remaining = m.coinbase_tx_value_remaining;
+        if value_remaining < subsidy {
+            warn!(
+                "Template {} claims {} sats, less than the subsidy of {} sats at height {}",
+                m.template_id, value_remaining, subsidy, height
+            );
+        }
+        if let Some(max_fees) = self.max_template_fees {
+            if value_remaining > subsidy.saturating_add(max_fees) {
+                error!(
+                    "Template {} claims {} sats, more than the subsidy of {} sats at height {} plus {} sats of fees",
+                    m.template_id, value_remaining, subsidy, height, max_fees
+                );
+                return Err(Error::InvalidTemplateSubsidy(
+                    height,
+                    subsidy,
+                    value_remaining,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the `SetCustomMiningJobError` error code if the channel of the custom job is not an
+    // open extended channel, if the job is not built on the current prev hash, if its coinbase
+    // outputs do not comply with the coinbase output policy or if, when `max_template_fees` is set
+    // and the height can be read from the coinbase prefix, the coinbase outputs pay more than the
+    // subsidy plus the max fees.
+    fn check_set_custom_mining_job(
+        &self,
+        set_custom_mining_job: &SetCustomMiningJob<'static>,
+    ) -> Result<(), &'static str> {
+        let channel_id = set_custom_mining_job.channel_id;
+        if !self.inner.extended_channels.contains_key(&channel_id) {
+            error!("Custom job for unknown channel {}", channel_id);
+            return Err(SetCustomMiningJobError::invalid_channel_id_error_code());
+        }
+        let is_current_tip = self
+            .inner
+            .last_prev_hash
+            .as_ref()
+            .is_some_and(|(prev_hash, _)| {
+                prev_hash.prev_hash.inner_as_ref() == set_custom_mining_job.prev_hash.inner_as_ref()
+            });
+        if !is_current_tip {
+            error!(
+                "Custom job of channel {} is not built on the current prev hash",
+                channel_id
+            );
+            return Err(SetCustomMiningJobError::invalid_prev_hash_error_code());
+        }
+        let outputs = job_creator::tx_outputs_to_costum_scripts(
+            set_custom_mining_job.coinbase_tx_outputs.inner_as_ref(),
+        );
+        let outputs_value = outputs.iter().try_fold(0_u64, |total, output| {
+            total.checked_add(output.value.to_sat())
+        });
+        if let Some(violation) = self.coinbase_output_policy_violation(&outputs) {
+            error!("Custom job of channel {} {}", channel_id, violation);
+            return Err(SetCustomMiningJobError::invalid_coinbase_tx_outputs_error_code());
+        }
+        let outputs_value = match outputs_value {
+            Some(outputs_value) => outputs_value,
+            None => {
+                error!(
+                    "Custom job of channel {} outputs value overflows",
+                    channel_id
+                );
+                return Err(SetCustomMiningJobError::invalid_coinbase_tx_outputs_error_code());
+            }
+        };
+        let height = coinbase_prefix_height(set_custom_mining_job.coinbase_prefix.inner_as_ref());
+        if let (Some(height), Some(max_fees)) = (height, self.max_template_fees) {
+            let max_value = block_subsidy(height).saturating_add(max_fees);
+            if outputs_value > max_value {
+                error!(
+                    "Custom job of channel {} pays {} sats, more than the {} sats allowed at height {}",
+                    channel_id, outputs_value, max_value, height
+                );
+                return Err(SetCustomMiningJobError::invalid_coinbase_tx_outputs_error_code());
+            }
+        }
+        Ok(())
+    }
+
+    // Describes why `outputs` do not comply with the coinbase output policy, None if they comply.
+    // Without a policy every pool output must be paid.
+    fn coinbase_output_policy_violation(&self, outputs: &[TxOut]) -> Option<String> {
+        match &self.coinbase_output_policy {
+            None => missing_output(outputs, &self.pool_coinbase_outputs)
+                .map(|output| format!("does not pay the pool output {:?}", output)),
+            Some(CoinbaseOutputPolicy::MustContain(required)) => missing_output(outputs, required)
+                .map(|output| format!("does not pay the required output {:?}", output)),
+            Some(CoinbaseOutputPolicy::ExactMatch) => {
+                let is_exact_match = outputs.len() == self.pool_coinbase_outputs.len()
+                    && outputs.iter().zip(&self.pool_coinbase_outputs).all(
+                        |(output, pool_output)| {
+                            output.script_pubkey == pool_output.script_pubkey
+                                && output.value >= pool_output.value
+                        },
+                    );
+                match is_exact_match {
+                    true => None,
+                    false => Some("outputs are not the pool outputs".to_string()),
+                }
+            }
+            Some(CoinbaseOutputPolicy::MinValueToScript { script, min_sats }) => {
+                let paid = outputs
+                    .iter()
+                    .filter(|output| &output.script_pubkey == script)
+                    .fold(0_u64, |paid, output| {
+                        paid.saturating_add(output.value.to_sat())
+                    });
+                match paid < *min_sats {
+                    true => Some(format!(
+                        "pays {} sats to {:?}, less than the required {} sats",
+                        paid, script, min_sats
+                    )),
+                    false => None,
+                }
+            }
+        }
+    }
+
+    /// Get extended channel ids
+    pub fn get_extended_channels_ids(&self) -> Vec<u32> {
+        self.inner.extended_channels.keys().copied().collect()
+    }
+
+    /// Calls [`ChannelFactory::channels_snapshot`]
+    pub fn channels_snapshot(&self) -> Vec<ChannelInfo> {
+        self.inner.channels_snapshot()
+    }
+
+    /// Calls [`ChannelFactory::has_channel`]
+    pub fn has_channel(&self, channel_id: u32) -> bool {
+        self.inner.has_channel(channel_id)
+    }
+
+    /// Calls [`ChannelFactory::channel_group`]
+    pub fn channel_group(&self, channel_id: u32) -> Option<u32> {
+        self.inner.channel_group(channel_id)
+    }
+
+    /// Returns the expected value in satoshis of a share that meets the channel target:
+    /// `coinbase value * channel difficulty / network difficulty`, where the coinbase value is the
+    /// one of the last valid job and the network difficulty is derived from the prev hash nbits.
+    /// None if the channel does not exist, or there is no prev hash, valid job or pool output.
+    pub fn expected_share_value_sats(&self, channel_id: u32) -> Option<f64> {
+        if self.pool_coinbase_outputs.is_empty() {
+            return None;
+        }
+        let channel = self.inner.extended_channels.get(&channel_id)?;
+        let (prev_hash, _) = self.inner.last_prev_hash.as_ref()?;
+        let (job, _) = self.inner.last_valid_job.as_ref()?;
+        let coinbase_value = self.job_creator.coinbase_value_from_job(job.job_id)?;
+        let network_target: Target =
+            bitcoin::Target::from_compact(CompactTarget::from_consensus(prev_hash.nbits))
+                .to_le_bytes()
+                .into();
+        let channel_difficulty = crate::utils::target_to_difficulty(&channel.target.clone().into());
+        let network_difficulty = crate::utils::target_to_difficulty(&network_target);
+        Some(coinbase_value as f64 * channel_difficulty / network_difficulty)
+    }
+
+    /// Returns the last valid job with the channel id of the given extended channel, used to
+    /// resend the current job to a single channel. None if the channel does not exist or there is
+    /// no valid job.
+    pub fn extended_job_for_channel(
+        &self,
+        channel_id: u32,
+    ) -> Option<NewExtendedMiningJob<'static>> {
+        if !self.inner.extended_channels.contains_key(&channel_id) {
+            return None;
+        }
+        let mut job = self.inner.last_valid_job.as_ref()?.0.clone();
+        job.channel_id = channel_id;
+        Some(job)
+    }
+
+    /// Tell the factory that the jobs with `job_id` have been delivered to the extended channel by
+    /// the caller, so that they are not in [`PoolChannelFactory::undelivered_messages`] anymore.
+    /// Returns false if the channel or the job does not exist.
+    pub fn mark_job_delivered(&mut self, channel_id: u32, job_id: u32) -> bool {
+        self.inner.mark_job_delivered(channel_id, job_id)
+    }
+
+    /// Tell the factory that the current prev hash has been delivered to the extended channel by
+    /// the caller. Returns false if the channel does not exist or there is no prev hash.
+    pub fn mark_prev_hash_delivered(&mut self, channel_id: u32) -> bool {
+        self.inner.mark_prev_hash_delivered(channel_id)
+    }
+
+    /// Returns the active job, the current prev hash and the future jobs that have not been
+    /// marked as delivered to the extended channel, ready to be resent to it
+    pub fn undelivered_messages(&self, channel_id: u32) -> Vec<Mining<'static>> {
+        self.inner.undelivered_messages(channel_id)
+    }
+
+    /// Returns the job id and the coinbase outputs of the most recent blocks found on the factory
+    /// channels, oldest first, to reconcile payouts against the outputs that have been mined
+    pub fn found_block_coinbases(&self) -> Vec<(u32, Vec<TxOut>)> {
+        self.inner
+            .found_blocks
+            .iter()
+            .filter_map(|(job_id, coinbase, _)| {
+                match consensus::deserialize::<Transaction>(coinbase) {
+                    Ok(coinbase) => Some((*job_id, coinbase.output)),
+                    Err(e) => {
+                        error!(
+                            "Failed to decode coinbase of block for job {}: {}",
+                            job_id, e
+                        );
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Assembles the block of a share that met the bitcoin target, `coinbase` is the coinbase
+    /// returned in [`OnNewShare::ShareMeetBitcoinTarget`] and `transactions` the template
+    /// transactions in block order. The factory only knows the merkle path of its jobs, so the
+    /// transactions must be supplied by the caller, or be empty for coinbase-only templates.
+    ///
+    /// The header is the one used by the factory to check the share, only the recently found
+    /// blocks are remembered. Returns [`Error::BlockMerkleRootMismatch`] if the transactions do
+    /// not commit to the header merkle root.
+    pub fn assemble_block(
+        &self,
+        share: &Share,
+        coinbase: &[u8],
+        transactions: Vec<Transaction>,
+    ) -> Result<bitcoin::Block, Error> {
+        let header = self
+            .inner
+            .found_blocks
+            .iter()
+            .rev()
+            .find(|(job_id, found_coinbase, header)| {
+                *job_id == share.get_job_id()
+                    && found_coinbase[..] == coinbase[..]
+                    && header.nonce == share.get_nonce()
+                    && header.time == share.get_n_time()
+                    && header.version == share.get_version()
+            })
+            .map(|(_, _, header)| header.header())
+            .ok_or_else(|| Error::UnknownFoundBlock(share.get_job_id()))?;
+        let coinbase: Transaction =
+            consensus::deserialize(coinbase).map_err(|_| Error::InvalidCoinbase)?;
+        let mut txdata = Vec::with_capacity(transactions.len() + 1);
+        txdata.push(coinbase);
+        txdata.extend(transactions);
+        let block = bitcoin::Block { header, txdata };
+        if !block.check_merkle_root() {
+            return Err(Error::BlockMerkleRootMismatch);
+        }
+        Ok(block)
+    }
+
+    /// Moves the standard channels `channel_ids` to `group_id`, as requested by a
+    /// `SetGroupChannel`. If any of them is not an open standard channel no channel is moved and
+    /// the unknown ids are returned in [`Error::UnknownStandardChannels`].
+    pub fn set_group_channel(&mut self, group_id: u32, channel_ids: Vec<u32>) -> Result<(), Error> {
+        let unknown_channels: Vec<u32> = channel_ids
+            .iter()
+            .filter(|channel_id| !self.inner.standard_channels.contains_key(channel_id))
+            .copied()
+            .collect();
+        if !unknown_channels.is_empty() {
+            error!(
+                "Can not move channels {:?} to group {}, they are not open standard channels",
+                unknown_channels, group_id
+            );
+            return Err(Error::UnknownStandardChannels(unknown_channels));
+        }
+        for channel_id in channel_ids {
+            self.inner.channel_to_group_id.insert(channel_id, group_id);
+            if let Some(channel) = self.inner.standard_channels.get_mut(&channel_id) {
+                channel.group_id = group_id;
+            }
+        }
+        Ok(())
     }
 
-    /// Called only when a new template is received by a Template Provider
-    pub fn on_new_template(
+    /// Returns the ids of the channels in the group, ordered, so that the jobs of a group can be
+    /// picked from the jobs returned for each channel.
+    pub fn group_channel_ids(&self, group_id: u32) -> Vec<u32> {
+        let mut channel_ids: Vec<u32> = self
+            .inner
+            .channel_to_group_id
+            .iter()
+            .filter(|(_, g_id)| **g_id == group_id)
+            .map(|(channel_id, _)| *channel_id)
+            .collect();
+        channel_ids.sort_unstable();
+        channel_ids
+    }
+
+    /// Get the number of channels in the group
+    pub fn channels_in_group(&self, group_id: u32) -> usize {
+        self.inner
+            .channel_to_group_id
+            .values()
+            .filter(|g_id| **g_id == group_id)
+            .count()
+    }
+
+    /// Get the extranonce_2 size granted by default to new extended channels
+    pub fn default_channel_extranonce2_size(&self) -> usize {
+        self.inner.extranonces.get_range2_len()
+    }
+
+    /// Theoretical maximum number of extended channels granted the whole range_2 that fit in the
+    /// extranonce configuration. Each one consumes one value of range_1, the prefix part owned by
+    /// the factory, so the maximum is the number of non zero values of range_1. Saturates at
+    /// `u128::MAX`.
+    pub fn max_possible_channels(&self) -> u128 {
+        let prefix_bytes =
+            self.inner.extranonces.get_prefix_len() - self.inner.extranonces.get_range0_len();
+        // The first prefix handed out is 1, range_1 starts from zero
+        1_u128
+            .checked_shl(prefix_bytes as u32 * 8)
+            .map_or(u128::MAX, |values| values - 1)
+    }
+
+    /// Number of extranonce prefixes that can still be handed out to new extended channels, once
+    /// it reaches 0 every new extended channel is refused with `extranonce-prefixes-exhausted`.
+    /// Like [`PoolChannelFactory::max_possible_channels`] it counts every value of range_1 and
+    /// saturates at `u128::MAX`. Standard channels take values of range_1 as well, one for all the
+    /// values of range_2.
+    pub fn remaining_extended_prefixes(&self) -> u128 {
+        let range_0_len = self.inner.extranonces.get_range0_len();
+        // range_1 holds the last prefix handed out
+        let last_prefix = self.inner.extranonces.upstream_part().to_vec()[range_0_len..]
+            .iter()
+            .fold(0_u128, |value, byte| {
+                value.saturating_mul(256).saturating_add(*byte as u128)
+            });
+        self.max_possible_channels().saturating_sub(last_prefix)
+    }
+
+    /// The largest extranonce2 size that can be granted to a new extended channel: the whole
+    /// range_2, or 0 once [`PoolChannelFactory::remaining_extended_prefixes`] is 0. Channels that
+    /// request less are granted exactly what they request and share an extranonce prefix with the
+    /// channels of the same size.
+    pub fn available_extranonce2_size(&self) -> usize {
+        match self.remaining_extended_prefixes() {
+            0 => 0,
+            _ => self.inner.extranonces.get_range2_len(),
+        }
+    }
+
+    /// Returns the requested, available and granted extranonce sizes of the last extended channel
+    /// request, used to tell the miner by how much a refused request exceeded the available space
+    pub fn last_extranonce_grant(&self) -> Option<ExtranonceGrant> {
+        self.inner.last_extranonce_grant
+    }
+
+    /// Calls [`ChannelFactory::channel_extranonce2_size`]
+    pub fn channel_extranonce2_size(&self, channel_id: u32) -> Option<usize> {
+        self.inner.channel_extranonce2_size(channel_id)
+    }
+
+    /// Calls [`ChannelFactory::channel_fingerprint`], the fingerprint identifies the channel in
+    /// the logs across restarts, when the channel may get another id
+    pub fn channel_fingerprint(&self, channel_id: u32) -> Option<[u8; 16]> {
+        self.inner.channel_fingerprint(channel_id)
+    }
+
+    /// Calls [`ChannelFactory::set_extranonce_prefix`]
+    pub fn set_extranonce_prefix(
         &mut self,
-        m: &mut NewTemplate<'static>,
-    ) -> Result<HashMap<u32, Mining<'static>, BuildNoHashHasher<u32>>, Error> {
-        let new_job =
-            self.job_creator
-                .on_new_template(m, true, self.pool_coinbase_outputs.clone())?;
-        self.inner.on_new_extended_mining_job(new_job)
+        channel_id: u32,
+        new_prefix: Vec<u8>,
+    ) -> Result<Mining<'static>, Error> {
+        self.record_input(|| RecordedInput::SetExtranoncePrefix {
+            channel_id,
+            prefix: new_prefix.clone(),
+        });
+        self.inner.set_extranonce_prefix(channel_id, new_prefix)
     }
 
-    /// Called when a `SubmitSharesStandard` message is received from the downstream. We check the
-    /// shares against the channel's respective target and return `OnNewShare` to let us know if
-    /// and where the shares should be relayed
-    pub fn on_submit_shares_standard(
+    pub fn get_shares_per_minute(&self) -> f32 {
+        self.inner.share_per_min
+    }
+
+    /// Update coinbase outputs
+    pub fn update_pool_outputs(&mut self, outs: Vec<TxOut>) {
+        self.pool_coinbase_outputs = outs;
+    }
+
+    /// Set the max number of coinbase outputs (pool outputs included) accepted in new templates
+    /// and custom jobs. By default there is no limit.
+    pub fn set_max_coinbase_outputs(&mut self, max_coinbase_outputs: usize) {
+        self.max_coinbase_outputs = max_coinbase_outputs;
+    }
+
+    /// Set the max fees in satoshis that a new template can claim on top of the block subsidy,
+    /// templates that claim more are rejected. None, the default, disables the check.
+    pub fn set_max_template_fees(&mut self, max_template_fees: Option<u64>) {
+        self.max_template_fees = max_template_fees;
+    }
+
+    /// Set the policy the coinbase outputs of the custom jobs are checked against. None, the
+    /// default, requires every pool coinbase output to be paid with at least its value.
+    pub fn set_coinbase_output_policy(&mut self, policy: Option<CoinbaseOutputPolicy>) {
+        self.coinbase_output_policy = policy;
+    }
+
+    /// Calls [`ChannelFactory::update_target_for_channel`]
+    /// Set a particular downstream channel target.
+    pub fn update_target_for_channel(
         &mut self,
-        m: SubmitSharesStandard,
-    ) -> Result<OnNewShare, Error> {
-        match self.inner.channel_to_group_id.get(&m.channel_id) {
-            Some(g_id) => {
-                let referenced_job = self
-                    .inner
-                    .last_valid_job
-                    .clone()
-                    .ok_or(Error::ShareDoNotMatchAnyJob)?
-                    .0;
-                let merkle_path = referenced_job.merkle_path.to_vec();
-                let template_id = self
-                    .job_creator
-                    .get_template_id_from_job(referenced_job.job_id)
-                    .ok_or(Error::NoTemplateForId)?;
-                let target = self.job_creator.last_target();
-                let prev_blockhash = self
-                    .inner
-                    .last_prev_hash_
-                    .ok_or(Error::ShareDoNotMatchAnyJob)?;
-                let bits = self
-                    .inner
-                    .last_prev_hash
-                    .as_ref()
-                    .ok_or(Error::ShareDoNotMatchAnyJob)?
-                    .0
-                    .nbits;
-                self.inner.check_target(
-                    Share::Standard((m, *g_id)),
-                    target,
-                    Some(template_id),
-                    0,
-                    merkle_path,
-                    referenced_job.coinbase_tx_prefix.as_ref(),
-                    referenced_job.coinbase_tx_suffix.as_ref(),
-                    prev_blockhash,
-                    bits,
-                )
+        channel_id: u32,
+        new_target: Target,
+    ) -> Option<bool> {
+        self.inner.update_target_for_channel(channel_id, new_target)
+    }
+
+    /// Calls [`ChannelFactory::update_target_for_channel_with_message`]
+    /// Set a particular downstream channel target and return the `SetTarget` that must be sent to
+    /// the downstream. Returns None if the channel does not exist.
+    pub fn update_target_for_channel_with_message(
+        &mut self,
+        channel_id: u32,
+        new_target: Target,
+    ) -> Option<Mining<'static>> {
+        self.inner
+            .update_target_for_channel_with_message(channel_id, new_target)
+    }
+
+    /// Handles an `UpdateChannel` sent by the downstream of an extended or standard channel. The
+    /// new target of the channel is the one of the nominal hash rate, capped to the requested
+    /// maximum target, and is returned in a `SetTarget`. An `UpdateChannelError` is returned
+    /// with `invalid-channel-id` if the channel is not open, with `invalid-nominal-hashrate` if
+    /// the nominal hash rate is negative and with `max-target-out-of-range` if the maximum target
+    /// is harder than the one set with [`PoolChannelFactory::set_min_downstream_target`].
+    pub fn on_update_channel(&mut self, m: UpdateChannel) -> Result<Mining<'static>, Error> {
+        self.record_input(|| RecordedInput::UpdateChannel(m.clone().into_static()));
+        let channel_id = m.channel_id;
+        if !self.inner.extended_channels.contains_key(&channel_id)
+            && !self.inner.standard_channels.contains_key(&channel_id)
+        {
+            error!("Received UpdateChannel for unknown channel {}", channel_id);
+            return Ok(update_channel_error(channel_id, "invalid-channel-id"));
+        }
+        let maximum_target: Target = m.maximum_target.into();
+        if let Some(min_target) = self.min_downstream_target.as_ref() {
+            if &maximum_target < min_target {
+                error!(
+                    "Channel {} requested a maximum target harder than the min downstream target",
+                    channel_id
+                );
+                return Ok(update_channel_error(channel_id, "max-target-out-of-range"));
             }
-            None => {
-                let err = SubmitSharesError {
-                    channel_id: m.channel_id,
-                    sequence_number: m.sequence_number,
-                    error_code: SubmitSharesError::invalid_channel_error_code()
-                        .to_string()
-                        .try_into()
-                        .unwrap(),
-                };
-                Ok(OnNewShare::SendErrorDownstream(err))
+        }
+        if m.nominal_hash_rate.is_sign_negative() {
+            error!(
+                "Channel {} requested a negative nominal hash rate {}",
+                channel_id, m.nominal_hash_rate
+            );
+            return Ok(update_channel_error(channel_id, "invalid-nominal-hashrate"));
+        }
+        let hash_rate_target: Target = crate::utils::hash_rate_to_target(
+            m.nominal_hash_rate.into(),
+            self.inner.share_per_min.into(),
+        )?
+        .into();
+        let mut new_target = std::cmp::min(hash_rate_target, maximum_target);
+        if let Some(min_target) = self.min_downstream_target.as_ref() {
+            new_target = std::cmp::max(new_target, min_target.clone());
+        }
+        Ok(self
+            .inner
+            .update_target_for_channel_with_message(channel_id, new_target)
+            .unwrap_or_else(|| update_channel_error(channel_id, "invalid-channel-id")))
+    }
+
+    /// Calls [`ChannelFactory::try_vardiff`]
+    /// Meant to be called periodically for every extended channel, when a new target is returned
+    /// it has already been set and must be sent downstream with a `SetTarget`.
+    pub fn try_vardiff(&mut self, channel_id: u32) -> Option<Target> {
+        self.inner.try_vardiff(channel_id)
+    }
+
+    /// Calls [`ChannelFactory::estimated_hashrate`]
+    pub fn estimated_hashrate(&self, channel_id: u32) -> Option<f64> {
+        self.inner.estimated_hashrate(channel_id)
+    }
+
+    /// Calls [`ChannelFactory::get_channel_stats`]
+    pub fn get_channel_stats(&self, channel_id: u32) -> Option<ChannelStats> {
+        self.inner.get_channel_stats(channel_id)
+    }
+
+    /// Set the downstream target of an extended channel and return the messages that must be sent
+    /// to it: a `SetTarget` and, when the difficulty changes by more than `job_resend_factor`
+    /// times in either direction, the current job, so that the miner restarts its search with
+    /// the new target. Returns None if the channel does not exist.
+    pub fn update_target_for_channel_with_job(
+        &mut self,
+        channel_id: u32,
+        new_target: Target,
+        job_resend_factor: f64,
+    ) -> Option<Vec<Mining<'static>>> {
+        let old_target = self
+            .inner
+            .extended_channels
+            .get(&channel_id)?
+            .target
+            .clone();
+        let old_difficulty = crate::utils::target_to_difficulty(&old_target.into());
+        let new_difficulty = crate::utils::target_to_difficulty(&new_target);
+        let difficulty_change = f64::max(
+            new_difficulty / old_difficulty,
+            old_difficulty / new_difficulty,
+        );
+        let mut messages = vec![self
+            .inner
+            .update_target_for_channel_with_message(channel_id, new_target)?];
+        if difficulty_change > job_resend_factor {
+            if let Some(job) = self.extended_job_for_channel(channel_id) {
+                messages.push(Mining::NewExtendedMiningJob(job));
             }
         }
+        Some(messages)
     }
 
-    /// Called when a `SubmitSharesExtended` message is received from the downstream. We check the
-    /// shares against the channel's respective target and return `OnNewShare` to let us know if
-    /// and where the shares should be relayed
-    pub fn on_submit_shares_extended(
+    /// Set the downstream target of every channel in the group and return the `SetTarget`
+    /// messages that must be sent to each of them, ordered by channel id.
+    pub fn update_group_target(
         &mut self,
-        m: SubmitSharesExtended,
-    ) -> Result<OnNewShare, Error> {
-        let target = self.job_creator.last_target();
-        // When downstream set a custom mining job we add the job to the negotiated job
-        // hashmap, with the extended channel id as a key. Whenever the pool receive a share must
-        // first check if the channel have a negotiated job if so we can not retrieve the template
-        // via the job creator but we create a new one from the set custom job.
-        if self.negotiated_jobs.contains_key(&m.channel_id) {
-            let referenced_job = self.negotiated_jobs.get(&m.channel_id).unwrap();
-            let merkle_path = referenced_job.merkle_path.to_vec();
-            let extended_job = job_creator::extended_job_from_custom_job(
-                referenced_job,
-                self.inner.extranonces.get_len() as u8,
-            )
-            .unwrap();
-            let prev_blockhash = crate::utils::u256_to_block_hash(referenced_job.prev_hash.clone());
-            let bits = referenced_job.nbits;
-            self.inner.check_target(
-                Share::Extended(m.into_static()),
-                target,
-                None,
-                0,
-                merkle_path,
-                extended_job.coinbase_tx_prefix.as_ref(),
-                extended_job.coinbase_tx_suffix.as_ref(),
-                prev_blockhash,
-                bits,
-            )
-        } else {
-            let referenced_job = self
-                .inner
-                .last_valid_job
-                .clone()
-                .ok_or(Error::ShareDoNotMatchAnyJob)?
-                .0;
-            let merkle_path = referenced_job.merkle_path.to_vec();
-            let template_id = self
-                .job_creator
-                .get_template_id_from_job(referenced_job.job_id)
-                .ok_or(Error::NoTemplateForId)?;
-            let prev_blockhash = self
+        group_id: u32,
+        new_target: Target,
+    ) -> Vec<(u32, Mining<'static>)> {
+        let mut channel_ids: Vec<u32> = self
+            .inner
+            .channel_to_group_id
+            .iter()
+            .filter(|(_, g_id)| **g_id == group_id)
+            .map(|(channel_id, _)| *channel_id)
+            .collect();
+        channel_ids.sort_unstable();
+        let mut messages = Vec::with_capacity(channel_ids.len());
+        for channel_id in channel_ids {
+            if let Some(set_target) = self
                 .inner
-                .last_prev_hash_
-                .ok_or(Error::ShareDoNotMatchAnyJob)?;
-            let bits = self
+                .update_target_for_channel_with_message(channel_id, new_target.clone())
+            {
+                messages.push((channel_id, set_target));
+            }
+        }
+        messages
+    }
+
+    /// Set the target for this channel. This is the upstream target.
+    pub fn set_target(&mut self, new_target: &mut Target) {
+        self.inner.kind.set_target(new_target);
+    }
+
+    /// Calls [`ChannelFactory::set_test_targets`]
+    #[cfg(any(test, feature = "test_targets"))]
+    pub fn set_test_targets(&mut self, downstream: Target, upstream: Target, bitcoin: Target) {
+        self.inner.set_test_targets(downstream, upstream, bitcoin);
+    }
+
+    /// Returns the accounting of the shares submitted on the channel
+    pub fn share_accounting(&self, channel_id: u32) -> Option<&ShareAccounting> {
+        self.inner.share_accounting.get(&channel_id)
+    }
+
+    /// Returns a [`ChannelOverview`] of every extended and standard channel, ordered by channel
+    /// id, so that a dashboard can get the state of all the channels with a single call.
+    pub fn channel_overview(&self) -> Vec<ChannelOverview> {
+        let now = self.inner.clock.now();
+        let extended_channels = self
+            .inner
+            .extended_channels
+            .iter()
+            .map(|(channel_id, channel)| (*channel_id, Target::from(channel.target.clone())));
+        let standard_channels = self
+            .inner
+            .standard_channels
+            .iter()
+            .map(|(channel_id, channel)| (*channel_id, channel.target.clone()));
+        let mut overview: Vec<ChannelOverview> = extended_channels
+            .chain(standard_channels)
+            .map(|(channel_id, target)| {
+                let accounting = self.inner.share_accounting.get(&channel_id);
+                ChannelOverview {
+                    channel_id,
+                    group_id: *self
+                        .inner
+                        .channel_to_group_id
+                        .get(&channel_id)
+                        .unwrap_or(&0),
+                    difficulty: crate::utils::target_to_difficulty(&target),
+                    target,
+                    shares_accepted: accounting.map_or(0, |a| a.shares_accepted()),
+                    shares_rejected: accounting.map_or(0, |a| a.shares_rejected()),
+                    last_share_age: self
+                        .inner
+                        .last_share_time
+                        .get(&channel_id)
+                        .map(|time| now.saturating_duration_since(*time)),
+                }
+            })
+            .collect();
+        overview.sort_unstable_by_key(|channel| channel.channel_id);
+        overview
+    }
+
+    /// Returns the hashrate implied by the target of the extended or standard channel
+    /// `channel_id` at the factory shares per minute, `None` if the channel is unknown.
+    pub fn channel_implied_hashrate(&self, channel_id: u32) -> Option<f64> {
+        let target = match self.inner.extended_channels.get(&channel_id) {
+            Some(channel) => Target::from(channel.target.clone()),
+            None => self
                 .inner
-                .last_prev_hash
+                .standard_channels
+                .get(&channel_id)?
+                .target
+                .clone(),
+        };
+        Some(crate::utils::target_to_hash_rate(
+            &target,
+            self.inner.share_per_min.into(),
+        ))
+    }
+
+    /// Buckets the extended and standard channels by difficulty. `bands` are the ascending
+    /// difficulties that separate the buckets, so `bands.len() + 1` buckets are returned: the
+    /// first has the channels below `bands[0]`, bucket `i` the channels from `bands[i - 1]`
+    /// included to `bands[i]` excluded and the last the channels at or above the top band. The
+    /// channel ids of a bucket are ordered.
+    pub fn channels_by_difficulty_band(&self, bands: &[f64]) -> Vec<Vec<u32>> {
+        let mut buckets = vec![Vec::new(); bands.len() + 1];
+        for channel in self.channel_overview() {
+            let band = bands
+                .iter()
+                .take_while(|band| channel.difficulty >= **band)
+                .count();
+            buckets[band].push(channel.channel_id);
+        }
+        buckets
+    }
+
+    /// Returns the accepted, rejected and block shares counts and the current difficulty of every
+    /// extended channel in the Prometheus text exposition format, labeled by channel and group id.
+    pub fn metrics_text(&self) -> String {
+        #[allow(clippy::type_complexity)]
+        let metrics: [(&str, &str, &str, fn(&ShareAccounting, f64) -> f64); 4] = [
+            (
+                "sv2_channel_shares_accepted_total",
+                "counter",
+                "Shares that met at least the channel target",
+                |accounting, _| accounting.shares_accepted() as f64,
+            ),
+            (
+                "sv2_channel_shares_rejected_total",
+                "counter",
+                "Shares rejected by the factory",
+                |accounting, _| accounting.shares_rejected() as f64,
+            ),
+            (
+                "sv2_channel_blocks_found_total",
+                "counter",
+                "Shares that met the bitcoin target",
+                |accounting, _| accounting.blocks_found() as f64,
+            ),
+            (
+                "sv2_channel_difficulty",
+                "gauge",
+                "Current channel difficulty",
+                |_, difficulty| difficulty,
+            ),
+        ];
+        let mut channel_ids: Vec<u32> = self.inner.extended_channels.keys().copied().collect();
+        channel_ids.sort_unstable();
+        let empty_accounting = ShareAccounting::new();
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics.iter() {
+            // Infallible unwraps writing to a String never fails
+            writeln!(text, "# HELP {name} {help}").unwrap();
+            writeln!(text, "# TYPE {name} {kind}").unwrap();
+            for channel_id in &channel_ids {
+                let channel = &self.inner.extended_channels[channel_id];
+                let group_id = self.inner.channel_to_group_id.get(channel_id).unwrap_or(&0);
+                let accounting = self
+                    .inner
+                    .share_accounting
+                    .get(channel_id)
+                    .unwrap_or(&empty_accounting);
+                let difficulty = crate::utils::target_to_difficulty(&channel.target.clone().into());
+                writeln!(
+                    text,
+                    "{name}{{channel_id=\"{channel_id}\",group_id=\"{group_id}\"}} {}",
+                    value(accounting, difficulty)
+                )
+                .unwrap();
+            }
+        }
+        text
+    }
+
+    /// Set the [`ShareCredit`] invoked on each accepted share, it replaces the previous one
+    /// together with the credit that it accumulated.
+    pub fn set_share_credit(&mut self, share_credit: Box<dyn ShareCredit>) {
+        self.inner.share_credit = share_credit;
+    }
+
+    /// Returns the credit accumulated by the channel according to the [`ShareCredit`] in use.
+    pub fn share_credit(&self, channel_id: u32) -> f64 {
+        self.inner.share_credit.credit(channel_id)
+    }
+
+    /// Returns the extended channels opened at least `grace` ago that never submitted an
+    /// accepted share, ordered by channel id.
+    pub fn unproductive_channels(&self, grace: Duration) -> Vec<u32> {
+        let now = self.inner.clock.now();
+        let mut channel_ids: Vec<u32> = self
+            .inner
+            .extended_channels
+            .keys()
+            .copied()
+            .filter(|channel_id| {
+                let open_long_enough = self
+                    .inner
+                    .channel_open_time
+                    .get(channel_id)
+                    .is_some_and(|open_time| now.saturating_duration_since(*open_time) >= grace);
+                let accepted_shares = self
+                    .inner
+                    .share_accounting
+                    .get(channel_id)
+                    .map_or(0, |accounting| accounting.shares_accepted());
+                open_long_enough && accepted_shares == 0
+            })
+            .collect();
+        channel_ids.sort_unstable();
+        channel_ids
+    }
+
+    /// Set the [`Clock`] used by the factory, the opening time of the channels that are already
+    /// open is not updated.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.inner.clock = clock;
+    }
+
+    /// Start recording the inputs of the factory with `recorder`, None, the default, stops the
+    /// recording. Each input is recorded with the reading of the factory [`Clock`]. The recording
+    /// can be applied to a fresh factory with [`super::input_recorder::replay`].
+    pub fn set_input_recorder(&mut self, recorder: Option<InputRecorder>) {
+        self.input_recorder = recorder;
+    }
+
+    /// Stops the recording and returns the recorder, None if the inputs were not recorded.
+    pub fn take_input_recorder(&mut self) -> Option<InputRecorder> {
+        self.input_recorder.take()
+    }
+
+    /// Returns the state of the factory that must survive a restart: the channels with their
+    /// groups, flags, targets and extranonces, the current prev hash and job, the last job id and
+    /// the negotiated jobs. The factory configuration, the share stats, the templates and the
+    /// previous tips are not part of it.
+    pub fn to_snapshot(&self) -> Result<PoolChannelFactorySnapshot, Error> {
+        let inner = &self.inner;
+        let extended_channels = sorted_keys(&inner.extended_channels)
+            .into_iter()
+            .map(|channel_id| -> Result<_, Error> {
+                Ok(ExtendedChannelSnapshot {
+                    channel: binary_sv2::to_bytes(inner.extended_channels[&channel_id].clone())?,
+                    flags: inner
+                        .channel_flags
+                        .get(&channel_id)
+                        .copied()
+                        .unwrap_or_else(ConnectionFlags::all),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let standard_channels = sorted_keys(&inner.standard_channels)
+            .into_iter()
+            .map(|channel_id| {
+                let channel = &inner.standard_channels[&channel_id];
+                StandardChannelSnapshot {
+                    channel_id,
+                    group_id: channel.group_id,
+                    target: binary_sv2::U256::from(channel.target.clone()).to_vec(),
+                    extranonce: channel.extranonce.clone(),
+                }
+            })
+            .collect();
+        let channel_to_group_id = sorted_keys(&inner.channel_to_group_id)
+            .into_iter()
+            .map(|channel_id| (channel_id, inner.channel_to_group_id[&channel_id]))
+            .collect();
+        let extranonces = ExtranoncesSnapshot {
+            bytes: mining_sv2::Extranonce::from(&mut inner.extranonces.clone()).to_vec(),
+            range_0_end: inner.extranonces.get_range0_len(),
+            range_1_end: inner.extranonces.get_prefix_len(),
+            range_2_end: inner.extranonces.get_len(),
+        };
+        let (last_group_id, last_channel_id, freed_channel_ids) =
+            inner.ids.safe_lock(|ids| ids.state())?;
+        let ids = IdsSnapshot {
+            last_group_id,
+            last_channel_id,
+            freed_channel_ids,
+        };
+        let last_prev_hash = inner
+            .last_prev_hash
+            .as_ref()
+            .map(|(prev_hash, _)| binary_sv2::to_bytes(prev_hash.into_set_p_hash(0, None)))
+            .transpose()?;
+        let last_valid_job = match inner.last_valid_job.as_ref() {
+            Some((job, _)) => {
+                let template_id = self
+                    .job_creator
+                    .get_template_id_from_job(job.job_id)
+                    .ok_or(Error::NoTemplateForId)?;
+                Some((binary_sv2::to_bytes(job.clone())?, template_id))
+            }
+            None => None,
+        };
+        let negotiated_jobs = sorted_keys(&self.negotiated_jobs)
+            .into_iter()
+            .map(|channel_id| -> Result<_, Error> {
+                let (job_id, job) = &self.negotiated_jobs[&channel_id];
+                Ok((channel_id, *job_id, binary_sv2::to_bytes(job.clone())?))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(PoolChannelFactorySnapshot {
+            extended_channels,
+            standard_channels,
+            channel_to_group_id,
+            extranonces,
+            ids,
+            last_prev_hash,
+            last_valid_job,
+            bitcoin_target: binary_sv2::U256::from(self.job_creator.last_target()).to_vec(),
+            last_job_id: self.job_creator.last_job_id(),
+            negotiated_jobs,
+        })
+    }
+
+    /// Builds a factory from a snapshot taken with [`PoolChannelFactory::to_snapshot`], the other
+    /// parameters are the ones of [`PoolChannelFactory::new`]. `job_creator` must be new, its job
+    /// ids continue from the last one of the snapshot, and `ids` is advanced past the group and
+    /// channel ids of the snapshot, so that it does not hand out the ids of the restored channels.
+    /// The active job and prev hash are resent to every channel by
+    /// [`PoolChannelFactory::undelivered_messages`], the factory configuration must be set again.
+    pub fn from_snapshot(
+        snapshot: PoolChannelFactorySnapshot,
+        ids: Arc<Mutex<GroupId>>,
+        mut job_creator: JobsCreators,
+        share_per_min: f32,
+        pool_coinbase_outputs: Vec<TxOut>,
+    ) -> Result<Self, Error> {
+        let ExtranoncesSnapshot {
+            bytes,
+            range_0_end,
+            range_1_end,
+            range_2_end,
+        } = snapshot.extranonces;
+        let extranonces = ExtendedExtranonce::from_upstream_extranonce(
+            bytes.try_into().map_err(|_| Error::MalformedSnapshot)?,
+            0..range_0_end,
+            range_0_end..range_1_end,
+            range_1_end..range_2_end,
+        )
+        .map_err(Error::ExtranoncePrefixFactoryError)?;
+        let last_prev_hash = match snapshot.last_prev_hash {
+            Some(mut prev_hash) => {
+                let prev_hash = binary_sv2::from_bytes::<SetNewPrevHash>(&mut prev_hash)
+                    .map_err(|_| Error::MalformedSnapshot)?
+                    .into_static();
+                Some(StagedPhash {
+                    job_id: prev_hash.job_id,
+                    prev_hash: prev_hash.prev_hash,
+                    min_ntime: prev_hash.min_ntime,
+                    nbits: prev_hash.nbits,
+                })
+            }
+            None => None,
+        };
+        let last_valid_job = match snapshot.last_valid_job {
+            Some((mut job, template_id)) => Some((
+                binary_sv2::from_bytes::<NewExtendedMiningJob>(&mut job)
+                    .map_err(|_| Error::MalformedSnapshot)?
+                    .into_static(),
+                template_id,
+            )),
+            None => None,
+        };
+        job_creator.restore(
+            snapshot.last_job_id,
+            last_valid_job
                 .as_ref()
-                .ok_or(Error::ShareDoNotMatchAnyJob)?
-                .0
-                .nbits;
-            self.inner.check_target(
-                Share::Extended(m.into_static()),
-                target,
-                Some(template_id),
-                0,
-                merkle_path,
-                referenced_job.coinbase_tx_prefix.as_ref(),
-                referenced_job.coinbase_tx_suffix.as_ref(),
-                prev_blockhash,
-                bits,
+                .map(|(job, template_id)| (job.job_id, *template_id)),
+            snapshot_target(snapshot.bitcoin_target)?,
+            last_prev_hash.as_ref().map(|prev_hash| prev_hash.min_ntime),
+        );
+        let channel_ids_in_use: Vec<u32> = snapshot
+            .channel_to_group_id
+            .iter()
+            .map(|(channel_id, _)| *channel_id)
+            .collect();
+        ids.safe_lock(|ids| {
+            ids.restore(
+                snapshot.ids.last_group_id,
+                snapshot.ids.last_channel_id,
+                &snapshot.ids.freed_channel_ids,
+                &channel_ids_in_use,
             )
+        })?;
+
+        let mut factory = Self::new(
+            ids,
+            extranonces,
+            job_creator,
+            share_per_min,
+            ExtendedChannelKind::Pool,
+            pool_coinbase_outputs,
+        );
+        let inner = &mut factory.inner;
+        let now = inner.clock.now();
+        for ExtendedChannelSnapshot { mut channel, flags } in snapshot.extended_channels {
+            let channel = binary_sv2::from_bytes::<OpenExtendedMiningChannelSuccess>(&mut channel)
+                .map_err(|_| Error::MalformedSnapshot)?
+                .into_static();
+            inner
+                .share_accounting
+                .insert(channel.channel_id, ShareAccounting::new());
+            inner.channel_flags.insert(channel.channel_id, flags);
+            inner.channel_open_time.insert(channel.channel_id, now);
+            inner.extended_channels.insert(channel.channel_id, channel);
+        }
+        for channel in snapshot.standard_channels {
+            inner
+                .share_accounting
+                .insert(channel.channel_id, ShareAccounting::new());
+            inner.standard_channels.insert(
+                channel.channel_id,
+                StandardChannel {
+                    group_id: channel.group_id,
+                    target: snapshot_target(channel.target)?,
+                    extranonce: channel.extranonce,
+                },
+            );
+        }
+        inner.channel_to_group_id = snapshot.channel_to_group_id.into_iter().collect();
+        inner.last_prev_hash = last_prev_hash.map(|prev_hash| (prev_hash, vec![]));
+        inner.last_valid_job = last_valid_job.map(|(job, _)| (job, vec![]));
+        for (channel_id, job_id, mut job) in snapshot.negotiated_jobs {
+            let job = binary_sv2::from_bytes::<SetCustomMiningJob>(&mut job)
+                .map_err(|_| Error::MalformedSnapshot)?
+                .into_static();
+            factory.negotiated_jobs.insert(channel_id, (job_id, job));
         }
+        Ok(factory)
+    }
+
+    /// Forget the current prev hash and the active job, channels stay open but every share is
+    /// rejected with `stale-share` until a new prev hash is received. The previous tips are
+    /// forgotten as well.
+    pub fn expire_prev_hash(&mut self) {
+        self.record_input(|| RecordedInput::ExpirePrevHash);
+        self.inner.last_prev_hash = None;
+        self.inner.last_valid_job = None;
+        self.previous_tips.clear();
+        self.template_after_prev_hash = None;
+    }
+
+    /// Enable or disable the opening of new channels. When disabled every open channel request
+    /// is answered with an `OpenMiningChannelError` with the `maintenance` error code, channels
+    /// that are already open keep receiving jobs and can still submit shares.
+    pub fn set_accepting_new_channels(&mut self, accept: bool) {
+        self.inner.accepting_new_channels = accept;
+    }
+
+    /// Enable or disable strict job id enforcement, enabled by default. When enabled shares that
+    /// do not reference the last valid job are rejected with `stale-share` if they reference a
+    /// superseded job and with `invalid-job-id` otherwise, when disabled they are checked against
+    /// the last valid job.
+    pub fn set_strict_job_id(&mut self, strict: bool) {
+        self.inner.strict_job_id = strict;
+    }
+
+    /// Set the bits of the block version that shares can roll, BIP320 `0x1fffe000` by default.
+    /// Shares whose version differs from the job version outside of the mask are rejected with
+    /// `invalid-version`, channels that did not negotiate version rolling can not roll any bit.
+    pub fn set_version_rolling_mask(&mut self, mask: u32) {
+        self.inner.version_rolling_mask = mask;
+    }
+
+    /// Set how far in the future, from the clock unix time, the ntime of a share can be, shares
+    /// with a later ntime are rejected with `ntime-too-far-in-future`. It is 2 hours by default,
+    /// like the max future block time of bitcoin, None disables the check. Shares with an ntime
+    /// before the `min_ntime` of their job are always rejected with `ntime-below-min-ntime`.
+    pub fn set_max_ntime_drift(&mut self, drift: Option<Duration>) {
+        self.inner.max_ntime_drift = drift;
     }
 
-    /// Utility function to return a new group id
-    pub fn new_group_id(&mut self) -> u32 {
-        let new_id = self.inner.ids.safe_lock(|ids| ids.new_group_id()).unwrap();
-        new_id
+    /// When true the coinbase and the merkle path of every new job are prepared once, and the
+    /// merkle root of the `NewMiningJob` of each standard channel only hashes the coinbase with
+    /// the channel extranonce, so that the factory does not deserialize the coinbase of each
+    /// standard channel on every new job. The shares of the job reuse the same preparation. It is
+    /// false by default.
+    pub fn set_lazy_merkle_roots(&mut self, lazy: bool) {
+        self.inner.lazy_merkle_roots = lazy;
     }
 
-    /// Utility function to return a new standard channel id
-    pub fn new_standard_id_for_hom(&mut self) -> u32 {
-        let hom_group_id = 0;
-        let new_id = self
-            .inner
-            .ids
-            .safe_lock(|ids| ids.new_channel_id(hom_group_id))
-            .unwrap();
-        new_id
+    /// Set the hardest target that the downstreams can get with an `UpdateChannel`, requests for
+    /// a harder maximum target are refused with `max-target-out-of-range` and the targets derived
+    /// from the nominal hash rate are capped to it. None, the default, accepts any target.
+    pub fn set_min_downstream_target(&mut self, min_target: Option<Target>) {
+        self.min_downstream_target = min_target;
     }
 
-    /// Returns the full extranonce, extranonce1 (static for channel) + extranonce2 (miner nonce
-    /// space)
-    pub fn extranonce_from_downstream_extranonce(
-        &self,
-        ext: mining_sv2::Extranonce,
-    ) -> Option<mining_sv2::Extranonce> {
-        self.inner
-            .extranonces
-            .extranonce_from_downstream_extranonce(ext)
-            .ok()
+    /// Set the max number of future jobs queued until the next prev hash, by default there is no
+    /// limit. When a new future job exceeds it the oldest future job is evicted.
+    pub fn set_max_future_jobs(&mut self, max_future_jobs: usize) {
+        self.inner.max_future_jobs = max_future_jobs;
+        self.inner.evict_future_jobs();
     }
 
-    /// Called when a new custom mining job arrives
-    pub fn on_new_set_custom_mining_job(
-        &mut self,
-        set_custom_mining_job: SetCustomMiningJob<'static>,
-    ) -> SetCustomMiningJobSuccess {
-        if self.check_set_custom_mining_job(&set_custom_mining_job) {
-            self.negotiated_jobs.insert(
-                set_custom_mining_job.channel_id,
-                set_custom_mining_job.clone(),
-            );
-            SetCustomMiningJobSuccess {
-                channel_id: set_custom_mining_job.channel_id,
-                request_id: set_custom_mining_job.request_id,
-                job_id: self.inner.job_ids.next(),
-            }
-        } else {
-            todo!()
-        }
+    /// Enable or disable rejection details, disabled by default. When enabled every
+    /// [`OnNewShare::SendErrorDownstream`] comes with a [`ShareRejectionDetail`] for the role to
+    /// log, the error sent downstream is the same.
+    pub fn set_rejection_details(&mut self, enabled: bool) {
+        self.inner.rejection_details = enabled;
     }
 
-    fn check_set_custom_mining_job(
-        &self,
-        _set_custom_mining_job: &SetCustomMiningJob<'static>,
-    ) -> bool {
-        true
+    /// Set a callback invoked with every `NewExtendedMiningJob` received by the factory, before
+    /// the jobs for the downstream channels are built. It replaces the previous one.
+    pub fn set_on_new_job<F>(&mut self, on_new_job: F)
+    where
+        F: Fn(&NewExtendedMiningJob<'static>) + Send + 'static,
+    {
+        self.inner.on_new_job = Some(OnNewJobHook(Box::new(on_new_job)));
     }
+}
 
-    /// Get extended channel ids
-    pub fn get_extended_channels_ids(&self) -> Vec<u32> {
-        self.inner.extended_channels.keys().copied().collect()
+/// Builds a [`PoolChannelFactory`] from named parameters, the same ones of
+/// [`PoolChannelFactory::new`]. `ids`, `extranonces`, `job_creator` and `share_per_min` are
+/// required, the pool coinbase outputs are empty if not set.
+#[derive(Debug, Default)]
+pub struct PoolChannelFactoryBuilder {
+    ids: Option<Arc<Mutex<GroupId>>>,
+    extranonces: Option<ExtendedExtranonce>,
+    job_creator: Option<JobsCreators>,
+    share_per_min: Option<f32>,
+    pool_coinbase_outputs: Vec<TxOut>,
+}
+
+impl PoolChannelFactoryBuilder {
+    /// Creates a builder without parameters
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    pub fn get_shares_per_minute(&self) -> f32 {
-        self.inner.share_per_min
+    /// Set the ids of the channels and groups
+    pub fn ids(mut self, ids: Arc<Mutex<GroupId>>) -> Self {
+        self.ids = Some(ids);
+        self
     }
 
-    /// Update coinbase outputs
-    pub fn update_pool_outputs(&mut self, outs: Vec<TxOut>) {
-        self.pool_coinbase_outputs = outs;
+    /// Set the extranonces the channel prefixes are taken from
+    pub fn extranonces(mut self, extranonces: ExtendedExtranonce) -> Self {
+        self.extranonces = Some(extranonces);
+        self
     }
 
-    /// Calls [`ChannelFactory::update_target_for_channel`]
-    /// Set a particular downstream channel target.
-    pub fn update_target_for_channel(
-        &mut self,
-        channel_id: u32,
-        new_target: Target,
-    ) -> Option<bool> {
-        self.inner.update_target_for_channel(channel_id, new_target)
+    /// Set the job creator that creates the jobs from the templates
+    pub fn job_creator(mut self, job_creator: JobsCreators) -> Self {
+        self.job_creator = Some(job_creator);
+        self
     }
 
-    /// Set the target for this channel. This is the upstream target.
-    pub fn set_target(&mut self, new_target: &mut Target) {
-        self.inner.kind.set_target(new_target);
+    /// Set the number of shares per minute the channel targets are computed for
+    pub fn share_per_min(mut self, share_per_min: f32) -> Self {
+        self.share_per_min = Some(share_per_min);
+        self
+    }
+
+    /// Set the coinbase outputs of the pool
+    pub fn pool_coinbase_outputs(mut self, pool_coinbase_outputs: Vec<TxOut>) -> Self {
+        self.pool_coinbase_outputs = pool_coinbase_outputs;
+        self
+    }
+
+    /// Builds the factory, fails with [`Error::MissingFactoryParameter`] if a required parameter
+    /// is not set
+    pub fn build(self) -> Result<PoolChannelFactory, Error> {
+        Ok(PoolChannelFactory::new(
+            self.ids.ok_or(Error::MissingFactoryParameter("ids"))?,
+            self.extranonces
+                .ok_or(Error::MissingFactoryParameter("extranonces"))?,
+            self.job_creator
+                .ok_or(Error::MissingFactoryParameter("job_creator"))?,
+            self.share_per_min
+                .ok_or(Error::MissingFactoryParameter("share_per_min"))?,
+            ExtendedChannelKind::Pool,
+            self.pool_coinbase_outputs,
+        ))
     }
 }
 
@@ -905,10 +4763,14 @@ pub struct ProxyExtendedChannelFactory {
     pool_coinbase_outputs: Option<Vec<TxOut>>,
     // Id assigned to the extended channel by upstream
     extended_channel_id: u32,
+    // When true a job created with a merkle path different from the template one is an error
+    strict_merkle_path: bool,
+    // When true a prev hash that activates a job without a stored future template is an error
+    strict_future_templates: bool,
 }
 
 impl ProxyExtendedChannelFactory {
-    /// Constructor
+    /// Constructor, panics where [`ProxyExtendedChannelFactory::try_new`] fails
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         ids: Arc<Mutex<GroupId>>,
@@ -919,39 +4781,87 @@ impl ProxyExtendedChannelFactory {
         pool_coinbase_outputs: Option<Vec<TxOut>>,
         extended_channel_id: u32,
     ) -> Self {
-        match &kind {
-            ExtendedChannelKind::Proxy { .. } => {
-                if job_creator.is_some() {
-                    panic!("Channel factory of kind Proxy can not be initialized with a JobCreators");
-                };
-            },
-            ExtendedChannelKind::ProxyJd { .. } => {
-                if job_creator.is_none() {
-                    panic!("Channel factory of kind ProxyJd must be initialized with a JobCreators");
-                };
-            }
-            ExtendedChannelKind::Pool => panic!("Try to construct an ProxyExtendedChannelFactory with pool kind, kind must be Proxy or ProxyJd"),
-        };
+        match Self::try_new(
+            ids,
+            extranonces,
+            job_creator,
+            share_per_min,
+            kind,
+            pool_coinbase_outputs,
+            extended_channel_id,
+        ) {
+            Ok(factory) => factory,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Constructor. Fails with [`Error::ProxyFactoryWithJobCreator`] if a factory of kind Proxy
+    /// is given a job creator, with [`Error::ProxyJdFactoryWithoutJobCreator`] if a factory of
+    /// kind ProxyJd is not given one and with [`Error::ProxyFactoryWithPoolKind`] if the kind is
+    /// Pool.
+    #[allow(clippy::too_many_arguments)]
+    pub fn try_new(
+        ids: Arc<Mutex<GroupId>>,
+        extranonces: ExtendedExtranonce,
+        job_creator: Option<JobsCreators>,
+        share_per_min: f32,
+        kind: ExtendedChannelKind,
+        pool_coinbase_outputs: Option<Vec<TxOut>>,
+        extended_channel_id: u32,
+    ) -> Result<Self, Error> {
+        check_proxy_factory_kind(&kind, job_creator.is_some())?;
         let inner = ChannelFactory {
             ids,
             extended_channels: HashMap::with_hasher(BuildNoHashHasher::default()),
             extranonces,
             share_per_min,
             future_jobs: Vec::new(),
+            max_future_jobs: usize::MAX,
             last_prev_hash: None,
-            last_prev_hash_: None,
             last_valid_job: None,
+            stale_job_ids: VecDeque::with_capacity(STALE_JOB_IDS_HISTORY_LEN),
             kind,
-            job_ids: Id::new(),
             channel_to_group_id: HashMap::with_hasher(BuildNoHashHasher::default()),
+            standard_channels: HashMap::with_hasher(BuildNoHashHasher::default()),
             future_templates: HashMap::with_hasher(BuildNoHashHasher::default()),
+            accepting_new_channels: true,
+            strict_job_id: true,
+            extranonce2_diversity: None,
+            share_credit: Box::new(NoShareCredit),
+            on_new_job: None,
+            clock: Box::new(SystemClock),
+            channel_open_time: HashMap::with_hasher(BuildNoHashHasher::default()),
+            last_extranonce_grant: None,
+            reduced_extranonce_prefixes: HashMap::with_hasher(BuildNoHashHasher::default()),
+            last_standard_extranonce: None,
+            block_detection_disabled: HashSet::with_hasher(BuildNoHashHasher::default()),
+            recent_shares: HashMap::with_hasher(BuildNoHashHasher::default()),
+            channel_stats: HashMap::with_hasher(BuildNoHashHasher::default()),
+            last_share_time: HashMap::with_hasher(BuildNoHashHasher::default()),
+            vardiff: HashMap::with_hasher(BuildNoHashHasher::default()),
+            found_blocks: VecDeque::with_capacity(FOUND_BLOCKS_HISTORY_LEN),
+            block_debounce: None,
+            last_found_block: None,
+            closed_channel_ttl: None,
+            recently_closed_channels: HashMap::with_hasher(BuildNoHashHasher::default()),
+            share_accounting: HashMap::with_hasher(BuildNoHashHasher::default()),
+            channel_flags: HashMap::with_hasher(BuildNoHashHasher::default()),
+            rejection_details: false,
+            max_ntime_drift: Some(DEFAULT_MAX_NTIME_DRIFT),
+            merkle_caches: HashMap::with_hasher(BuildNoHashHasher::default()),
+            lazy_merkle_roots: false,
+            version_rolling_mask: VERSION_ROLLING_MASK,
+            #[cfg(any(test, feature = "test_targets"))]
+            test_targets: None,
         };
-        ProxyExtendedChannelFactory {
+        Ok(ProxyExtendedChannelFactory {
             inner,
             job_creator,
             pool_coinbase_outputs,
             extended_channel_id,
-        }
+            strict_merkle_path: false,
+            strict_future_templates: false,
+        })
     }
 
     /// Calls [`ChannelFactory::new_extended_channel`]
@@ -960,20 +4870,80 @@ impl ProxyExtendedChannelFactory {
         request_id: u32,
         hash_rate: f32,
         min_extranonce_size: u16,
+        flags: ConnectionFlags,
     ) -> Result<Vec<Mining>, Error> {
         self.inner
-            .new_extended_channel(request_id, hash_rate, min_extranonce_size)
+            .new_extended_channel(request_id, hash_rate, min_extranonce_size, flags)
+    }
+
+    /// Calls [`ChannelFactory::new_extended_channel_structured`]
+    pub fn new_extended_channel_structured(
+        &mut self,
+        request_id: u32,
+        hash_rate: f32,
+        min_extranonce_size: u16,
+        flags: ConnectionFlags,
+    ) -> Result<Result<NewExtendedChannel, OpenMiningChannelError<'static>>, Error> {
+        self.inner.new_extended_channel_structured(
+            request_id,
+            hash_rate,
+            min_extranonce_size,
+            flags,
+        )
+    }
+
+    /// Calls [`ChannelFactory::close_channel`]
+    pub fn close_channel(&mut self, channel_id: u32) -> bool {
+        self.inner.close_channel(channel_id)
+    }
+
+    /// Called when a `CloseChannel` message is received from the downstream, the reason is logged
+    /// and the channel is closed with [`ProxyExtendedChannelFactory::close_channel`]. Fails with
+    /// [`Error::NotFoundChannelId`] if the channel is not open.
+    pub fn on_close_channel(&mut self, m: CloseChannel) -> Result<(), Error> {
+        info!(
+            "Downstream closed channel {}: {}",
+            m.channel_id,
+            m.reason_code.as_utf8_or_hex()
+        );
+        match self.close_channel(m.channel_id) {
+            true => Ok(()),
+            false => Err(Error::NotFoundChannelId),
+        }
+    }
+
+    /// Set for how long closed channels are remembered, shares for a channel closed less than
+    /// `ttl` ago are rejected with `channel-closed` in place of `invalid-channel-id`. None, the
+    /// default, disables it.
+    pub fn set_closed_channel_ttl(&mut self, ttl: Option<Duration>) {
+        self.inner.closed_channel_ttl = ttl;
+        if ttl.is_none() {
+            self.inner.recently_closed_channels.clear();
+        }
+    }
+
+    /// Enable or disable, enabled by default, the check of the channel shares against the bitcoin
+    /// target. Shares of a channel with block detection disabled never meet the bitcoin target,
+    /// they are only checked against the upstream and downstream targets.
+    pub fn set_block_detection(&mut self, channel_id: u32, enabled: bool) {
+        match enabled {
+            true => self.inner.block_detection_disabled.remove(&channel_id),
+            false => self.inner.block_detection_disabled.insert(channel_id),
+        };
     }
 
     /// Called only when a new prev hash is received by a Template Provider when job declaration is
     /// used. It matches the message with a `job_id`, creates a new custom job, and calls
-    /// [`ChannelFactory::on_new_prev_hash`]
+    /// [`ChannelFactory::on_new_prev_hash`]. If no future template is stored for the job no custom
+    /// job is returned, see [`ProxyExtendedChannelFactory::set_strict_future_templates`].
     pub fn on_new_prev_hash_from_tp(
         &mut self,
         m: &SetNewPrevHashFromTp<'static>,
     ) -> Result<Option<(PartialSetCustomMiningJob, u32)>, Error> {
+        check_nbits(m.n_bits)?;
         if let Some(job_creator) = self.job_creator.as_mut() {
-            let job_id = job_creator.on_new_prev_hash(m).unwrap_or(0);
+            let activated_job_id = job_creator.on_new_prev_hash(m);
+            let job_id = activated_job_id.unwrap_or(0);
             let new_prev_hash = StagedPhash {
                 job_id,
                 prev_hash: m.prev_hash.clone(),
@@ -1002,6 +4972,21 @@ impl ProxyExtendedChannelFactory {
             }
             self.inner.future_templates = HashMap::with_hasher(BuildNoHashHasher::default());
             self.inner.on_new_prev_hash(new_prev_hash)?;
+            let live_job_ids = self.inner.live_job_ids();
+            self.inner.retain_recent_shares(&live_job_ids);
+            // Shares are only checked against the active job, the older mappings are dropped
+            if let Some((job, _)) = self.inner.last_valid_job.as_ref() {
+                job_creator.retain_mappings(&[job.job_id]);
+            }
+            if custom_job.is_none() {
+                warn!(
+                    "No future template for job {}, no custom job is declared for the new prev hash",
+                    job_id
+                );
+                if self.strict_future_templates {
+                    return Err(Error::FutureTemplateMissing(activated_job_id));
+                }
+            }
             Ok(custom_job)
         } else {
             panic!("A channel factory without job creator do not have declaration capabilities")
@@ -1031,7 +5016,22 @@ impl ProxyExtendedChannelFactory {
             self.job_creator.as_mut(),
             self.pool_coinbase_outputs.as_mut(),
         ) {
+            if pool_coinbase_outputs.is_empty() {
+                error!("Received a new template but no pool coinbase outputs are configured");
+                return Err(Error::NoCoinbaseOutputsConfigured);
+            }
+            job_creator.skip_live_job_ids(&self.inner.live_job_ids());
             let new_job = job_creator.on_new_template(m, true, pool_coinbase_outputs.clone())?;
+            // Shares are checked against the job merkle path, it must be the template one
+            if new_job.merkle_path.to_vec() != m.merkle_path.to_vec() {
+                error!(
+                    "Job {} merkle path differs from the merkle path of template {}",
+                    new_job.job_id, m.template_id
+                );
+                if self.strict_merkle_path {
+                    return Err(Error::MerklePathMismatch);
+                }
+            }
             let id = new_job.job_id;
             if !new_job.is_future() && self.inner.last_prev_hash.is_some() {
                 let prev_hash = self.last_prev_hash().unwrap();
@@ -1067,6 +5067,26 @@ impl ProxyExtendedChannelFactory {
         }
     }
 
+    /// Like [`ProxyExtendedChannelFactory::on_new_template`] but when the template coinbase has a
+    /// witness commitment output it is first checked against `wtxids`, the witness hashes of the
+    /// template transactions in block order.
+    #[allow(clippy::type_complexity)]
+    pub fn on_new_template_with_wtxids(
+        &mut self,
+        m: &mut NewTemplate<'static>,
+        wtxids: &[[u8; 32]],
+    ) -> Result<
+        (
+            HashMap<u32, Mining<'static>, BuildNoHashHasher<u32>>,
+            Option<PartialSetCustomMiningJob>,
+            u32,
+        ),
+        Error,
+    > {
+        check_witness_commitment(m, wtxids)?;
+        self.on_new_template(m)
+    }
+
     /// Called when a `SubmitSharesStandard` message is received from the downstream. We check the
     /// shares against the channel's respective target and return `OnNewShare` to let us know if
     /// and where the shares should be relayed
@@ -1090,18 +5110,9 @@ impl ProxyExtendedChannelFactory {
             .ok_or(Error::ShareDoNotMatchAnyJob)?
             .0;
 
-        if referenced_job.job_id != m.job_id {
-            let error = SubmitSharesError {
-                channel_id: m.channel_id,
-                sequence_number: m.sequence_number,
-                // Infallible unwrap we already know the len of the error code (is a
-                // static string)
-                error_code: SubmitSharesError::invalid_job_id_error_code()
-                    .to_string()
-                    .try_into()
-                    .unwrap(),
-            };
-            return Ok(OnNewShare::SendErrorDownstream(error));
+        let share = Share::Extended(m);
+        if let Some(error) = self.inner.check_job_id(&share, referenced_job.job_id) {
+            return Ok(error);
         }
 
         if let Some(job_creator) = self.job_creator.as_mut() {
@@ -1111,17 +5122,16 @@ impl ProxyExtendedChannelFactory {
             let bitcoin_target = job_creator.last_target();
             let prev_blockhash = self
                 .inner
-                .last_prev_hash_
+                .last_prev_blockhash()
                 .ok_or(Error::ShareDoNotMatchAnyJob)?;
-            let bits = self
+            let (bits, min_ntime) = self
                 .inner
                 .last_prev_hash
                 .as_ref()
-                .ok_or(Error::ShareDoNotMatchAnyJob)?
-                .0
-                .nbits;
+                .map(|(prev_hash, _)| (prev_hash.nbits, prev_hash.min_ntime))
+                .ok_or(Error::ShareDoNotMatchAnyJob)?;
             self.inner.check_target(
-                Share::Extended(m),
+                share,
                 bitcoin_target,
                 Some(template_id),
                 self.extended_channel_id,
@@ -1130,6 +5140,8 @@ impl ProxyExtendedChannelFactory {
                 referenced_job.coinbase_tx_suffix.as_ref(),
                 prev_blockhash,
                 bits,
+                min_ntime,
+                referenced_job.version,
             )
         } else {
             let bitcoin_target = [0; 32];
@@ -1137,17 +5149,16 @@ impl ProxyExtendedChannelFactory {
             // bitcoin target so we set bitcoin_target = 0.
             let prev_blockhash = self
                 .inner
-                .last_prev_hash_
+                .last_prev_blockhash()
                 .ok_or(Error::ShareDoNotMatchAnyJob)?;
-            let bits = self
+            let (bits, min_ntime) = self
                 .inner
                 .last_prev_hash
                 .as_ref()
-                .ok_or(Error::ShareDoNotMatchAnyJob)?
-                .0
-                .nbits;
+                .map(|(prev_hash, _)| (prev_hash.nbits, prev_hash.min_ntime))
+                .ok_or(Error::ShareDoNotMatchAnyJob)?;
             self.inner.check_target(
-                Share::Extended(m),
+                share,
                 bitcoin_target.into(),
                 None,
                 self.extended_channel_id,
@@ -1156,6 +5167,8 @@ impl ProxyExtendedChannelFactory {
                 referenced_job.coinbase_tx_suffix.as_ref(),
                 prev_blockhash,
                 bits,
+                min_ntime,
+                referenced_job.version,
             )
         }
     }
@@ -1192,15 +5205,14 @@ impl ProxyExtendedChannelFactory {
                     let bitcoin_target = job_creator.last_target();
                     let prev_blockhash = self
                         .inner
-                        .last_prev_hash_
+                        .last_prev_blockhash()
                         .ok_or(Error::ShareDoNotMatchAnyJob)?;
-                    let bits = self
+                    let (bits, min_ntime) = self
                         .inner
                         .last_prev_hash
                         .as_ref()
-                        .ok_or(Error::ShareDoNotMatchAnyJob)?
-                        .0
-                        .nbits;
+                        .map(|(prev_hash, _)| (prev_hash.nbits, prev_hash.min_ntime))
+                        .ok_or(Error::ShareDoNotMatchAnyJob)?;
                     self.inner.check_target(
                         Share::Standard((m, *g_id)),
                         bitcoin_target,
@@ -1211,20 +5223,21 @@ impl ProxyExtendedChannelFactory {
                         referenced_job.coinbase_tx_suffix.as_ref(),
                         prev_blockhash,
                         bits,
+                        min_ntime,
+                        referenced_job.version,
                     )
                 } else {
                     let bitcoin_target = [0; 32];
                     let prev_blockhash = self
                         .inner
-                        .last_prev_hash_
+                        .last_prev_blockhash()
                         .ok_or(Error::ShareDoNotMatchAnyJob)?;
-                    let bits = self
+                    let (bits, min_ntime) = self
                         .inner
                         .last_prev_hash
                         .as_ref()
-                        .ok_or(Error::ShareDoNotMatchAnyJob)?
-                        .0
-                        .nbits;
+                        .map(|(prev_hash, _)| (prev_hash.nbits, prev_hash.min_ntime))
+                        .ok_or(Error::ShareDoNotMatchAnyJob)?;
                     // if there is not job_creator is not proxy duty to check if target is below or
                     // above bitcoin target so we set bitcoin_target = 0.
                     self.inner.check_target(
@@ -1237,31 +5250,36 @@ impl ProxyExtendedChannelFactory {
                         referenced_job.coinbase_tx_suffix.as_ref(),
                         prev_blockhash,
                         bits,
+                        min_ntime,
+                        referenced_job.version,
                     )
                 }
             }
             None => {
-                let err = SubmitSharesError {
-                    channel_id: m.channel_id,
-                    sequence_number: m.sequence_number,
-                    error_code: SubmitSharesError::invalid_channel_error_code()
-                        .to_string()
-                        .try_into()
-                        .unwrap(),
-                };
-                Ok(OnNewShare::SendErrorDownstream(err))
+                let error = self
+                    .inner
+                    .unknown_channel_error(m.channel_id, m.sequence_number);
+                Ok(self
+                    .inner
+                    .reject_share(error, ShareRejectionReason::UnknownChannel, None))
             }
         }
     }
 
     /// Calls [`ChannelFactory::on_new_prev_hash`]
-    pub fn on_new_prev_hash(&mut self, m: SetNewPrevHash<'static>) -> Result<(), Error> {
-        self.inner.on_new_prev_hash(StagedPhash {
+    pub fn on_new_prev_hash(
+        &mut self,
+        m: SetNewPrevHash<'static>,
+    ) -> Result<HashMap<u32, Mining<'static>, BuildNoHashHasher<u32>>, Error> {
+        let messages = self.inner.on_new_prev_hash(StagedPhash {
             job_id: m.job_id,
             prev_hash: m.prev_hash.clone().into_static(),
             min_ntime: m.min_ntime,
             nbits: m.nbits,
-        })
+        })?;
+        let live_job_ids = self.inner.live_job_ids();
+        self.inner.retain_recent_shares(&live_job_ids);
+        Ok(messages)
     }
 
     /// Calls [`ChannelFactory::on_new_extended_mining_job`]
@@ -1277,21 +5295,116 @@ impl ProxyExtendedChannelFactory {
         self.inner.kind.set_target(new_target);
     }
 
+    /// Get the upstream target, None if the factory kind is `Pool`
+    pub fn upstream_target(&self) -> Option<Target> {
+        self.inner.kind.upstream_target().cloned()
+    }
+
+    /// Get the difficulty of the upstream target, for display. None if the factory kind is `Pool`
+    pub fn upstream_difficulty(&self) -> Option<f64> {
+        self.upstream_target()
+            .map(crate::utils::target_to_difficulty)
+    }
+
+    /// Enable or disable strict job id enforcement, enabled by default. When enabled extended
+    /// shares that do not reference the last valid job are rejected with `stale-share` if they
+    /// reference a superseded job and with `invalid-job-id` otherwise, when disabled they are
+    /// checked against the last valid job.
+    pub fn set_strict_job_id(&mut self, strict: bool) {
+        self.inner.strict_job_id = strict;
+    }
+
+    /// Set the bits of the block version that shares can roll, BIP320 `0x1fffe000` by default.
+    /// Shares whose version differs from the job version outside of the mask are rejected with
+    /// `invalid-version`, channels that did not negotiate version rolling can not roll any bit.
+    pub fn set_version_rolling_mask(&mut self, mask: u32) {
+        self.inner.version_rolling_mask = mask;
+    }
+
+    /// Set how far in the future, from the clock unix time, the ntime of a share can be, shares
+    /// with a later ntime are rejected with `ntime-too-far-in-future`. It is 2 hours by default,
+    /// like the max future block time of bitcoin, None disables the check. Shares with an ntime
+    /// before the `min_ntime` of their job are always rejected with `ntime-below-min-ntime`.
+    pub fn set_max_ntime_drift(&mut self, drift: Option<Duration>) {
+        self.inner.max_ntime_drift = drift;
+    }
+
+    /// When true the coinbase and the merkle path of every new job are prepared once, and the
+    /// merkle root of the `NewMiningJob` of each standard channel only hashes the coinbase with
+    /// the channel extranonce, so that the factory does not deserialize the coinbase of each
+    /// standard channel on every new job. The shares of the job reuse the same preparation. It is
+    /// false by default.
+    pub fn set_lazy_merkle_roots(&mut self, lazy: bool) {
+        self.inner.lazy_merkle_roots = lazy;
+    }
+
+    /// Set the max number of future jobs queued until the next prev hash, by default there is no
+    /// limit. When a new future job exceeds it the oldest future job is evicted.
+    pub fn set_max_future_jobs(&mut self, max_future_jobs: usize) {
+        self.inner.max_future_jobs = max_future_jobs;
+        self.inner.evict_future_jobs();
+    }
+
+    /// Enable or disable rejection details, disabled by default. When enabled every
+    /// [`OnNewShare::SendErrorDownstream`] comes with a [`ShareRejectionDetail`] for the role to
+    /// log, the error sent downstream is the same.
+    pub fn set_rejection_details(&mut self, enabled: bool) {
+        self.inner.rejection_details = enabled;
+    }
+
+    /// Set a callback invoked with every `NewExtendedMiningJob` received by the factory, before
+    /// the jobs for the downstream channels are built. It replaces the previous one.
+    pub fn set_on_new_job<F>(&mut self, on_new_job: F)
+    where
+        F: Fn(&NewExtendedMiningJob<'static>) + Send + 'static,
+    {
+        self.inner.on_new_job = Some(OnNewJobHook(Box::new(on_new_job)));
+    }
+
+    /// Enable or disable strict merkle path checking, disabled by default. When enabled
+    /// [`ProxyExtendedChannelFactory::on_new_template`] returns [`Error::MerklePathMismatch`] if
+    /// the created job has a merkle path different from the template one, when disabled the
+    /// mismatch is only logged.
+    pub fn set_strict_merkle_path(&mut self, strict: bool) {
+        self.strict_merkle_path = strict;
+    }
+
+    /// Enable or disable strict future templates checking, disabled by default. When enabled
+    /// [`ProxyExtendedChannelFactory::on_new_prev_hash_from_tp`] returns
+    /// [`Error::FutureTemplateMissing`] if no future template is stored for the activated job,
+    /// when disabled it returns no custom job and the missing template is only logged. In both
+    /// cases the prev hash is stored.
+    pub fn set_strict_future_templates(&mut self, strict: bool) {
+        self.strict_future_templates = strict;
+    }
+
+    /// Returns the number of jobs mapped to their template by the job creator, 0 if job
+    /// declaration is not used. Old mappings are dropped on each new prev hash.
+    pub fn template_mappings_len(&self) -> usize {
+        self.job_creator
+            .as_ref()
+            .map_or(0, |job_creator| job_creator.template_mappings_len())
+    }
+
     /// Get last valid job version
     pub fn last_valid_job_version(&self) -> Option<u32> {
         self.inner.last_valid_job.as_ref().map(|j| j.0.version)
     }
 
-    /// Returns the full extranonce, extranonce1 (static for channel) + extranonce2 (miner nonce
-    /// space)
+    /// Calls [`ChannelFactory::extranonce_from_downstream_extranonce`]
     pub fn extranonce_from_downstream_extranonce(
         &self,
         ext: mining_sv2::Extranonce,
+    ) -> Result<mining_sv2::Extranonce, Error> {
+        self.inner.extranonce_from_downstream_extranonce(ext)
+    }
+
+    /// Same as [`Self::extranonce_from_downstream_extranonce`] but returns None on failure
+    pub fn extranonce_from_downstream_extranonce_opt(
+        &self,
+        ext: mining_sv2::Extranonce,
     ) -> Option<mining_sv2::Extranonce> {
-        self.inner
-            .extranonces
-            .extranonce_from_downstream_extranonce(ext)
-            .ok()
+        self.extranonce_from_downstream_extranonce(ext).ok()
     }
 
     /// Returns the most recent prev hash
@@ -1317,11 +5430,47 @@ impl ProxyExtendedChannelFactory {
         self.inner.extranonces.get_len()
     }
 
-    /// Get extranonce_2 size
-    pub fn channel_extranonce2_size(&self) -> usize {
+    /// Get the factory wide extranonce_2 size, that is the extranonce len minus the upstream
+    /// extranonce prefix len
+    pub fn default_channel_extranonce2_size(&self) -> usize {
         self.inner.extranonces.get_len() - self.inner.extranonces.get_range0_len()
     }
 
+    /// Returns the requested, available and granted extranonce sizes of the last extended channel
+    /// request, used to tell the miner by how much a refused request exceeded the available space
+    pub fn last_extranonce_grant(&self) -> Option<ExtranonceGrant> {
+        self.inner.last_extranonce_grant
+    }
+
+    /// Calls [`ChannelFactory::channel_extranonce2_size`]
+    pub fn channel_extranonce2_size(&self, channel_id: u32) -> Option<usize> {
+        self.inner.channel_extranonce2_size(channel_id)
+    }
+
+    /// Calls [`ChannelFactory::channels_snapshot`]
+    pub fn channels_snapshot(&self) -> Vec<ChannelInfo> {
+        self.inner.channels_snapshot()
+    }
+
+    /// Calls [`ChannelFactory::has_channel`]
+    pub fn has_channel(&self, channel_id: u32) -> bool {
+        self.inner.has_channel(channel_id)
+    }
+
+    /// Calls [`ChannelFactory::channel_group`]
+    pub fn channel_group(&self, channel_id: u32) -> Option<u32> {
+        self.inner.channel_group(channel_id)
+    }
+
+    /// Calls [`ChannelFactory::set_extranonce_prefix`]
+    pub fn set_extranonce_prefix(
+        &mut self,
+        channel_id: u32,
+        new_prefix: Vec<u8>,
+    ) -> Result<Mining<'static>, Error> {
+        self.inner.set_extranonce_prefix(channel_id, new_prefix)
+    }
+
     // Only used when the proxy is using Job Declaration
     /// Updates pool outputs
     pub fn update_pool_outputs(&mut self, outs: Vec<TxOut>) {
@@ -1333,12 +5482,63 @@ impl ProxyExtendedChannelFactory {
         self.extended_channel_id
     }
 
+    /// Builds the share to send upstream for a share answered with
+    /// [`OnNewShare::RelaySubmitShareUpstream`], `sequence_number` is the one allocated for the
+    /// upstream. An extended share is relayed on the upstream extended channel, its extranonce is
+    /// the channel prefix plus the share extranonce without the upstream part. A standard share
+    /// is the same share with the new sequence number. Fails with [`Error::NotFoundChannelId`] if
+    /// the channel of the share is not open.
+    pub fn relay_share_upstream(
+        &self,
+        share: &Share,
+        sequence_number: u32,
+    ) -> Result<Share, Error> {
+        match share {
+            Share::Extended(m) => {
+                let (_, extranonce) = self
+                    .inner
+                    .get_channel_specific_mining_info(share)
+                    .ok_or(Error::NotFoundChannelId)?;
+                let upstream_extranonce_space = self.inner.extranonces.get_range0_len();
+                Ok(Share::Extended(SubmitSharesExtended {
+                    channel_id: self.extended_channel_id,
+                    sequence_number,
+                    job_id: m.job_id,
+                    nonce: m.nonce,
+                    ntime: m.ntime,
+                    version: m.version,
+                    extranonce: extranonce[upstream_extranonce_space..]
+                        .to_vec()
+                        .try_into()?,
+                }))
+            }
+            Share::Standard((m, group_id)) => {
+                if !self.inner.standard_channels.contains_key(&m.channel_id) {
+                    return Err(Error::NotFoundChannelId);
+                }
+                Ok(Share::Standard((
+                    SubmitSharesStandard {
+                        sequence_number,
+                        ..m.clone()
+                    },
+                    *group_id,
+                )))
+            }
+        }
+    }
+
     /// Returns the extranonce1 len of the upstream. For a proxy, this would
     /// be the extranonce_prefix len
     pub fn get_upstream_extranonce1_len(&self) -> usize {
         self.inner.extranonces.get_range0_len()
     }
 
+    /// Returns the extranonce1 len imposed to the downstreams of the proxy, the upstream
+    /// extranonce prefix plus the space carved by the proxy (range0 + range1)
+    pub fn downstream_extranonce1_len(&self) -> usize {
+        self.inner.extranonces.get_len() - self.inner.extranonces.get_range2_len()
+    }
+
     /// Calls [`ChannelFactory::update_target_for_channel`]
     pub fn update_target_for_channel(
         &mut self,
@@ -1347,6 +5547,187 @@ impl ProxyExtendedChannelFactory {
     ) -> Option<bool> {
         self.inner.update_target_for_channel(channel_id, new_target)
     }
+
+    /// Calls [`ChannelFactory::update_target_for_channel_with_message`]
+    /// Set a particular downstream channel target and return the `SetTarget` that must be sent to
+    /// the downstream. Returns None if the channel does not exist.
+    pub fn update_target_for_channel_with_message(
+        &mut self,
+        channel_id: u32,
+        new_target: Target,
+    ) -> Option<Mining<'static>> {
+        self.inner
+            .update_target_for_channel_with_message(channel_id, new_target)
+    }
+
+    /// Calls [`ChannelFactory::try_vardiff`]
+    /// Meant to be called periodically for every extended channel, when a new target is returned
+    /// it has already been set and must be sent downstream with a `SetTarget`.
+    pub fn try_vardiff(&mut self, channel_id: u32) -> Option<Target> {
+        self.inner.try_vardiff(channel_id)
+    }
+
+    /// Calls [`ChannelFactory::estimated_hashrate`]
+    pub fn estimated_hashrate(&self, channel_id: u32) -> Option<f64> {
+        self.inner.estimated_hashrate(channel_id)
+    }
+
+    /// Calls [`ChannelFactory::get_channel_stats`]
+    pub fn get_channel_stats(&self, channel_id: u32) -> Option<ChannelStats> {
+        self.inner.get_channel_stats(channel_id)
+    }
+}
+
+// Increments `bytes` as a big endian number, returns false and leaves them unchanged if they are
+// already the max value
+fn increment_bytes_be(bytes: &mut [u8]) -> bool {
+    match bytes.iter().rposition(|byte| *byte != u8::MAX) {
+        Some(position) => {
+            bytes[position] += 1;
+            bytes[position + 1..].iter_mut().for_each(|byte| *byte = 0);
+            true
+        }
+        None => false,
+    }
+}
+
+// Error returned to downstream when every extranonce prefix of the factory has been handed out
+fn prefixes_exhausted_open_channel_error(request_id: u32) -> OpenMiningChannelError<'static> {
+    OpenMiningChannelError {
+        request_id,
+        // Infallible unwrap we already know the len of the error code (is a static string)
+        error_code: "extranonce-prefixes-exhausted"
+            .to_string()
+            .try_into()
+            .unwrap(),
+    }
+}
+
+// Returns the keys of a channel map in ascending order
+fn sorted_keys<V>(map: &HashMap<u32, V, BuildNoHashHasher<u32>>) -> Vec<u32> {
+    let mut keys: Vec<u32> = map.keys().copied().collect();
+    keys.sort_unstable();
+    keys
+}
+
+// Decodes a 32 bytes little endian target of a snapshot
+fn snapshot_target(bytes: Vec<u8>) -> Result<Target, Error> {
+    let bytes: [u8; 32] = bytes.try_into().map_err(|_| Error::MalformedSnapshot)?;
+    Ok(bytes.into())
+}
+
+// Error returned to downstream when an UpdateChannel can not be accepted
+fn update_channel_error(channel_id: u32, error_code: &str) -> Mining<'static> {
+    Mining::UpdateChannelError(UpdateChannelError {
+        channel_id,
+        // Infallible unwrap we already know the len of the error code (is a static string)
+        error_code: error_code.to_string().try_into().unwrap(),
+    })
+}
+
+// Error returned to downstream when the factory is not accepting new channels
+fn maintenance_open_channel_error(request_id: u32) -> OpenMiningChannelError<'static> {
+    OpenMiningChannelError {
+        request_id,
+        // Infallible unwrap we already know the len of the error code (is a static string)
+        error_code: "maintenance".to_string().try_into().unwrap(),
+    }
+}
+
+/// Builds a [`ProxyExtendedChannelFactory`] from named parameters, the same ones of
+/// [`ProxyExtendedChannelFactory::new`]. `ids`, `extranonces`, `share_per_min`, `kind` and
+/// `extended_channel_id` are required. The job creator and the pool coinbase outputs are only
+/// used by the ProxyJd kind.
+#[derive(Debug, Default)]
+pub struct ProxyChannelFactoryBuilder {
+    ids: Option<Arc<Mutex<GroupId>>>,
+    extranonces: Option<ExtendedExtranonce>,
+    job_creator: Option<JobsCreators>,
+    share_per_min: Option<f32>,
+    kind: Option<ExtendedChannelKind>,
+    pool_coinbase_outputs: Option<Vec<TxOut>>,
+    extended_channel_id: Option<u32>,
+}
+
+impl ProxyChannelFactoryBuilder {
+    /// Creates a builder without parameters
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the ids of the channels and groups
+    pub fn ids(mut self, ids: Arc<Mutex<GroupId>>) -> Self {
+        self.ids = Some(ids);
+        self
+    }
+
+    /// Set the extranonces the channel prefixes are taken from
+    pub fn extranonces(mut self, extranonces: ExtendedExtranonce) -> Self {
+        self.extranonces = Some(extranonces);
+        self
+    }
+
+    /// Set the job creator, required by the ProxyJd kind and refused by the Proxy kind
+    pub fn job_creator(mut self, job_creator: JobsCreators) -> Self {
+        self.job_creator = Some(job_creator);
+        self
+    }
+
+    /// Set the number of shares per minute the channel targets are computed for
+    pub fn share_per_min(mut self, share_per_min: f32) -> Self {
+        self.share_per_min = Some(share_per_min);
+        self
+    }
+
+    /// Set the kind of the factory, Proxy or ProxyJd
+    pub fn kind(mut self, kind: ExtendedChannelKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    /// Set the coinbase outputs of the pool, used with job declaration
+    pub fn pool_coinbase_outputs(mut self, pool_coinbase_outputs: Vec<TxOut>) -> Self {
+        self.pool_coinbase_outputs = Some(pool_coinbase_outputs);
+        self
+    }
+
+    /// Set the id assigned to the extended channel by upstream
+    pub fn extended_channel_id(mut self, extended_channel_id: u32) -> Self {
+        self.extended_channel_id = Some(extended_channel_id);
+        self
+    }
+
+    /// Builds the factory. Fails with [`Error::MissingFactoryParameter`] if a required parameter
+    /// is not set, and like [`ProxyExtendedChannelFactory::try_new`] if the kind and the job
+    /// creator do not match.
+    pub fn build(self) -> Result<ProxyExtendedChannelFactory, Error> {
+        let kind = self.kind.ok_or(Error::MissingFactoryParameter("kind"))?;
+        ProxyExtendedChannelFactory::try_new(
+            self.ids.ok_or(Error::MissingFactoryParameter("ids"))?,
+            self.extranonces
+                .ok_or(Error::MissingFactoryParameter("extranonces"))?,
+            self.job_creator,
+            self.share_per_min
+                .ok_or(Error::MissingFactoryParameter("share_per_min"))?,
+            kind,
+            self.pool_coinbase_outputs,
+            self.extended_channel_id
+                .ok_or(Error::MissingFactoryParameter("extended_channel_id"))?,
+        )
+    }
+}
+
+// Checks that `kind` is a proxy kind and that a job creator is given only to ProxyJd factories
+fn check_proxy_factory_kind(
+    kind: &ExtendedChannelKind,
+    has_job_creator: bool,
+) -> Result<(), Error> {
+    match (kind, has_job_creator) {
+        (ExtendedChannelKind::Proxy { .. }, true) => Err(Error::ProxyFactoryWithJobCreator),
+        (ExtendedChannelKind::ProxyJd { .. }, false) => Err(Error::ProxyJdFactoryWithoutJobCreator),
+        (ExtendedChannelKind::Pool, _) => Err(Error::ProxyFactoryWithPoolKind),
+        _ => Ok(()),
+    }
 }
 
 /// Used by proxies for tracking upstream targets.
@@ -1367,4 +5748,16 @@ impl ExtendedChannelKind {
             ExtendedChannelKind::Pool => warn!("Try to set upstream target for a pool"),
         }
     }
+
+    /// Get the upstream target, None for a pool
+    pub fn upstream_target(&self) -> Option<&Target> {
+        match self {
+            ExtendedChannelKind::Proxy { upstream_target }
+            | ExtendedChannelKind::ProxyJd { upstream_target } => Some(upstream_target),
+            ExtendedChannelKind::Pool => None,
+        }
+    }
 }
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,5241 @@
+// Channel factory test suite
+use super::*;
+use bitcoin::{Amount, ScriptBuf};
+use codec_sv2::binary_sv2::U256;
+use std::collections::VecDeque;
+
+// Channels get an 8 bytes prefix and 8 bytes of miner nonce space
+const EXTRANONCE_LEN: usize = 16;
+const DOWNSTREAM_EXTRANONCE_LEN: usize = 8;
+const SHARES_PER_MINUTE: f32 = 1.0;
+const BLOCK_REWARD: u64 = 5_000_000_000;
+const NBITS: u32 = 545259519;
+const VERSION: u32 = 536870912;
+
+// Every hash meets this target
+const MAX_TARGET: [u8; 32] = [0xff; 32];
+// No hash meets this target
+const MIN_TARGET: [u8; 32] = [0; 32];
+
+fn pool_coinbase_outputs() -> Vec<TxOut> {
+    vec![TxOut {
+        value: Amount::from_sat(0),
+        script_pubkey: ScriptBuf::from(vec![0x51]),
+    }]
+}
+
+fn new_pool_factory() -> PoolChannelFactory {
+    let ids = Arc::new(Mutex::new(GroupId::new()));
+    let extranonces = ExtendedExtranonce::new(0..0, 0..8, 8..EXTRANONCE_LEN, None).unwrap();
+    let job_creator = JobsCreators::new(EXTRANONCE_LEN as u8);
+    PoolChannelFactory::new(
+        ids,
+        extranonces,
+        job_creator,
+        SHARES_PER_MINUTE,
+        ExtendedChannelKind::Pool,
+        pool_coinbase_outputs(),
+    )
+}
+
+fn new_template(template_id: u64, future_template: bool) -> NewTemplate<'static> {
+    NewTemplate {
+        template_id,
+        future_template,
+        version: VERSION,
+        coinbase_tx_version: 2,
+        coinbase_prefix: vec![3_u8, 76, 163, 38].try_into().unwrap(),
+        coinbase_tx_input_sequence: u32::MAX,
+        coinbase_tx_value_remaining: BLOCK_REWARD,
+        coinbase_tx_outputs_count: 0,
+        coinbase_tx_outputs: Vec::<u8>::new().try_into().unwrap(),
+        coinbase_tx_locktime: 0,
+        merkle_path: Vec::<U256>::new().into(),
+    }
+}
+
+fn new_prev_hash(template_id: u64, bitcoin_target: [u8; 32]) -> SetNewPrevHashFromTp<'static> {
+    SetNewPrevHashFromTp {
+        template_id,
+        prev_hash: U256::from([1; 32]),
+        header_timestamp: 1_700_000_000,
+        n_bits: NBITS,
+        target: U256::from(bitcoin_target),
+    }
+}
+
+// Sends a future template followed by the prev hash that activates it, returns the job id
+fn activate_job(
+    factory: &mut PoolChannelFactory,
+    template_id: u64,
+    bitcoin_target: [u8; 32],
+) -> u32 {
+    factory
+        .on_new_template(&mut new_template(template_id, true))
+        .unwrap();
+    factory
+        .on_new_prev_hash_from_tp(&new_prev_hash(template_id, bitcoin_target))
+        .unwrap();
+    active_job_id(factory)
+}
+
+fn active_job_id(factory: &PoolChannelFactory) -> u32 {
+    factory.inner.last_valid_job.as_ref().unwrap().0.job_id
+}
+
+// A channel opened with 0 hashrate has the maximum target, so every share meets it
+fn open_extended_channel(factory: &mut PoolChannelFactory, request_id: u32) -> u32 {
+    let messages = factory
+        .new_extended_channel(
+            request_id,
+            0.0,
+            DOWNSTREAM_EXTRANONCE_LEN as u16,
+            ConnectionFlags::all(),
+        )
+        .unwrap();
+    match &messages[0] {
+        Mining::OpenExtendedMiningChannelSuccess(success) => success.channel_id,
+        m => panic!("Expected OpenExtendedMiningChannelSuccess, got {m}"),
+    }
+}
+
+fn extended_share(
+    channel_id: u32,
+    sequence_number: u32,
+    job_id: u32,
+    extranonce: Vec<u8>,
+) -> SubmitSharesExtended<'static> {
+    SubmitSharesExtended {
+        channel_id,
+        sequence_number,
+        job_id,
+        nonce: sequence_number,
+        ntime: 1_700_000_001,
+        version: VERSION,
+        extranonce: extranonce.try_into().unwrap(),
+    }
+}
+
+#[test]
+fn test_refuse_new_channels_when_not_accepting() {
+    let mut factory = new_pool_factory();
+    factory.set_accepting_new_channels(false);
+
+    let messages = factory
+        .new_extended_channel(
+            7,
+            0.0,
+            DOWNSTREAM_EXTRANONCE_LEN as u16,
+            ConnectionFlags::all(),
+        )
+        .unwrap();
+    assert_eq!(messages.len(), 1);
+    match &messages[0] {
+        Mining::OpenMiningChannelError(error) => {
+            assert_eq!(error.request_id, 7);
+            assert_eq!(error.error_code.as_utf8_or_hex(), "maintenance");
+        }
+        m => panic!("Expected OpenMiningChannelError, got {m}"),
+    }
+    assert!(factory.get_extended_channels_ids().is_empty());
+
+    factory.set_accepting_new_channels(true);
+    open_extended_channel(&mut factory, 8);
+    assert_eq!(factory.get_extended_channels_ids().len(), 1);
+}
+
+#[test]
+fn test_existing_channels_keep_working_when_not_accepting() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    factory.set_accepting_new_channels(false);
+
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+
+    let share = extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+}
+
+#[test]
+fn test_coinbase_script_sig_contains_extranonce() {
+    let mut factory = new_pool_factory();
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    let coinbase_prefix = new_template(1, true).coinbase_prefix.to_vec();
+    let extranonce: Vec<u8> = (1..=EXTRANONCE_LEN as u8).collect();
+
+    let script_sig = factory.coinbase_script_sig(job_id, &extranonce).unwrap();
+    assert_eq!(script_sig.len(), coinbase_prefix.len() + EXTRANONCE_LEN);
+    assert_eq!(&script_sig[..coinbase_prefix.len()], &coinbase_prefix[..]);
+    assert_eq!(&script_sig[coinbase_prefix.len()..], &extranonce[..]);
+
+    assert!(factory
+        .coinbase_script_sig(job_id, &extranonce[..DOWNSTREAM_EXTRANONCE_LEN])
+        .is_none());
+    assert!(factory
+        .coinbase_script_sig(job_id + 1, &extranonce)
+        .is_none());
+}
+
+// A job with a valid coinbase split created from `new_template`
+fn template_extended_job() -> NewExtendedMiningJob<'static> {
+    JobsCreators::new(EXTRANONCE_LEN as u8)
+        .on_new_template(&mut new_template(1, true), true, pool_coinbase_outputs())
+        .unwrap()
+}
+
+fn future_extended_job(job_id: u32, version: u32) -> NewExtendedMiningJob<'static> {
+    let job = template_extended_job();
+    NewExtendedMiningJob {
+        channel_id: 0,
+        job_id,
+        min_ntime: binary_sv2::Sv2Option::new(None),
+        version,
+        version_rolling_allowed: true,
+        merkle_path: Vec::<U256>::new().into(),
+        coinbase_tx_prefix: job.coinbase_tx_prefix,
+        coinbase_tx_suffix: job.coinbase_tx_suffix,
+    }
+}
+
+#[test]
+fn test_duplicate_future_jobs_activate_most_recent() {
+    let mut factory = new_pool_factory();
+    factory
+        .inner
+        .on_new_extended_mining_job(future_extended_job(5, 1))
+        .unwrap();
+    factory
+        .inner
+        .on_new_extended_mining_job(future_extended_job(6, 2))
+        .unwrap();
+    factory
+        .inner
+        .on_new_extended_mining_job(future_extended_job(5, 3))
+        .unwrap();
+
+    factory
+        .inner
+        .on_new_prev_hash(StagedPhash {
+            job_id: 5,
+            prev_hash: U256::from([1; 32]),
+            min_ntime: 0,
+            nbits: NBITS,
+        })
+        .unwrap();
+
+    let (job, _) = factory.inner.last_valid_job.as_ref().unwrap();
+    assert_eq!(job.job_id, 5);
+    assert_eq!(job.version, 3);
+    assert!(!job.is_future());
+    assert!(factory.inner.future_jobs.is_empty());
+}
+
+fn custom_mining_job(channel_id: u32, outputs: &[TxOut]) -> SetCustomMiningJob<'static> {
+    let coinbase_tx_outputs: Vec<u8> = outputs
+        .iter()
+        .flat_map(bitcoin::consensus::serialize)
+        .collect();
+    SetCustomMiningJob {
+        channel_id,
+        request_id: 1,
+        token: Vec::<u8>::new().try_into().unwrap(),
+        version: VERSION,
+        prev_hash: U256::from([1; 32]),
+        min_ntime: 1_700_000_000,
+        nbits: NBITS,
+        coinbase_tx_version: 2,
+        coinbase_prefix: vec![3_u8, 76, 163, 38].try_into().unwrap(),
+        coinbase_tx_input_n_sequence: u32::MAX,
+        coinbase_tx_outputs: coinbase_tx_outputs.try_into().unwrap(),
+        coinbase_tx_locktime: 0,
+        merkle_path: Vec::<U256>::new().into(),
+    }
+}
+
+fn coinbase_outputs(count: usize) -> Vec<TxOut> {
+    (0..count)
+        .map(|_| TxOut {
+            value: Amount::from_sat(BLOCK_REWARD / count as u64),
+            script_pubkey: ScriptBuf::from(vec![0x51]),
+        })
+        .collect()
+}
+
+#[test]
+fn test_max_coinbase_outputs_on_new_template() {
+    let mut factory = new_pool_factory();
+    factory.set_max_coinbase_outputs(2);
+
+    factory.update_pool_outputs(coinbase_outputs(2));
+    assert!(factory.on_new_template(&mut new_template(1, true)).is_ok());
+
+    factory.update_pool_outputs(coinbase_outputs(3));
+    assert!(matches!(
+        factory.on_new_template(&mut new_template(2, true)),
+        Err(Error::TooManyCoinbaseOutputs(3, 2))
+    ));
+}
+
+fn is_custom_job_success(result: Result<OnNewSetCustomMiningJob, Error>) -> bool {
+    matches!(result, Ok(OnNewSetCustomMiningJob::Success(_)))
+}
+
+// Sets a custom job that must be accepted, returns its job id
+fn set_custom_job(factory: &mut PoolChannelFactory, job: SetCustomMiningJob<'static>) -> u32 {
+    match factory.on_new_set_custom_mining_job(job).unwrap() {
+        OnNewSetCustomMiningJob::Success(success) => success.job_id,
+        OnNewSetCustomMiningJob::SendErrorDownstream(error) => {
+            panic!("Custom job rejected: {error}")
+        }
+    }
+}
+
+#[test]
+fn test_max_coinbase_outputs_on_new_set_custom_mining_job() {
+    let mut factory = new_pool_factory();
+    factory.set_max_coinbase_outputs(2);
+    let channel_id = open_extended_channel(&mut factory, 1);
+    activate_job(&mut factory, 1, MIN_TARGET);
+
+    let job = custom_mining_job(channel_id, &coinbase_outputs(2));
+    assert!(is_custom_job_success(
+        factory.on_new_set_custom_mining_job(job)
+    ));
+
+    let job = custom_mining_job(channel_id, &coinbase_outputs(3));
+    assert!(matches!(
+        factory.on_new_set_custom_mining_job(job),
+        Err(Error::TooManyCoinbaseOutputs(3, 2))
+    ));
+}
+
+#[test]
+fn test_full_extranonce() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let prefix = factory.inner.extended_channels[&channel_id]
+        .extranonce_prefix
+        .to_vec();
+    let extranonce2 = vec![0xab; DOWNSTREAM_EXTRANONCE_LEN];
+
+    let extranonce = factory.full_extranonce(channel_id, &extranonce2).unwrap();
+    assert_eq!(extranonce.len(), EXTRANONCE_LEN);
+    assert_eq!(&extranonce[..prefix.len()], &prefix[..]);
+    assert_eq!(&extranonce[prefix.len()..], &extranonce2[..]);
+
+    assert!(factory
+        .full_extranonce(channel_id, &extranonce2[1..])
+        .is_none());
+    assert!(factory
+        .full_extranonce(channel_id, &[0xab; DOWNSTREAM_EXTRANONCE_LEN + 1])
+        .is_none());
+    assert!(factory
+        .full_extranonce(channel_id + 1, &extranonce2)
+        .is_none());
+}
+
+#[test]
+fn test_extranonce2_diversity() {
+    let mut factory = new_pool_factory();
+    let fixed_channel = open_extended_channel(&mut factory, 1);
+    let varied_channel = open_extended_channel(&mut factory, 2);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+
+    // Not a duplicate of the shares below, duplicates are not tracked
+    let share = extended_share(fixed_channel, 4, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    factory.on_submit_shares_extended(share).unwrap();
+    assert_eq!(factory.extranonce2_diversity(fixed_channel), None);
+
+    factory.set_extranonce2_tracking(true);
+    assert_eq!(factory.extranonce2_diversity(fixed_channel), None);
+    for i in 0..4 {
+        let fixed = extended_share(fixed_channel, i, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+        factory.on_submit_shares_extended(fixed).unwrap();
+        let varied = extended_share(
+            varied_channel,
+            i,
+            job_id,
+            vec![i as u8; DOWNSTREAM_EXTRANONCE_LEN],
+        );
+        factory.on_submit_shares_extended(varied).unwrap();
+    }
+    assert_eq!(factory.extranonce2_diversity(fixed_channel), Some(0.25));
+    assert_eq!(factory.extranonce2_diversity(varied_channel), Some(1.0));
+
+    factory.set_extranonce2_tracking(false);
+    assert_eq!(factory.extranonce2_diversity(varied_channel), None);
+}
+
+#[test]
+fn test_check_target_outcomes_with_test_targets() {
+    let max = || Target::from(MAX_TARGET);
+    let min = || Target::from(MIN_TARGET);
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    // Each share has a different nonce, so that it is not a duplicate of the previous ones
+    let share = |nonce| {
+        extended_share(
+            channel_id,
+            nonce,
+            job_id,
+            vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+        )
+    };
+
+    factory.set_test_targets(min(), min(), max());
+    assert!(matches!(
+        factory.on_submit_shares_extended(share(0)).unwrap(),
+        OnNewShare::ShareMeetBitcoinTarget(_)
+    ));
+
+    factory.set_test_targets(min(), max(), min());
+    assert!(matches!(
+        factory.on_submit_shares_extended(share(1)).unwrap(),
+        OnNewShare::SendSubmitShareUpstream(_)
+    ));
+
+    factory.set_test_targets(max(), min(), min());
+    assert!(matches!(
+        factory.on_submit_shares_extended(share(2)).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+
+    factory.set_test_targets(min(), min(), min());
+    match factory.on_submit_shares_extended(share(3)).unwrap() {
+        OnNewShare::SendErrorDownstream((error, _)) => assert_eq!(
+            error.error_code.as_utf8_or_hex(),
+            SubmitSharesError::difficulty_too_low_error_code()
+        ),
+        _ => panic!("Expected SendErrorDownstream"),
+    }
+}
+
+// PPLNS like crediting, only the last `window` accepted shares are credited
+#[derive(Debug)]
+struct Pplns {
+    window: usize,
+    last_shares: VecDeque<(u32, f64)>,
+    // every share credited, shared with the test
+    credited: Arc<std::sync::Mutex<Vec<(u32, f64)>>>,
+}
+
+impl ShareCredit for Pplns {
+    fn on_accepted_share(&mut self, channel_id: u32, difficulty: f64) {
+        self.credited.lock().unwrap().push((channel_id, difficulty));
+        self.last_shares.push_back((channel_id, difficulty));
+        if self.last_shares.len() > self.window {
+            self.last_shares.pop_front();
+        }
+    }
+
+    fn credit(&self, channel_id: u32) -> f64 {
+        self.last_shares
+            .iter()
+            .filter(|(id, _)| *id == channel_id)
+            .map(|(_, difficulty)| difficulty)
+            .sum()
+    }
+}
+
+#[test]
+fn test_share_credit_pplns_window() {
+    let mut factory = new_pool_factory();
+    let channel_a = open_extended_channel(&mut factory, 1);
+    let channel_b = open_extended_channel(&mut factory, 2);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    assert_eq!(factory.share_credit(channel_a), 0.0);
+
+    let credited = Arc::new(std::sync::Mutex::new(Vec::new()));
+    factory.set_share_credit(Box::new(Pplns {
+        window: 3,
+        last_shares: VecDeque::new(),
+        credited: credited.clone(),
+    }));
+
+    let channels = [channel_a, channel_a, channel_b, channel_a, channel_b];
+    for (i, channel_id) in channels.iter().enumerate() {
+        let share = extended_share(
+            *channel_id,
+            i as u32,
+            job_id,
+            vec![i as u8; DOWNSTREAM_EXTRANONCE_LEN],
+        );
+        factory.on_submit_shares_extended(share).unwrap();
+    }
+    // Rejected shares are not credited
+    factory.update_target_for_channel(channel_b, Target::new(0, 0));
+    let share = extended_share(channel_b, 5, job_id, vec![5; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::SendErrorDownstream(_)
+    ));
+
+    let credited = credited.lock().unwrap().clone();
+    assert_eq!(credited.len(), channels.len());
+    assert!(credited.iter().all(|(_, difficulty)| *difficulty > 0.0));
+    let window = &credited[credited.len() - 3..];
+    for channel_id in [channel_a, channel_b] {
+        let expected: f64 = window
+            .iter()
+            .filter(|(id, _)| *id == channel_id)
+            .map(|(_, difficulty)| difficulty)
+            .sum();
+        assert_eq!(factory.share_credit(channel_id), expected);
+    }
+}
+
+#[test]
+fn test_on_new_template_without_coinbase_outputs() {
+    let mut factory = new_pool_factory();
+    factory.update_pool_outputs(vec![]);
+    assert!(matches!(
+        factory.on_new_template(&mut new_template(1, true)),
+        Err(Error::NoCoinbaseOutputsConfigured)
+    ));
+
+    factory.update_pool_outputs(pool_coinbase_outputs());
+    assert!(factory.on_new_template(&mut new_template(1, true)).is_ok());
+}
+
+#[test]
+fn test_channel_extranonce2_size_matches_granted_size() {
+    let mut factory = new_pool_factory();
+    let messages = factory
+        .new_extended_channel(
+            1,
+            0.0,
+            DOWNSTREAM_EXTRANONCE_LEN as u16,
+            ConnectionFlags::all(),
+        )
+        .unwrap();
+    let success = match &messages[0] {
+        Mining::OpenExtendedMiningChannelSuccess(success) => success.clone(),
+        m => panic!("Expected OpenExtendedMiningChannelSuccess, got {m}"),
+    };
+    assert_eq!(
+        factory.channel_extranonce2_size(success.channel_id),
+        Some(success.extranonce_size as usize)
+    );
+    assert_eq!(
+        factory.default_channel_extranonce2_size(),
+        DOWNSTREAM_EXTRANONCE_LEN
+    );
+
+    // A channel with a reduced grant
+    let reduced_size = DOWNSTREAM_EXTRANONCE_LEN as u16 - 4;
+    let extranonce_prefix: mining_sv2::Extranonce = vec![9_u8; 12].try_into().unwrap();
+    factory
+        .replicate_upstream_extended_channel_only_jd(
+            U256::from(MAX_TARGET),
+            extranonce_prefix,
+            100,
+            reduced_size,
+        )
+        .unwrap();
+    assert_eq!(
+        factory.channel_extranonce2_size(100),
+        Some(reduced_size as usize)
+    );
+    assert_eq!(
+        factory.inner.extended_channels[&100].extranonce_size,
+        reduced_size
+    );
+    assert_eq!(factory.channel_extranonce2_size(101), None);
+}
+
+#[test]
+fn test_share_version_base_must_match_job_version() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+
+    // Rolling bits inside the BIP320 mask is allowed
+    let mut share = extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    share.version = VERSION | 0x0000_2000 | 0x1000_0000;
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+
+    for tampered_version in [VERSION | 0x4000_0000, VERSION | 0x1, VERSION ^ 0x2000_0000] {
+        let mut share = extended_share(channel_id, 1, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+        share.version = tampered_version;
+        match factory.on_submit_shares_extended(share).unwrap() {
+            OnNewShare::SendErrorDownstream((error, _)) => {
+                assert_eq!(error.error_code.as_utf8_or_hex(), "invalid-version")
+            }
+            _ => panic!("Expected SendErrorDownstream for version {tampered_version:#x}"),
+        }
+    }
+}
+
+#[test]
+fn test_share_accounting_on_submitted_shares() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    assert_eq!(
+        factory.share_accounting(channel_id),
+        Some(&ShareAccounting::new())
+    );
+
+    for i in 0..2 {
+        let share = extended_share(channel_id, i, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+        factory.on_submit_shares_extended(share).unwrap();
+    }
+    factory.update_target_for_channel(channel_id, Target::new(0, 0));
+    let share = extended_share(channel_id, 2, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    factory.on_submit_shares_extended(share).unwrap();
+    factory.set_test_targets(
+        Target::from(MAX_TARGET),
+        Target::from(MIN_TARGET),
+        Target::from(MAX_TARGET),
+    );
+    let share = extended_share(channel_id, 3, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    factory.on_submit_shares_extended(share).unwrap();
+
+    let accounting = factory.share_accounting(channel_id).unwrap();
+    assert_eq!(accounting.shares_accepted(), 3);
+    assert_eq!(accounting.shares_rejected(), 1);
+    assert_eq!(accounting.blocks_found(), 1);
+    let difficulty = crate::utils::target_to_difficulty(&Target::from(MAX_TARGET));
+    assert_eq!(
+        accounting.difficulty_sum(),
+        difficulty + difficulty + difficulty
+    );
+    assert_eq!(factory.share_accounting(channel_id + 1), None);
+}
+
+#[test]
+fn test_nonce_space_adequate() {
+    // 100 TH/s
+    let asic_hashrate = 100_000_000_000_000.0;
+    let mut factory = new_pool_factory();
+    let large_channel = open_extended_channel(&mut factory, 1);
+    let extranonce_prefix: mining_sv2::Extranonce = vec![9_u8; 15].try_into().unwrap();
+    factory
+        .replicate_upstream_extended_channel_only_jd(
+            U256::from(MAX_TARGET),
+            extranonce_prefix,
+            100,
+            1,
+        )
+        .unwrap();
+
+    // 2^32 * 2^8 hashes per second are ~1.1 TH/s
+    assert!(!factory.nonce_space_adequate(100, asic_hashrate));
+    assert!(factory.nonce_space_adequate(100, 1_000_000_000_000.0));
+    assert!(factory.nonce_space_adequate(large_channel, asic_hashrate));
+    assert!(!factory.nonce_space_adequate(101, asic_hashrate));
+}
+
+#[test]
+fn test_channel_fingerprint_stable_across_restarts() {
+    let mut factory = new_pool_factory();
+    let channels = [
+        open_extended_channel(&mut factory, 1),
+        open_extended_channel(&mut factory, 2),
+    ];
+    let fingerprints: Vec<_> = channels
+        .iter()
+        .map(|channel_id| factory.channel_fingerprint(*channel_id).unwrap())
+        .collect();
+    assert_ne!(fingerprints[0], fingerprints[1]);
+    assert_eq!(factory.channel_fingerprint(channels[1] + 1), None);
+
+    // After a restart the channels are opened again with the same extranonce prefixes, but the
+    // ids already handed out get them other ids
+    let ids = Arc::new(Mutex::new(GroupId::new()));
+    ids.safe_lock(|ids| {
+        for _ in 0..5 {
+            ids.new_channel_id(0);
+        }
+    })
+    .unwrap();
+    let mut restarted = PoolChannelFactory::new(
+        ids,
+        ExtendedExtranonce::new(0..0, 0..8, 8..EXTRANONCE_LEN, None).unwrap(),
+        JobsCreators::new(EXTRANONCE_LEN as u8),
+        SHARES_PER_MINUTE,
+        ExtendedChannelKind::Pool,
+        pool_coinbase_outputs(),
+    );
+    for (request_id, fingerprint) in fingerprints.iter().enumerate() {
+        let channel_id = open_extended_channel(&mut restarted, request_id as u32 + 1);
+        assert!(!channels.contains(&channel_id));
+        assert_eq!(
+            restarted.channel_fingerprint(channel_id).as_ref(),
+            Some(fingerprint)
+        );
+    }
+}
+
+// Upstream reserves the first 4 bytes of the extranonce
+fn new_proxy_factory(upstream_target: [u8; 32]) -> ProxyExtendedChannelFactory {
+    let ids = Arc::new(Mutex::new(GroupId::new()));
+    let extranonces = ExtendedExtranonce::new(0..4, 4..8, 8..EXTRANONCE_LEN, None).unwrap();
+    ProxyExtendedChannelFactory::new(
+        ids,
+        extranonces,
+        None,
+        SHARES_PER_MINUTE,
+        ExtendedChannelKind::Proxy {
+            upstream_target: upstream_target.into(),
+        },
+        None,
+        1,
+    )
+}
+
+// Sends to the proxy a future job followed by the prev hash that activates it, returns the job
+// id
+fn proxy_activate_job(proxy: &mut ProxyExtendedChannelFactory, nbits: u32) -> u32 {
+    let job = template_extended_job();
+    let job_id = job.job_id;
+    proxy.on_new_extended_mining_job(job).unwrap();
+    proxy
+        .on_new_prev_hash(SetNewPrevHash {
+            channel_id: 1,
+            job_id,
+            prev_hash: U256::from([1; 32]),
+            min_ntime: 1_700_000_000,
+            nbits,
+        })
+        .unwrap();
+    job_id
+}
+
+fn proxy_open_extended_channel(proxy: &mut ProxyExtendedChannelFactory, request_id: u32) -> u32 {
+    let messages = proxy
+        .new_extended_channel(
+            request_id,
+            0.0,
+            DOWNSTREAM_EXTRANONCE_LEN as u16,
+            ConnectionFlags::all(),
+        )
+        .unwrap();
+    match &messages[0] {
+        Mining::OpenExtendedMiningChannelSuccess(success) => success.channel_id,
+        m => panic!("Expected OpenExtendedMiningChannelSuccess, got {m}"),
+    }
+}
+
+#[test]
+fn test_forward_reason_met_upstream_target() {
+    let mut proxy = new_proxy_factory(MAX_TARGET);
+    let channel_id = proxy_open_extended_channel(&mut proxy, 1);
+    // Network target is 1, no share meets it
+    let job_id = proxy_activate_job(&mut proxy, 0x0300_0001);
+
+    let share = extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    match proxy.on_submit_shares_extended(share).unwrap() {
+        OnNewShare::SendSubmitShareUpstream((Share::Extended(share), None, reason)) => {
+            assert_eq!(reason, ForwardReason::MetUpstreamTarget);
+            assert_eq!(share.channel_id, proxy.get_this_channel_id());
+        }
+        _ => panic!("Expected SendSubmitShareUpstream"),
+    }
+}
+
+#[test]
+fn test_forward_reason_block_relay() {
+    let mut proxy = new_proxy_factory(MAX_TARGET);
+    let channel_id = proxy_open_extended_channel(&mut proxy, 1);
+    // Network target is 0xffff << 240, almost every hash meets it
+    let job_id = proxy_activate_job(&mut proxy, 0x2100_ffff);
+
+    let share = extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    match proxy.on_submit_shares_extended(share).unwrap() {
+        OnNewShare::SendSubmitShareUpstream((_, _, reason)) => {
+            assert_eq!(reason, ForwardReason::BlockRelay)
+        }
+        _ => panic!("Expected SendSubmitShareUpstream"),
+    }
+}
+
+#[test]
+fn test_last_prev_hash_and_blockhash_never_diverge() {
+    let mut factory = new_pool_factory();
+    assert_eq!(factory.inner.last_prev_blockhash(), None);
+    for i in 1..=3_u8 {
+        factory
+            .on_new_template(&mut new_template(i as u64, true))
+            .unwrap();
+        let mut prev_hash = new_prev_hash(i as u64, MIN_TARGET);
+        prev_hash.prev_hash = U256::from([i; 32]);
+        factory.on_new_prev_hash_from_tp(&prev_hash).unwrap();
+
+        let (staged, _) = factory.inner.last_prev_hash.as_ref().unwrap();
+        assert_eq!(staged.prev_hash, prev_hash.prev_hash);
+        assert_eq!(
+            factory.inner.last_prev_blockhash(),
+            Some(crate::utils::u256_to_block_hash(
+                prev_hash.prev_hash.clone()
+            ))
+        );
+    }
+}
+
+#[test]
+fn test_update_group_target_sets_every_channel_in_group() {
+    let mut factory = new_pool_factory();
+    let channel_ids: Vec<u32> = (0..3)
+        .map(|request_id| open_extended_channel(&mut factory, request_id))
+        .collect();
+    // Move the last channel to another group, it must not be updated
+    let other_channel = open_extended_channel(&mut factory, 3);
+    factory.inner.channel_to_group_id.insert(other_channel, 1);
+
+    let new_target: Target = [7_u8; 32].into();
+    let messages = factory.update_group_target(0, new_target.clone());
+
+    assert_eq!(messages.len(), channel_ids.len());
+    for ((channel_id, message), expected_id) in messages.iter().zip(channel_ids.iter()) {
+        assert_eq!(channel_id, expected_id);
+        match message {
+            Mining::SetTarget(m) => {
+                assert_eq!(m.channel_id, *expected_id);
+                assert_eq!(Target::from(m.maximum_target.clone()), new_target);
+            }
+            m => panic!("Expected SetTarget, got {m}"),
+        }
+        let channel = factory.inner.extended_channels.get(expected_id).unwrap();
+        assert_eq!(Target::from(channel.target.clone()), new_target);
+    }
+    let other = factory.inner.extended_channels.get(&other_channel).unwrap();
+    assert_ne!(Target::from(other.target.clone()), new_target);
+    assert!(factory.update_group_target(2, new_target).is_empty());
+}
+
+#[test]
+fn test_found_block_header_reproduces_block_hash() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MAX_TARGET);
+    let share = extended_share(channel_id, 7, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+
+    let (coinbase, found) = match factory.on_submit_shares_extended(share.clone()).unwrap() {
+        OnNewShare::ShareMeetBitcoinTarget((_, _, coinbase, _, found)) => (coinbase, found),
+        m => panic!("Expected ShareMeetBitcoinTarget, got {m:?}"),
+    };
+
+    let (staged, _) = factory.inner.last_prev_hash.as_ref().unwrap();
+    assert_eq!(found.version, share.version);
+    assert_eq!(
+        Some(found.prev_blockhash),
+        factory.inner.last_prev_blockhash()
+    );
+    assert_eq!(found.time, share.ntime);
+    assert_eq!(found.bits, staged.nbits);
+    assert_eq!(found.nonce, share.nonce);
+    // With an empty merkle path the merkle root is the coinbase txid
+    let coinbase: bitcoin::Transaction = bitcoin::consensus::deserialize(&coinbase).unwrap();
+    assert_eq!(
+        found.merkle_root.to_raw_hash(),
+        coinbase.compute_txid().to_raw_hash()
+    );
+
+    let serialized = found.serialize();
+    assert_eq!(serialized.len(), 80);
+    let header: Header = bitcoin::consensus::deserialize(&serialized).unwrap();
+    assert_eq!(header.block_hash(), found.block_hash());
+    assert_eq!(FoundBlockHeader::from(&header), found);
+}
+
+#[test]
+fn test_assemble_block() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MAX_TARGET);
+    let share = extended_share(channel_id, 7, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+
+    let (share, coinbase, found) = match factory.on_submit_shares_extended(share).unwrap() {
+        OnNewShare::ShareMeetBitcoinTarget((share, _, coinbase, _, found)) => {
+            (share, coinbase, found)
+        }
+        m => panic!("Expected ShareMeetBitcoinTarget, got {m:?}"),
+    };
+
+    let block = factory.assemble_block(&share, &coinbase, vec![]).unwrap();
+    assert_eq!(block.block_hash(), found.block_hash());
+    assert_eq!(
+        bitcoin::consensus::serialize(&block.header),
+        found.serialize()
+    );
+    assert_eq!(bitcoin::consensus::serialize(&block.txdata[0]), coinbase);
+
+    // Transactions that are not in the template do not match the merkle root
+    let extra: bitcoin::Transaction = bitcoin::consensus::deserialize(&coinbase).unwrap();
+    assert!(matches!(
+        factory.assemble_block(&share, &coinbase, vec![extra]),
+        Err(Error::BlockMerkleRootMismatch)
+    ));
+    // Only found blocks can be assembled
+    let mut other_coinbase = coinbase.clone();
+    *other_coinbase.last_mut().unwrap() ^= 1;
+    assert!(matches!(
+        factory.assemble_block(&share, &other_coinbase, vec![]),
+        Err(Error::UnknownFoundBlock(id)) if id == job_id
+    ));
+}
+
+#[test]
+fn test_channels_in_group() {
+    let mut factory = new_pool_factory();
+    assert_eq!(factory.channels_in_group(0), 0);
+    let channel_ids: Vec<u32> = (0..5)
+        .map(|request_id| open_extended_channel(&mut factory, request_id))
+        .collect();
+    for channel_id in &channel_ids[3..] {
+        factory.inner.channel_to_group_id.insert(*channel_id, 1);
+    }
+    assert_eq!(factory.channels_in_group(0), 3);
+    assert_eq!(factory.channels_in_group(1), 2);
+    assert_eq!(factory.channels_in_group(2), 0);
+}
+
+#[test]
+fn test_valid_coinbase_split_is_accepted() {
+    let job = template_extended_job();
+    assert!(check_coinbase_split(
+        job.coinbase_tx_prefix.as_ref(),
+        job.coinbase_tx_suffix.as_ref(),
+        EXTRANONCE_LEN,
+    )
+    .is_ok());
+    let mut factory = new_pool_factory();
+    assert!(factory.inner.on_new_extended_mining_job(job).is_ok());
+}
+
+#[test]
+fn test_malformed_coinbase_split_is_rejected() {
+    let job = template_extended_job();
+    let prefix = job.coinbase_tx_prefix.to_vec();
+    let suffix = job.coinbase_tx_suffix.to_vec();
+
+    // Dropping a byte from the suffix gives bytes that do not parse as a transaction
+    let truncated = &suffix[..suffix.len() - 1];
+    assert!(matches!(
+        check_coinbase_split(&prefix, truncated, EXTRANONCE_LEN),
+        Err(Error::MalformedCoinbaseSplit)
+    ));
+
+    // Moving the split before the script len makes the extranonce land outside the script
+    let script_sig_prefix_len = new_template(1, true).coinbase_prefix.to_vec().len();
+    let split = prefix.len() - script_sig_prefix_len - 1;
+    let (shifted_prefix, moved) = prefix.split_at(split);
+    let shifted_suffix = [moved, &suffix[..]].concat();
+    assert!(matches!(
+        check_coinbase_split(shifted_prefix, &shifted_suffix, EXTRANONCE_LEN),
+        Err(Error::MalformedCoinbaseSplit)
+    ));
+
+    let mut corrupted = future_extended_job(1, VERSION);
+    corrupted.coinbase_tx_suffix = truncated.to_vec().try_into().unwrap();
+    let mut factory = new_pool_factory();
+    assert!(matches!(
+        factory.inner.on_new_extended_mining_job(corrupted),
+        Err(Error::MalformedCoinbaseSplit)
+    ));
+}
+
+fn is_invalid_job_id_error(on_new_share: &OnNewShare) -> bool {
+    match on_new_share {
+        OnNewShare::SendErrorDownstream((error, _)) => {
+            error.error_code.as_utf8_or_hex() == SubmitSharesError::invalid_job_id_error_code()
+        }
+        _ => false,
+    }
+}
+
+#[test]
+fn test_pool_strict_job_id() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    let stale = || {
+        extended_share(
+            channel_id,
+            0,
+            job_id + 1,
+            vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+        )
+    };
+
+    // Strict by default
+    assert!(is_invalid_job_id_error(
+        &factory.on_submit_shares_extended(stale()).unwrap()
+    ));
+    let share = extended_share(channel_id, 1, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+
+    // When lenient the share is checked against the last valid job
+    factory.set_strict_job_id(false);
+    assert!(matches!(
+        factory.on_submit_shares_extended(stale()).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+}
+
+#[test]
+fn test_proxy_strict_job_id() {
+    let mut proxy = new_proxy_factory(MAX_TARGET);
+    let channel_id = proxy_open_extended_channel(&mut proxy, 1);
+    let job_id = proxy_activate_job(&mut proxy, 0x0300_0001);
+    let stale = || {
+        extended_share(
+            channel_id,
+            0,
+            job_id + 1,
+            vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+        )
+    };
+
+    // Strict by default
+    assert!(is_invalid_job_id_error(
+        &proxy.on_submit_shares_extended(stale()).unwrap()
+    ));
+
+    proxy.set_strict_job_id(false);
+    assert!(matches!(
+        proxy.on_submit_shares_extended(stale()).unwrap(),
+        OnNewShare::SendSubmitShareUpstream(_)
+    ));
+}
+
+#[test]
+fn test_extended_job_for_channel() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let other_channel = open_extended_channel(&mut factory, 2);
+    assert!(factory.extended_job_for_channel(channel_id).is_none());
+
+    activate_job(&mut factory, 1, MIN_TARGET);
+    let active = factory.inner.last_valid_job.as_ref().unwrap().0.clone();
+    for id in [channel_id, other_channel] {
+        let job = factory.extended_job_for_channel(id).unwrap();
+        assert_eq!(job.channel_id, id);
+        assert_eq!(
+            NewExtendedMiningJob {
+                channel_id: active.channel_id,
+                ..job
+            },
+            active
+        );
+    }
+    assert!(factory
+        .extended_job_for_channel(other_channel + 1)
+        .is_none());
+}
+
+#[test]
+fn test_future_template_after_prev_hash_supersedes_active_job() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let active_job_id = activate_job(&mut factory, 1, MIN_TARGET);
+
+    // A future template received while a job is active is queued and sent downstream as future
+    let messages = factory.on_new_template(&mut new_template(2, true)).unwrap();
+    let future_job_id = match messages.get(&channel_id).unwrap() {
+        Mining::NewExtendedMiningJob(job) => {
+            assert_eq!(job.channel_id, channel_id);
+            assert!(job.is_future());
+            job.job_id
+        }
+        m => panic!("Expected NewExtendedMiningJob, got {m}"),
+    };
+    assert_ne!(future_job_id, active_job_id);
+
+    // The active job is still valid until the prev hash for the future job arrives
+    let (job, _) = factory.inner.last_valid_job.as_ref().unwrap();
+    assert_eq!(job.job_id, active_job_id);
+    let share = extended_share(
+        channel_id,
+        0,
+        active_job_id,
+        vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+    );
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+
+    let mut prev_hash = new_prev_hash(2, MIN_TARGET);
+    prev_hash.prev_hash = U256::from([2; 32]);
+    factory.on_new_prev_hash_from_tp(&prev_hash).unwrap();
+    assert_eq!(active_job_id(&factory), future_job_id);
+    let job = factory.extended_job_for_channel(channel_id).unwrap();
+    assert_eq!(job.job_id, future_job_id);
+    assert!(!job.is_future());
+    assert!(factory.inner.future_jobs.is_empty());
+    assert_eq!(
+        factory.inner.last_prev_blockhash(),
+        Some(crate::utils::u256_to_block_hash(prev_hash.prev_hash))
+    );
+}
+
+#[test]
+fn test_prev_hash_for_active_job_keeps_it() {
+    let mut factory = new_pool_factory();
+    let staged = |job_id| StagedPhash {
+        job_id,
+        prev_hash: U256::from([1; 32]),
+        min_ntime: 0,
+        nbits: NBITS,
+    };
+    factory
+        .inner
+        .on_new_extended_mining_job(future_extended_job(5, VERSION))
+        .unwrap();
+    factory.inner.on_new_prev_hash(staged(5)).unwrap();
+    factory
+        .inner
+        .on_new_extended_mining_job(future_extended_job(6, VERSION))
+        .unwrap();
+
+    factory.inner.on_new_prev_hash(staged(5)).unwrap();
+    let (job, _) = factory.inner.last_valid_job.as_ref().unwrap();
+    assert_eq!(job.job_id, 5);
+
+    // A prev hash that matches neither the active nor a future job leaves no active job, the old
+    // job is stale
+    factory
+        .inner
+        .on_new_extended_mining_job(future_extended_job(7, VERSION))
+        .unwrap();
+    factory.inner.on_new_prev_hash(staged(8)).unwrap();
+    assert!(factory.inner.last_valid_job.is_none());
+    assert!(factory.inner.stale_job_ids.contains(&5));
+    assert!(factory.inner.future_jobs.is_empty());
+}
+
+#[test]
+fn test_expected_share_value_sats() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    assert_eq!(factory.expected_share_value_sats(channel_id), None);
+    activate_job(&mut factory, 1, MIN_TARGET);
+    assert_eq!(factory.expected_share_value_sats(channel_id + 1), None);
+
+    // NBITS is 0x207fffff, the network target is 0x7fffff << 232
+    let mut network_target = [0_u8; 32];
+    network_target[29..].copy_from_slice(&[0xff, 0xff, 0x7f]);
+    // Half the network difficulty
+    let mut double_network_target = [0_u8; 32];
+    double_network_target[29..].copy_from_slice(&[0xfe, 0xff, 0xff]);
+
+    let expected = [
+        (network_target, BLOCK_REWARD as f64),
+        (double_network_target, BLOCK_REWARD as f64 / 2.0),
+    ];
+    for (target, expected_value) in expected {
+        factory.update_target_for_channel(channel_id, target.into());
+        let value = factory.expected_share_value_sats(channel_id).unwrap();
+        assert!((value - expected_value).abs() / expected_value < 1e-9);
+    }
+
+    factory.update_pool_outputs(vec![]);
+    assert_eq!(factory.expected_share_value_sats(channel_id), None);
+}
+
+#[test]
+fn test_on_new_job_hook_fires_once_per_template() {
+    let mut factory = new_pool_factory();
+    let observed = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let observed_ = observed.clone();
+    factory.set_on_new_job(move |job| observed_.lock().unwrap().push(job.clone()));
+
+    let mut sent = Vec::new();
+    for template_id in 1..=3 {
+        open_extended_channel(&mut factory, template_id as u32);
+        let messages = factory
+            .on_new_template(&mut new_template(template_id, true))
+            .unwrap();
+        match messages.values().next().unwrap() {
+            Mining::NewExtendedMiningJob(job) => sent.push(job.job_id),
+            m => panic!("Expected NewExtendedMiningJob, got {m}"),
+        }
+    }
+
+    let observed = observed.lock().unwrap();
+    assert_eq!(observed.len(), 3);
+    for (job, job_id) in observed.iter().zip(sent) {
+        assert_eq!(job.job_id, job_id);
+        assert!(job.is_future());
+    }
+}
+
+#[test]
+fn test_downstream_extranonce1_len() {
+    // The upstream prefix is 0..4 and the proxy carves 4..8 for its downstreams
+    let mut proxy = new_proxy_factory(MAX_TARGET);
+    assert_eq!(proxy.get_upstream_extranonce1_len(), 4);
+    assert_eq!(proxy.downstream_extranonce1_len(), 8);
+    let channel_id = proxy_open_extended_channel(&mut proxy, 1);
+    assert_eq!(
+        proxy.downstream_extranonce1_len() + proxy.channel_extranonce2_size(channel_id).unwrap(),
+        proxy.extranonce_size()
+    );
+}
+
+fn is_stale_share_error(on_new_share: &OnNewShare) -> bool {
+    match on_new_share {
+        OnNewShare::SendErrorDownstream((error, _)) => {
+            error.error_code.as_utf8_or_hex() == SubmitSharesError::stale_share_error_code()
+        }
+        _ => false,
+    }
+}
+
+#[test]
+fn test_custom_job_on_current_tip() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    activate_job(&mut factory, 1, MIN_TARGET);
+    // `custom_mining_job` uses the same prev hash of `new_prev_hash`
+    let job = custom_mining_job(channel_id, &pool_coinbase_outputs());
+    let job_id = set_custom_job(&mut factory, job);
+
+    let share = extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+}
+
+#[test]
+fn test_custom_job_on_stale_tip() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    activate_job(&mut factory, 1, MIN_TARGET);
+    let job = custom_mining_job(channel_id, &pool_coinbase_outputs());
+    let job_id = set_custom_job(&mut factory, job);
+    let share = || extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+
+    assert!(!is_stale_share_error(
+        &factory.on_submit_shares_extended(share()).unwrap()
+    ));
+
+    // The tip moves, the custom job is now built on a stale prev hash
+    factory.on_new_template(&mut new_template(2, true)).unwrap();
+    let mut prev_hash = new_prev_hash(2, MIN_TARGET);
+    prev_hash.prev_hash = U256::from([2; 32]);
+    factory.on_new_prev_hash_from_tp(&prev_hash).unwrap();
+    assert!(is_stale_share_error(
+        &factory.on_submit_shares_extended(share()).unwrap()
+    ));
+    assert_eq!(
+        factory
+            .share_accounting(channel_id)
+            .unwrap()
+            .shares_rejected(),
+        1
+    );
+}
+
+#[test]
+fn test_negotiated_jobs_dropped_on_new_prev_hash() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let other_channel_id = open_extended_channel(&mut factory, 2);
+    activate_job(&mut factory, 1, MIN_TARGET);
+    let stale_job_id = set_custom_job(
+        &mut factory,
+        custom_mining_job(channel_id, &pool_coinbase_outputs()),
+    );
+    set_custom_job(
+        &mut factory,
+        custom_mining_job(other_channel_id, &pool_coinbase_outputs()),
+    );
+
+    factory.on_new_template(&mut new_template(2, true)).unwrap();
+    let mut prev_hash = new_prev_hash(2, MIN_TARGET);
+    prev_hash.prev_hash = U256::from([2; 32]);
+    factory.on_new_prev_hash_from_tp(&prev_hash).unwrap();
+    assert!(factory.negotiated_jobs.is_empty());
+
+    // The dropped job is not used anymore, its shares are stale
+    let share = extended_share(
+        channel_id,
+        0,
+        stale_job_id,
+        vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+    );
+    assert!(is_stale_share_error(
+        &factory.on_submit_shares_extended(share).unwrap()
+    ));
+
+    // A custom job on the new tip replaces it
+    let mut job = custom_mining_job(channel_id, &pool_coinbase_outputs());
+    job.prev_hash = U256::from([2; 32]);
+    let job_id = set_custom_job(&mut factory, job);
+    assert!(!factory.stale_negotiated_jobs.contains_key(&channel_id));
+    let share = extended_share(channel_id, 1, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+}
+
+#[test]
+fn test_metrics_text() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    for sequence_number in 0..2 {
+        let share = extended_share(
+            channel_id,
+            sequence_number,
+            job_id,
+            vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+        );
+        factory.on_submit_shares_extended(share).unwrap();
+    }
+    let mut share = extended_share(channel_id, 2, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    share.version = VERSION | 0x1;
+    factory.on_submit_shares_extended(share).unwrap();
+
+    let text = factory.metrics_text();
+    let labels = format!("{{channel_id=\"{channel_id}\",group_id=\"0\"}}");
+    let channel_target = factory.inner.extended_channels[&channel_id].target.clone();
+    let difficulty = crate::utils::target_to_difficulty(&channel_target.into());
+    for (name, kind, value) in [
+        (
+            "sv2_channel_shares_accepted_total",
+            "counter",
+            "2".to_string(),
+        ),
+        (
+            "sv2_channel_shares_rejected_total",
+            "counter",
+            "1".to_string(),
+        ),
+        ("sv2_channel_blocks_found_total", "counter", "0".to_string()),
+        ("sv2_channel_difficulty", "gauge", difficulty.to_string()),
+    ] {
+        assert!(text.contains(&format!("# TYPE {name} {kind}\n")));
+        assert!(text.contains(&format!("{name}{labels} {value}\n")));
+    }
+}
+
+#[test]
+fn test_expire_prev_hash() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    let share = |job_id| extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+
+    factory.expire_prev_hash();
+    assert_eq!(factory.inner.last_prev_blockhash(), None);
+    assert!(factory.get_extended_channels_ids().contains(&channel_id));
+    assert!(factory.inner.last_valid_job.is_none());
+    for _ in 0..2 {
+        assert!(is_stale_share_error(
+            &factory.on_submit_shares_extended(share(job_id)).unwrap()
+        ));
+    }
+
+    let job_id = activate_job(&mut factory, 2, MIN_TARGET);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share(job_id)).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+}
+
+#[test]
+fn test_audit_extranonce_disjointness() {
+    let mut factory = new_pool_factory();
+    assert_eq!(factory.audit_extranonce_disjointness(), Ok(()));
+    let channel_ids: Vec<u32> = (0..3)
+        .map(|request_id| open_extended_channel(&mut factory, request_id))
+        .collect();
+    assert_eq!(factory.audit_extranonce_disjointness(), Ok(()));
+
+    // Give the last channel the prefix of the first one
+    let prefix = factory.inner.extended_channels[&channel_ids[0]]
+        .extranonce_prefix
+        .clone();
+    factory
+        .inner
+        .extended_channels
+        .get_mut(&channel_ids[2])
+        .unwrap()
+        .extranonce_prefix = prefix;
+    assert_eq!(
+        factory.audit_extranonce_disjointness(),
+        Err(vec![(channel_ids[0], channel_ids[2])])
+    );
+
+    // Standard extranonces are checked against the extended prefixes and each other
+    let mut factory = new_pool_factory();
+    let group_id = factory.new_group_id().unwrap();
+    let extended_channel = open_extended_channel(&mut factory, 1);
+    let (standard_channel, _, _) = open_standard_channel(&mut factory, 2, group_id);
+    let (other_standard_channel, extranonce, _) = open_standard_channel(&mut factory, 3, group_id);
+    assert_eq!(factory.audit_extranonce_disjointness(), Ok(()));
+    factory
+        .inner
+        .standard_channels
+        .get_mut(&standard_channel)
+        .unwrap()
+        .extranonce = extranonce;
+    assert_eq!(
+        factory.audit_extranonce_disjointness(),
+        Err(vec![(standard_channel, other_standard_channel)])
+    );
+    let prefix = factory.inner.extended_channels[&extended_channel]
+        .extranonce_prefix
+        .to_vec();
+    factory
+        .inner
+        .standard_channels
+        .get_mut(&standard_channel)
+        .unwrap()
+        .extranonce = [prefix, vec![0; DOWNSTREAM_EXTRANONCE_LEN]].concat();
+    assert_eq!(
+        factory.audit_extranonce_disjointness(),
+        Err(vec![(extended_channel, standard_channel)])
+    );
+}
+
+#[test]
+fn test_connection_flags_from_setup_connection_flags() {
+    // REQUIRES_VERSION_ROLLING is bit 1 and REQUIRES_WORK_SELECTION is bit 2
+    assert_eq!(ConnectionFlags::from(0b110), ConnectionFlags::all());
+    assert_eq!(
+        ConnectionFlags::from(0b001),
+        ConnectionFlags {
+            version_rolling: false,
+            work_selection: false,
+        }
+    );
+}
+
+#[test]
+fn test_connection_flags_enforced_per_channel() {
+    let mut factory = new_pool_factory();
+    let flagged_channel = open_extended_channel(&mut factory, 1);
+    let no_flags = ConnectionFlags {
+        version_rolling: false,
+        work_selection: false,
+    };
+    let unflagged_channel = match &factory
+        .new_extended_channel(2, 0.0, DOWNSTREAM_EXTRANONCE_LEN as u16, no_flags)
+        .unwrap()[0]
+    {
+        Mining::OpenExtendedMiningChannelSuccess(success) => success.channel_id,
+        m => panic!("Expected OpenExtendedMiningChannelSuccess, got {m}"),
+    };
+
+    let messages = factory.on_new_template(&mut new_template(1, true)).unwrap();
+    for (channel_id, version_rolling_allowed) in
+        [(flagged_channel, true), (unflagged_channel, false)]
+    {
+        match messages.get(&channel_id).unwrap() {
+            Mining::NewExtendedMiningJob(job) => {
+                assert_eq!(job.version_rolling_allowed, version_rolling_allowed)
+            }
+            m => panic!("Expected NewExtendedMiningJob, got {m}"),
+        }
+    }
+    factory
+        .on_new_prev_hash_from_tp(&new_prev_hash(1, MIN_TARGET))
+        .unwrap();
+    let job_id = active_job_id(&factory);
+
+    // Version rolling is rejected only when not negotiated
+    let rolled_share = |channel_id| {
+        let mut share = extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+        share.version = VERSION | 0x0000_2000;
+        share
+    };
+    assert!(matches!(
+        factory
+            .on_submit_shares_extended(rolled_share(flagged_channel))
+            .unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+    match factory
+        .on_submit_shares_extended(rolled_share(unflagged_channel))
+        .unwrap()
+    {
+        OnNewShare::SendErrorDownstream((error, _)) => {
+            assert_eq!(error.error_code.as_utf8_or_hex(), "invalid-version")
+        }
+        _ => panic!("Expected SendErrorDownstream"),
+    }
+
+    // Custom jobs are rejected only when work selection is not negotiated
+    let outputs = pool_coinbase_outputs();
+    assert!(is_custom_job_success(factory.on_new_set_custom_mining_job(
+        custom_mining_job(flagged_channel, &outputs)
+    )));
+    assert!(matches!(
+        factory.on_new_set_custom_mining_job(custom_mining_job(unflagged_channel, &outputs)),
+        Err(Error::WorkSelectionNotNegotiated(id)) if id == unflagged_channel
+    ));
+
+    // Unknown channels are rejected, no job is negotiated for them
+    let unknown_channel = flagged_channel.max(unflagged_channel) + 1;
+    match factory
+        .on_new_set_custom_mining_job(custom_mining_job(unknown_channel, &outputs))
+        .unwrap()
+    {
+        OnNewSetCustomMiningJob::SendErrorDownstream(error) => {
+            assert_eq!(error.channel_id, unknown_channel);
+            assert_eq!(
+                error.error_code.as_utf8_or_hex(),
+                SetCustomMiningJobError::invalid_channel_id_error_code()
+            );
+        }
+        OnNewSetCustomMiningJob::Success(_) => panic!("Expected SendErrorDownstream"),
+    }
+    assert!(!factory.negotiated_jobs.contains_key(&unknown_channel));
+}
+
+// Unix time of a MockClock when it is created
+const MOCK_UNIX_TIME: u64 = 1_700_000_000;
+
+// Clock that only moves when advanced by the test, its unix time starts at `MOCK_UNIX_TIME`
+#[derive(Debug, Clone)]
+struct MockClock(Arc<std::sync::Mutex<(std::time::Instant, Duration)>>);
+
+impl MockClock {
+    fn new() -> Self {
+        Self(Arc::new(std::sync::Mutex::new((
+            std::time::Instant::now(),
+            Duration::ZERO,
+        ))))
+    }
+
+    fn advance(&self, duration: Duration) {
+        let mut clock = self.0.lock().unwrap();
+        clock.0 += duration;
+        clock.1 += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> std::time::Instant {
+        self.0.lock().unwrap().0
+    }
+
+    fn unix_time(&self) -> u64 {
+        MOCK_UNIX_TIME + self.0.lock().unwrap().1.as_secs()
+    }
+}
+
+#[test]
+fn test_unproductive_channels() {
+    let grace = Duration::from_secs(600);
+    let clock = MockClock::new();
+    let mut factory = new_pool_factory();
+    factory.set_clock(Box::new(clock.clone()));
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    assert!(factory.unproductive_channels(grace).is_empty());
+
+    clock.advance(grace);
+    let late_channel = open_extended_channel(&mut factory, 2);
+    assert_eq!(factory.unproductive_channels(grace), vec![channel_id]);
+
+    let share = extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    factory.on_submit_shares_extended(share).unwrap();
+    assert!(factory.unproductive_channels(grace).is_empty());
+
+    clock.advance(grace);
+    assert_eq!(factory.unproductive_channels(grace), vec![late_channel]);
+}
+
+#[test]
+fn test_last_extranonce_grant_records_shortfall() {
+    let mut factory = new_pool_factory();
+    assert_eq!(factory.last_extranonce_grant(), None);
+    let available = factory.default_channel_extranonce2_size() as u16;
+
+    open_extended_channel(&mut factory, 1);
+    let grant = factory.last_extranonce_grant().unwrap();
+    assert_eq!(grant.request_id, 1);
+    assert_eq!(grant.granted, available);
+    assert_eq!(grant.shortfall(), 0);
+
+    let messages = factory
+        .new_extended_channel(2, 0.0, available + 3, ConnectionFlags::all())
+        .unwrap();
+    assert!(matches!(messages[0], Mining::OpenMiningChannelError(_)));
+    assert_eq!(
+        factory.last_extranonce_grant(),
+        Some(ExtranonceGrant {
+            request_id: 2,
+            requested: available + 3,
+            available,
+            granted: 0,
+        })
+    );
+    assert_eq!(factory.last_extranonce_grant().unwrap().shortfall(), 3);
+}
+
+fn new_proxy_jd_factory() -> ProxyExtendedChannelFactory {
+    let ids = Arc::new(Mutex::new(GroupId::new()));
+    let extranonces = ExtendedExtranonce::new(0..4, 4..8, 8..EXTRANONCE_LEN, None).unwrap();
+    ProxyExtendedChannelFactory::new(
+        ids,
+        extranonces,
+        Some(JobsCreators::new(EXTRANONCE_LEN as u8)),
+        SHARES_PER_MINUTE,
+        ExtendedChannelKind::ProxyJd {
+            upstream_target: MAX_TARGET.into(),
+        },
+        Some(pool_coinbase_outputs()),
+        1,
+    )
+}
+
+#[test]
+fn test_job_merkle_path_matches_template() {
+    let mut proxy = new_proxy_jd_factory();
+    proxy.set_strict_merkle_path(true);
+    let mut template = new_template(1, true);
+    template.merkle_path = vec![U256::from([4; 32]), U256::from([5; 32])].into();
+
+    let (messages, _, job_id) = proxy.on_new_template(&mut template).unwrap();
+    assert!(messages.is_empty());
+    let (job, _) = proxy
+        .inner
+        .future_jobs
+        .iter()
+        .find(|(job, _)| job.job_id == job_id)
+        .unwrap();
+    assert_eq!(job.merkle_path.to_vec(), template.merkle_path.to_vec());
+}
+
+fn share_error_code(on_new_share: &OnNewShare) -> Option<String> {
+    match on_new_share {
+        OnNewShare::SendErrorDownstream((error, _)) => Some(error.error_code.as_utf8_or_hex()),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_shares_for_closed_channels() {
+    let ttl = Duration::from_secs(60);
+    let clock = MockClock::new();
+    let mut factory = new_pool_factory();
+    factory.set_clock(Box::new(clock.clone()));
+    factory.set_closed_channel_ttl(Some(ttl));
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+
+    assert!(factory.close_channel(channel_id));
+    assert!(!factory.close_channel(channel_id));
+    let share = extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    let result = factory.on_submit_shares_extended(share.clone()).unwrap();
+    assert_eq!(
+        share_error_code(&result).as_deref(),
+        Some(CHANNEL_CLOSED_ERROR_CODE)
+    );
+
+    let never_opened = extended_share(channel_id + 100, 0, job_id, vec![0; 8]);
+    let result = factory.on_submit_shares_extended(never_opened).unwrap();
+    assert_eq!(
+        share_error_code(&result).as_deref(),
+        Some(SubmitSharesError::invalid_channel_error_code())
+    );
+
+    clock.advance(ttl);
+    let result = factory.on_submit_shares_extended(share).unwrap();
+    assert_eq!(
+        share_error_code(&result).as_deref(),
+        Some(SubmitSharesError::invalid_channel_error_code())
+    );
+}
+
+fn new_pool_factory_with_ranges(
+    range_0: std::ops::Range<usize>,
+    range_1: std::ops::Range<usize>,
+) -> PoolChannelFactory {
+    let ids = Arc::new(Mutex::new(GroupId::new()));
+    let range_2 = range_1.end..EXTRANONCE_LEN;
+    let extranonces = ExtendedExtranonce::new(range_0, range_1, range_2, None).unwrap();
+    PoolChannelFactory::new(
+        ids,
+        extranonces,
+        JobsCreators::new(EXTRANONCE_LEN as u8),
+        SHARES_PER_MINUTE,
+        ExtendedChannelKind::Pool,
+        pool_coinbase_outputs(),
+    )
+}
+
+#[test]
+fn test_max_possible_channels() {
+    assert_eq!(new_pool_factory().max_possible_channels(), u64::MAX as u128);
+    assert_eq!(
+        new_pool_factory_with_ranges(0..2, 2..3).max_possible_channels(),
+        255
+    );
+    assert_eq!(
+        new_pool_factory_with_ranges(0..0, 0..EXTRANONCE_LEN).max_possible_channels(),
+        u128::MAX
+    );
+    assert_eq!(
+        new_pool_factory_with_ranges(0..4, 4..4).max_possible_channels(),
+        0
+    );
+
+    let mut factory = new_pool_factory_with_ranges(0..2, 2..3);
+    let extranonce2_size = factory.available_extranonce2_size() as u16;
+    let prefixes: HashSet<Vec<u8>> = (0..factory.max_possible_channels() as u32)
+        .map(|request_id| {
+            let messages = factory
+                .new_extended_channel(request_id, 0.0, extranonce2_size, ConnectionFlags::all())
+                .unwrap();
+            match &messages[0] {
+                Mining::OpenExtendedMiningChannelSuccess(success) => {
+                    success.extranonce_prefix.to_vec()
+                }
+                _ => panic!("expected OpenExtendedMiningChannelSuccess"),
+            }
+        })
+        .collect();
+    assert_eq!(prefixes.len(), 255);
+}
+
+fn template_at_height(template_id: u64, height: u32, value_remaining: u64) -> NewTemplate<'static> {
+    let mut template = new_template(template_id, true);
+    let height = height.to_le_bytes();
+    template.coinbase_prefix = vec![3, height[0], height[1], height[2]].try_into().unwrap();
+    template.coinbase_tx_value_remaining = value_remaining;
+    template
+}
+
+#[test]
+fn test_coinbase_prefix_height() {
+    assert_eq!(coinbase_prefix_height(&[3, 76, 163, 38]), Some(2532172));
+    assert_eq!(coinbase_prefix_height(&[0x51]), Some(1));
+    assert_eq!(coinbase_prefix_height(&[0x60, 0xff]), Some(16));
+    assert_eq!(coinbase_prefix_height(&[1, 0x80]), None);
+    assert_eq!(coinbase_prefix_height(&[3, 76, 163]), None);
+    assert_eq!(coinbase_prefix_height(&[]), None);
+}
+
+#[test]
+fn test_template_subsidy_across_halving() {
+    let max_fees = 100_000_000;
+    let mut factory = new_pool_factory();
+    factory.set_max_template_fees(Some(max_fees));
+    assert_eq!(block_subsidy(209_999), 5_000_000_000);
+    assert_eq!(block_subsidy(210_000), 2_500_000_000);
+
+    let before_halving = 5_000_000_000 + max_fees;
+    let mut template = template_at_height(1, 209_999, before_halving);
+    assert!(factory.on_new_template(&mut template).is_ok());
+    let mut template = template_at_height(2, 210_000, before_halving);
+    assert!(matches!(
+        factory.on_new_template(&mut template),
+        Err(Error::InvalidTemplateSubsidy(210_000, 2_500_000_000, value)) if value == before_halving
+    ));
+    let mut template = template_at_height(3, 210_000, 2_500_000_000 + max_fees);
+    assert!(factory.on_new_template(&mut template).is_ok());
+    // Claiming less than the subsidy is allowed
+    let mut template = template_at_height(4, 209_999, 2_500_000_000);
+    assert!(factory.on_new_template(&mut template).is_ok());
+
+    factory.set_max_template_fees(None);
+    let mut template = template_at_height(5, 210_000, before_halving);
+    assert!(factory.on_new_template(&mut template).is_ok());
+}
+
+fn message_kinds(messages: &[Mining]) -> Vec<&'static str> {
+    messages
+        .iter()
+        .map(|message| match message {
+            Mining::NewExtendedMiningJob(job) if job.is_future() => "future job",
+            Mining::NewExtendedMiningJob(_) => "job",
+            Mining::NewMiningJob(job) if job.is_future() => "future standard job",
+            Mining::NewMiningJob(_) => "standard job",
+            Mining::SetNewPrevHash(_) => "prev hash",
+            _ => "other",
+        })
+        .collect()
+}
+
+#[test]
+fn test_marked_deliveries_are_not_resent() {
+    let mut factory = new_pool_factory();
+    let marked_channel = open_extended_channel(&mut factory, 1);
+    let other_channel = open_extended_channel(&mut factory, 2);
+    let active_job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    factory.on_new_template(&mut new_template(2, true)).unwrap();
+    let future_job_id = factory.inner.future_jobs[0].0.job_id;
+
+    let all = vec!["future job", "prev hash", "future job"];
+    assert_eq!(
+        message_kinds(&factory.undelivered_messages(marked_channel)),
+        all
+    );
+
+    assert!(factory.mark_prev_hash_delivered(marked_channel));
+    assert_eq!(
+        message_kinds(&factory.undelivered_messages(marked_channel)),
+        vec!["job", "future job"]
+    );
+    assert!(factory.mark_job_delivered(marked_channel, active_job_id));
+    assert!(factory.mark_job_delivered(marked_channel, future_job_id));
+    assert!(factory.undelivered_messages(marked_channel).is_empty());
+    assert_eq!(
+        message_kinds(&factory.undelivered_messages(other_channel)),
+        all
+    );
+    match &factory.undelivered_messages(other_channel)[1] {
+        Mining::SetNewPrevHash(prev_hash) => {
+            assert_eq!(prev_hash.channel_id, other_channel);
+            assert_eq!(prev_hash.job_id, active_job_id);
+        }
+        _ => panic!("expected SetNewPrevHash"),
+    }
+
+    assert!(!factory.mark_job_delivered(marked_channel, future_job_id + 100));
+    assert!(!factory.mark_prev_hash_delivered(other_channel + 100));
+    // The future job keeps its deliveries when it is activated, the new prev hash is undelivered
+    factory
+        .on_new_prev_hash_from_tp(&new_prev_hash(2, MIN_TARGET))
+        .unwrap();
+    assert_eq!(
+        message_kinds(&factory.undelivered_messages(marked_channel)),
+        vec!["prev hash"]
+    );
+}
+
+#[test]
+fn test_coinbase_only_template_end_to_end() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MAX_TARGET);
+    let job = factory.extended_job_for_channel(channel_id).unwrap();
+    assert!(job.merkle_path.to_vec().is_empty());
+
+    let extranonce = vec![0; DOWNSTREAM_EXTRANONCE_LEN];
+    let share = extended_share(channel_id, 0, job_id, extranonce.clone());
+    let (coinbase, found) = match factory.on_submit_shares_extended(share).unwrap() {
+        OnNewShare::ShareMeetBitcoinTarget((_, _, coinbase, _, found)) => (coinbase, found),
+        m => panic!("Expected ShareMeetBitcoinTarget, got {m:?}"),
+    };
+
+    // A standard job for the same extranonce has the coinbase txid as merkle root
+    let full_extranonce = [
+        factory.inner.extended_channels[&channel_id]
+            .extranonce_prefix
+            .to_vec(),
+        extranonce,
+    ]
+    .concat();
+    let standard_job =
+        crate::channel_logic::extended_to_standard_job(&job, &full_extranonce, channel_id, None)
+            .unwrap();
+    assert_eq!(
+        standard_job.merkle_root.to_vec(),
+        bitcoin::consensus::serialize(&found.merkle_root)
+    );
+
+    // The block is the header followed by the coinbase only
+    let coinbase_tx: bitcoin::Transaction = bitcoin::consensus::deserialize(&coinbase).unwrap();
+    let block = bitcoin::Block {
+        header: found.header(),
+        txdata: vec![coinbase_tx],
+    };
+    assert!(block.check_merkle_root());
+    assert_eq!(block.block_hash(), found.block_hash());
+    let serialized = bitcoin::consensus::serialize(&block);
+    assert_eq!(
+        serialized,
+        [&found.serialize()[..], &[1], &coinbase[..]].concat()
+    );
+
+    // Job declaration proxies build the same empty path jobs from coinbase-only templates
+    let mut proxy = new_proxy_jd_factory();
+    let (_, _, proxy_job_id) = proxy.on_new_template(&mut new_template(1, true)).unwrap();
+    let (proxy_job, _) = proxy
+        .inner
+        .future_jobs
+        .iter()
+        .find(|(job, _)| job.job_id == proxy_job_id)
+        .unwrap();
+    assert!(proxy_job.merkle_path.to_vec().is_empty());
+}
+
+#[test]
+fn test_upstream_difficulty() {
+    let mut proxy = new_proxy_factory(MAX_TARGET);
+    // Difficulty 1 target: 0x00000000ffff0000..., little endian
+    let mut difficulty_one = [0; 32];
+    difficulty_one[26] = 0xff;
+    difficulty_one[27] = 0xff;
+    proxy.set_target(&mut difficulty_one.into());
+    assert_eq!(proxy.upstream_target(), Some(difficulty_one.into()));
+    assert_eq!(proxy.upstream_difficulty(), Some(1.0));
+
+    let mut half = [0; 32];
+    half[26] = 0xff;
+    half[27] = 0x7f;
+    proxy.set_target(&mut half.into());
+    let difficulty = proxy.upstream_difficulty().unwrap();
+    assert!((difficulty - 2.0).abs() < 1e-3);
+
+    let pool_kind = ProxyExtendedChannelFactory::new(
+        Arc::new(Mutex::new(GroupId::new())),
+        ExtendedExtranonce::new(0..4, 4..8, 8..EXTRANONCE_LEN, None).unwrap(),
+        None,
+        SHARES_PER_MINUTE,
+        ExtendedChannelKind::Pool,
+        None,
+        1,
+    );
+    assert_eq!(pool_kind.upstream_target(), None);
+    assert_eq!(pool_kind.upstream_difficulty(), None);
+}
+
+#[test]
+fn test_extranonce_from_downstream_extranonce_errors() {
+    let pool = new_pool_factory();
+    let proxy = new_proxy_factory(MAX_TARGET);
+    let downstream = |len: usize| -> mining_sv2::Extranonce { vec![7; len].try_into().unwrap() };
+
+    let full = pool
+        .extranonce_from_downstream_extranonce(downstream(DOWNSTREAM_EXTRANONCE_LEN))
+        .unwrap();
+    assert_eq!(full.to_vec().len(), EXTRANONCE_LEN);
+    let full = proxy
+        .extranonce_from_downstream_extranonce(downstream(DOWNSTREAM_EXTRANONCE_LEN))
+        .unwrap();
+    assert_eq!(full.to_vec()[8..], [7; DOWNSTREAM_EXTRANONCE_LEN]);
+
+    for len in [DOWNSTREAM_EXTRANONCE_LEN - 1, DOWNSTREAM_EXTRANONCE_LEN + 1] {
+        assert!(matches!(
+            pool.extranonce_from_downstream_extranonce(downstream(len)),
+            Err(Error::ExtranoncePrefixFactoryError(
+                mining_sv2::ExtendedExtranonceError::InvalidDownstreamLength
+            ))
+        ));
+        assert!(matches!(
+            proxy.extranonce_from_downstream_extranonce(downstream(len)),
+            Err(Error::ExtranoncePrefixFactoryError(
+                mining_sv2::ExtendedExtranonceError::InvalidDownstreamLength
+            ))
+        ));
+        assert!(pool
+            .extranonce_from_downstream_extranonce_opt(downstream(len))
+            .is_none());
+    }
+
+    // A downstream extranonce that does not fit the max extranonce len is out of range
+    let out_of_range =
+        downstream(mining_sv2::MAX_EXTRANONCE_LEN - EXTRANONCE_LEN + DOWNSTREAM_EXTRANONCE_LEN + 1);
+    let out_of_range_error = pool
+        .extranonce_from_downstream_extranonce(out_of_range.clone())
+        .unwrap_err();
+    assert!(matches!(
+        out_of_range_error,
+        Error::ExtranoncePrefixFactoryError(mining_sv2::ExtendedExtranonceError::ExceedsMaxLength)
+    ));
+    assert!(matches!(
+        proxy.extranonce_from_downstream_extranonce(out_of_range),
+        Err(Error::ExtranoncePrefixFactoryError(
+            mining_sv2::ExtendedExtranonceError::ExceedsMaxLength
+        ))
+    ));
+    let wrong_length_error = pool
+        .extranonce_from_downstream_extranonce(downstream(DOWNSTREAM_EXTRANONCE_LEN + 1))
+        .unwrap_err();
+    assert_ne!(
+        format!("{out_of_range_error:?}"),
+        format!("{wrong_length_error:?}")
+    );
+}
+
+#[test]
+fn test_block_detection_disabled_for_channel() {
+    let mut factory = new_pool_factory();
+    let disabled_channel = open_extended_channel(&mut factory, 1);
+    let other_channel = open_extended_channel(&mut factory, 2);
+    // Every share solves a block
+    let job_id = activate_job(&mut factory, 1, MAX_TARGET);
+    factory.set_block_detection(disabled_channel, false);
+
+    for sequence_number in 0..3 {
+        let share = extended_share(disabled_channel, sequence_number, job_id, vec![0; 8]);
+        assert!(matches!(
+            factory.on_submit_shares_extended(share).unwrap(),
+            OnNewShare::ShareMeetDownstreamTarget
+        ));
+    }
+    let accounting = factory.share_accounting(disabled_channel).unwrap();
+    assert_eq!(accounting.shares_accepted(), 3);
+    assert_eq!(accounting.blocks_found(), 0);
+
+    let share = extended_share(other_channel, 0, job_id, vec![0; 8]);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetBitcoinTarget(_)
+    ));
+
+    factory.set_block_detection(disabled_channel, true);
+    let share = extended_share(disabled_channel, 3, job_id, vec![0; 8]);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetBitcoinTarget(_)
+    ));
+}
+
+#[test]
+fn test_invalid_nbits() {
+    for nbits in [0, 0x0100_00ff, 0x0480_0001, 0x2300_0100] {
+        assert!(matches!(check_nbits(nbits), Err(Error::InvalidNbits(n)) if n == nbits));
+    }
+    for nbits in [NBITS, 0x0300_0001, 0x2100_ffff, 0x1d00_ffff] {
+        assert!(check_nbits(nbits).is_ok());
+    }
+
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    factory.on_new_template(&mut new_template(1, true)).unwrap();
+    let mut prev_hash = new_prev_hash(1, MAX_TARGET);
+    prev_hash.n_bits = 0;
+    assert!(matches!(
+        factory.on_new_prev_hash_from_tp(&prev_hash),
+        Err(Error::InvalidNbits(0))
+    ));
+    // No job is activated so no share can be detected as a block
+    let share = extended_share(channel_id, 0, 1, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(!matches!(
+        factory.on_submit_shares_extended(share),
+        Ok(OnNewShare::ShareMeetBitcoinTarget(_))
+    ));
+
+    let mut proxy = new_proxy_factory(MAX_TARGET);
+    let prev_hash = SetNewPrevHash {
+        channel_id: 0,
+        job_id: 1,
+        prev_hash: U256::from([1; 32]),
+        min_ntime: 1_700_000_000,
+        nbits: 0,
+    };
+    assert!(matches!(
+        proxy.on_new_prev_hash(prev_hash),
+        Err(Error::InvalidNbits(0))
+    ));
+    assert!(proxy.last_prev_hash().is_none());
+}
+
+fn standard_share(channel_id: u32, sequence_number: u32, job_id: u32) -> SubmitSharesStandard {
+    SubmitSharesStandard {
+        channel_id,
+        sequence_number,
+        job_id,
+        nonce: sequence_number,
+        ntime: 1_700_000_001,
+        version: VERSION,
+    }
+}
+
+#[test]
+fn test_standard_channel_shares() {
+    let mut factory = new_pool_factory();
+    let channel_id = factory.new_standard_id_for_hom().unwrap();
+    let extranonce = factory
+        .add_standard_channel(channel_id, 0, MAX_TARGET.into())
+        .unwrap();
+    assert_eq!(extranonce.clone().to_vec().len(), EXTRANONCE_LEN);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    assert!(matches!(
+        factory
+            .on_submit_shares_standard(standard_share(channel_id, 0, job_id))
+            .unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+
+    // A block found by a standard channel has the channel extranonce in the coinbase
+    let job_id = activate_job(&mut factory, 2, MAX_TARGET);
+    let job = factory.inner.last_valid_job.clone().unwrap().0;
+    match factory
+        .on_submit_shares_standard(standard_share(channel_id, 1, job_id))
+        .unwrap()
+    {
+        OnNewShare::ShareMeetBitcoinTarget((share, _, coinbase, share_extranonce, found)) => {
+            assert_eq!(share.get_channel_id(), channel_id);
+            assert_eq!(share_extranonce, extranonce.clone().to_vec());
+            let expected_coinbase = [
+                job.coinbase_tx_prefix.to_vec(),
+                extranonce.to_vec(),
+                job.coinbase_tx_suffix.to_vec(),
+            ]
+            .concat();
+            assert_eq!(coinbase, expected_coinbase);
+            assert_eq!(found.nonce, 1);
+        }
+        m => panic!("Expected ShareMeetBitcoinTarget, got {m:?}"),
+    }
+
+    let extended_channel = open_extended_channel(&mut factory, 1);
+    assert!(matches!(
+        factory
+            .on_submit_shares_standard(standard_share(extended_channel, 2, job_id))
+            .unwrap(),
+        OnNewShare::SendErrorDownstream(_)
+    ));
+
+    // A share for a group that is not the group of the channel is rejected
+    factory.inner.channel_to_group_id.insert(channel_id, 1);
+    match factory
+        .on_submit_shares_standard(standard_share(channel_id, 3, job_id))
+        .unwrap()
+    {
+        OnNewShare::SendErrorDownstream((error, _)) => assert_eq!(
+            error.error_code.as_utf8_or_hex(),
+            SubmitSharesError::invalid_channel_error_code()
+        ),
+        m => panic!("Expected SendErrorDownstream, got {m:?}"),
+    }
+    assert_eq!(factory.get_channel_stats(channel_id).unwrap().rejected, 1);
+}
+
+#[test]
+fn test_found_block_coinbases() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    assert!(factory.found_block_coinbases().is_empty());
+    let job_id = activate_job(&mut factory, 1, MAX_TARGET);
+    let share = extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetBitcoinTarget(_)
+    ));
+
+    let found = factory.found_block_coinbases();
+    assert_eq!(found.len(), 1);
+    let (found_job_id, outputs) = &found[0];
+    assert_eq!(*found_job_id, job_id);
+    // The first pool output gets the value remaining of the template
+    let mut expected = pool_coinbase_outputs();
+    expected[0].value = Amount::from_sat(BLOCK_REWARD);
+    assert_eq!(outputs, &expected);
+
+    for sequence_number in 1..FOUND_BLOCKS_HISTORY_LEN as u32 + 5 {
+        let share = extended_share(channel_id, sequence_number, job_id, vec![0; 8]);
+        factory.on_submit_shares_extended(share).unwrap();
+    }
+    assert_eq!(
+        factory.found_block_coinbases().len(),
+        FOUND_BLOCKS_HISTORY_LEN
+    );
+}
+
+#[test]
+fn test_close_channel_frees_id() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let other_channel = open_extended_channel(&mut factory, 2);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    factory.negotiated_jobs.insert(
+        channel_id,
+        (
+            job_id + 1,
+            custom_mining_job(channel_id, &coinbase_outputs(1)),
+        ),
+    );
+
+    assert!(factory.close_channel(channel_id));
+    assert!(!factory.close_channel(channel_id));
+    assert!(!factory.inner.extended_channels.contains_key(&channel_id));
+    assert!(!factory.inner.channel_to_group_id.contains_key(&channel_id));
+    assert!(!factory.negotiated_jobs.contains_key(&channel_id));
+    let share = extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    let result = factory.on_submit_shares_extended(share).unwrap();
+    assert_eq!(
+        share_error_code(&result).as_deref(),
+        Some(SubmitSharesError::invalid_channel_error_code())
+    );
+
+    // The freed id is not given to the next channels while fresh ids are available, so a late
+    // share of the closed channel is not credited to them
+    assert_eq!(open_extended_channel(&mut factory, 3), other_channel + 1);
+    assert_eq!(open_extended_channel(&mut factory, 4), other_channel + 2);
+    let share = extended_share(channel_id, 1, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    let result = factory.on_submit_shares_extended(share).unwrap();
+    assert_eq!(
+        share_error_code(&result).as_deref(),
+        Some(SubmitSharesError::invalid_channel_error_code())
+    );
+}
+
+#[test]
+fn test_concurrent_channel_ids_are_unique() {
+    const THREADS: u32 = 8;
+    const CHANNELS_PER_THREAD: u32 = 50;
+    let ids = Arc::new(Mutex::new(GroupId::new()));
+    let handles: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let ids = ids.clone();
+            std::thread::spawn(move || {
+                let extranonces =
+                    ExtendedExtranonce::new(0..0, 0..8, 8..EXTRANONCE_LEN, None).unwrap();
+                let mut factory = PoolChannelFactory::new(
+                    ids,
+                    extranonces,
+                    JobsCreators::new(EXTRANONCE_LEN as u8),
+                    SHARES_PER_MINUTE,
+                    ExtendedChannelKind::Pool,
+                    pool_coinbase_outputs(),
+                );
+                (0..CHANNELS_PER_THREAD)
+                    .map(|request_id| open_extended_channel(&mut factory, request_id))
+                    .collect::<Vec<u32>>()
+            })
+        })
+        .collect();
+    let channel_ids: Vec<u32> = handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect();
+    let unique: HashSet<u32> = channel_ids.iter().copied().collect();
+    assert_eq!(channel_ids.len(), (THREADS * CHANNELS_PER_THREAD) as usize);
+    assert_eq!(unique.len(), channel_ids.len());
+    assert_eq!(unique, (1..=THREADS * CHANNELS_PER_THREAD).collect());
+}
+
+#[test]
+fn test_duplicate_shares_are_rejected() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    let share = extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+
+    assert!(matches!(
+        factory.on_submit_shares_extended(share.clone()).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+    // Same share resubmitted with a new sequence number
+    let mut duplicate = share.clone();
+    duplicate.sequence_number = 1;
+    let result = factory.on_submit_shares_extended(duplicate).unwrap();
+    assert_eq!(
+        share_error_code(&result).as_deref(),
+        Some(DUPLICATE_SHARE_ERROR_CODE)
+    );
+    let accounting = factory.share_accounting(channel_id).unwrap();
+    assert_eq!(accounting.shares_accepted(), 1);
+    assert_eq!(accounting.shares_rejected(), 1);
+
+    // Same share except for the nonce
+    let mut other_nonce = share.clone();
+    other_nonce.sequence_number = 2;
+    other_nonce.nonce += 1;
+    assert!(matches!(
+        factory.on_submit_shares_extended(other_nonce).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+
+    // Invalid shares are not remembered, resubmitted they get their own error again
+    let remembered = factory.inner.recent_shares[&channel_id].fingerprints.len();
+    let mut invalid_version = share.clone();
+    invalid_version.nonce += 2;
+    invalid_version.version ^= 1;
+    let mut invalid_ntime = share.clone();
+    invalid_ntime.nonce += 3;
+    invalid_ntime.ntime = 0;
+    for (invalid, error_code) in [
+        (invalid_version, INVALID_VERSION_ERROR_CODE),
+        (invalid_ntime, NTIME_BELOW_MIN_NTIME_ERROR_CODE),
+    ] {
+        for _ in 0..2 {
+            let result = factory.on_submit_shares_extended(invalid.clone()).unwrap();
+            assert_eq!(share_error_code(&result).as_deref(), Some(error_code));
+        }
+    }
+    assert_eq!(
+        factory.inner.recent_shares[&channel_id].fingerprints.len(),
+        remembered
+    );
+
+    // The job is a previous tip after a new prev hash, its shares are still duplicates
+    let previous_job_id = job_id;
+    let job_id = activate_job(&mut factory, 2, MIN_TARGET);
+    let mut duplicate = share.clone();
+    duplicate.sequence_number = 3;
+    let result = factory.on_submit_shares_extended(duplicate).unwrap();
+    assert_eq!(
+        share_error_code(&result).as_deref(),
+        Some(DUPLICATE_SHARE_ERROR_CODE)
+    );
+    let share = extended_share(channel_id, 4, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+
+    // The shares of a job are forgotten once the job can not be referenced anymore
+    for template_id in 3..STALE_JOB_IDS_HISTORY_LEN as u64 + 4 {
+        activate_job(&mut factory, template_id, MIN_TARGET);
+    }
+    assert!(factory.inner.recent_shares[&channel_id]
+        .fingerprints
+        .iter()
+        .all(|fingerprint| fingerprint.job_id != previous_job_id));
+}
+
+#[test]
+fn test_update_target_for_channel_with_job() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    let kinds = |messages: &[Mining]| {
+        messages
+            .iter()
+            .map(|message| match message {
+                Mining::SetTarget(_) => "target",
+                Mining::NewExtendedMiningJob(_) => "job",
+                _ => "other",
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Slightly harder than the max target
+    let mut small_change = MAX_TARGET;
+    small_change[31] = 0xfe;
+    let messages = factory
+        .update_target_for_channel_with_job(channel_id, small_change.into(), 2.0)
+        .unwrap();
+    assert_eq!(kinds(&messages), ["target"]);
+    match &messages[0] {
+        Mining::SetTarget(set_target) => {
+            assert_eq!(set_target.channel_id, channel_id);
+            assert_eq!(set_target.maximum_target.inner_as_ref(), &small_change[..]);
+        }
+        _ => panic!("expected SetTarget"),
+    }
+
+    // 2^16 times harder
+    let mut large_change = MAX_TARGET;
+    large_change[30] = 0;
+    large_change[31] = 0;
+    let messages = factory
+        .update_target_for_channel_with_job(channel_id, large_change.into(), 2.0)
+        .unwrap();
+    assert_eq!(kinds(&messages), ["target", "job"]);
+    match &messages[1] {
+        Mining::NewExtendedMiningJob(job) => {
+            assert_eq!(job.channel_id, channel_id);
+            assert_eq!(job.job_id, job_id);
+        }
+        _ => panic!("expected NewExtendedMiningJob"),
+    }
+    assert_eq!(
+        factory.inner.extended_channels[&channel_id]
+            .target
+            .inner_as_ref(),
+        &large_change[..]
+    );
+
+    assert!(factory
+        .update_target_for_channel_with_job(channel_id + 1, large_change.into(), 2.0)
+        .is_none());
+}
+
+#[test]
+fn test_update_target_for_channel_with_message() {
+    let mut new_target = MAX_TARGET;
+    new_target[31] = 0x0f;
+    let assert_set_target = |message: Option<Mining>, channel_id: u32| match message {
+        Some(Mining::SetTarget(set_target)) => {
+            assert_eq!(set_target.channel_id, channel_id);
+            assert_eq!(set_target.maximum_target.inner_as_ref(), &new_target[..]);
+        }
+        m => panic!("Expected SetTarget, got {m:?}"),
+    };
+
+    let mut factory = new_pool_factory();
+    open_extended_channel(&mut factory, 1);
+    let channel_id = open_extended_channel(&mut factory, 2);
+    let message = factory.update_target_for_channel_with_message(channel_id, new_target.into());
+    assert_set_target(message, channel_id);
+    assert_eq!(
+        factory.inner.extended_channels[&channel_id]
+            .target
+            .inner_as_ref(),
+        &new_target[..]
+    );
+    assert!(factory
+        .update_target_for_channel_with_message(channel_id + 1, new_target.into())
+        .is_none());
+
+    let mut proxy = new_proxy_factory(MAX_TARGET);
+    let channel_id = proxy_open_extended_channel(&mut proxy, 1);
+    let message = proxy.update_target_for_channel_with_message(channel_id, new_target.into());
+    assert_set_target(message, channel_id);
+    assert_eq!(
+        proxy.inner.extended_channels[&channel_id]
+            .target
+            .inner_as_ref(),
+        &new_target[..]
+    );
+}
+
+#[test]
+fn test_shares_for_superseded_and_unknown_jobs() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let standard_channel_id = factory.new_standard_id_for_hom().unwrap();
+    factory
+        .add_standard_channel(standard_channel_id, 0, MAX_TARGET.into())
+        .unwrap();
+    let previous_job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    // The superseded job is older than the previous tips whose shares are still accepted
+    for template_id in 2..PREVIOUS_TIPS_HISTORY_LEN as u64 + 2 {
+        activate_job(&mut factory, template_id, MIN_TARGET);
+    }
+    let job_id = activate_job(
+        &mut factory,
+        PREVIOUS_TIPS_HISTORY_LEN as u64 + 2,
+        MIN_TARGET,
+    );
+    assert_ne!(previous_job_id, job_id);
+    let unknown_job_id = job_id + 100;
+    let share = |sequence_number, job_id| {
+        extended_share(
+            channel_id,
+            sequence_number,
+            job_id,
+            vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+        )
+    };
+
+    assert!(matches!(
+        factory.on_submit_shares_extended(share(0, job_id)).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+    assert!(is_stale_share_error(
+        &factory
+            .on_submit_shares_extended(share(1, previous_job_id))
+            .unwrap()
+    ));
+    assert!(is_invalid_job_id_error(
+        &factory
+            .on_submit_shares_extended(share(2, unknown_job_id))
+            .unwrap()
+    ));
+
+    assert!(matches!(
+        factory
+            .on_submit_shares_standard(standard_share(standard_channel_id, 0, job_id))
+            .unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+    assert!(is_stale_share_error(
+        &factory
+            .on_submit_shares_standard(standard_share(standard_channel_id, 1, previous_job_id))
+            .unwrap()
+    ));
+    assert!(is_invalid_job_id_error(
+        &factory
+            .on_submit_shares_standard(standard_share(standard_channel_id, 2, unknown_job_id))
+            .unwrap()
+    ));
+
+    let accounting = factory.share_accounting(channel_id).unwrap();
+    assert_eq!(accounting.shares_accepted(), 1);
+    assert_eq!(accounting.shares_rejected(), 2);
+}
+
+#[test]
+fn test_vardiff() {
+    let clock = MockClock::new();
+    let mut factory = new_pool_factory();
+    factory.set_clock(Box::new(clock.clone()));
+    let messages = factory
+        .new_extended_channel(
+            1,
+            1_000_000_000.0,
+            DOWNSTREAM_EXTRANONCE_LEN as u16,
+            ConnectionFlags::all(),
+        )
+        .unwrap();
+    let channel_id = match &messages[0] {
+        Mining::OpenExtendedMiningChannelSuccess(success) => success.channel_id,
+        m => panic!("Expected OpenExtendedMiningChannelSuccess, got {m}"),
+    };
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    // Every share meets the downstream target whatever the channel target is
+    factory.set_test_targets(
+        Target::from(MAX_TARGET),
+        Target::from(MIN_TARGET),
+        Target::from(MIN_TARGET),
+    );
+    let difficulty = |factory: &PoolChannelFactory| {
+        crate::utils::target_to_difficulty(
+            &factory.inner.extended_channels[&channel_id]
+                .target
+                .clone()
+                .into(),
+        )
+    };
+    let mut sequence_number = 0;
+    let mut submit = |factory: &mut PoolChannelFactory, shares: u32| {
+        for _ in 0..shares {
+            let share = extended_share(
+                channel_id,
+                sequence_number,
+                job_id,
+                vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+            );
+            factory.on_submit_shares_extended(share).unwrap();
+            sequence_number += 1;
+        }
+    };
+
+    // Ten times the expected share rate, the adjustment waits for the min interval
+    let initial_difficulty = difficulty(&factory);
+    submit(&mut factory, 10);
+    clock.advance(Duration::from_secs(30));
+    assert!(factory.try_vardiff(channel_id).is_none());
+    clock.advance(Duration::from_secs(30));
+    let target = factory.try_vardiff(channel_id).unwrap();
+    assert_eq!(
+        factory.inner.extended_channels[&channel_id]
+            .target
+            .inner_as_ref(),
+        U256::from(target).inner_as_ref()
+    );
+    // The step is clamped and smoothed: 10x the rate only doubles the difficulty
+    let raised_difficulty = difficulty(&factory);
+    assert!((raised_difficulty / initial_difficulty - 2.0).abs() < 1e-3);
+
+    // No shares at all, the difficulty goes down
+    clock.advance(Duration::from_secs(60));
+    assert!(factory.try_vardiff(channel_id).is_some());
+    let lowered_difficulty = difficulty(&factory);
+    assert!((raised_difficulty / lowered_difficulty - 2.0).abs() < 1e-3);
+
+    // Shares at the expected rate keep the target
+    submit(&mut factory, 1);
+    clock.advance(Duration::from_secs(60));
+    assert!(factory.try_vardiff(channel_id).is_none());
+    assert_eq!(difficulty(&factory), lowered_difficulty);
+
+    assert!(factory.try_vardiff(channel_id + 1).is_none());
+}
+
+#[test]
+fn test_replicate_channel_extranonce_must_fit_factory_ranges() {
+    let mut factory = new_pool_factory();
+    let replicate = |factory: &mut PoolChannelFactory, prefix_len: usize, size: u16| {
+        let extranonce_prefix: mining_sv2::Extranonce = vec![9_u8; prefix_len].try_into().unwrap();
+        factory.replicate_upstream_extended_channel_only_jd(
+            U256::from(MAX_TARGET),
+            extranonce_prefix,
+            100,
+            size,
+        )
+    };
+
+    assert!(replicate(&mut factory, 8, 16).is_none());
+    assert!(replicate(&mut factory, 8, 4).is_none());
+    assert!(factory.channel_extranonce2_size(100).is_none());
+    assert!(factory.share_accounting(100).is_none());
+
+    assert!(replicate(&mut factory, 8, 8).is_some());
+    assert_eq!(factory.channel_extranonce2_size(100), Some(8));
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    let share = extended_share(100, 0, job_id, vec![0; 8]);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+
+    // When range0 is not empty the prefix must contain it
+    let extranonces = ExtendedExtranonce::new(0..4, 4..8, 8..EXTRANONCE_LEN, None).unwrap();
+    let mut factory = PoolChannelFactory::new(
+        Arc::new(Mutex::new(GroupId::new())),
+        extranonces,
+        JobsCreators::new(EXTRANONCE_LEN as u8),
+        SHARES_PER_MINUTE,
+        ExtendedChannelKind::Pool,
+        pool_coinbase_outputs(),
+    );
+    assert!(replicate(&mut factory, 2, 14).is_none());
+    assert!(replicate(&mut factory, 4, 12).is_some());
+}
+
+#[test]
+fn test_channel_overview() {
+    let clock = MockClock::new();
+    let mut factory = new_pool_factory();
+    factory.set_clock(Box::new(clock.clone()));
+    assert!(factory.channel_overview().is_empty());
+    let extended_channel = open_extended_channel(&mut factory, 1);
+    let idle_channel = open_extended_channel(&mut factory, 2);
+    let standard_channel = factory.new_standard_id_for_hom().unwrap();
+    let standard_target = Target::from([0xff; 32]);
+    factory
+        .add_standard_channel(standard_channel, 7, standard_target.clone())
+        .unwrap();
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+
+    for sequence_number in 0..2 {
+        let share = extended_share(
+            extended_channel,
+            sequence_number,
+            job_id,
+            vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+        );
+        factory.on_submit_shares_extended(share).unwrap();
+    }
+    let share = extended_share(
+        extended_channel,
+        1,
+        job_id,
+        vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+    );
+    factory.on_submit_shares_extended(share).unwrap();
+    clock.advance(Duration::from_secs(5));
+
+    let overview = factory.channel_overview();
+    let channel_ids: Vec<u32> = overview.iter().map(|channel| channel.channel_id).collect();
+    let mut expected_ids = vec![extended_channel, idle_channel, standard_channel];
+    expected_ids.sort_unstable();
+    assert_eq!(channel_ids, expected_ids);
+
+    let extended = overview
+        .iter()
+        .find(|channel| channel.channel_id == extended_channel)
+        .unwrap();
+    let extended_target: Target = factory.inner.extended_channels[&extended_channel]
+        .target
+        .clone()
+        .into();
+    assert_eq!(extended.group_id, 0);
+    assert_eq!(
+        extended.difficulty,
+        crate::utils::target_to_difficulty(&extended_target)
+    );
+    assert_eq!(extended.target, extended_target);
+    assert_eq!(extended.shares_accepted, 2);
+    assert_eq!(extended.shares_rejected, 1);
+    assert_eq!(extended.last_share_age, Some(Duration::from_secs(5)));
+
+    let idle = overview
+        .iter()
+        .find(|channel| channel.channel_id == idle_channel)
+        .unwrap();
+    assert_eq!(idle.shares_accepted, 0);
+    assert_eq!(idle.last_share_age, None);
+
+    let standard = overview
+        .iter()
+        .find(|channel| channel.channel_id == standard_channel)
+        .unwrap();
+    assert_eq!(standard.group_id, 7);
+    assert_eq!(standard.target, standard_target);
+}
+
+#[test]
+fn test_channel_stats() {
+    let clock = MockClock::new();
+    let mut factory = new_pool_factory();
+    factory.set_clock(Box::new(clock.clone()));
+    let channel_id = open_extended_channel(&mut factory, 1);
+    assert_eq!(
+        factory.get_channel_stats(channel_id),
+        Some(ChannelStats::default())
+    );
+    assert_eq!(factory.get_channel_stats(channel_id + 1), None);
+    let previous_job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    let job_id = activate_job(&mut factory, 2, MIN_TARGET);
+    let share = |sequence_number, job_id| {
+        extended_share(
+            channel_id,
+            sequence_number,
+            job_id,
+            vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+        )
+    };
+
+    // Valid
+    factory.on_submit_shares_extended(share(0, job_id)).unwrap();
+    factory.on_submit_shares_extended(share(1, job_id)).unwrap();
+    // Duplicate
+    let mut duplicate = share(1, job_id);
+    duplicate.sequence_number = 2;
+    factory.on_submit_shares_extended(duplicate).unwrap();
+    // Stale
+    factory
+        .on_submit_shares_extended(share(3, previous_job_id))
+        .unwrap();
+    let best_diff = factory.get_channel_stats(channel_id).unwrap().best_diff;
+    // Below target
+    clock.advance(Duration::from_secs(30));
+    factory.update_target_for_channel(channel_id, Target::new(0, 0));
+    factory.on_submit_shares_extended(share(4, job_id)).unwrap();
+
+    let stats = factory.get_channel_stats(channel_id).unwrap();
+    assert_eq!(stats.accepted, 2);
+    assert_eq!(stats.rejected, 2);
+    assert_eq!(stats.stale, 1);
+    assert!(stats.best_diff > 0.0);
+    assert!(stats.best_diff >= best_diff);
+    assert_eq!(stats.last_share_time as u64, MOCK_UNIX_TIME + 30);
+    // The counters are the ones of the channel share accounting
+    let accounting = factory.share_accounting(channel_id).unwrap();
+    assert_eq!(stats.accepted, accounting.shares_accepted());
+    assert_eq!(stats.rejected + stats.stale, accounting.shares_rejected());
+
+    factory.close_channel(channel_id);
+    assert_eq!(factory.get_channel_stats(channel_id), None);
+}
+
+#[test]
+fn test_check_set_custom_mining_job() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let pool_output = TxOut {
+        value: Amount::from_sat(1_000),
+        script_pubkey: ScriptBuf::from(vec![0x52]),
+    };
+    factory.update_pool_outputs(vec![pool_output.clone()]);
+    let other_output = TxOut {
+        value: Amount::from_sat(1_000),
+        script_pubkey: ScriptBuf::from(vec![0x53]),
+    };
+    let error_code = |result: Result<OnNewSetCustomMiningJob, Error>| match result.unwrap() {
+        OnNewSetCustomMiningJob::SendErrorDownstream(error) => {
+            assert_eq!(error.channel_id, channel_id);
+            assert_eq!(error.request_id, 1);
+            error.error_code.as_utf8_or_hex()
+        }
+        OnNewSetCustomMiningJob::Success(_) => panic!("Expected SendErrorDownstream"),
+    };
+    let prev_hash_error = SetCustomMiningJobError::invalid_prev_hash_error_code();
+    let outputs_error = SetCustomMiningJobError::invalid_coinbase_tx_outputs_error_code();
+
+    // No prev hash yet
+    let job = custom_mining_job(channel_id, &[pool_output.clone()]);
+    assert_eq!(
+        error_code(factory.on_new_set_custom_mining_job(job)),
+        prev_hash_error
+    );
+
+    activate_job(&mut factory, 1, MIN_TARGET);
+    let job = custom_mining_job(channel_id, &[other_output.clone(), pool_output.clone()]);
+    assert!(is_custom_job_success(
+        factory.on_new_set_custom_mining_job(job)
+    ));
+
+    // Built on an other prev hash
+    let mut job = custom_mining_job(channel_id, &[pool_output.clone()]);
+    job.prev_hash = U256::from([2; 32]);
+    assert_eq!(
+        error_code(factory.on_new_set_custom_mining_job(job)),
+        prev_hash_error
+    );
+
+    // The pool output is missing or pays less
+    let job = custom_mining_job(channel_id, &[other_output]);
+    assert_eq!(
+        error_code(factory.on_new_set_custom_mining_job(job)),
+        outputs_error
+    );
+    let mut underpaid_output = pool_output.clone();
+    underpaid_output.value = Amount::from_sat(999);
+    let job = custom_mining_job(channel_id, &[underpaid_output]);
+    assert_eq!(
+        error_code(factory.on_new_set_custom_mining_job(job)),
+        outputs_error
+    );
+
+    // More than the subsidy plus the max fees, the coinbase prefix encodes height 2_532_172
+    let subsidy = block_subsidy(2_532_172);
+    factory.set_max_template_fees(Some(1_000));
+    let mut overpaid_output = pool_output.clone();
+    overpaid_output.value = Amount::from_sat(subsidy + 1_000);
+    let job = custom_mining_job(channel_id, &[overpaid_output.clone()]);
+    assert!(is_custom_job_success(
+        factory.on_new_set_custom_mining_job(job)
+    ));
+    overpaid_output.value = Amount::from_sat(subsidy + 1_001);
+    let job = custom_mining_job(channel_id, &[overpaid_output]);
+    assert_eq!(
+        error_code(factory.on_new_set_custom_mining_job(job)),
+        outputs_error
+    );
+}
+
+#[test]
+fn test_missing_future_template_on_new_prev_hash() {
+    // No template has been received, the prev hash activates no job
+    let mut proxy = new_proxy_jd_factory();
+    assert!(proxy
+        .on_new_prev_hash_from_tp(&new_prev_hash(1, MAX_TARGET))
+        .unwrap()
+        .is_none());
+    assert!(proxy.last_prev_hash().is_some());
+
+    let mut proxy = new_proxy_jd_factory();
+    proxy.set_strict_future_templates(true);
+    assert!(matches!(
+        proxy.on_new_prev_hash_from_tp(&new_prev_hash(1, MAX_TARGET)),
+        Err(Error::FutureTemplateMissing(None))
+    ));
+    assert!(proxy.last_prev_hash().is_some());
+
+    // With the future template stored the custom job is declared
+    proxy.on_new_template(&mut new_template(2, true)).unwrap();
+    let (custom_job, _) = proxy
+        .on_new_prev_hash_from_tp(&new_prev_hash(2, MAX_TARGET))
+        .unwrap()
+        .unwrap();
+    assert_eq!(custom_job.coinbase_tx_value_remaining, BLOCK_REWARD);
+}
+
+fn template_with_witness_commitment(
+    template_id: u64,
+    commitment: [u8; 32],
+) -> NewTemplate<'static> {
+    let output = TxOut {
+        value: Amount::from_sat(0),
+        script_pubkey: ScriptBuf::from([&WITNESS_COMMITMENT_HEADER[..], &commitment[..]].concat()),
+    };
+    let mut template = new_template(template_id, true);
+    template.coinbase_tx_outputs_count = 1;
+    template.coinbase_tx_outputs = bitcoin::consensus::serialize(&output).try_into().unwrap();
+    template
+}
+
+#[test]
+fn test_witness_commitment_on_new_template() {
+    let wtxids = [[7; 32], [8; 32]];
+    let mut factory = new_pool_factory();
+
+    let mut template = template_with_witness_commitment(1, witness_commitment(&wtxids));
+    assert!(factory
+        .on_new_template_with_wtxids(&mut template, &wtxids)
+        .is_ok());
+
+    let mut template = template_with_witness_commitment(2, witness_commitment(&wtxids));
+    assert!(matches!(
+        factory.on_new_template_with_wtxids(&mut template, &wtxids[..1]),
+        Err(Error::InvalidWitnessCommitment(2))
+    ));
+
+    // Without a commitment output there is no segwit data to check
+    assert!(factory
+        .on_new_template_with_wtxids(&mut new_template(3, true), &wtxids)
+        .is_ok());
+}
+
+// Target of difficulty `256^shift`, the genesis target is the target of difficulty 1
+fn target_with_difficulty(shift: usize) -> Target {
+    let mut target = [0; 32];
+    target[26 - shift] = 0xff;
+    target[27 - shift] = 0xff;
+    Target::from(target)
+}
+
+#[test]
+fn test_channels_by_difficulty_band() {
+    let mut factory = new_pool_factory();
+    // Opened with hashrate 0, so with the max target and a difficulty below 1
+    let extended_channel = open_extended_channel(&mut factory, 1);
+    let mut standard_channels = Vec::new();
+    for shift in 0..3 {
+        let channel_id = factory.new_standard_id_for_hom().unwrap();
+        factory
+            .add_standard_channel(channel_id, 7, target_with_difficulty(shift))
+            .unwrap();
+        standard_channels.push(channel_id);
+    }
+
+    assert_eq!(
+        factory.channels_by_difficulty_band(&[10.0, 1_000.0]),
+        vec![
+            vec![extended_channel, standard_channels[0]],
+            vec![standard_channels[1]],
+            vec![standard_channels[2]],
+        ]
+    );
+    // A channel at a band difficulty is in the band above it
+    assert_eq!(
+        factory.channels_by_difficulty_band(&[256.0]),
+        vec![
+            vec![extended_channel, standard_channels[0]],
+            vec![standard_channels[1], standard_channels[2]],
+        ]
+    );
+    let all_channels = vec![
+        extended_channel,
+        standard_channels[0],
+        standard_channels[1],
+        standard_channels[2],
+    ];
+    assert_eq!(factory.channels_by_difficulty_band(&[]), vec![all_channels]);
+}
+
+#[test]
+fn test_into_extended_on_share_meet_downstream_target() {
+    let mut on_new_share = OnNewShare::ShareMeetDownstreamTarget;
+    on_new_share.into_extended(vec![0; EXTRANONCE_LEN], 1);
+    assert!(matches!(
+        on_new_share,
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+}
+
+// Opens a standard channel with 0 hashrate, returns the channel id, its extranonce and the
+// messages that follow the success
+fn open_standard_channel(
+    factory: &mut PoolChannelFactory,
+    request_id: u32,
+    group_id: u32,
+) -> (u32, Vec<u8>, Vec<Mining<'static>>) {
+    let mut messages = factory
+        .new_standard_channel(request_id, 0.0, group_id)
+        .unwrap();
+    match messages.remove(0) {
+        Mining::OpenStandardMiningChannelSuccess(success) => {
+            assert_eq!(success.request_id.as_u32(), request_id);
+            assert_eq!(success.group_channel_id, group_id);
+            assert_eq!(success.target.to_vec(), MAX_TARGET.to_vec());
+            let extranonce = success.extranonce_prefix.to_vec();
+            (success.channel_id, extranonce, messages)
+        }
+        m => panic!("Expected OpenStandardMiningChannelSuccess, got {m}"),
+    }
+}
+
+#[test]
+fn test_new_standard_channel() {
+    let mut factory = new_pool_factory();
+    let group_id = factory.new_group_id().unwrap();
+    let (channel_id, extranonce, messages) = open_standard_channel(&mut factory, 1, group_id);
+    assert!(messages.is_empty());
+    assert_eq!(extranonce.len(), EXTRANONCE_LEN);
+    assert_eq!(factory.inner.channel_to_group_id[&channel_id], group_id);
+
+    // A non future job is sent to the channel as a standard job with the channel merkle root
+    activate_job(&mut factory, 1, MIN_TARGET);
+    let jobs = factory
+        .on_new_template(&mut new_template(2, false))
+        .unwrap();
+    let job = factory.inner.last_valid_job.clone().unwrap().0;
+    let expected_job =
+        crate::channel_logic::extended_to_standard_job(&job, &extranonce, channel_id, None)
+            .unwrap();
+    match &jobs[&channel_id] {
+        Mining::NewMiningJob(standard_job) => {
+            assert!(!standard_job.is_future());
+            assert_eq!(standard_job.channel_id, channel_id);
+            assert_eq!(standard_job.job_id, job.job_id);
+            assert_eq!(
+                standard_job.merkle_root.to_vec(),
+                expected_job.merkle_root.to_vec()
+            );
+        }
+        m => panic!("Expected NewMiningJob, got {m}"),
+    }
+    let share = standard_share(channel_id, 0, job.job_id);
+    assert!(matches!(
+        factory.on_submit_shares_standard(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+
+    // A channel opened later gets the valid job and the prev hash, as for extended channels
+    factory.on_new_template(&mut new_template(3, true)).unwrap();
+    let (other_channel_id, _, messages) = open_standard_channel(&mut factory, 2, group_id);
+    assert_ne!(other_channel_id, channel_id);
+    assert_eq!(
+        message_kinds(&messages),
+        vec!["future standard job", "prev hash", "future standard job"]
+    );
+    match &messages[1] {
+        Mining::SetNewPrevHash(prev_hash) => {
+            assert_eq!(prev_hash.channel_id, other_channel_id);
+            assert_eq!(prev_hash.job_id, job.job_id);
+        }
+        m => panic!("Expected SetNewPrevHash, got {m}"),
+    }
+}
+
+#[test]
+fn test_new_standard_channel_failures() {
+    // No range_1 value is available for the standard extranonces, the failed channel takes no
+    // channel id
+    let mut factory = new_pool_factory_with_ranges(0..0, 0..0);
+    let group_id = factory.new_group_id().unwrap();
+    let new_channel_id = |factory: &PoolChannelFactory| {
+        factory
+            .inner
+            .ids
+            .safe_lock(|ids| ids.new_channel_id(group_id))
+            .unwrap()
+    };
+    let channel_id = new_channel_id(&factory);
+    assert!(matches!(
+        factory.new_standard_channel(1, 0.0, group_id),
+        Err(Error::ExtranoncePrefixFactoryError(
+            mining_sv2::ExtendedExtranonceError::MaxValueReached
+        ))
+    ));
+    assert_eq!(new_channel_id(&factory), channel_id + 1);
+    assert!(factory.inner.standard_channels.is_empty());
+
+    // A standard channel whose job can not be built does not stop the job of the other channels
+    let mut factory = new_pool_factory();
+    let group_id = factory.new_group_id().unwrap();
+    let (channel_id, _, _) = open_standard_channel(&mut factory, 1, group_id);
+    let (broken_channel_id, _, _) = open_standard_channel(&mut factory, 2, group_id);
+    factory
+        .inner
+        .standard_channels
+        .get_mut(&broken_channel_id)
+        .unwrap()
+        .extranonce = vec![];
+    activate_job(&mut factory, 1, MIN_TARGET);
+    let jobs = factory
+        .on_new_template(&mut new_template(2, false))
+        .unwrap();
+    assert!(matches!(jobs[&channel_id], Mining::NewMiningJob(_)));
+    assert!(!jobs.contains_key(&broken_channel_id));
+}
+
+#[test]
+fn test_standard_extranonces_do_not_overlap_extended_prefixes() {
+    let mut factory = new_pool_factory();
+    let group_id = factory.new_group_id().unwrap();
+    let extended_prefix = |factory: &PoolChannelFactory, channel_id| {
+        factory.inner.extended_channels[&channel_id]
+            .extranonce_prefix
+            .to_vec()
+    };
+    let first_extended = open_extended_channel(&mut factory, 1);
+    let (_, first_standard, _) = open_standard_channel(&mut factory, 2, group_id);
+    let (_, second_standard, _) = open_standard_channel(&mut factory, 3, group_id);
+    let second_extended = open_extended_channel(&mut factory, 4);
+
+    // The standard extranonces share a range_1 value that no extended channel gets
+    for extended in [first_extended, second_extended] {
+        let prefix = extended_prefix(&factory, extended);
+        assert!(!first_standard.starts_with(&prefix));
+        assert!(!second_standard.starts_with(&prefix));
+    }
+    assert_ne!(first_standard, second_standard);
+    let prefix_len = factory.inner.extranonces.get_prefix_len();
+    assert_eq!(first_standard[..prefix_len], second_standard[..prefix_len]);
+    assert!(factory.audit_extranonce_disjointness().is_ok());
+}
+
+// Opens a channel with `new_extended_channel` and one with `new_extended_channel_structured` and
+// checks that they get the same jobs and prev hash
+fn assert_structured_channel_matches_messages(factory: &mut PoolChannelFactory) {
+    let messages = factory
+        .new_extended_channel(
+            1,
+            0.0,
+            DOWNSTREAM_EXTRANONCE_LEN as u16,
+            ConnectionFlags::all(),
+        )
+        .unwrap();
+    let channel = factory
+        .new_extended_channel_structured(
+            2,
+            0.0,
+            DOWNSTREAM_EXTRANONCE_LEN as u16,
+            ConnectionFlags::all(),
+        )
+        .unwrap()
+        .unwrap();
+    assert_eq!(channel.success.request_id, 2);
+    let structured_messages = channel.clone().into_messages();
+    assert_eq!(
+        message_kinds(&structured_messages),
+        message_kinds(&messages)
+    );
+
+    let job_ids = |messages: &[Mining]| -> Vec<u32> {
+        messages
+            .iter()
+            .filter_map(|message| match message {
+                Mining::NewExtendedMiningJob(job) => Some(job.job_id),
+                Mining::SetNewPrevHash(prev_hash) => Some(prev_hash.job_id),
+                _ => None,
+            })
+            .collect()
+    };
+    assert_eq!(job_ids(&structured_messages), job_ids(&messages));
+    assert_eq!(
+        channel.job.is_some(),
+        factory.inner.last_valid_job.is_some()
+    );
+    assert_eq!(
+        channel.prev_hash.is_some(),
+        factory.inner.last_prev_hash.is_some()
+    );
+    assert_eq!(channel.future_jobs.len(), factory.inner.future_jobs.len());
+    if let Some(prev_hash) = &channel.prev_hash {
+        assert_eq!(prev_hash.channel_id, channel.success.channel_id);
+    }
+}
+
+#[test]
+fn test_new_extended_channel_structured() {
+    let mut factory = new_pool_factory();
+    // No job and no prev hash
+    assert_structured_channel_matches_messages(&mut factory);
+
+    // Only future jobs
+    factory.on_new_template(&mut new_template(1, true)).unwrap();
+    assert_structured_channel_matches_messages(&mut factory);
+
+    // A valid job with its prev hash and a future job
+    factory
+        .on_new_prev_hash_from_tp(&new_prev_hash(1, MIN_TARGET))
+        .unwrap();
+    factory.on_new_template(&mut new_template(2, true)).unwrap();
+    assert_structured_channel_matches_messages(&mut factory);
+
+    // Refused channels return the OpenMiningChannelError of the message
+    let error = factory
+        .new_extended_channel_structured(3, 0.0, EXTRANONCE_LEN as u16, ConnectionFlags::all())
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(error.request_id, 3);
+    match &factory
+        .new_extended_channel(4, 0.0, EXTRANONCE_LEN as u16, ConnectionFlags::all())
+        .unwrap()[..]
+    {
+        [Mining::OpenMiningChannelError(message)] => {
+            assert_eq!(message.error_code.to_vec(), error.error_code.to_vec())
+        }
+        m => panic!("Expected OpenMiningChannelError, got {m:?}"),
+    }
+}
+
+#[test]
+fn test_shares_for_negotiated_and_pool_jobs() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    // Every share is a block, the template id tells which job the share has been checked against
+    let pool_job_id = activate_job(&mut factory, 1, MAX_TARGET);
+    let job = custom_mining_job(channel_id, &pool_coinbase_outputs());
+    let negotiated_job_id = set_custom_job(&mut factory, job);
+    assert_ne!(negotiated_job_id, pool_job_id);
+    let share = |sequence_number, job_id| {
+        extended_share(
+            channel_id,
+            sequence_number,
+            job_id,
+            vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+        )
+    };
+    let checked_template_id = |result: OnNewShare| match result {
+        OnNewShare::ShareMeetBitcoinTarget((_, template_id, _, _, _)) => template_id,
+        m => panic!("Expected ShareMeetBitcoinTarget, got {m:?}"),
+    };
+
+    let result = factory
+        .on_submit_shares_extended(share(0, pool_job_id))
+        .unwrap();
+    assert_eq!(checked_template_id(result), Some(1));
+    let result = factory
+        .on_submit_shares_extended(share(1, negotiated_job_id))
+        .unwrap();
+    assert_eq!(checked_template_id(result), None);
+
+    // A job id that matches neither job is rejected, even when job ids are not strict
+    factory.set_strict_job_id(false);
+    let unknown_job_id = pool_job_id.max(negotiated_job_id) + 1;
+    let result = factory
+        .on_submit_shares_extended(share(2, unknown_job_id))
+        .unwrap();
+    assert_eq!(
+        share_error_code(&result).as_deref(),
+        Some(SubmitSharesError::invalid_job_id_error_code())
+    );
+}
+
+// Opens `channels` standard channels, sends them a job with one transaction besides the
+// coinbase and returns the jobs that they get and the outcome of a valid and of an invalid share
+// for each of them
+fn standard_channels_jobs_and_outcomes(
+    lazy: bool,
+    channels: u32,
+) -> (
+    HashMap<u32, Mining<'static>, BuildNoHashHasher<u32>>,
+    Vec<String>,
+) {
+    let mut factory = new_pool_factory();
+    factory.set_lazy_merkle_roots(lazy);
+    let channel_ids: Vec<u32> = (0..channels)
+        .map(|_| {
+            let channel_id = factory.new_standard_id_for_hom().unwrap();
+            factory
+                .add_standard_channel(channel_id, 0, MAX_TARGET.into())
+                .unwrap();
+            channel_id
+        })
+        .collect();
+    let mut template = new_template(1, true);
+    template.merkle_path = vec![U256::from([7; 32])].into();
+    let jobs = factory.on_new_template(&mut template).unwrap();
+    factory
+        .on_new_prev_hash_from_tp(&new_prev_hash(1, MAX_TARGET))
+        .unwrap();
+    let job_id = active_job_id(&factory);
+    let mut outcomes = vec![];
+    for channel_id in channel_ids {
+        for share in [
+            standard_share(channel_id, 0, job_id),
+            standard_share(channel_id, 1, job_id + 1),
+        ] {
+            let outcome = factory.on_submit_shares_standard(share).unwrap();
+            outcomes.push(format!("{:?}", outcome));
+        }
+    }
+    (jobs, outcomes)
+}
+
+#[test]
+fn test_lazy_merkle_roots() {
+    let channels = 200;
+    let (eager_jobs, eager_outcomes) = standard_channels_jobs_and_outcomes(false, channels);
+    let (lazy_jobs, lazy_outcomes) = standard_channels_jobs_and_outcomes(true, channels);
+    assert_eq!(eager_jobs.len(), channels as usize);
+    assert_eq!(lazy_jobs.len(), channels as usize);
+
+    // Standard channels get the same job with their merkle root in both modes
+    for (channel_id, job) in &lazy_jobs {
+        let standard_job = match &eager_jobs[channel_id] {
+            Mining::NewMiningJob(job) => job,
+            m => panic!("Expected NewMiningJob, got {m}"),
+        };
+        match job {
+            Mining::NewMiningJob(job) => assert_eq!(job, standard_job),
+            m => panic!("Expected NewMiningJob, got {m}"),
+        }
+    }
+
+    // Validating the shares gives the same outcome, with the same merkle root, in both modes
+    assert_eq!(eager_outcomes.len(), 2 * channels as usize);
+    assert_eq!(eager_outcomes, lazy_outcomes);
+    for outcome in eager_outcomes.chunks(2) {
+        assert!(outcome[0].starts_with("ShareMeetBitcoinTarget"));
+        assert!(outcome[1].starts_with("SendErrorDownstream"));
+    }
+}
+
+// A serialized coinbase whose input script is 4 bytes followed by EXTRANONCE_LEN zeroed bytes,
+// the script starts at byte 42, or 44 with the segwit marker and flag
+fn coinbase_with_extranonce_space(with_witness: bool) -> Vec<u8> {
+    let mut script = vec![3, 1, 2, 3];
+    script.extend_from_slice(&[0; EXTRANONCE_LEN]);
+    let witness = match with_witness {
+        true => Witness::from_slice(&[[0; 32]]),
+        false => Witness::default(),
+    };
+    let coinbase = Transaction {
+        version: bitcoin::transaction::Version::TWO,
+        lock_time: bitcoin::absolute::LockTime::ZERO,
+        input: vec![bitcoin::TxIn {
+            previous_output: bitcoin::OutPoint::null(),
+            script_sig: ScriptBuf::from(script),
+            sequence: bitcoin::Sequence::MAX,
+            witness,
+        }],
+        output: vec![output(50, 0x51)],
+    };
+    consensus::serialize(&coinbase)
+}
+
+#[test]
+fn test_job_merkle_cache_matches_merkle_root_from_path() {
+    let legacy = coinbase_with_extranonce_space(false);
+    let segwit = coinbase_with_extranonce_space(true);
+    // (coinbase, extranonce start, extranonce len, extranonce inside the input script)
+    let splits = [
+        (legacy.clone(), 46, EXTRANONCE_LEN, true),
+        (segwit, 48, EXTRANONCE_LEN, true),
+        // The extranonce is in place of the value of the output
+        (legacy, 67, 8, false),
+    ];
+    let extranonces = |len: usize| vec![vec![0; len], vec![0xff; len], (1..=len as u8).collect()];
+    for (coinbase, start, len, inside_script) in splits {
+        let (prefix, suffix) = (&coinbase[..start], &coinbase[start + len..]);
+        for path_len in 0..4 {
+            let path: Vec<Vec<u8>> = (0..path_len).map(|i| vec![i as u8 + 1; 32]).collect();
+            let cache = JobMerkleCache::new(prefix, suffix, len, &path).unwrap();
+            assert_eq!(cache.txid_parts.is_some(), inside_script);
+            assert!(cache.matches(prefix, suffix, len, &path));
+            for extranonce in extranonces(len) {
+                let expected =
+                    crate::utils::merkle_root_from_path(prefix, suffix, &extranonce, &path)
+                        .unwrap();
+                assert_eq!(cache.merkle_root(&extranonce).unwrap().to_vec(), expected);
+            }
+        }
+    }
+
+    // The caches of the factory last until the next prev hash
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    let share = extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    factory.on_submit_shares_extended(share).unwrap();
+    assert_eq!(factory.inner.merkle_caches.len(), 1);
+    activate_job(&mut factory, 2, MIN_TARGET);
+    assert!(factory.inner.merkle_caches.is_empty());
+}
+
+#[test]
+fn test_invalid_share_coinbase_part() {
+    // The script of the coinbase input starts at byte 42 and is 20 bytes long, the extranonce
+    // starts at byte 46
+    let coinbase = coinbase_with_extranonce_space(false);
+    let path = vec![vec![1; 32]];
+    let error = |prefix: &[u8], suffix: &[u8], extranonce_len, path: &[Vec<u8>]| {
+        JobMerkleCache::new(prefix, suffix, extranonce_len, path).unwrap_err()
+    };
+    assert_eq!(
+        error(&coinbase[..30], &coinbase[62..], EXTRANONCE_LEN, &path),
+        CoinbasePart::Prefix
+    );
+    assert_eq!(
+        error(&coinbase[..46], &coinbase[62..], EXTRANONCE_LEN + 4, &path),
+        CoinbasePart::ExtranonceLen
+    );
+    assert_eq!(
+        error(
+            &coinbase[..46],
+            &coinbase[62..coinbase.len() - 1],
+            EXTRANONCE_LEN,
+            &path
+        ),
+        CoinbasePart::Suffix
+    );
+    assert_eq!(
+        error(
+            &coinbase[..46],
+            &coinbase[62..],
+            EXTRANONCE_LEN,
+            &[vec![1; 31]]
+        ),
+        CoinbasePart::MerklePath
+    );
+
+    // A share for a job with a truncated coinbase suffix reports the suffix
+    let mut proxy = new_proxy_factory(MAX_TARGET);
+    let channel_id = proxy_open_extended_channel(&mut proxy, 1);
+    let job_id = proxy_activate_job(&mut proxy, 0x0300_0001);
+    let (job, _) = proxy.inner.last_valid_job.as_mut().unwrap();
+    let suffix = job.coinbase_tx_suffix.to_vec();
+    job.coinbase_tx_suffix = suffix[..suffix.len() - 1].to_vec().try_into().unwrap();
+    let share = extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(matches!(
+        proxy.on_submit_shares_extended(share),
+        Err(Error::InvalidShareCoinbase(CoinbasePart::Suffix))
+    ));
+}
+
+// Benchmarks of the merkle roots of many shares of a job without and with the job cache, compare
+// their run times with
+// `cargo +nightly test --release bench_merkle_roots -- --ignored -Z unstable-options --report-time`
+const BENCH_SHARES: u32 = 100_000;
+
+// Coinbase prefix, coinbase suffix and merkle path of the benchmarked job
+fn bench_job() -> (Vec<u8>, Vec<u8>, Vec<Vec<u8>>) {
+    let coinbase = coinbase_with_extranonce_space(true);
+    let path = (0..12).map(|i| vec![i as u8; 32]).collect();
+    (
+        coinbase[..48].to_vec(),
+        coinbase[48 + EXTRANONCE_LEN..].to_vec(),
+        path,
+    )
+}
+
+fn bench_extranonce(i: u32) -> Vec<u8> {
+    [vec![0; EXTRANONCE_LEN - 4], i.to_le_bytes().to_vec()].concat()
+}
+
+#[test]
+#[ignore]
+fn bench_merkle_roots_without_cache() {
+    let (prefix, suffix, path) = bench_job();
+    for i in 0..BENCH_SHARES {
+        std::hint::black_box(
+            crate::utils::merkle_root_from_path(&prefix, &suffix, &bench_extranonce(i), &path)
+                .unwrap(),
+        );
+    }
+}
+
+#[test]
+#[ignore]
+fn bench_merkle_roots_with_cache() {
+    let (prefix, suffix, path) = bench_job();
+    let cache = JobMerkleCache::new(&prefix, &suffix, EXTRANONCE_LEN, &path).unwrap();
+    for i in 0..BENCH_SHARES {
+        std::hint::black_box(cache.merkle_root(&bench_extranonce(i)).unwrap());
+    }
+}
+
+#[test]
+fn test_standard_channels_get_standard_jobs() {
+    use bitcoin::hashes::Hash as _;
+    let mut factory = new_pool_factory();
+    let extended_channel = open_extended_channel(&mut factory, 1);
+    let channel_id = factory.new_standard_id_for_hom().unwrap();
+    let extranonce = factory
+        .add_standard_channel(channel_id, 0, MAX_TARGET.into())
+        .unwrap()
+        .to_vec();
+    // The standard extranonce is outside of the extranonce space of the extended channel
+    let extended_prefix = factory.inner.extended_channels[&extended_channel]
+        .extranonce_prefix
+        .to_vec();
+    assert!(!extranonce.starts_with(&extended_prefix));
+    // The template has one transaction besides the coinbase
+    let txid = [7; 32];
+    let mut template = new_template(1, true);
+    template.merkle_path = vec![U256::from(txid)].into();
+
+    let jobs = factory.on_new_template(&mut template).unwrap();
+    assert!(matches!(
+        jobs[&extended_channel],
+        Mining::NewExtendedMiningJob(_)
+    ));
+    let standard_job = match &jobs[&channel_id] {
+        Mining::NewMiningJob(job) => job.clone(),
+        m => panic!("Expected NewMiningJob, got {m}"),
+    };
+    let extended_job = factory.inner.future_jobs[0].0.clone();
+    assert!(standard_job.is_future());
+    assert_eq!(standard_job.channel_id, channel_id);
+    assert_eq!(standard_job.job_id, extended_job.job_id);
+    assert_eq!(standard_job.version, extended_job.version);
+
+    let coinbase: bitcoin::Transaction = bitcoin::consensus::deserialize(
+        &[
+            extended_job.coinbase_tx_prefix.to_vec(),
+            extranonce,
+            extended_job.coinbase_tx_suffix.to_vec(),
+        ]
+        .concat(),
+    )
+    .unwrap();
+    let merkle_root = bitcoin::merkle_tree::calculate_root(
+        std::iter::once(coinbase.compute_txid())
+            .chain(std::iter::once(bitcoin::Txid::from_byte_array(txid))),
+    )
+    .unwrap();
+    assert_eq!(
+        standard_job.merkle_root.to_vec(),
+        merkle_root.to_byte_array().to_vec()
+    );
+
+    // A block found by the channel has the merkle root of its standard job
+    factory
+        .on_new_prev_hash_from_tp(&new_prev_hash(1, MAX_TARGET))
+        .unwrap();
+    let job_id = active_job_id(&factory);
+    match factory
+        .on_submit_shares_standard(standard_share(channel_id, 0, job_id))
+        .unwrap()
+    {
+        OnNewShare::ShareMeetBitcoinTarget((_, _, _, _, found)) => assert_eq!(
+            bitcoin::consensus::serialize(&found.merkle_root),
+            standard_job.merkle_root.to_vec()
+        ),
+        m => panic!("Expected ShareMeetBitcoinTarget, got {m:?}"),
+    }
+}
+
+#[test]
+fn test_set_group_channel() {
+    let mut factory = new_pool_factory();
+    let extended_channel = open_extended_channel(&mut factory, 1);
+    let first_group = factory.new_group_id().unwrap();
+    let (first_channel, _, _) = open_standard_channel(&mut factory, 2, first_group);
+    let (second_channel, _, _) = open_standard_channel(&mut factory, 3, first_group);
+    let (other_channel, _, _) = open_standard_channel(&mut factory, 4, first_group);
+    let group_id = factory.new_group_id().unwrap();
+
+    // Nothing is moved if a channel is unknown or is not a standard channel
+    let unknown_channel = other_channel + 100;
+    match factory.set_group_channel(
+        group_id,
+        vec![first_channel, extended_channel, unknown_channel],
+    ) {
+        Err(Error::UnknownStandardChannels(channel_ids)) => {
+            assert_eq!(channel_ids, vec![extended_channel, unknown_channel])
+        }
+        result => panic!("Expected UnknownStandardChannels, got {result:?}"),
+    }
+    assert!(factory.group_channel_ids(group_id).is_empty());
+
+    factory
+        .set_group_channel(group_id, vec![first_channel, second_channel])
+        .unwrap();
+    assert_eq!(
+        factory.group_channel_ids(group_id),
+        vec![first_channel, second_channel]
+    );
+    assert_eq!(factory.group_channel_ids(first_group), vec![other_channel]);
+
+    // Every channel of the group gets the group jobs and can submit shares as part of the group
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    let jobs = factory.on_new_template(&mut new_template(2, true)).unwrap();
+    for (sequence_number, channel_id) in factory.group_channel_ids(group_id).into_iter().enumerate()
+    {
+        assert!(matches!(
+            &jobs[&channel_id],
+            Mining::NewMiningJob(job) if job.channel_id == channel_id
+        ));
+        let share = standard_share(channel_id, sequence_number as u32, job_id);
+        assert!(matches!(
+            factory.on_submit_shares_standard(share).unwrap(),
+            OnNewShare::ShareMeetDownstreamTarget
+        ));
+        assert_eq!(
+            factory.inner.standard_channels[&channel_id].group_id,
+            group_id
+        );
+    }
+}
+
+#[test]
+fn test_block_submission_debounce() {
+    let window = Duration::from_secs(10);
+    let clock = MockClock::new();
+    let mut factory = new_pool_factory();
+    factory.set_clock(Box::new(clock.clone()));
+    factory.set_block_submission_debounce(Some(window));
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MAX_TARGET);
+    let is_block = |result: &OnNewShare| matches!(result, OnNewShare::ShareMeetBitcoinTarget(_));
+
+    let share = extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(is_block(&factory.on_submit_shares_extended(share).unwrap()));
+
+    // Within the window the winning share is accepted without being flagged as a block
+    clock.advance(window / 2);
+    let share = extended_share(channel_id, 1, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    let result = factory.on_submit_shares_extended(share).unwrap();
+    assert!(matches!(
+        result,
+        OnNewShare::ShareMeetDownstreamTarget | OnNewShare::SendSubmitShareUpstream(_)
+    ));
+    let accounting = factory.share_accounting(channel_id).unwrap();
+    assert_eq!(accounting.shares_accepted(), 2);
+    assert_eq!(accounting.blocks_found(), 1);
+    assert_eq!(factory.found_block_coinbases().len(), 1);
+
+    // Outside the window the winning share is a block again
+    clock.advance(window);
+    let share = extended_share(channel_id, 2, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(is_block(&factory.on_submit_shares_extended(share).unwrap()));
+    assert_eq!(
+        factory.share_accounting(channel_id).unwrap().blocks_found(),
+        2
+    );
+
+    // Shares for other jobs are never debounced
+    let job_id = activate_job(&mut factory, 2, MAX_TARGET);
+    let share = extended_share(channel_id, 3, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(is_block(&factory.on_submit_shares_extended(share).unwrap()));
+
+    // Without a window every winning share is a block
+    factory.set_block_submission_debounce(None);
+    let share = extended_share(channel_id, 4, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(is_block(&factory.on_submit_shares_extended(share).unwrap()));
+}
+
+#[test]
+fn test_channel_implied_hashrate() {
+    let mut factory = new_pool_factory();
+    let hash_rate = 1.0e12;
+    let is_close = |implied: Option<f64>| ((implied.unwrap() - hash_rate) / hash_rate).abs() < 1e-6;
+    let messages = factory
+        .new_extended_channel(
+            1,
+            hash_rate as f32,
+            DOWNSTREAM_EXTRANONCE_LEN as u16,
+            ConnectionFlags::all(),
+        )
+        .unwrap();
+    let extended_channel_id = match &messages[0] {
+        Mining::OpenExtendedMiningChannelSuccess(success) => success.channel_id,
+        m => panic!("Expected OpenExtendedMiningChannelSuccess, got {m}"),
+    };
+    assert!(is_close(
+        factory.channel_implied_hashrate(extended_channel_id)
+    ));
+
+    let group_id = factory.new_group_id().unwrap();
+    let messages = factory
+        .new_standard_channel(2, hash_rate as f32, group_id)
+        .unwrap();
+    let standard_channel_id = match &messages[0] {
+        Mining::OpenStandardMiningChannelSuccess(success) => success.channel_id,
+        m => panic!("Expected OpenStandardMiningChannelSuccess, got {m}"),
+    };
+    assert!(is_close(
+        factory.channel_implied_hashrate(standard_channel_id)
+    ));
+
+    assert!(factory
+        .channel_implied_hashrate(standard_channel_id + 1)
+        .is_none());
+}
+
+#[test]
+fn test_shares_for_previous_tips() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let standard_channel_id = factory.new_standard_id_for_hom().unwrap();
+    factory
+        .add_standard_channel(standard_channel_id, 0, MAX_TARGET.into())
+        .unwrap();
+    // Prev hash A then prev hash B
+    let job_a = activate_job(&mut factory, 1, MIN_TARGET);
+    let mut prev_hash_b = new_prev_hash(2, MIN_TARGET);
+    prev_hash_b.prev_hash = U256::from([2; 32]);
+    factory.on_new_template(&mut new_template(2, true)).unwrap();
+    factory.on_new_prev_hash_from_tp(&prev_hash_b).unwrap();
+    let job_b = active_job_id(&factory);
+    assert_ne!(job_a, job_b);
+
+    // Shares built on A's job are checked against A and still accepted
+    let share = extended_share(channel_id, 0, job_a, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+    assert!(matches!(
+        factory
+            .on_submit_shares_standard(standard_share(standard_channel_id, 0, job_a))
+            .unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+    // The advertised job is still B's
+    assert_eq!(
+        factory.inner.last_valid_job.as_ref().unwrap().0.job_id,
+        job_b
+    );
+    let share = extended_share(channel_id, 1, job_b, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+
+    // A share meeting the bitcoin target of a previous tip is a block found on that tip
+    for template_id in 3..PREVIOUS_TIPS_HISTORY_LEN as u64 + 2 {
+        activate_job(&mut factory, template_id, MIN_TARGET);
+    }
+    let job_c = activate_job(
+        &mut factory,
+        PREVIOUS_TIPS_HISTORY_LEN as u64 + 2,
+        MAX_TARGET,
+    );
+    activate_job(
+        &mut factory,
+        PREVIOUS_TIPS_HISTORY_LEN as u64 + 3,
+        MIN_TARGET,
+    );
+    let share = extended_share(channel_id, 2, job_c, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    match factory.on_submit_shares_extended(share).unwrap() {
+        OnNewShare::ShareMeetBitcoinTarget((_, _, _, _, header)) => {
+            assert_eq!(
+                header.prev_blockhash,
+                crate::utils::u256_to_block_hash(U256::from([1; 32]))
+            );
+        }
+        result => panic!("Expected ShareMeetBitcoinTarget, got {:?}", result),
+    }
+
+    // Out of the retained window the share is stale
+    let share = extended_share(channel_id, 3, job_a, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(is_stale_share_error(
+        &factory.on_submit_shares_extended(share).unwrap()
+    ));
+
+    // Previous tips are forgotten with the prev hash
+    factory.expire_prev_hash();
+    assert!(factory.previous_tip(job_c).is_none());
+}
+
+#[test]
+fn test_on_new_prev_hash_returns_messages_for_each_channel() {
+    let mut factory = new_pool_factory();
+    let first_channel = open_extended_channel(&mut factory, 1);
+    let second_channel = open_extended_channel(&mut factory, 2);
+    let standard_channel = factory.new_standard_id_for_hom().unwrap();
+    factory
+        .add_standard_channel(standard_channel, 0, MAX_TARGET.into())
+        .unwrap();
+    factory.on_new_template(&mut new_template(1, true)).unwrap();
+    let prev_hash = new_prev_hash(1, MIN_TARGET);
+    let messages = factory.on_new_prev_hash_from_tp(&prev_hash).unwrap();
+    let job_id = active_job_id(&factory);
+
+    assert_eq!(messages.len(), 3);
+    for channel_id in [first_channel, second_channel, standard_channel] {
+        match &messages[&channel_id] {
+            Mining::SetNewPrevHash(m) => {
+                assert_eq!(m.channel_id, channel_id);
+                assert_eq!(m.job_id, job_id);
+                assert_eq!(m.prev_hash, prev_hash.prev_hash);
+                assert_eq!(m.min_ntime, prev_hash.header_timestamp);
+                assert_eq!(m.nbits, prev_hash.n_bits);
+            }
+            m => panic!("Expected SetNewPrevHash, got {m}"),
+        }
+    }
+
+    // The proxy addresses the prev hash it receives to each of its channels
+    let mut proxy = new_proxy_factory(MAX_TARGET);
+    let channel_ids: Vec<u32> = (1..3)
+        .map(|request_id| proxy_open_extended_channel(&mut proxy, request_id))
+        .collect();
+    let messages = proxy
+        .on_new_prev_hash(SetNewPrevHash {
+            channel_id: 0,
+            job_id: 7,
+            prev_hash: U256::from([1; 32]),
+            min_ntime: 1_700_000_000,
+            nbits: NBITS,
+        })
+        .unwrap();
+    assert_eq!(messages.len(), channel_ids.len());
+    for channel_id in channel_ids {
+        match &messages[&channel_id] {
+            Mining::SetNewPrevHash(m) => {
+                assert_eq!(m.channel_id, channel_id);
+                assert_eq!(m.job_id, 7);
+            }
+            m => panic!("Expected SetNewPrevHash, got {m}"),
+        }
+    }
+}
+
+#[test]
+fn test_share_rejection_details() {
+    let mut factory = new_pool_factory();
+    let messages = factory
+        .new_extended_channel(
+            1,
+            1.0e20,
+            DOWNSTREAM_EXTRANONCE_LEN as u16,
+            ConnectionFlags::all(),
+        )
+        .unwrap();
+    let (channel_id, required_difficulty) = match &messages[0] {
+        Mining::OpenExtendedMiningChannelSuccess(success) => (
+            success.channel_id,
+            crate::utils::target_to_difficulty(&success.target.clone().into()),
+        ),
+        m => panic!("Expected OpenExtendedMiningChannelSuccess, got {m}"),
+    };
+    let previous_job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    for template_id in 2..PREVIOUS_TIPS_HISTORY_LEN as u64 + 3 {
+        activate_job(&mut factory, template_id, MIN_TARGET);
+    }
+    let job_id = active_job_id(&factory);
+    let share = |sequence_number, job_id| {
+        extended_share(
+            channel_id,
+            sequence_number,
+            job_id,
+            vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+        )
+    };
+    let detail = |result: OnNewShare| match result {
+        OnNewShare::SendErrorDownstream((_, detail)) => detail,
+        result => panic!("Expected SendErrorDownstream, got {:?}", result),
+    };
+
+    // Disabled by default
+    assert!(detail(factory.on_submit_shares_extended(share(0, job_id)).unwrap()).is_none());
+
+    factory.set_rejection_details(true);
+    let difficulty_too_low =
+        detail(factory.on_submit_shares_extended(share(1, job_id)).unwrap()).unwrap();
+    assert_eq!(
+        difficulty_too_low.reason,
+        ShareRejectionReason::DifficultyTooLow
+    );
+    assert_eq!(
+        difficulty_too_low.required_difficulty,
+        Some(required_difficulty)
+    );
+    assert!(difficulty_too_low.achieved_difficulty.unwrap() < required_difficulty);
+
+    let stale = detail(
+        factory
+            .on_submit_shares_extended(share(2, previous_job_id))
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        stale,
+        ShareRejectionDetail {
+            achieved_difficulty: None,
+            required_difficulty: Some(required_difficulty),
+            reason: ShareRejectionReason::StaleJob,
+        }
+    );
+    let invalid_job_id = detail(
+        factory
+            .on_submit_shares_extended(share(3, job_id + 100))
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(invalid_job_id.reason, ShareRejectionReason::InvalidJobId);
+    assert_eq!(invalid_job_id.achieved_difficulty, None);
+
+    let unknown_channel = detail(
+        factory
+            .on_submit_shares_standard(standard_share(channel_id + 1, 0, job_id))
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(unknown_channel.reason, ShareRejectionReason::UnknownChannel);
+    assert_eq!(unknown_channel.required_difficulty, None);
+}
+
+#[derive(Clone, Copy, Debug)]
+enum TpMessage {
+    FutureTemplate(u64),
+    Template(u64),
+    PrevHash(u64),
+}
+
+#[test]
+fn test_out_of_order_templates_and_prev_hashes() {
+    use TpMessage::*;
+    // (messages, template of the final active job, number of messages refused)
+    let cases: &[(&[TpMessage], Option<u64>, usize)] = &[
+        (&[FutureTemplate(1), PrevHash(1)], Some(1), 0),
+        (&[PrevHash(1), FutureTemplate(1)], Some(1), 0),
+        (&[FutureTemplate(1), PrevHash(1), Template(2)], Some(2), 0),
+        (&[PrevHash(1), Template(2)], Some(2), 0),
+        (&[PrevHash(1), FutureTemplate(1), Template(2)], Some(2), 0),
+        (&[PrevHash(1), Template(2), FutureTemplate(1)], Some(2), 0),
+        (&[Template(2), FutureTemplate(1), PrevHash(1)], Some(1), 1),
+        (
+            &[FutureTemplate(1), FutureTemplate(2), PrevHash(2)],
+            Some(2),
+            0,
+        ),
+        (
+            &[FutureTemplate(1), FutureTemplate(2), PrevHash(1)],
+            Some(1),
+            0,
+        ),
+        (&[FutureTemplate(1), PrevHash(1), PrevHash(1)], Some(1), 0),
+        (
+            &[
+                FutureTemplate(1),
+                PrevHash(1),
+                FutureTemplate(2),
+                PrevHash(2),
+            ],
+            Some(2),
+            0,
+        ),
+        (
+            &[
+                FutureTemplate(1),
+                PrevHash(1),
+                PrevHash(2),
+                FutureTemplate(2),
+            ],
+            Some(2),
+            0,
+        ),
+        (&[PrevHash(1)], None, 0),
+    ];
+
+    for (messages, expected_template, expected_refused) in cases {
+        let mut factory = new_pool_factory();
+        let channel_id = open_extended_channel(&mut factory, 1);
+        let mut refused = 0;
+        let mut last_prev_hash = None;
+        for message in messages.iter() {
+            let result = match *message {
+                FutureTemplate(id) => factory
+                    .on_new_template(&mut new_template(id, true))
+                    .map(|_| ()),
+                Template(id) => factory
+                    .on_new_template(&mut new_template(id, false))
+                    .map(|_| ()),
+                PrevHash(id) => {
+                    let mut prev_hash = new_prev_hash(id, MIN_TARGET);
+                    prev_hash.prev_hash = U256::from([id as u8; 32]);
+                    last_prev_hash = Some(prev_hash.prev_hash.clone());
+                    factory.on_new_prev_hash_from_tp(&prev_hash).map(|_| ())
+                }
+            };
+            if result.is_err() {
+                refused += 1;
+            }
+        }
+        assert_eq!(refused, *expected_refused, "{:?}", messages);
+
+        let active_job = factory
+            .inner
+            .last_valid_job
+            .as_ref()
+            .map(|(job, _)| job.job_id);
+        let active_template =
+            active_job.and_then(|job_id| factory.job_creator.get_template_id_from_job(job_id));
+        assert_eq!(active_template, *expected_template, "{:?}", messages);
+        if let Some(job_id) = active_job {
+            // The active job is mined on the last prev hash, that references it
+            let (prev_hash, _) = factory.inner.last_prev_hash.as_ref().unwrap();
+            assert_eq!(
+                Some(prev_hash.prev_hash.clone()),
+                last_prev_hash,
+                "{:?}",
+                messages
+            );
+            assert_eq!(prev_hash.job_id, job_id, "{:?}", messages);
+            // A new channel gets the active job activated by the last prev hash
+            let channel =
+                factory.new_extended_channel_structured(2, 0.0, 8, ConnectionFlags::all());
+            let channel = channel.unwrap().unwrap();
+            assert_eq!(channel.job.unwrap().job_id, job_id, "{:?}", messages);
+            assert_eq!(channel.prev_hash.unwrap().job_id, job_id, "{:?}", messages);
+            assert!(factory.extended_job_for_channel(channel_id).is_some());
+        }
+    }
+
+    // [FutureTemplate(1), PrevHash(1), PrevHash(2), FutureTemplate(2)]: the prev hash received
+    // before its template does not move the old job on the new prev hash
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let old_job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    let mut prev_hash = new_prev_hash(2, MIN_TARGET);
+    prev_hash.prev_hash = U256::from([2; 32]);
+    let messages = factory.on_new_prev_hash_from_tp(&prev_hash).unwrap();
+    assert_eq!(messages.len(), 1);
+    match &messages[&channel_id] {
+        Mining::SetNewPrevHash(m) => {
+            assert_eq!(m.prev_hash, prev_hash.prev_hash);
+            assert_ne!(m.job_id, old_job_id);
+        }
+        m => panic!("Expected SetNewPrevHash, got {m}"),
+    }
+    assert!(factory.inner.last_valid_job.is_none());
+    assert!(factory.extended_job_for_channel(channel_id).is_none());
+    // Shares of the old job are still checked against the tip it was mined on
+    let share = extended_share(
+        channel_id,
+        0,
+        old_job_id,
+        vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+    );
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+    let jobs = factory.on_new_template(&mut new_template(2, true)).unwrap();
+    match &jobs[&channel_id] {
+        Mining::NewExtendedMiningJob(job) => {
+            assert!(!job.is_future());
+            assert_eq!(job.job_id, active_job_id(&factory));
+        }
+        m => panic!("Expected NewExtendedMiningJob, got {m}"),
+    }
+}
+
+#[test]
+fn test_max_future_jobs() {
+    let mut factory = new_pool_factory();
+    let staged = |job_id| StagedPhash {
+        job_id,
+        prev_hash: U256::from([1; 32]),
+        min_ntime: 0,
+        nbits: NBITS,
+    };
+    let future_job_ids = |factory: &PoolChannelFactory| -> Vec<u32> {
+        factory
+            .inner
+            .future_jobs
+            .iter()
+            .map(|(job, _)| job.job_id)
+            .collect()
+    };
+    factory.set_max_future_jobs(2);
+    for job_id in 1..=3 {
+        factory
+            .inner
+            .on_new_extended_mining_job(future_extended_job(job_id, VERSION))
+            .unwrap();
+    }
+    // The oldest future job has been evicted
+    assert_eq!(future_job_ids(&factory), vec![2, 3]);
+    factory.inner.on_new_prev_hash(staged(1)).unwrap();
+    assert!(factory.inner.last_valid_job.is_none());
+
+    factory
+        .inner
+        .on_new_extended_mining_job(future_extended_job(4, VERSION))
+        .unwrap();
+    factory
+        .inner
+        .on_new_extended_mining_job(future_extended_job(5, VERSION))
+        .unwrap();
+    factory.inner.on_new_prev_hash(staged(5)).unwrap();
+    assert_eq!(factory.inner.last_valid_job.as_ref().unwrap().0.job_id, 5);
+
+    // Lowering the cap evicts the future jobs above it
+    for job_id in 6..=8 {
+        factory
+            .inner
+            .on_new_extended_mining_job(future_extended_job(job_id, VERSION))
+            .unwrap();
+    }
+    factory.set_max_future_jobs(1);
+    assert_eq!(future_job_ids(&factory), vec![8]);
+
+    // A prev hash for an unknown job leaves no active job
+    factory.inner.on_new_prev_hash(staged(42)).unwrap();
+    assert!(factory.inner.last_valid_job.is_none());
+    assert!(future_job_ids(&factory).is_empty());
+}
+
+// Poisons the ids lock panicking while it is held
+fn poison_ids(ids: &Arc<Mutex<GroupId>>) {
+    let ids = ids.clone();
+    let result = std::thread::spawn(move || {
+        ids.safe_lock(|_| panic!("Poisoning the ids lock")).unwrap();
+    })
+    .join();
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_poisoned_ids_lock() {
+    let ids = Arc::new(Mutex::new(GroupId::new()));
+    let mut factory = PoolChannelFactory::new(
+        ids.clone(),
+        ExtendedExtranonce::new(0..0, 0..8, 8..EXTRANONCE_LEN, None).unwrap(),
+        JobsCreators::new(EXTRANONCE_LEN as u8),
+        SHARES_PER_MINUTE,
+        ExtendedChannelKind::Pool,
+        pool_coinbase_outputs(),
+    );
+    let channel_id = open_extended_channel(&mut factory, 1);
+    poison_ids(&ids);
+
+    assert!(matches!(
+        factory.new_extended_channel(
+            2,
+            0.0,
+            DOWNSTREAM_EXTRANONCE_LEN as u16,
+            ConnectionFlags::all()
+        ),
+        Err(Error::PoisonLock(_))
+    ));
+    assert!(matches!(
+        factory.new_standard_channel(3, 0.0, 1),
+        Err(Error::PoisonLock(_))
+    ));
+    assert!(matches!(factory.new_group_id(), Err(Error::PoisonLock(_))));
+    assert!(matches!(
+        factory.new_standard_id_for_hom(),
+        Err(Error::PoisonLock(_))
+    ));
+    // The channel is closed even if its id can not be freed
+    assert!(factory.close_channel(channel_id));
+    assert!(!factory.inner.extended_channels.contains_key(&channel_id));
+}
+
+#[test]
+fn test_replay_recorded_inputs() {
+    use crate::channel_logic::input_recorder::{replay, ReplayOutcome};
+
+    let mut factory = new_pool_factory();
+    factory.set_input_recorder(Some(InputRecorder::new()));
+    let mut outcomes = Vec::new();
+
+    let messages = factory
+        .new_extended_channel(
+            1,
+            0.0,
+            DOWNSTREAM_EXTRANONCE_LEN as u16,
+            ConnectionFlags::all(),
+        )
+        .unwrap();
+    let channel_id = match &messages[0] {
+        Mining::OpenExtendedMiningChannelSuccess(success) => success.channel_id,
+        m => panic!("Expected OpenExtendedMiningChannelSuccess, got {m}"),
+    };
+    outcomes.push(ReplayOutcome::Channel(Ok(messages)));
+    let group_id = factory.new_group_id().unwrap();
+    outcomes.push(ReplayOutcome::Id(Ok(group_id)));
+    let messages = factory.new_standard_channel(2, 0.0, group_id);
+    let standard_id = match &messages.as_ref().unwrap()[0] {
+        Mining::OpenStandardMiningChannelSuccess(success) => success.channel_id,
+        m => panic!("Expected OpenStandardMiningChannelSuccess, got {m}"),
+    };
+    outcomes.push(ReplayOutcome::Channel(messages));
+
+    outcomes.push(ReplayOutcome::Jobs(
+        factory.on_new_template(&mut new_template(1, true)),
+    ));
+    outcomes.push(ReplayOutcome::Jobs(
+        factory.on_new_prev_hash_from_tp(&new_prev_hash(1, MIN_TARGET)),
+    ));
+    let job_id = active_job_id(&factory);
+    let extranonce = vec![0; DOWNSTREAM_EXTRANONCE_LEN];
+    // Accepted, duplicate and invalid job id shares
+    for share in [
+        extended_share(channel_id, 1, job_id, extranonce.clone()),
+        extended_share(channel_id, 1, job_id, extranonce.clone()),
+        extended_share(channel_id, 2, job_id + 10, extranonce.clone()),
+    ] {
+        outcomes.push(ReplayOutcome::Share(
+            factory.on_submit_shares_extended(share),
+        ));
+    }
+    outcomes.push(ReplayOutcome::Share(
+        factory.on_submit_shares_standard(standard_share(standard_id, 3, job_id)),
+    ));
+    outcomes.push(ReplayOutcome::Jobs(
+        factory.on_new_template(&mut new_template(2, false)),
+    ));
+    outcomes.push(ReplayOutcome::ChannelClosed(
+        factory.close_channel(channel_id),
+    ));
+    outcomes.push(ReplayOutcome::Share(factory.on_submit_shares_extended(
+        extended_share(channel_id, 4, active_job_id(&factory), extranonce),
+    )));
+    factory.expire_prev_hash();
+    outcomes.push(ReplayOutcome::PrevHashExpired);
+    outcomes.push(ReplayOutcome::Share(
+        factory.on_submit_shares_standard(standard_share(standard_id, 5, job_id)),
+    ));
+
+    let recording = factory.take_input_recorder().unwrap().into_recording();
+    let mut replayed = new_pool_factory();
+    let replayed_outcomes = replay(&recording, &mut replayed).unwrap();
+    assert_eq!(replayed_outcomes.len(), outcomes.len());
+    assert_eq!(format!("{replayed_outcomes:?}"), format!("{outcomes:?}"));
+    assert_eq!(
+        replayed.share_accounting(standard_id),
+        factory.share_accounting(standard_id)
+    );
+    assert_eq!(active_job_id(&replayed), active_job_id(&factory));
+}
+
+#[test]
+fn test_replay_recorded_clock() {
+    use crate::channel_logic::input_recorder::{replay, ReplayOutcome};
+
+    let clock = MockClock::new();
+    let mut factory = new_pool_factory();
+    factory.set_clock(Box::new(clock.clone()));
+    factory.set_max_ntime_drift(Some(Duration::from_secs(60)));
+    factory.set_input_recorder(Some(InputRecorder::new()));
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    let share = |sequence_number| {
+        let mut share = extended_share(
+            channel_id,
+            sequence_number,
+            job_id,
+            vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+        );
+        share.ntime = MOCK_UNIX_TIME as u32 + 60 * 60;
+        share
+    };
+    // The share is too far in the future until the clock moves an hour later
+    let result = factory.on_submit_shares_extended(share(0)).unwrap();
+    assert_eq!(
+        share_error_code(&result).as_deref(),
+        Some("ntime-too-far-in-future")
+    );
+    clock.advance(Duration::from_secs(60 * 60));
+    assert!(matches!(
+        factory.on_submit_shares_extended(share(1)).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+
+    // The replayed factory reads the recorded time, not the system time
+    let recording = factory.take_input_recorder().unwrap().into_recording();
+    let mut replayed = new_pool_factory();
+    replayed.set_max_ntime_drift(Some(Duration::from_secs(60)));
+    let outcomes = replay(&recording, &mut replayed).unwrap();
+    match &outcomes[outcomes.len() - 2..] {
+        [ReplayOutcome::Share(Ok(rejected)), ReplayOutcome::Share(Ok(accepted))] => {
+            assert_eq!(
+                share_error_code(rejected).as_deref(),
+                Some("ntime-too-far-in-future")
+            );
+            assert!(matches!(accepted, OnNewShare::ShareMeetDownstreamTarget));
+        }
+        outcomes => panic!("Expected two share outcomes, got {outcomes:?}"),
+    }
+    assert_eq!(replayed.inner.clock.unix_time(), MOCK_UNIX_TIME + 60 * 60);
+}
+
+#[test]
+fn test_extranonce_prefixes_exhausted() {
+    let mut factory = new_pool_factory_with_ranges(0..2, 2..3);
+    assert_eq!(factory.remaining_extended_prefixes(), 255);
+    // Each channel granted the whole range_2 takes a prefix
+    let extranonce2_size = factory.available_extranonce2_size() as u16;
+    for request_id in 0..255 {
+        factory
+            .new_extended_channel(request_id, 0.0, extranonce2_size, ConnectionFlags::all())
+            .unwrap();
+        assert_eq!(
+            factory.remaining_extended_prefixes(),
+            254 - request_id as u128
+        );
+    }
+    for request_id in 255..257 {
+        let messages = factory
+            .new_extended_channel(request_id, 0.0, 0, ConnectionFlags::all())
+            .unwrap();
+        match &messages[..] {
+            [Mining::OpenMiningChannelError(error)] => {
+                assert_eq!(error.request_id, request_id);
+                assert_eq!(error.error_code.to_vec(), b"extranonce-prefixes-exhausted");
+            }
+            m => panic!("Expected OpenMiningChannelError, got {m:?}"),
+        }
+    }
+    assert_eq!(factory.remaining_extended_prefixes(), 0);
+    // The refused channels did not take a channel id
+    assert_eq!(factory.get_extended_channels_ids().len(), 255);
+    assert_eq!(factory.inner.channel_to_group_id.len(), 255);
+}
+
+#[test]
+fn test_extended_channel_with_invalid_hash_rate() {
+    let mut factory = new_pool_factory_with_ranges(0..2, 2..3);
+    let extranonce2_size = factory.available_extranonce2_size() as u16;
+    let next_channel_id = |factory: &PoolChannelFactory| {
+        factory
+            .inner
+            .ids
+            .safe_lock(|ids| ids.state().1 + 1)
+            .unwrap()
+    };
+    let channel_id = next_channel_id(&factory);
+    assert!(matches!(
+        factory.new_extended_channel(1, -1.0, extranonce2_size, ConnectionFlags::all()),
+        Err(Error::TargetError(_))
+    ));
+    // The failed channel took no prefix, no id and no group
+    assert_eq!(factory.remaining_extended_prefixes(), 255);
+    assert_eq!(next_channel_id(&factory), channel_id);
+    assert!(factory.inner.channel_to_group_id.is_empty());
+    assert_eq!(open_extended_channel(&mut factory, 2), channel_id);
+}
+
+#[test]
+fn test_share_extranonce_size_must_match_channel() {
+    let mut factory = new_pool_factory();
+    factory.set_rejection_details(true);
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    for (sequence_number, len) in [
+        (0, DOWNSTREAM_EXTRANONCE_LEN - 1),
+        (1, DOWNSTREAM_EXTRANONCE_LEN + 1),
+        (2, 0),
+        (3, 32),
+    ] {
+        let share = extended_share(channel_id, sequence_number, job_id, vec![0; len]);
+        match factory.on_submit_shares_extended(share).unwrap() {
+            OnNewShare::SendErrorDownstream((error, detail)) => {
+                assert_eq!(error.error_code.as_utf8_or_hex(), "invalid-extranonce-size");
+                assert_eq!(
+                    detail.unwrap().reason,
+                    ShareRejectionReason::InvalidExtranonceSize
+                );
+            }
+            m => panic!("Expected SendErrorDownstream for extranonce len {len}, got {m:?}"),
+        }
+    }
+    let accounting = factory.share_accounting(channel_id).unwrap();
+    assert_eq!(accounting.shares_rejected(), 4);
+    assert_eq!(accounting.shares_accepted(), 0);
+    // The rejected shares are not remembered as duplicates
+    let share = extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+
+    let mut proxy = new_proxy_factory(MIN_TARGET);
+    let channel_id = proxy_open_extended_channel(&mut proxy, 1);
+    let job_id = proxy_activate_job(&mut proxy, NBITS);
+    for len in [DOWNSTREAM_EXTRANONCE_LEN - 1, DOWNSTREAM_EXTRANONCE_LEN + 1] {
+        let share = extended_share(channel_id, 0, job_id, vec![0; len]);
+        assert_eq!(
+            share_error_code(&proxy.on_submit_shares_extended(share).unwrap()).as_deref(),
+            Some("invalid-extranonce-size")
+        );
+    }
+}
+
+#[test]
+fn test_share_ntime_bounds() {
+    let mut factory = new_pool_factory();
+    factory.set_rejection_details(true);
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    let now = SystemClock.unix_time() as u32;
+    let share_with_ntime = |sequence_number: u32, ntime: u32| {
+        let mut share = extended_share(
+            channel_id,
+            sequence_number,
+            job_id,
+            vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+        );
+        share.ntime = ntime;
+        share
+    };
+
+    // The min_ntime of the job is the prev hash header timestamp
+    let min_ntime = new_prev_hash(1, MIN_TARGET).header_timestamp;
+    for (share, error_code) in [
+        (share_with_ntime(0, min_ntime - 1), "ntime-below-min-ntime"),
+        (
+            share_with_ntime(1, now + 3 * 60 * 60),
+            "ntime-too-far-in-future",
+        ),
+    ] {
+        match factory.on_submit_shares_extended(share).unwrap() {
+            OnNewShare::SendErrorDownstream((error, detail)) => {
+                assert_eq!(error.error_code.as_utf8_or_hex(), error_code);
+                assert_eq!(detail.unwrap().reason, ShareRejectionReason::InvalidNtime);
+            }
+            m => panic!("Expected SendErrorDownstream with {error_code}, got {m:?}"),
+        }
+    }
+    for share in [
+        share_with_ntime(2, min_ntime),
+        share_with_ntime(3, now + 60 * 60),
+    ] {
+        assert!(matches!(
+            factory.on_submit_shares_extended(share).unwrap(),
+            OnNewShare::ShareMeetDownstreamTarget
+        ));
+    }
+
+    factory.set_max_ntime_drift(Some(Duration::from_secs(60)));
+    let share = share_with_ntime(4, now + 60 * 60);
+    assert_eq!(
+        share_error_code(&factory.on_submit_shares_extended(share).unwrap()).as_deref(),
+        Some("ntime-too-far-in-future")
+    );
+    factory.set_max_ntime_drift(None);
+    let share = share_with_ntime(5, u32::MAX);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+    let share = share_with_ntime(6, min_ntime - 1);
+    assert_eq!(
+        share_error_code(&factory.on_submit_shares_extended(share).unwrap()).as_deref(),
+        Some("ntime-below-min-ntime")
+    );
+}
+
+#[test]
+fn test_version_rolling_mask() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    let share_with_version = |sequence_number: u32, version: u32| {
+        let mut share = extended_share(
+            channel_id,
+            sequence_number,
+            job_id,
+            vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+        );
+        share.version = version;
+        share
+    };
+
+    // Only the bits 13 to 15 can be rolled
+    factory.set_version_rolling_mask(0x0000_e000);
+    let share = share_with_version(0, VERSION | 0x0000_a000);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+    for (sequence_number, version) in [(1, VERSION | 0x1000_0000), (2, VERSION | 0x0001_0000)] {
+        let share = share_with_version(sequence_number, version);
+        assert_eq!(
+            share_error_code(&factory.on_submit_shares_extended(share).unwrap()).as_deref(),
+            Some("invalid-version")
+        );
+    }
+
+    // The BIP320 bits
+    factory.set_version_rolling_mask(0x1fff_e000);
+    let share = share_with_version(3, VERSION | 0x1000_0000);
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+}
+
+#[test]
+fn test_on_update_channel() {
+    let update =
+        |channel_id: u32, nominal_hash_rate: f32, maximum_target: [u8; 32]| UpdateChannel {
+            channel_id,
+            nominal_hash_rate,
+            maximum_target: maximum_target.into(),
+        };
+    let assert_set_target = |message: Mining, channel_id: u32, target: &Target| match message {
+        Mining::SetTarget(set_target) => {
+            assert_eq!(set_target.channel_id, channel_id);
+            assert_eq!(&Target::from(set_target.maximum_target), target);
+        }
+        m => panic!("Expected SetTarget, got {m}"),
+    };
+    let error_code = |message: Mining| match message {
+        Mining::UpdateChannelError(error) => error.error_code.as_utf8_or_hex(),
+        m => panic!("Expected UpdateChannelError, got {m}"),
+    };
+
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let group_id = factory.new_group_id().unwrap();
+    let (standard_channel_id, _, _) = open_standard_channel(&mut factory, 2, group_id);
+    let hash_rate_target: Target =
+        crate::utils::hash_rate_to_target(1_000_000.0, factory.inner.share_per_min.into())
+            .unwrap()
+            .into();
+
+    // The target of the nominal hash rate is used when it is below the maximum target
+    let message = factory
+        .on_update_channel(update(channel_id, 1_000_000.0, MAX_TARGET))
+        .unwrap();
+    assert_set_target(message, channel_id, &hash_rate_target);
+    assert_eq!(
+        Target::from(factory.inner.extended_channels[&channel_id].target.clone()),
+        hash_rate_target
+    );
+    let message = factory
+        .on_update_channel(update(standard_channel_id, 1_000_000.0, MAX_TARGET))
+        .unwrap();
+    assert_set_target(message, standard_channel_id, &hash_rate_target);
+    assert_eq!(
+        factory.inner.standard_channels[&standard_channel_id].target,
+        hash_rate_target
+    );
+
+    // Otherwise the requested maximum target is used
+    let mut maximum_target = MAX_TARGET;
+    for byte in maximum_target[28..].iter_mut() {
+        *byte = 0;
+    }
+    let message = factory
+        .on_update_channel(update(channel_id, 1_000_000.0, maximum_target))
+        .unwrap();
+    assert_set_target(message, channel_id, &maximum_target.into());
+    let message = factory
+        .update_target_for_channel_with_message(standard_channel_id, maximum_target.into())
+        .unwrap();
+    assert_set_target(message, standard_channel_id, &maximum_target.into());
+    assert_eq!(
+        factory.inner.standard_channels[&standard_channel_id].target,
+        maximum_target.into()
+    );
+
+    assert_eq!(
+        error_code(
+            factory
+                .on_update_channel(update(channel_id + 100, 1_000_000.0, MAX_TARGET))
+                .unwrap()
+        ),
+        "invalid-channel-id"
+    );
+    for channel_id in [channel_id, standard_channel_id] {
+        assert_eq!(
+            error_code(
+                factory
+                    .on_update_channel(update(channel_id, -1.0, MAX_TARGET))
+                    .unwrap()
+            ),
+            "invalid-nominal-hashrate"
+        );
+    }
+}
+
+#[test]
+fn test_on_update_channel_max_target_out_of_range() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let mut min_target = MAX_TARGET;
+    min_target[31] = 0;
+    factory.set_min_downstream_target(Some(min_target.into()));
+
+    // Harder than the min downstream target
+    let mut maximum_target = min_target;
+    maximum_target[30] = 0;
+    let message = factory
+        .on_update_channel(UpdateChannel {
+            channel_id,
+            nominal_hash_rate: 1_000_000.0,
+            maximum_target: maximum_target.into(),
+        })
+        .unwrap();
+    match message {
+        Mining::UpdateChannelError(error) => {
+            assert_eq!(error.channel_id, channel_id);
+            assert_eq!(error.error_code.as_utf8_or_hex(), "max-target-out-of-range");
+        }
+        m => panic!("Expected UpdateChannelError, got {m}"),
+    }
+    assert_eq!(
+        factory.inner.extended_channels[&channel_id]
+            .target
+            .inner_as_ref(),
+        &MAX_TARGET[..]
+    );
+
+    // The target of the nominal hash rate is capped to the min downstream target
+    let message = factory
+        .on_update_channel(UpdateChannel {
+            channel_id,
+            nominal_hash_rate: 1_000_000.0,
+            maximum_target: MAX_TARGET.into(),
+        })
+        .unwrap();
+    match message {
+        Mining::SetTarget(set_target) => {
+            assert_eq!(set_target.maximum_target.inner_as_ref(), &min_target[..])
+        }
+        m => panic!("Expected SetTarget, got {m}"),
+    }
+}
+
+#[test]
+fn test_set_extranonce_prefix() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let other_channel_id = open_extended_channel(&mut factory, 2);
+    let job_id = activate_job(&mut factory, 1, MAX_TARGET);
+
+    // The first prefix handed out is 1, 0 is free
+    let new_prefix = vec![0; 8];
+    match factory
+        .set_extranonce_prefix(channel_id, new_prefix.clone())
+        .unwrap()
+    {
+        Mining::SetExtranoncePrefix(m) => {
+            assert_eq!(m.channel_id, channel_id);
+            assert_eq!(m.extranonce_prefix.to_vec(), new_prefix);
+        }
+        m => panic!("Expected SetExtranoncePrefix, got {m}"),
+    }
+
+    // The extranonce of later shares is built with the new prefix
+    let extranonce = vec![7; DOWNSTREAM_EXTRANONCE_LEN];
+    let share = extended_share(channel_id, 0, job_id, extranonce.clone());
+    match factory.on_submit_shares_extended(share).unwrap() {
+        OnNewShare::ShareMeetBitcoinTarget((_, _, coinbase, full_extranonce, _)) => {
+            let expected = [&new_prefix[..], &extranonce[..]].concat();
+            assert_eq!(full_extranonce, expected);
+            assert!(coinbase
+                .windows(expected.len())
+                .any(|window| window == &expected[..]));
+        }
+        m => panic!("Expected ShareMeetBitcoinTarget, got {m:?}"),
+    }
+
+    // The prefix of another channel, a prefix with a wrong len and an unknown channel
+    let other_prefix = factory.inner.extended_channels[&other_channel_id]
+        .extranonce_prefix
+        .to_vec();
+    assert!(matches!(
+        factory.set_extranonce_prefix(channel_id, other_prefix),
+        Err(Error::InvalidExtranoncePrefix(id)) if id == channel_id
+    ));
+    assert!(matches!(
+        factory.set_extranonce_prefix(channel_id, vec![0xbb; 7]),
+        Err(Error::InvalidExtranoncePrefix(_))
+    ));
+    assert!(matches!(
+        factory.set_extranonce_prefix(channel_id + 100, vec![0xbb; 8]),
+        Err(Error::NotFoundChannelId)
+    ));
+    assert_eq!(
+        factory.inner.extended_channels[&channel_id]
+            .extranonce_prefix
+            .to_vec(),
+        new_prefix
+    );
+
+    // Proxies can not change the upstream part of the prefix
+    let mut proxy = new_proxy_factory(MAX_TARGET);
+    let channel_id = proxy_open_extended_channel(&mut proxy, 1);
+    assert!(matches!(
+        proxy.set_extranonce_prefix(channel_id, vec![0xaa; 8]),
+        Err(Error::InvalidExtranoncePrefix(_))
+    ));
+    assert!(proxy.set_extranonce_prefix(channel_id, vec![0; 8]).is_ok());
+
+    // A prefix that has not been handed out yet would collide with a later channel
+    let mut factory = new_pool_factory_with_ranges(0..2, 2..3);
+    let extranonce2_size = factory.available_extranonce2_size() as u16;
+    let open_channel = |factory: &mut PoolChannelFactory, request_id| match &factory
+        .new_extended_channel(request_id, 0.0, extranonce2_size, ConnectionFlags::all())
+        .unwrap()[0]
+    {
+        Mining::OpenExtendedMiningChannelSuccess(success) => success.channel_id,
+        m => panic!("Expected OpenExtendedMiningChannelSuccess, got {m}"),
+    };
+    let channel_id = open_channel(&mut factory, 0);
+    assert!(matches!(
+        factory.set_extranonce_prefix(channel_id, vec![0, 0, 5]),
+        Err(Error::InvalidExtranoncePrefix(id)) if id == channel_id
+    ));
+    assert!(factory
+        .set_extranonce_prefix(channel_id, vec![0, 0, 0])
+        .is_ok());
+    for request_id in 1..6 {
+        open_channel(&mut factory, request_id);
+    }
+    assert!(factory.audit_extranonce_disjointness().is_ok());
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+    let extranonces = ExtendedExtranonce::new(0..0, 0..8, 8..EXTRANONCE_LEN, None).unwrap();
+    let mut factory = PoolChannelFactory::new(
+        Arc::new(Mutex::new(GroupId::new())),
+        extranonces,
+        JobsCreators::new(EXTRANONCE_LEN as u8),
+        SHARES_PER_MINUTE,
+        ExtendedChannelKind::Pool,
+        pool_coinbase_outputs(),
+    );
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let custom_channel_id = open_extended_channel(&mut factory, 2);
+    let group_id = factory.new_group_id().unwrap();
+    let (standard_channel_id, _, _) = open_standard_channel(&mut factory, 3, group_id);
+    let closed_channel_id = open_extended_channel(&mut factory, 4);
+    assert!(factory.close_channel(closed_channel_id));
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    let custom_job_id = set_custom_job(
+        &mut factory,
+        custom_mining_job(custom_channel_id, &pool_coinbase_outputs()),
+    );
+
+    let snapshot = factory.to_snapshot().unwrap();
+    assert_eq!(snapshot.extended_channels.len(), 2);
+    assert_eq!(snapshot.standard_channels.len(), 1);
+    assert_eq!(snapshot.negotiated_jobs.len(), 1);
+    assert_eq!(snapshot.ids.freed_channel_ids, vec![closed_channel_id]);
+    // The restored factory gets new ids, as after a restart
+    let mut restored = PoolChannelFactory::from_snapshot(
+        snapshot.clone(),
+        Arc::new(Mutex::new(GroupId::new())),
+        JobsCreators::new(EXTRANONCE_LEN as u8),
+        SHARES_PER_MINUTE,
+        pool_coinbase_outputs(),
+    )
+    .unwrap();
+    assert_eq!(restored.to_snapshot().unwrap(), snapshot);
+    assert_eq!(
+        restored.inner.standard_channels[&standard_channel_id].group_id,
+        group_id
+    );
+
+    // The active job and prev hash are resent, shares for them and for the negotiated job are
+    // checked as before the restart
+    assert_eq!(restored.undelivered_messages(channel_id).len(), 2);
+    for (channel_id, job_id) in [(channel_id, job_id), (custom_channel_id, custom_job_id)] {
+        let share = extended_share(channel_id, 0, job_id, vec![0; DOWNSTREAM_EXTRANONCE_LEN]);
+        assert!(matches!(
+            restored.on_submit_shares_extended(share).unwrap(),
+            OnNewShare::ShareMeetDownstreamTarget
+        ));
+    }
+
+    // New job, group and channel ids and extranonce prefixes do not collide with the restored ones
+    assert!(restored.job_creator.new_job_id() > custom_job_id);
+    assert!(restored.new_group_id().unwrap() > group_id);
+    let new_channel_id = open_extended_channel(&mut restored, 5);
+    let mut prefixes: Vec<Vec<u8>> = restored
+        .inner
+        .extended_channels
+        .values()
+        .map(|channel| channel.extranonce_prefix.to_vec())
+        .collect();
+    prefixes.sort();
+    prefixes.dedup();
+    assert_eq!(prefixes.len(), 3);
+    assert!(![
+        channel_id,
+        custom_channel_id,
+        standard_channel_id,
+        closed_channel_id
+    ]
+    .contains(&new_channel_id));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_snapshot_serde_round_trip() {
+    let mut factory = new_pool_factory();
+    open_extended_channel(&mut factory, 1);
+    let group_id = factory.new_group_id().unwrap();
+    open_standard_channel(&mut factory, 2, group_id);
+    activate_job(&mut factory, 1, MIN_TARGET);
+
+    let snapshot = factory.to_snapshot().unwrap();
+    let serialized = serde_json::to_string(&snapshot).unwrap();
+    let deserialized: PoolChannelFactorySnapshot = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(deserialized, snapshot);
+    let mut restored = PoolChannelFactory::from_snapshot(
+        deserialized,
+        Arc::new(Mutex::new(GroupId::new())),
+        JobsCreators::new(EXTRANONCE_LEN as u8),
+        SHARES_PER_MINUTE,
+        pool_coinbase_outputs(),
+    )
+    .unwrap();
+    assert_eq!(restored.to_snapshot().unwrap(), snapshot);
+}
+
+#[test]
+fn test_snapshot_malformed() {
+    let mut factory = new_pool_factory();
+    open_extended_channel(&mut factory, 1);
+    let mut snapshot = factory.to_snapshot().unwrap();
+    snapshot.extended_channels[0].channel.truncate(3);
+    assert!(matches!(
+        PoolChannelFactory::from_snapshot(
+            snapshot,
+            Arc::new(Mutex::new(GroupId::new())),
+            JobsCreators::new(EXTRANONCE_LEN as u8),
+            SHARES_PER_MINUTE,
+            pool_coinbase_outputs(),
+        ),
+        Err(Error::MalformedSnapshot)
+    ));
+}
+
+#[test]
+fn test_channels_snapshot() {
+    let mut factory = new_pool_factory();
+    let first_channel = open_extended_channel(&mut factory, 1);
+    let second_channel = open_extended_channel(&mut factory, 2);
+    let mut target = MAX_TARGET;
+    target[31] = 0x0f;
+    factory.update_target_for_channel(second_channel, target.into());
+    let group_id = factory.new_group_id().unwrap();
+    let (standard_channel, extranonce, _) = open_standard_channel(&mut factory, 3, group_id);
+
+    let channels = factory.channels_snapshot();
+    let ids: Vec<u32> = channels.iter().map(|channel| channel.channel_id).collect();
+    assert_eq!(ids, [first_channel, second_channel, standard_channel]);
+    for channel in &channels[..2] {
+        let opened = &factory.inner.extended_channels[&channel.channel_id];
+        assert_eq!(channel.group_id, 0);
+        assert_eq!(channel.extranonce_prefix, opened.extranonce_prefix.to_vec());
+        assert_eq!(channel.extranonce_size, DOWNSTREAM_EXTRANONCE_LEN as u16);
+    }
+    assert_ne!(channels[0].extranonce_prefix, channels[1].extranonce_prefix);
+    assert_eq!(channels[0].target, MAX_TARGET.into());
+    assert_eq!(channels[1].target, target.into());
+    assert_eq!(
+        channels[2],
+        ChannelInfo {
+            channel_id: standard_channel,
+            group_id,
+            target: MAX_TARGET.into(),
+            extranonce_prefix: extranonce,
+            extranonce_size: 0,
+        }
+    );
+
+    let mut proxy = new_proxy_factory(MAX_TARGET);
+    let channel_id = proxy_open_extended_channel(&mut proxy, 1);
+    let channels = proxy.channels_snapshot();
+    assert_eq!(channels.len(), 1);
+    assert_eq!(channels[0].channel_id, channel_id);
+    assert_eq!(
+        channels[0].extranonce_prefix,
+        proxy.inner.extended_channels[&channel_id]
+            .extranonce_prefix
+            .to_vec()
+    );
+}
+
+#[test]
+fn test_template_mappings_dropped_on_new_prev_hash() {
+    let mut factory = new_pool_factory();
+    for template_id in 0..50 {
+        activate_job(&mut factory, template_id * 2, MAX_TARGET);
+        factory
+            .on_new_template(&mut new_template(template_id * 2 + 1, false))
+            .unwrap();
+        let active_job_id = active_job_id(&factory);
+        assert_eq!(
+            factory.job_creator.get_template_id_from_job(active_job_id),
+            Some(template_id * 2 + 1)
+        );
+    }
+    // The jobs of the previous tips, the job activated by the last prev hash and the job of the
+    // last template
+    assert!(factory.template_mappings_len() <= PREVIOUS_TIPS_HISTORY_LEN + 2);
+    for tip in &factory.previous_tips {
+        assert!(factory
+            .job_creator
+            .get_template_id_from_job(tip.job.job_id)
+            .is_some());
+    }
+}
+
+#[test]
+fn test_job_ids_skip_live_jobs_after_wraparound() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let other_channel_id = open_extended_channel(&mut factory, 2);
+    let active_job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    let negotiated_job_id = set_custom_job(
+        &mut factory,
+        custom_mining_job(channel_id, &pool_coinbase_outputs()),
+    );
+
+    // The next job id wraps around
+    let target = factory.job_creator.last_target();
+    factory
+        .job_creator
+        .restore(u32::MAX, None, target, Some(1_700_000_000));
+    factory.on_new_template(&mut new_template(2, true)).unwrap();
+    let future_job_id = factory.inner.future_jobs.last().unwrap().0.job_id;
+    let other_negotiated_job_id = set_custom_job(
+        &mut factory,
+        custom_mining_job(other_channel_id, &pool_coinbase_outputs()),
+    );
+
+    let job_ids = [
+        active_job_id,
+        negotiated_job_id,
+        future_job_id,
+        other_negotiated_job_id,
+    ];
+    for (i, job_id) in job_ids.iter().enumerate() {
+        assert_ne!(*job_id, 0);
+        assert!(!job_ids[i + 1..].contains(job_id));
+    }
+    let share = extended_share(
+        channel_id,
+        0,
+        negotiated_job_id,
+        vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+    );
+    assert!(matches!(
+        factory.on_submit_shares_extended(share).unwrap(),
+        OnNewShare::ShareMeetDownstreamTarget
+    ));
+}
+
+#[test]
+fn test_on_close_channel() {
+    let mut factory = new_pool_factory();
+    let channel_id = open_extended_channel(&mut factory, 1);
+    let close_channel = |channel_id| CloseChannel {
+        channel_id,
+        reason_code: "miner-shutdown".to_string().try_into().unwrap(),
+    };
+
+    factory.on_close_channel(close_channel(channel_id)).unwrap();
+    assert!(!factory.inner.extended_channels.contains_key(&channel_id));
+    assert!(!factory.inner.channel_to_group_id.contains_key(&channel_id));
+    assert!(factory.channels_snapshot().is_empty());
+    assert!(matches!(
+        factory.on_close_channel(close_channel(channel_id)),
+        Err(Error::NotFoundChannelId)
+    ));
+}
+
+#[test]
+fn test_has_channel_and_channel_group() {
+    let mut factory = new_pool_factory();
+    let extended_channel = open_extended_channel(&mut factory, 1);
+    let group_id = factory.new_group_id().unwrap();
+    let (standard_channel, _, _) = open_standard_channel(&mut factory, 2, group_id);
+    assert!(factory.has_channel(extended_channel));
+    assert!(factory.has_channel(standard_channel));
+    assert_eq!(factory.channel_group(extended_channel), Some(0));
+    assert_eq!(factory.channel_group(standard_channel), Some(group_id));
+    assert!(!factory.has_channel(standard_channel + 1));
+    assert_eq!(factory.channel_group(standard_channel + 1), None);
+
+    factory.close_channel(extended_channel);
+    factory.close_channel(standard_channel);
+    for channel_id in [extended_channel, standard_channel] {
+        assert!(!factory.has_channel(channel_id));
+        assert_eq!(factory.channel_group(channel_id), None);
+    }
+}
+
+fn output(value: u64, script: u8) -> TxOut {
+    TxOut {
+        value: Amount::from_sat(value),
+        script_pubkey: ScriptBuf::from(vec![script]),
+    }
+}
+
+// Opens a channel on a factory with `policy` and returns whether a custom job paying `outputs`
+// is accepted
+fn custom_job_accepted_with_policy(policy: CoinbaseOutputPolicy, outputs: &[TxOut]) -> bool {
+    let mut factory = new_pool_factory();
+    factory.set_coinbase_output_policy(Some(policy));
+    let channel_id = open_extended_channel(&mut factory, 1);
+    activate_job(&mut factory, 1, MIN_TARGET);
+    is_custom_job_success(
+        factory.on_new_set_custom_mining_job(custom_mining_job(channel_id, outputs)),
+    )
+}
+
+#[test]
+fn test_coinbase_output_policy_exact_match() {
+    let policy = CoinbaseOutputPolicy::ExactMatch;
+    assert!(custom_job_accepted_with_policy(
+        policy.clone(),
+        &[output(BLOCK_REWARD, 0x51)]
+    ));
+    // Outputs of the downstream are not allowed
+    assert!(!custom_job_accepted_with_policy(
+        policy.clone(),
+        &[output(BLOCK_REWARD - 1000, 0x51), output(1000, 0x52)]
+    ));
+    assert!(!custom_job_accepted_with_policy(
+        policy,
+        &[output(BLOCK_REWARD, 0x52)]
+    ));
+}
+
+#[test]
+fn test_coinbase_output_policy_must_contain() {
+    let policy = CoinbaseOutputPolicy::MustContain(vec![output(1000, 0x52)]);
+    assert!(custom_job_accepted_with_policy(
+        policy.clone(),
+        &[output(BLOCK_REWARD - 1000, 0x53), output(1000, 0x52)]
+    ));
+    assert!(!custom_job_accepted_with_policy(
+        policy.clone(),
+        &[output(BLOCK_REWARD - 999, 0x53), output(999, 0x52)]
+    ));
+    assert!(!custom_job_accepted_with_policy(
+        policy,
+        &[output(BLOCK_REWARD, 0x51)]
+    ));
+}
+
+#[test]
+fn test_coinbase_output_policy_min_value_to_script() {
+    let policy = CoinbaseOutputPolicy::MinValueToScript {
+        script: ScriptBuf::from(vec![0x52]),
+        min_sats: 1000,
+    };
+    // The value can be split across many outputs to the script
+    assert!(custom_job_accepted_with_policy(
+        policy.clone(),
+        &[
+            output(BLOCK_REWARD - 1000, 0x53),
+            output(500, 0x52),
+            output(500, 0x52)
+        ]
+    ));
+    assert!(!custom_job_accepted_with_policy(
+        policy,
+        &[output(BLOCK_REWARD - 999, 0x53), output(999, 0x52)]
+    ));
+}
+
+#[test]
+fn test_extended_channels_granted_requested_extranonce_size() {
+    let mut factory = new_pool_factory_with_ranges(0..2, 2..3);
+    let range_2_len = factory.available_extranonce2_size();
+    assert_eq!(range_2_len, EXTRANONCE_LEN - 3);
+
+    let mut channels = vec![];
+    for (request_id, size) in [8, 8, range_2_len, 4, 8, 4].iter().enumerate() {
+        let messages = factory
+            .new_extended_channel(request_id as u32, 0.0, *size as u16, ConnectionFlags::all())
+            .unwrap();
+        match &messages[0] {
+            Mining::OpenExtendedMiningChannelSuccess(success) => {
+                assert_eq!(success.extranonce_size as usize, *size);
+                let prefix = success.extranonce_prefix.to_vec();
+                assert_eq!(prefix.len() + size, EXTRANONCE_LEN);
+                channels.push((success.channel_id, prefix, *size));
+            }
+            m => panic!("Expected OpenExtendedMiningChannelSuccess, got {m}"),
+        }
+    }
+    // No prefix is the start of another, the extranonces of two channels never overlap
+    for (i, (_, prefix, _)) in channels.iter().enumerate() {
+        for (_, other, _) in &channels[i + 1..] {
+            assert!(!prefix.starts_with(other) && !other.starts_with(prefix));
+        }
+    }
+    // The channels of the same size share a prefix of the factory
+    assert_eq!(factory.remaining_extended_prefixes(), 255 - 3);
+
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    for (sequence_number, (channel_id, _, size)) in channels.iter().enumerate() {
+        let share = extended_share(*channel_id, sequence_number as u32, job_id, vec![0; *size]);
+        assert!(matches!(
+            factory.on_submit_shares_extended(share).unwrap(),
+            OnNewShare::ShareMeetDownstreamTarget
+        ));
+    }
+}
+
+#[test]
+fn test_pool_channel_factory_builder() {
+    let builder = || {
+        PoolChannelFactoryBuilder::new()
+            .ids(Arc::new(Mutex::new(GroupId::new())))
+            .extranonces(ExtendedExtranonce::new(0..0, 0..8, 8..EXTRANONCE_LEN, None).unwrap())
+            .share_per_min(SHARES_PER_MINUTE)
+            .pool_coinbase_outputs(pool_coinbase_outputs())
+    };
+    let mut factory = builder()
+        .job_creator(JobsCreators::new(EXTRANONCE_LEN as u8))
+        .build()
+        .unwrap();
+    open_extended_channel(&mut factory, 1);
+    activate_job(&mut factory, 1, MIN_TARGET);
+
+    assert!(matches!(
+        builder().build(),
+        Err(Error::MissingFactoryParameter("job_creator"))
+    ));
+}
+
+#[test]
+fn test_proxy_channel_factory_builder() {
+    let proxy_kind = || ExtendedChannelKind::Proxy {
+        upstream_target: MAX_TARGET.into(),
+    };
+    let proxy_jd_kind = || ExtendedChannelKind::ProxyJd {
+        upstream_target: MAX_TARGET.into(),
+    };
+    let builder = || {
+        ProxyChannelFactoryBuilder::new()
+            .ids(Arc::new(Mutex::new(GroupId::new())))
+            .extranonces(ExtendedExtranonce::new(0..4, 4..8, 8..EXTRANONCE_LEN, None).unwrap())
+            .share_per_min(SHARES_PER_MINUTE)
+            .extended_channel_id(1)
+    };
+    let job_creator = || JobsCreators::new(EXTRANONCE_LEN as u8);
+
+    let mut proxy = builder().kind(proxy_kind()).build().unwrap();
+    proxy_open_extended_channel(&mut proxy, 1);
+    assert!(builder()
+        .kind(proxy_jd_kind())
+        .job_creator(job_creator())
+        .pool_coinbase_outputs(pool_coinbase_outputs())
+        .build()
+        .is_ok());
+
+    // The combinations of kind and job creator are checked by
+    // `ProxyExtendedChannelFactory::try_new`, see `test_proxy_factory_try_new`
+    assert!(matches!(
+        builder().build(),
+        Err(Error::MissingFactoryParameter("kind"))
+    ));
+}
+
+#[test]
+fn test_proxy_factory_try_new() {
+    let try_new = |kind: ExtendedChannelKind, job_creator: Option<JobsCreators>| {
+        ProxyExtendedChannelFactory::try_new(
+            Arc::new(Mutex::new(GroupId::new())),
+            ExtendedExtranonce::new(0..4, 4..8, 8..EXTRANONCE_LEN, None).unwrap(),
+            job_creator,
+            SHARES_PER_MINUTE,
+            kind,
+            Some(pool_coinbase_outputs()),
+            1,
+        )
+    };
+    let proxy = || ExtendedChannelKind::Proxy {
+        upstream_target: MAX_TARGET.into(),
+    };
+    let proxy_jd = || ExtendedChannelKind::ProxyJd {
+        upstream_target: MAX_TARGET.into(),
+    };
+
+    // (kind, with a job creator, expected error), the errors are the combinations for which
+    // `ProxyExtendedChannelFactory::new` panics
+    let cases = [
+        (proxy(), false, None),
+        (proxy_jd(), true, None),
+        (proxy(), true, Some(Error::ProxyFactoryWithJobCreator)),
+        (
+            proxy_jd(),
+            false,
+            Some(Error::ProxyJdFactoryWithoutJobCreator),
+        ),
+        (
+            ExtendedChannelKind::Pool,
+            true,
+            Some(Error::ProxyFactoryWithPoolKind),
+        ),
+        (
+            ExtendedChannelKind::Pool,
+            false,
+            Some(Error::ProxyFactoryWithPoolKind),
+        ),
+    ];
+    for (kind, with_job_creator, expected) in cases {
+        let job_creator = with_job_creator.then(|| JobsCreators::new(EXTRANONCE_LEN as u8));
+        match (try_new(kind.clone(), job_creator), expected) {
+            (Ok(_), None) => (),
+            (Err(e), Some(expected))
+                if std::mem::discriminant(&e) == std::mem::discriminant(&expected) => {}
+            (result, expected) => panic!(
+                "Expected {:?} for {:?}, got {:?}",
+                expected,
+                kind,
+                result.err()
+            ),
+        }
+    }
+}
+
+#[test]
+fn test_relay_share_upstream() {
+    let mut proxy = new_proxy_factory(MAX_TARGET);
+    let channel_id = proxy_open_extended_channel(&mut proxy, 1);
+    let prefix = proxy.inner.extended_channels[&channel_id]
+        .extranonce_prefix
+        .to_vec();
+    let mut share = extended_share(channel_id, 7, 3, vec![0xab; DOWNSTREAM_EXTRANONCE_LEN]);
+    share.nonce = 42;
+
+    let relayed = match proxy
+        .relay_share_upstream(&Share::Extended(share.clone()), 100)
+        .unwrap()
+    {
+        Share::Extended(relayed) => relayed,
+        s => panic!("Expected an extended share, got {s:?}"),
+    };
+    assert_eq!(relayed.channel_id, proxy.get_this_channel_id());
+    assert_eq!(relayed.sequence_number, 100);
+    assert_eq!(relayed.job_id, share.job_id);
+    assert_eq!(relayed.nonce, share.nonce);
+    assert_eq!(relayed.ntime, share.ntime);
+    assert_eq!(relayed.version, share.version);
+    // The upstream part of the prefix is dropped
+    let upstream_len = proxy.get_upstream_extranonce1_len();
+    assert_eq!(
+        relayed.extranonce.to_vec(),
+        [
+            &prefix[upstream_len..],
+            &[0xab; DOWNSTREAM_EXTRANONCE_LEN][..]
+        ]
+        .concat()
+    );
+
+    share.channel_id = channel_id + 1;
+    assert!(matches!(
+        proxy.relay_share_upstream(&Share::Extended(share), 100),
+        Err(Error::NotFoundChannelId)
+    ));
+}
+
+#[test]
+fn test_estimated_hashrate() {
+    let clock = MockClock::new();
+    let mut factory = new_pool_factory();
+    factory.set_clock(Box::new(clock.clone()));
+    let hash_rate = 1_000_000_000.0;
+    let messages = factory
+        .new_extended_channel(
+            1,
+            hash_rate,
+            DOWNSTREAM_EXTRANONCE_LEN as u16,
+            ConnectionFlags::all(),
+        )
+        .unwrap();
+    let channel_id = match &messages[0] {
+        Mining::OpenExtendedMiningChannelSuccess(success) => success.channel_id,
+        m => panic!("Expected OpenExtendedMiningChannelSuccess, got {m}"),
+    };
+    let job_id = activate_job(&mut factory, 1, MIN_TARGET);
+    // Every share meets the downstream target whatever the channel target is
+    factory.set_test_targets(
+        Target::from(MAX_TARGET),
+        Target::from(MIN_TARGET),
+        Target::from(MIN_TARGET),
+    );
+    let difficulty = crate::utils::target_to_difficulty(
+        &factory.inner.extended_channels[&channel_id]
+            .target
+            .clone()
+            .into(),
+    );
+
+    let assert_estimate = |factory: &PoolChannelFactory, expected: f64| {
+        let estimate = factory.estimated_hashrate(channel_id).unwrap();
+        assert!((estimate - expected).abs() / expected < 0.01);
+    };
+    // One share every 2 seconds
+    let submit_shares = |factory: &mut PoolChannelFactory,
+                         sequence_numbers: std::ops::Range<u32>| {
+        for sequence_number in sequence_numbers {
+            if sequence_number == HASHRATE_ESTIMATE_MIN_SHARES as u32 - 1 {
+                assert_eq!(factory.estimated_hashrate(channel_id), None);
+            }
+            let mut share = extended_share(
+                channel_id,
+                sequence_number,
+                job_id,
+                vec![0; DOWNSTREAM_EXTRANONCE_LEN],
+            );
+            share.nonce = sequence_number;
+            factory.on_submit_shares_extended(share).unwrap();
+            clock.advance(Duration::from_secs(2));
+        }
+    };
+    submit_shares(&mut factory, 0..20);
+    assert_estimate(&factory, 0.5 * difficulty * 2_f64.powi(32));
+    assert_eq!(factory.estimated_hashrate(channel_id + 1), None);
+
+    // A target change does not restart the estimate, the shares accepted before it keep the
+    // difficulty they had
+    factory
+        .inner
+        .update_target_for_channel(
+            channel_id,
+            crate::utils::difficulty_to_target(difficulty * 2.0),
+        )
+        .unwrap();
+    assert_estimate(&factory, 0.5 * difficulty * 2_f64.powi(32));
+    submit_shares(&mut factory, 20..40);
+    // 19 shares at the old difficulty and 20 at the new one in 78 seconds
+    assert_estimate(&factory, (19.0 + 40.0) * difficulty / 78.0 * 2_f64.powi(32));
+}
@@ -0,0 +1,84 @@
+//! # Factory Snapshot
+//!
+//! Persistable state of a [`PoolChannelFactory`], taken with [`PoolChannelFactory::to_snapshot`]
+//! before a restart and loaded in a new factory with [`PoolChannelFactory::from_snapshot`].
+//!
+//! The Sv2 messages of the snapshot are kept in the Sv2 binary encoding and the targets as 32
+//! little endian bytes, so that with the `serde` feature a snapshot can be serialized in any
+//! serde format.
+//!
+//! [`PoolChannelFactory`]: super::channel_factory::PoolChannelFactory
+//! [`PoolChannelFactory::to_snapshot`]: super::channel_factory::PoolChannelFactory::to_snapshot
+//! [`PoolChannelFactory::from_snapshot`]: super::channel_factory::PoolChannelFactory::from_snapshot
+
+use super::channel_factory::ConnectionFlags;
+
+/// State of a `PoolChannelFactory` that must survive a restart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PoolChannelFactorySnapshot {
+    /// Extended channels, ordered by channel id
+    pub extended_channels: Vec<ExtendedChannelSnapshot>,
+    /// Standard channels, ordered by channel id
+    pub standard_channels: Vec<StandardChannelSnapshot>,
+    /// (channel id, group id) of every channel, ordered by channel id
+    pub channel_to_group_id: Vec<(u32, u32)>,
+    /// State of the extranonces handed out by the factory
+    pub extranonces: ExtranoncesSnapshot,
+    /// State of the group and channel ids handed out by the factory
+    pub ids: IdsSnapshot,
+    /// Sv2 encoded `SetNewPrevHash` of the current prev hash, its channel id is 0
+    pub last_prev_hash: Option<Vec<u8>>,
+    /// Sv2 encoded `NewExtendedMiningJob` of the active job and the id of its template
+    pub last_valid_job: Option<(Vec<u8>, u64)>,
+    /// Bitcoin target of the current prev hash
+    pub bitcoin_target: Vec<u8>,
+    /// Last job id handed out by the job creator
+    pub last_job_id: u32,
+    /// (channel id, job id, Sv2 encoded `SetCustomMiningJob`) of the negotiated jobs, ordered by
+    /// channel id
+    pub negotiated_jobs: Vec<(u32, u32, Vec<u8>)>,
+}
+
+/// An extended channel of a [`PoolChannelFactorySnapshot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExtendedChannelSnapshot {
+    /// Sv2 encoded `OpenExtendedMiningChannelSuccess` of the channel, with its current target and
+    /// extranonce prefix
+    pub channel: Vec<u8>,
+    /// Flags negotiated by the downstream of the channel
+    pub flags: ConnectionFlags,
+}
+
+/// A standard channel of a [`PoolChannelFactorySnapshot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StandardChannelSnapshot {
+    pub channel_id: u32,
+    pub group_id: u32,
+    pub target: Vec<u8>,
+    /// Full extranonce of the channel
+    pub extranonce: Vec<u8>,
+}
+
+/// The ids of a [`PoolChannelFactorySnapshot`]: the last group and channel ids handed out and the
+/// ids of the closed channels, oldest first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IdsSnapshot {
+    pub last_group_id: u32,
+    pub last_channel_id: u32,
+    pub freed_channel_ids: Vec<u32>,
+}
+
+/// The extranonces of a [`PoolChannelFactorySnapshot`]: the bytes of the last extranonce handed
+/// out and the end of its three ranges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExtranoncesSnapshot {
+    pub bytes: Vec<u8>,
+    pub range_0_end: usize,
+    pub range_1_end: usize,
+    pub range_2_end: usize,
+}
@@ -0,0 +1,489 @@
+//! # Input Recorder
+//!
+//! Records the inputs received by a [`PoolChannelFactory`] so that a session can be replayed into
+//! a fresh factory, for example to reproduce a share validation bug seen in production.
+//!
+//! Recording is opt-in, an [`InputRecorder`] is given to the factory with
+//! [`PoolChannelFactory::set_input_recorder`] and serializes the inputs of every template, prev
+//! hash, id request, channel open, update and close, extranonce prefix change, prev hash
+//! expiration and share submission.
+//! The recording is then applied in order to another factory with [`replay`].
+//! Each input is recorded with the reading of the factory [`Clock`], the replayed factory reads
+//! the recorded time so that the time dependent checks (ntime drift, closed channels ttl,
+//! vardiff, ...) have the same outcomes. The factory configuration (setters, share credit, ...)
+//! and the ids taken by others from the shared ids are not recorded, the fresh factory must be
+//! set up like the recorded one for the outcomes to be the same.
+
+use super::channel_factory::{Clock, ConnectionFlags, OnNewShare, PoolChannelFactory};
+use crate::{utils::Mutex, Error};
+
+use codec_sv2::binary_sv2;
+use mining_sv2::{SubmitSharesExtended, SubmitSharesStandard, UpdateChannel};
+use nohash_hasher::BuildNoHashHasher;
+use parsers_sv2::Mining;
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use template_distribution_sv2::{NewTemplate, SetNewPrevHash as SetNewPrevHashFromTp};
+use tracing::error;
+
+// Version of the binary encoding, it is the first byte of a recording
+const RECORDING_VERSION: u8 = 2;
+// tag + elapsed nanoseconds + unix time + payload len
+const ENTRY_HEADER_LEN: usize = 1 + 8 + 8 + 4;
+
+const NEW_TEMPLATE_TAG: u8 = 0;
+const NEW_PREV_HASH_TAG: u8 = 1;
+const OPEN_EXTENDED_CHANNEL_TAG: u8 = 2;
+const OPEN_STANDARD_CHANNEL_TAG: u8 = 3;
+const CLOSE_CHANNEL_TAG: u8 = 4;
+const EXPIRE_PREV_HASH_TAG: u8 = 5;
+const SUBMIT_SHARES_STANDARD_TAG: u8 = 6;
+const SUBMIT_SHARES_EXTENDED_TAG: u8 = 7;
+const NEW_GROUP_ID_TAG: u8 = 8;
+const NEW_STANDARD_ID_FOR_HOM_TAG: u8 = 9;
+const UPDATE_CHANNEL_TAG: u8 = 10;
+const SET_EXTRANONCE_PREFIX_TAG: u8 = 11;
+
+/// An input received by a [`PoolChannelFactory`], each variant is the call of a public method
+#[derive(Debug, Clone)]
+pub enum RecordedInput {
+    /// [`PoolChannelFactory::on_new_template`]
+    NewTemplate(NewTemplate<'static>),
+    /// [`PoolChannelFactory::on_new_prev_hash_from_tp`]
+    NewPrevHash(SetNewPrevHashFromTp<'static>),
+    /// [`PoolChannelFactory::new_extended_channel`] and
+    /// [`PoolChannelFactory::new_extended_channel_structured`]
+    OpenExtendedChannel {
+        request_id: u32,
+        hash_rate: f32,
+        min_extranonce_size: u16,
+        flags: ConnectionFlags,
+    },
+    /// [`PoolChannelFactory::new_standard_channel`]
+    OpenStandardChannel {
+        request_id: u32,
+        hash_rate: f32,
+        group_id: u32,
+    },
+    /// [`PoolChannelFactory::new_group_id`]
+    NewGroupId,
+    /// [`PoolChannelFactory::new_standard_id_for_hom`]
+    NewStandardIdForHom,
+    /// [`PoolChannelFactory::on_update_channel`]
+    UpdateChannel(UpdateChannel<'static>),
+    /// [`PoolChannelFactory::set_extranonce_prefix`]
+    SetExtranoncePrefix { channel_id: u32, prefix: Vec<u8> },
+    /// [`PoolChannelFactory::close_channel`]
+    CloseChannel(u32),
+    /// [`PoolChannelFactory::expire_prev_hash`]
+    ExpirePrevHash,
+    /// [`PoolChannelFactory::on_submit_shares_standard`]
+    SubmitSharesStandard(SubmitSharesStandard),
+    /// [`PoolChannelFactory::on_submit_shares_extended`]
+    SubmitSharesExtended(SubmitSharesExtended<'static>),
+}
+
+/// The time read from the factory [`Clock`] when an input was received
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ClockReading {
+    /// Time elapsed since the first recorded input
+    pub elapsed: Duration,
+    /// Unix time in seconds
+    pub unix_time: u64,
+}
+
+// Clock of a replayed factory, it returns the reading recorded with the input being replayed
+#[derive(Debug, Clone)]
+struct ReplayClock {
+    start: Instant,
+    reading: Arc<Mutex<ClockReading>>,
+}
+
+impl ReplayClock {
+    fn reading(&self) -> ClockReading {
+        // The lock is only held to copy the reading, if it is poisoned the replay starts over
+        self.reading
+            .safe_lock(|reading| *reading)
+            .unwrap_or_default()
+    }
+
+    fn set_reading(&self, reading: ClockReading) {
+        if self.reading.safe_lock(|r| *r = reading).is_err() {
+            error!("Replay clock lock poisoned, the clock is not updated");
+        }
+    }
+}
+
+impl Clock for ReplayClock {
+    fn now(&self) -> Instant {
+        self.start + self.reading().elapsed
+    }
+
+    fn unix_time(&self) -> u64 {
+        self.reading().unix_time
+    }
+}
+
+/// What the factory returned for a replayed [`RecordedInput`]
+#[derive(Debug)]
+pub enum ReplayOutcome {
+    /// Messages returned when a channel is opened
+    Channel(Result<Vec<Mining<'static>>, Error>),
+    /// Messages returned for a template or a prev hash, by channel id
+    Jobs(Result<HashMap<u32, Mining<'static>, BuildNoHashHasher<u32>>, Error>),
+    /// Id returned by an id request
+    Id(Result<u32, Error>),
+    /// Message returned for a channel update
+    ChannelUpdated(Result<Mining<'static>, Error>),
+    /// Message returned for an extranonce prefix change
+    ExtranoncePrefixSet(Result<Mining<'static>, Error>),
+    /// True if the closed channel was open
+    ChannelClosed(bool),
+    /// The prev hash has been expired
+    PrevHashExpired,
+    /// Outcome of a share submission
+    Share(Result<OnNewShare, Error>),
+}
+
+impl RecordedInput {
+    /// Calls the factory method that received the input and returns its outcome
+    pub fn apply(self, factory: &mut PoolChannelFactory) -> ReplayOutcome {
+        match self {
+            RecordedInput::NewTemplate(mut m) => {
+                ReplayOutcome::Jobs(factory.on_new_template(&mut m))
+            }
+            RecordedInput::NewPrevHash(m) => {
+                ReplayOutcome::Jobs(factory.on_new_prev_hash_from_tp(&m))
+            }
+            RecordedInput::OpenExtendedChannel {
+                request_id,
+                hash_rate,
+                min_extranonce_size,
+                flags,
+            } => ReplayOutcome::Channel(factory.new_extended_channel(
+                request_id,
+                hash_rate,
+                min_extranonce_size,
+                flags,
+            )),
+            RecordedInput::OpenStandardChannel {
+                request_id,
+                hash_rate,
+                group_id,
+            } => ReplayOutcome::Channel(
+                factory.new_standard_channel(request_id, hash_rate, group_id),
+            ),
+            RecordedInput::NewGroupId => ReplayOutcome::Id(factory.new_group_id()),
+            RecordedInput::NewStandardIdForHom => {
+                ReplayOutcome::Id(factory.new_standard_id_for_hom())
+            }
+            RecordedInput::UpdateChannel(m) => {
+                ReplayOutcome::ChannelUpdated(factory.on_update_channel(m))
+            }
+            RecordedInput::SetExtranoncePrefix { channel_id, prefix } => {
+                ReplayOutcome::ExtranoncePrefixSet(
+                    factory.set_extranonce_prefix(channel_id, prefix),
+                )
+            }
+            RecordedInput::CloseChannel(channel_id) => {
+                ReplayOutcome::ChannelClosed(factory.close_channel(channel_id))
+            }
+            RecordedInput::ExpirePrevHash => {
+                factory.expire_prev_hash();
+                ReplayOutcome::PrevHashExpired
+            }
+            RecordedInput::SubmitSharesStandard(m) => {
+                ReplayOutcome::Share(factory.on_submit_shares_standard(m))
+            }
+            RecordedInput::SubmitSharesExtended(m) => {
+                ReplayOutcome::Share(factory.on_submit_shares_extended(m))
+            }
+        }
+    }
+
+    // Returns the tag and the payload of the entry that encodes the input
+    fn encode(&self) -> Result<(u8, Vec<u8>), Error> {
+        Ok(match self {
+            RecordedInput::NewTemplate(m) => (NEW_TEMPLATE_TAG, binary_sv2::to_bytes(m.clone())?),
+            RecordedInput::NewPrevHash(m) => (NEW_PREV_HASH_TAG, binary_sv2::to_bytes(m.clone())?),
+            RecordedInput::OpenExtendedChannel {
+                request_id,
+                hash_rate,
+                min_extranonce_size,
+                flags,
+            } => {
+                let mut payload = Vec::with_capacity(4 + 4 + 2 + 1 + 1);
+                payload.extend_from_slice(&request_id.to_le_bytes());
+                payload.extend_from_slice(&hash_rate.to_le_bytes());
+                payload.extend_from_slice(&min_extranonce_size.to_le_bytes());
+                payload.push(flags.version_rolling as u8);
+                payload.push(flags.work_selection as u8);
+                (OPEN_EXTENDED_CHANNEL_TAG, payload)
+            }
+            RecordedInput::OpenStandardChannel {
+                request_id,
+                hash_rate,
+                group_id,
+            } => {
+                let mut payload = Vec::with_capacity(4 + 4 + 4);
+                payload.extend_from_slice(&request_id.to_le_bytes());
+                payload.extend_from_slice(&hash_rate.to_le_bytes());
+                payload.extend_from_slice(&group_id.to_le_bytes());
+                (OPEN_STANDARD_CHANNEL_TAG, payload)
+            }
+            RecordedInput::UpdateChannel(m) => {
+                (UPDATE_CHANNEL_TAG, binary_sv2::to_bytes(m.clone())?)
+            }
+            RecordedInput::SetExtranoncePrefix { channel_id, prefix } => {
+                let mut payload = Vec::with_capacity(4 + prefix.len());
+                payload.extend_from_slice(&channel_id.to_le_bytes());
+                payload.extend_from_slice(prefix);
+                (SET_EXTRANONCE_PREFIX_TAG, payload)
+            }
+            RecordedInput::CloseChannel(channel_id) => {
+                (CLOSE_CHANNEL_TAG, channel_id.to_le_bytes().to_vec())
+            }
+            RecordedInput::NewGroupId => (NEW_GROUP_ID_TAG, vec![]),
+            RecordedInput::NewStandardIdForHom => (NEW_STANDARD_ID_FOR_HOM_TAG, vec![]),
+            RecordedInput::ExpirePrevHash => (EXPIRE_PREV_HASH_TAG, vec![]),
+            RecordedInput::SubmitSharesStandard(m) => {
+                (SUBMIT_SHARES_STANDARD_TAG, binary_sv2::to_bytes(m.clone())?)
+            }
+            RecordedInput::SubmitSharesExtended(m) => {
+                (SUBMIT_SHARES_EXTENDED_TAG, binary_sv2::to_bytes(m.clone())?)
+            }
+        })
+    }
+
+    fn decode(tag: u8, payload: &[u8]) -> Result<Self, Error> {
+        // Sv2 messages are decoded in place
+        let mut message = payload.to_vec();
+        let field = |start: usize, len: usize| -> Result<&[u8], Error> {
+            payload
+                .get(start..start + len)
+                .ok_or(Error::MalformedRecording)
+        };
+        // Infallible unwraps `field` returns slices of the requested len
+        let u32_at = |start: usize| -> Result<u32, Error> {
+            Ok(u32::from_le_bytes(field(start, 4)?.try_into().unwrap()))
+        };
+        let f32_at = |start: usize| -> Result<f32, Error> {
+            Ok(f32::from_le_bytes(field(start, 4)?.try_into().unwrap()))
+        };
+        let input = match tag {
+            NEW_TEMPLATE_TAG => RecordedInput::NewTemplate(
+                binary_sv2::from_bytes::<NewTemplate>(&mut message)
+                    .map_err(|_| Error::MalformedRecording)?
+                    .into_static(),
+            ),
+            NEW_PREV_HASH_TAG => RecordedInput::NewPrevHash(
+                binary_sv2::from_bytes::<SetNewPrevHashFromTp>(&mut message)
+                    .map_err(|_| Error::MalformedRecording)?
+                    .into_static(),
+            ),
+            OPEN_EXTENDED_CHANNEL_TAG if payload.len() == 12 => {
+                RecordedInput::OpenExtendedChannel {
+                    request_id: u32_at(0)?,
+                    hash_rate: f32_at(4)?,
+                    min_extranonce_size: u16::from_le_bytes(field(8, 2)?.try_into().unwrap()),
+                    flags: ConnectionFlags {
+                        version_rolling: payload[10] != 0,
+                        work_selection: payload[11] != 0,
+                    },
+                }
+            }
+            OPEN_STANDARD_CHANNEL_TAG if payload.len() == 12 => {
+                RecordedInput::OpenStandardChannel {
+                    request_id: u32_at(0)?,
+                    hash_rate: f32_at(4)?,
+                    group_id: u32_at(8)?,
+                }
+            }
+            UPDATE_CHANNEL_TAG => RecordedInput::UpdateChannel(
+                binary_sv2::from_bytes::<UpdateChannel>(&mut message)
+                    .map_err(|_| Error::MalformedRecording)?
+                    .into_static(),
+            ),
+            SET_EXTRANONCE_PREFIX_TAG => RecordedInput::SetExtranoncePrefix {
+                channel_id: u32_at(0)?,
+                prefix: payload[4..].to_vec(),
+            },
+            CLOSE_CHANNEL_TAG if payload.len() == 4 => RecordedInput::CloseChannel(u32_at(0)?),
+            NEW_GROUP_ID_TAG if payload.is_empty() => RecordedInput::NewGroupId,
+            NEW_STANDARD_ID_FOR_HOM_TAG if payload.is_empty() => RecordedInput::NewStandardIdForHom,
+            EXPIRE_PREV_HASH_TAG if payload.is_empty() => RecordedInput::ExpirePrevHash,
+            SUBMIT_SHARES_STANDARD_TAG => RecordedInput::SubmitSharesStandard(
+                binary_sv2::from_bytes::<SubmitSharesStandard>(&mut message)
+                    .map_err(|_| Error::MalformedRecording)?,
+            ),
+            SUBMIT_SHARES_EXTENDED_TAG => RecordedInput::SubmitSharesExtended(
+                binary_sv2::from_bytes::<SubmitSharesExtended>(&mut message)
+                    .map_err(|_| Error::MalformedRecording)?
+                    .into_static(),
+            ),
+            _ => return Err(Error::MalformedRecording),
+        };
+        Ok(input)
+    }
+
+    /// Decodes the inputs of a recording made by an [`InputRecorder`] with the clock reading of
+    /// each input, in the order they have been received.
+    pub fn from_recording(recording: &[u8]) -> Result<Vec<(Self, ClockReading)>, Error> {
+        match recording.split_first() {
+            Some((&RECORDING_VERSION, mut entries)) => {
+                let mut inputs = Vec::new();
+                while !entries.is_empty() {
+                    if entries.len() < ENTRY_HEADER_LEN {
+                        return Err(Error::MalformedRecording);
+                    }
+                    // Infallible unwraps we already checked the len of `entries`
+                    let elapsed = u64::from_le_bytes(entries[1..9].try_into().unwrap());
+                    let unix_time = u64::from_le_bytes(entries[9..17].try_into().unwrap());
+                    let len = u32::from_le_bytes(entries[17..ENTRY_HEADER_LEN].try_into().unwrap())
+                        as usize;
+                    let payload = entries
+                        .get(ENTRY_HEADER_LEN..ENTRY_HEADER_LEN + len)
+                        .ok_or(Error::MalformedRecording)?;
+                    let reading = ClockReading {
+                        elapsed: Duration::from_nanos(elapsed),
+                        unix_time,
+                    };
+                    inputs.push((Self::decode(entries[0], payload)?, reading));
+                    entries = &entries[ENTRY_HEADER_LEN + len..];
+                }
+                Ok(inputs)
+            }
+            _ => Err(Error::MalformedRecording),
+        }
+    }
+}
+
+/// Serializes the inputs received by a [`PoolChannelFactory`] in a compact binary recording.
+///
+/// A recording is the encoding version (1 byte) followed by an entry for each input: a tag (1
+/// byte) telling which input it is, the clock reading (nanoseconds elapsed since the first input
+/// and unix time in seconds, u64 little endian), the payload len (u32 little endian) and the
+/// payload. Sv2 messages are encoded in the Sv2 binary format, the other inputs as their fields
+/// in little endian.
+#[derive(Debug, Clone)]
+pub struct InputRecorder {
+    recording: Vec<u8>,
+    // Clock instant of the first recorded input
+    start: Option<Instant>,
+}
+
+impl Default for InputRecorder {
+    fn default() -> Self {
+        Self {
+            recording: vec![RECORDING_VERSION],
+            start: None,
+        }
+    }
+}
+
+impl InputRecorder {
+    /// Creates an [`InputRecorder`] with an empty recording.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Called by the factory for each input it receives with the reading of its clock. An input
+    // that can not be encoded is not recorded, a replay of the recording would then diverge from
+    // the recorded session.
+    pub(crate) fn record(&mut self, input: RecordedInput, now: Instant, unix_time: u64) {
+        match input.encode() {
+            Ok((tag, payload)) => {
+                let start = *self.start.get_or_insert(now);
+                let elapsed = now.saturating_duration_since(start).as_nanos() as u64;
+                self.recording.push(tag);
+                self.recording.extend_from_slice(&elapsed.to_le_bytes());
+                self.recording.extend_from_slice(&unix_time.to_le_bytes());
+                self.recording
+                    .extend_from_slice(&(payload.len() as u32).to_le_bytes());
+                self.recording.extend_from_slice(&payload);
+            }
+            Err(e) => error!("Failed to record {:?}: {}", input, e),
+        }
+    }
+
+    /// Returns the recording.
+    pub fn recording(&self) -> &[u8] {
+        &self.recording
+    }
+
+    /// Returns the recording, consuming the recorder.
+    pub fn into_recording(self) -> Vec<u8> {
+        self.recording
+    }
+}
+
+/// Applies in order the inputs of `recording` to `factory` and returns the outcome of each input.
+/// The clock of `factory` is replaced by one that returns the time recorded with the input being
+/// applied, it keeps returning the time of the last input after the replay.
+/// An error is returned only when `recording` is malformed, the errors returned by the factory are
+/// part of the outcomes.
+pub fn replay(
+    recording: &[u8],
+    factory: &mut PoolChannelFactory,
+) -> Result<Vec<ReplayOutcome>, Error> {
+    let inputs = RecordedInput::from_recording(recording)?;
+    let clock = ReplayClock {
+        start: Instant::now(),
+        reading: Arc::new(Mutex::new(ClockReading::default())),
+    };
+    factory.set_clock(Box::new(clock.clone()));
+    Ok(inputs
+        .into_iter()
+        .map(|(input, reading)| {
+            clock.set_reading(reading);
+            input.apply(factory)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recording(inputs: &[RecordedInput]) -> Vec<u8> {
+        let mut recorder = InputRecorder::new();
+        let start = Instant::now();
+        for (i, input) in inputs.iter().enumerate() {
+            let elapsed = Duration::from_secs(i as u64);
+            recorder.record(input.clone(), start + elapsed, 1_700_000_000 + i as u64);
+        }
+        recorder.into_recording()
+    }
+
+    #[test]
+    fn test_recording_from_malformed_bytes() {
+        let bytes = recording(&[
+            RecordedInput::OpenStandardChannel {
+                request_id: 1,
+                hash_rate: 10.0,
+                group_id: 1,
+            },
+            RecordedInput::CloseChannel(2),
+            RecordedInput::ExpirePrevHash,
+        ]);
+        let inputs = RecordedInput::from_recording(&bytes).unwrap();
+        assert_eq!(inputs.len(), 3);
+        assert_eq!(
+            inputs[2].1,
+            ClockReading {
+                elapsed: Duration::from_secs(2),
+                unix_time: 1_700_000_002,
+            }
+        );
+        assert_eq!(RecordedInput::from_recording(&bytes[..1]).unwrap().len(), 0);
+        assert!(RecordedInput::from_recording(&bytes[..bytes.len() - 6]).is_err());
+        assert!(RecordedInput::from_recording(&[]).is_err());
+        let mut bytes = bytes;
+        bytes[0] = RECORDING_VERSION + 1;
+        assert!(RecordedInput::from_recording(&bytes).is_err());
+    }
+}
@@ -2,11 +2,17 @@
 //!
 //! A module for managing channels on applications.
 //!
-//! Divided in two submodules:
+//! Divided in the following submodules:
 //! - [`channel_factory`]
+//! - [`factory_snapshot`]
+//! - [`input_recorder`]
 //! - [`proxy_group_channel`]
+//! - [`share_accounting`]
 
 pub mod channel_factory;
+pub mod factory_snapshot;
+pub mod input_recorder;
+pub mod share_accounting;
 
 use mining_sv2::{NewExtendedMiningJob, NewMiningJob};
 use std::convert::TryInto;
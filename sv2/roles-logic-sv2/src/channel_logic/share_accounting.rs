@@ -0,0 +1,154 @@
+//! # Share Accounting
+//!
+//! Per channel accounting of the shares checked by the channel factories.
+//!
+//! A [`ShareAccounting`] can be exported in a compact binary form and merged with other
+//! accountings, so that the stats of many processes can be aggregated.
+
+use crate::Error;
+use std::convert::TryInto;
+
+// Version of the binary encoding, it is the first byte of the encoded accounting
+const ENCODING_VERSION: u8 = 1;
+// version + shares accepted + shares rejected + blocks found + difficulty sum
+const ENCODED_LEN: usize = 1 + 8 + 8 + 8 + 8;
+
+/// Accounting of the shares submitted on a channel.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShareAccounting {
+    shares_accepted: u64,
+    shares_rejected: u64,
+    blocks_found: u64,
+    // Sum of the channel difficulty at the time each accepted share was submitted
+    difficulty_sum: f64,
+}
+
+impl ShareAccounting {
+    /// Creates an empty [`ShareAccounting`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Called for each share that meets at least the channel target, `difficulty` is the
+    // channel difficulty
+    pub(crate) fn on_accepted_share(&mut self, difficulty: f64) {
+        self.shares_accepted += 1;
+        self.difficulty_sum += difficulty;
+    }
+
+    // Called for each share that is rejected
+    pub(crate) fn on_rejected_share(&mut self) {
+        self.shares_rejected += 1;
+    }
+
+    // Called for each share that meets the bitcoin target
+    pub(crate) fn on_block_found(&mut self) {
+        self.blocks_found += 1;
+    }
+
+    /// Returns the number of accepted shares.
+    pub fn shares_accepted(&self) -> u64 {
+        self.shares_accepted
+    }
+
+    /// Returns the number of rejected shares.
+    pub fn shares_rejected(&self) -> u64 {
+        self.shares_rejected
+    }
+
+    /// Returns the number of shares that met the bitcoin target.
+    pub fn blocks_found(&self) -> u64 {
+        self.blocks_found
+    }
+
+    /// Returns the sum of the channel difficulty of every accepted share.
+    pub fn difficulty_sum(&self) -> f64 {
+        self.difficulty_sum
+    }
+
+    /// Adds the counts and the difficulty sum of `other` to `self`.
+    pub fn merge(&mut self, other: &ShareAccounting) {
+        self.shares_accepted += other.shares_accepted;
+        self.shares_rejected += other.shares_rejected;
+        self.blocks_found += other.blocks_found;
+        self.difficulty_sum += other.difficulty_sum;
+    }
+
+    /// Encodes the accounting as: encoding version (1 byte), shares accepted, shares rejected,
+    /// blocks found (u64 little endian) and difficulty sum (f64 little endian).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(ENCODED_LEN);
+        bytes.push(ENCODING_VERSION);
+        bytes.extend_from_slice(&self.shares_accepted.to_le_bytes());
+        bytes.extend_from_slice(&self.shares_rejected.to_le_bytes());
+        bytes.extend_from_slice(&self.blocks_found.to_le_bytes());
+        bytes.extend_from_slice(&self.difficulty_sum.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes an accounting encoded with [`ShareAccounting::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != ENCODED_LEN || bytes[0] != ENCODING_VERSION {
+            return Err(Error::MalformedShareAccounting);
+        }
+        // Infallible unwrap we already checked the len of `bytes`
+        let field = |index: usize| -> [u8; 8] {
+            bytes[1 + index * 8..1 + (index + 1) * 8]
+                .try_into()
+                .unwrap()
+        };
+        Ok(Self {
+            shares_accepted: u64::from_le_bytes(field(0)),
+            shares_rejected: u64::from_le_bytes(field(1)),
+            blocks_found: u64::from_le_bytes(field(2)),
+            difficulty_sum: f64::from_le_bytes(field(3)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accounting(accepted: u64, rejected: u64, blocks: u64, difficulty: f64) -> ShareAccounting {
+        let mut accounting = ShareAccounting::new();
+        for _ in 0..accepted {
+            accounting.on_accepted_share(difficulty);
+        }
+        for _ in 0..rejected {
+            accounting.on_rejected_share();
+        }
+        for _ in 0..blocks {
+            accounting.on_block_found();
+        }
+        accounting
+    }
+
+    #[test]
+    fn test_share_accounting_bytes_round_trip() {
+        let accounting = accounting(10, 3, 1, 1.5);
+        let bytes = accounting.to_bytes();
+        assert_eq!(bytes.len(), ENCODED_LEN);
+        assert_eq!(ShareAccounting::from_bytes(&bytes).unwrap(), accounting);
+    }
+
+    #[test]
+    fn test_share_accounting_from_malformed_bytes() {
+        let mut bytes = accounting(1, 1, 1, 1.0).to_bytes();
+        assert!(ShareAccounting::from_bytes(&bytes[..ENCODED_LEN - 1]).is_err());
+        bytes[0] = ENCODING_VERSION + 1;
+        assert!(ShareAccounting::from_bytes(&bytes).is_err());
+        assert!(ShareAccounting::from_bytes(&[]).is_err());
+    }
+
+    #[test]
+    fn test_share_accounting_merge() {
+        let mut first = accounting(10, 3, 1, 1.5);
+        let second = ShareAccounting::from_bytes(&accounting(4, 2, 2, 0.5).to_bytes()).unwrap();
+        first.merge(&second);
+        assert_eq!(first.shares_accepted(), 14);
+        assert_eq!(first.shares_rejected(), 5);
+        assert_eq!(first.blocks_found(), 3);
+        assert_eq!(first.difficulty_sum(), 10.0 * 1.5 + 4.0 * 0.5);
+    }
+}
@@ -129,10 +129,85 @@ pub enum Error {
     FailedToSendSolution,
     FailedToSetCustomMiningJob(ExtendedChannelError),
     FailedToDeserializeCoinbaseOutputs,
+    /// No pool coinbase outputs have been configured, a coinbase without outputs is invalid
+    NoCoinbaseOutputsConfigured,
+    /// Bytes are not a valid encoded `ShareAccounting`
+    MalformedShareAccounting,
+    /// Bytes are not a valid recording of factory inputs
+    MalformedRecording,
+    /// A snapshot of a channel factory can not be loaded
+    MalformedSnapshot,
+    /// No block has been found for the share and coinbase. Params: job id
+    UnknownFoundBlock(u32),
+    /// The extranonce prefix can not be assigned to the extended channel, it does not leave the
+    /// channel its extranonce2 size, is outside of the upstream range or overlaps the prefix of
+    /// another channel. Params: channel id
+    InvalidExtranoncePrefix(u32),
+    /// The transactions of the block do not commit to the merkle root of the found header
+    BlockMerkleRootMismatch,
+    /// Coinbase has more outputs than allowed. Params: (outputs, max allowed)
+    TooManyCoinbaseOutputs(usize, usize),
+    /// Coinbase prefix + extranonce + coinbase suffix is not a valid transaction or the extranonce
+    /// is not inside the coinbase input script
+    MalformedCoinbaseSplit,
+    /// A channel set a custom mining job without negotiating work selection. Params: channel id
+    WorkSelectionNotNegotiated(u32),
+    /// The merkle path of a job created from a template differs from the template merkle path
+    MerklePathMismatch,
+    /// A template claims more than the block subsidy plus the max fees. Params: (height, subsidy,
+    /// value remaining)
+    InvalidTemplateSubsidy(u64, u64, u64),
+    /// A prev hash has nbits that do not encode a valid target. Params: nbits
+    InvalidNbits(u32),
+    /// No future template is stored for the job activated by a prev hash. Params: job id, None if
+    /// the prev hash activates no job
+    FutureTemplateMissing(Option<u32>),
+    /// The witness commitment output of a template does not commit to the template
+    /// transactions. Params: template id
+    InvalidWitnessCommitment(u64),
+    /// Channels that are not open standard channels. Params: channel ids
+    UnknownStandardChannels(Vec<u32>),
+    /// A channel factory builder is missing a required parameter. Params: parameter name
+    MissingFactoryParameter(&'static str),
+    /// A proxy channel factory of kind Proxy is given a job creator
+    ProxyFactoryWithJobCreator,
+    /// A proxy channel factory of kind ProxyJd is not given a job creator
+    ProxyJdFactoryWithoutJobCreator,
+    /// A proxy channel factory is given the Pool kind
+    ProxyFactoryWithPoolKind,
+    /// The coinbase and the merkle root of a share can not be reconstructed from its job. Params:
+    /// the part of the job that is malformed
+    InvalidShareCoinbase(CoinbasePart),
     /// Error from parsers_sv2
     ParserError(parsers_sv2::ParserError),
 }
 
+/// The part of a job that makes the coinbase of a share impossible to reconstruct, see
+/// [`Error::InvalidShareCoinbase`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoinbasePart {
+    /// The coinbase prefix is not the start of a transaction up to the script of the coinbase
+    /// input
+    Prefix,
+    /// The extranonce does not fit in the script of the coinbase input
+    ExtranonceLen,
+    /// The coinbase suffix does not complete the transaction
+    Suffix,
+    /// A hash of the merkle path is not 32 bytes long
+    MerklePath,
+}
+
+impl Display for CoinbasePart {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CoinbasePart::Prefix => write!(f, "coinbase prefix"),
+            CoinbasePart::ExtranonceLen => write!(f, "extranonce len"),
+            CoinbasePart::Suffix => write!(f, "coinbase suffix"),
+            CoinbasePart::MerklePath => write!(f, "merkle path"),
+        }
+    }
+}
+
 impl From<BinarySv2Error> for Error {
     fn from(v: BinarySv2Error) -> Error {
         Error::BinarySv2Error(v)
@@ -257,6 +332,28 @@ impl Display for Error {
             FailedToProcessSetNewPrevHashExtendedChannel(e) => write!(f, "Failed to process SetNewPrevHash: {e:?}"),
             FailedToProcessSetNewPrevHashStandardChannel(e) => write!(f, "Failed to process SetNewPrevHash: {e:?}"),
             FailedToDeserializeCoinbaseOutputs => write!(f, "Failed to deserialize coinbase outputs"),
+            NoCoinbaseOutputsConfigured => write!(f, "No pool coinbase outputs have been configured"),
+            MalformedShareAccounting => write!(f, "Bytes are not a valid encoded ShareAccounting"),
+            MalformedRecording => write!(f, "Bytes are not a valid recording of factory inputs"),
+            MalformedSnapshot => write!(f, "Malformed channel factory snapshot"),
+            UnknownFoundBlock(job_id) => write!(f, "No block has been found for the share on job {job_id}"),
+            InvalidExtranoncePrefix(channel_id) => write!(f, "Invalid extranonce prefix for channel {channel_id}"),
+            BlockMerkleRootMismatch => write!(f, "Block transactions do not match the merkle root of the found header"),
+            TooManyCoinbaseOutputs(outputs, max) => write!(f, "Too many coinbase outputs: {outputs}, max allowed {max}"),
+            MalformedCoinbaseSplit => write!(f, "Coinbase prefix and suffix do not wrap the extranonce in a valid coinbase script"),
+            WorkSelectionNotNegotiated(channel_id) => write!(f, "Channel {channel_id} set a custom mining job without negotiating work selection"),
+            MerklePathMismatch => write!(f, "Job merkle path differs from the template merkle path"),
+            InvalidTemplateSubsidy(height, subsidy, value) => write!(f, "Template claims {value} sats but the subsidy at height {height} is {subsidy} sats"),
+            InvalidNbits(nbits) => write!(f, "Nbits {nbits:#010x} do not encode a valid target"),
+            FutureTemplateMissing(Some(job_id)) => write!(f, "No future template for job {job_id} activated by the prev hash"),
+            FutureTemplateMissing(None) => write!(f, "No future template, the prev hash activates no job"),
+            InvalidWitnessCommitment(template_id) => write!(f, "Template {template_id} has an invalid witness commitment"),
+            UnknownStandardChannels(channel_ids) => write!(f, "Channels {channel_ids:?} are not open standard channels"),
+            MissingFactoryParameter(parameter) => write!(f, "Channel factory built without {parameter}"),
+            ProxyFactoryWithJobCreator => write!(f, "Channel factory of kind Proxy can not be initialized with a JobCreators"),
+            ProxyJdFactoryWithoutJobCreator => write!(f, "Channel factory of kind ProxyJd must be initialized with a JobCreators"),
+            ProxyFactoryWithPoolKind => write!(f, "ProxyExtendedChannelFactory kind must be Proxy or ProxyJd, not Pool"),
+            InvalidShareCoinbase(part) => write!(f, "Coinbase of the share can not be reconstructed, invalid {part}"),
             ParserError(v) => write!(f, "Parser error: {v}"),
         }
     }
@@ -63,11 +63,73 @@ impl JobsCreators {
         }
     }
 
+    /// Returns a job id that is never given to the jobs created from templates, for the jobs that
+    /// do not come from a template
+    pub(crate) fn new_job_id(&mut self) -> u32 {
+        self.skip_live_job_ids(&[]);
+        self.ids.next()
+    }
+
+    // Skips the ids in `live_job_ids` and the ids of the jobs mapped to a template, so that once
+    // the job ids wrap around a new job never takes the id of a job that is still in use
+    pub(crate) fn skip_live_job_ids(&mut self, live_job_ids: &[u32]) {
+        let job_to_template_id = &self.job_to_template_id;
+        self.ids
+            .skip_in_use(|id| live_job_ids.contains(&id) || job_to_template_id.contains_key(&id));
+    }
+
+    // Returns the last job id handed out
+    pub(crate) fn last_job_id(&self) -> u32 {
+        self.ids.last()
+    }
+
+    // Restores a new job creator from a factory snapshot: the job ids follow `last_job_id`, the
+    // active job (job id, template id) is mapped to its template and the target and ntime are the
+    // ones of the current prev hash
+    pub(crate) fn restore(
+        &mut self,
+        last_job_id: u32,
+        active_job: Option<(u32, u64)>,
+        target: mining_sv2::Target,
+        ntime: Option<u32>,
+    ) {
+        self.ids.skip_to(last_job_id);
+        if let Some((job_id, template_id)) = active_job {
+            // Template ids are stored + 1, see `on_new_template`
+            self.job_to_template_id.insert(job_id, template_id + 1);
+            self.templte_to_job_id.insert(template_id + 1, job_id);
+        }
+        self.last_target = target;
+        self.last_ntime = ntime;
+    }
+
+    // Drops the job to template mappings of the jobs that are not in `job_ids`, the ids are not
+    // compared by age as they wrap around
+    pub(crate) fn retain_mappings(&mut self, job_ids: &[u32]) {
+        self.job_to_template_id.retain(|id, _| job_ids.contains(id));
+        self.templte_to_job_id.retain(|_, id| job_ids.contains(id));
+    }
+
+    /// Returns the number of jobs mapped to their template
+    pub fn template_mappings_len(&self) -> usize {
+        self.job_to_template_id.len()
+    }
+
     /// Get template id from job
     pub fn get_template_id_from_job(&self, job_id: u32) -> Option<u64> {
         self.job_to_template_id.get(&job_id).map(|x| x - 1)
     }
 
+    /// Get the coinbase value of the template used for the job, None if the template is no longer
+    /// stored
+    pub fn coinbase_value_from_job(&self, job_id: u32) -> Option<u64> {
+        let template_id = self.get_template_id_from_job(job_id)?;
+        self.lasts_new_template
+            .iter()
+            .find(|template| template.template_id == template_id)
+            .map(|template| template.coinbase_tx_value_remaining)
+    }
+
     /// Used to create new jobs when a new template arrives
     pub fn on_new_template(
         &mut self,
@@ -84,6 +146,7 @@ impl JobsCreators {
         // Then we will do the inverse (-1) where needed
         let template_id = template.template_id + 1;
         self.lasts_new_template.push(template.as_static());
+        self.skip_live_job_ids(&[]);
         let next_job_id = self.ids.next();
         self.job_to_template_id.insert(next_job_id, template_id);
         self.templte_to_job_id.insert(template_id, next_job_id);
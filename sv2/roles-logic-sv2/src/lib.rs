@@ -17,6 +17,8 @@
 //! This crate can be built with the following features:
 //!
 //! - `prop_test`: Enables support for property testing in [`template_distribution_sv2`] crate.
+//! - `test_targets`: Allows overriding the targets used by the channel factories when checking
+//!   shares, so that every share outcome can be tested deterministically.
 pub mod channel_logic;
 pub mod errors;
 pub mod handlers;
@@ -20,6 +20,7 @@ use mining_sv2::Target;
 use primitive_types::U256 as U256Primitive;
 use std::{
     cmp::max,
+    collections::VecDeque,
     convert::TryInto,
     fmt::Write,
     io::Cursor,
@@ -44,12 +45,34 @@ impl Id {
         Self { state: 0 }
     }
 
-    /// Increments then returns the internal state on a new ID.
+    /// Increments then returns the internal state on a new ID. After `u32::MAX` it wraps around to
+    /// `1`, `0` is never returned.
     #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> u32 {
-        self.state += 1;
+        self.state = self.state.checked_add(1).unwrap_or(1);
         self.state
     }
+
+    // Advances the state so that the next id returned is the first one for which `in_use` is false
+    pub(crate) fn skip_in_use(&mut self, in_use: impl Fn(u32) -> bool) {
+        loop {
+            let next = self.state.checked_add(1).unwrap_or(1);
+            if !in_use(next) {
+                break;
+            }
+            self.state = next;
+        }
+    }
+
+    // Returns the last id handed out, 0 if none
+    pub(crate) fn last(&self) -> u32 {
+        self.state
+    }
+
+    // Makes the next ids follow `last`, unless greater ids have already been handed out
+    pub(crate) fn skip_to(&mut self, last: u32) {
+        self.state = self.state.max(last);
+    }
 }
 
 impl Default for Id {
@@ -226,6 +249,49 @@ pub fn merkle_root_from_path_<T: AsRef<[u8]>>(coinbase_id: [u8; 32], path: &[T])
     }
 }
 
+/// Computes the Merkle branch of the coinbase transaction of a block, i.e. the `path` that
+/// [`merkle_root_from_path`] combines with the coinbase hash to get the Merkle root.
+///
+/// If the block only contains the coinbase transaction, the branch is empty.
+///
+/// ## Components
+/// * `txids`: Hashes of every transaction in the block in block order, the coinbase first. The
+///   coinbase hash is never part of the branch, so any value can be used for it.
+pub fn coinbase_merkle_branch(txids: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    let mut branch = Vec::new();
+    let mut level = txids.to_vec();
+    while level.len() > 1 {
+        // As in bitcoin, the last hash of a level with an odd number of hashes is paired with
+        // itself
+        if level.len() % 2 == 1 {
+            level.push(level[level.len() - 1]);
+        }
+        branch.push(level[1]);
+        level = level
+            .chunks(2)
+            .map(|pair| DHash::hash(&[&pair[0][..], &pair[1][..]].concat()).to_byte_array())
+            .collect();
+    }
+    branch
+}
+
+/// Computes the witness commitment of a block as defined in BIP141: the double-SHA256 of the
+/// witness Merkle root followed by the witness reserved value.
+///
+/// The coinbase wtxid is `0x00..00` in the witness Merkle tree and the witness reserved value is
+/// assumed to be 32 zero bytes, as in the blocks built by bitcoin core.
+///
+/// ## Components
+/// * `wtxids`: Witness hashes of every transaction in the block in block order, excluding the
+///   coinbase.
+pub fn witness_commitment(wtxids: &[[u8; 32]]) -> [u8; 32] {
+    let mut leaves = Vec::with_capacity(wtxids.len() + 1);
+    leaves.push([0; 32]);
+    leaves.extend_from_slice(wtxids);
+    let witness_root = merkle_root_from_path_([0; 32], &coinbase_merkle_branch(&leaves));
+    DHash::hash(&[&witness_root[..], &[0; 32][..]].concat()).to_byte_array()
+}
+
 // Helper function to format bytes as hex string
 // useful for visualizing targets
 pub fn bytes_to_hex(bytes: &[u8]) -> String {
@@ -412,19 +478,54 @@ pub fn hash_rate_from_target(target: U256<'static>, share_per_min: f64) -> Resul
     Ok(result as f64)
 }
 
-/// Converts a `Target` to a `f64` difficulty.
-pub fn target_to_difficulty(target: Target) -> f64 {
-    // Genesis block target: 0x00000000ffff0000000000000000000000000000000000000000000000000000
-    // (in little endian)
-    let max_target_bytes = [
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00,
-        0x00, 0x00,
-    ];
-    let max_target = U256Primitive::from_little_endian(&max_target_bytes);
+/// Calculates the hashrate (H/s) implied by `target` for a channel that is expected to submit
+/// `shares_per_min` shares per minute.
+///
+/// It is the inverse of [`hash_rate_to_target`]. Unlike [`hash_rate_from_target`] it is computed
+/// with floating point arithmetic, so it never fails: a `shares_per_min` that is not positive
+/// implies no hashrate.
+///
+/// ## Formula
+/// ```text
+/// h = (2^256 - t) / (s * (t + 1))
+/// ```
+///
+/// Where:
+/// - `h`: Mining device hashrate (H/s).
+/// - `t`: Target threshold.
+/// - `s`: Seconds between shares `60 / shares_per_min`.
+pub fn target_to_hash_rate(target: &Target, shares_per_min: f64) -> f64 {
+    if shares_per_min.is_nan() || shares_per_min <= 0.0 {
+        return 0.0;
+    }
+    let target = target_to_f64(target.clone());
+    (2.0f64.powi(256) - target) / (target + 1.0) * shares_per_min / 60.0
+}
+
+// Converts a `Target` to the nearest `f64`
+fn target_to_f64(target: Target) -> f64 {
+    let target: U256<'static> = target.into();
+    let target = U256Primitive::from_little_endian(target.inner_as_ref());
+    let high = (target >> 128).low_u128() as f64;
+    let low = target.low_u128() as f64;
+    high * 2.0f64.powi(128) + low
+}
+
+// Target of difficulty 1, the genesis block target:
+// 0x00000000ffff0000000000000000000000000000000000000000000000000000 (in little endian)
+const DIFFICULTY_1_TARGET: [u8; 32] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Converts a `Target` to a `f64` difficulty, the difficulty 1 target is the genesis block target
+/// `0x00000000ffff0000000000000000000000000000000000000000000000000000`. A zero target (or share
+/// hash) is handled as a target of 1, so that the difficulty is always finite.
+pub fn target_to_difficulty(target: &Target) -> f64 {
+    let max_target = U256Primitive::from_little_endian(&DIFFICULTY_1_TARGET);
 
     // Convert input target to U256Primitive
-    let target_u256: U256<'static> = target.into();
+    let target_u256: U256<'static> = target.clone().into();
     let mut target_bytes = [0u8; 32];
     target_bytes.copy_from_slice(target_u256.inner_as_ref());
     let target = U256Primitive::from_little_endian(&target_bytes);
@@ -441,7 +542,20 @@ pub fn target_to_difficulty(target: Target) -> f64 {
     let max_target_f64 = max_target_high * (2.0f64.powi(128)) + max_target_low;
     let target_f64 = target_high * (2.0f64.powi(128)) + target_low;
 
-    max_target_f64 / target_f64
+    max_target_f64 / target_f64.max(1.0)
+}
+
+/// Converts a `f64` difficulty to the `Target` that has that difficulty, it is the inverse of
+/// [`target_to_difficulty`] within floating point precision. Difficulties that are not positive or
+/// so low that the target would not fit 256 bits give the max target.
+pub fn difficulty_to_target(difficulty: f64) -> Target {
+    let target = target_to_f64(DIFFICULTY_1_TARGET.into()) / difficulty;
+    if difficulty.is_nan() || difficulty <= 0.0 || target >= 2.0f64.powi(256) {
+        return Target::new(u128::MAX, u128::MAX);
+    }
+    let high = (target / 2.0f64.powi(128)).floor();
+    let low = target - high * 2.0f64.powi(128);
+    Target::new(low as u128, high as u128)
 }
 
 /// Converts a `u128` to a [`U256`].
@@ -471,10 +585,17 @@ pub fn from_u128_to_u256(input: u128) -> U256Primitive {
 /// adjustments can be efficiently applied to all channels in a group. By treating a group as a
 /// single entity, the protocol reduces overhead of managing individual channels, especially in
 /// large mining farms.
+///
+/// Factories share a [`GroupId`] behind an `Arc<Mutex<GroupId>>`, every id is allocated or freed
+/// within a single [`Mutex::safe_lock`], so ids are unique across threads. With a single caller the
+/// sequence of ids is deterministic.
 #[derive(Debug, Default)]
 pub struct GroupId {
     group_ids: Id,
     channel_ids: Id,
+    // Ids of closed channels, handed out again oldest first once every other channel id has been
+    // handed out
+    freed_channel_ids: VecDeque<u32>,
 }
 
 impl GroupId {
@@ -485,6 +606,7 @@ impl GroupId {
         Self {
             group_ids: Id::new(),
             channel_ids: Id::new(),
+            freed_channel_ids: VecDeque::new(),
         }
     }
 
@@ -497,14 +619,57 @@ impl GroupId {
 
     /// Generates a new unique channel ID for a given group.
     ///
-    /// Increments the internal channel ID counter and returns the next available channel ID.
+    /// Increments the internal channel ID counter and returns the next channel ID. Once the
+    /// counter reaches `u32::MAX` it returns the id freed the longest time ago with
+    /// [`GroupId::free_channel_id`], if any, so that the id of a closed channel is reused as late
+    /// as possible and the late shares of the closed channel are not credited to a new one.
     ///
     /// **Note**: The `_group_id` parameter is reserved for future use to create a hierarchical
     /// structure of IDs without breaking compatibility with older versions.
     pub fn new_channel_id(&mut self, _group_id: u32) -> u32 {
+        if self.channel_ids.last() == u32::MAX {
+            if let Some(channel_id) = self.freed_channel_ids.pop_front() {
+                return channel_id;
+            }
+        }
         self.channel_ids.next()
     }
 
+    /// Makes the id of a closed channel available again to [`GroupId::new_channel_id`].
+    pub fn free_channel_id(&mut self, channel_id: u32) {
+        if !self.freed_channel_ids.contains(&channel_id) {
+            self.freed_channel_ids.push_back(channel_id);
+        }
+    }
+
+    // Returns the last group id, the last channel id handed out and the freed channel ids, oldest
+    // first
+    pub(crate) fn state(&self) -> (u32, u32, Vec<u32>) {
+        (
+            self.group_ids.last(),
+            self.channel_ids.last(),
+            self.freed_channel_ids.iter().copied().collect(),
+        )
+    }
+
+    // Restores a state returned by `GroupId::state`, unless greater ids have already been handed
+    // out, the ids in `channel_ids_in_use` are not handed out again
+    pub(crate) fn restore(
+        &mut self,
+        last_group_id: u32,
+        last_channel_id: u32,
+        freed_channel_ids: &[u32],
+        channel_ids_in_use: &[u32],
+    ) {
+        self.group_ids.skip_to(last_group_id);
+        self.channel_ids.skip_to(last_channel_id);
+        for channel_id in freed_channel_ids {
+            self.free_channel_id(*channel_id);
+        }
+        self.freed_channel_ids
+            .retain(|channel_id| !channel_ids_in_use.contains(channel_id));
+    }
+
     /// Combines a group ID and channel ID into a single 64-bit unique ID.
     ///
     /// Concatenates the group ID and channel ID, storing the group ID in the higher 32 bits and
@@ -550,6 +715,23 @@ fn test_group_id_new_channel_id() {
     let channel_id = group_ids.new_channel_id(id);
     assert!(channel_id == 1);
 }
+#[test]
+fn test_group_id_reuse_freed_channel_id() {
+    let mut group_ids = GroupId::new();
+    let first = group_ids.new_channel_id(0);
+    let second = group_ids.new_channel_id(0);
+    group_ids.free_channel_id(second);
+    group_ids.free_channel_id(first);
+    group_ids.free_channel_id(first);
+    // Freed ids are not reused while the counter has fresh ids
+    assert_eq!(group_ids.new_channel_id(0), 3);
+    group_ids.channel_ids.skip_to(u32::MAX - 1);
+    assert_eq!(group_ids.new_channel_id(0), u32::MAX);
+    // Then they are reused oldest first
+    assert_eq!(group_ids.new_channel_id(0), second);
+    assert_eq!(group_ids.new_channel_id(0), first);
+}
+
 #[test]
 fn test_group_id_new_into_complete_id() {
     let group_id = u32::from_le_bytes([0, 1, 2, 3]);
@@ -746,6 +928,49 @@ fn test_merkle_root_from_path() {
     );
 }
 
+#[test]
+fn test_coinbase_merkle_branch() {
+    for tx_count in 1..=4u8 {
+        let txids: Vec<[u8; 32]> = (0..tx_count).map(|i| [i + 1; 32]).collect();
+        let expected_root = bitcoin::merkle_tree::calculate_root(
+            txids
+                .iter()
+                .map(|txid| bitcoin::Txid::from_byte_array(*txid)),
+        )
+        .unwrap()
+        .to_byte_array();
+
+        let branch = coinbase_merkle_branch(&txids);
+        let expected_branch_len = match tx_count {
+            1 => 0,
+            2 => 1,
+            _ => 2,
+        };
+        assert_eq!(branch.len(), expected_branch_len);
+        assert_eq!(merkle_root_from_path_(txids[0], &branch), expected_root);
+    }
+    assert!(coinbase_merkle_branch(&[]).is_empty());
+}
+
+#[test]
+fn test_witness_commitment() {
+    for tx_count in 0..=3u8 {
+        let wtxids: Vec<[u8; 32]> = (0..tx_count).map(|i| [i + 1; 32]).collect();
+        let witness_root = bitcoin::merkle_tree::calculate_root(
+            std::iter::once([0; 32])
+                .chain(wtxids.iter().copied())
+                .map(bitcoin::Wtxid::from_byte_array),
+        )
+        .unwrap();
+        let expected = Block::compute_witness_commitment(
+            &bitcoin::WitnessMerkleNode::from_byte_array(witness_root.to_byte_array()),
+            &[0; 32],
+        )
+        .to_byte_array();
+        assert_eq!(witness_commitment(&wtxids), expected);
+    }
+}
+
 /// Converts a `u256` to a [`BlockHash`] type.
 pub fn u256_to_block_hash(v: U256<'static>) -> BlockHash {
     let hash: [u8; 32] = v.to_vec().try_into().unwrap();
@@ -1133,6 +1358,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_target_to_hash_rate_round_trip() {
+        for hash_rate in [1.0e3, 202470.828, 1.0e9, 1.0e12, 1.0e15, 1.0e18, 1.0e21] {
+            for share_per_min in [0.5, 1.0, 6.0, 60.0] {
+                let target: Target = hash_rate_to_target(hash_rate, share_per_min)
+                    .unwrap()
+                    .into();
+                let implied = target_to_hash_rate(&target, share_per_min);
+                assert!(
+                    ((implied - hash_rate) / hash_rate).abs() < 1e-5,
+                    "Expected hashrate {}, got {}",
+                    hash_rate,
+                    implied
+                );
+            }
+        }
+        let target: Target = hash_rate_to_target(1.0e12, 6.0).unwrap().into();
+        assert_eq!(target_to_hash_rate(&target, 0.0), 0.0);
+        assert_eq!(target_to_hash_rate(&target, -1.0), 0.0);
+    }
+
     #[test]
     fn test_super_safe_lock() {
         let m = super::Mutex::new(1u32);
@@ -1150,7 +1396,7 @@ mod tests {
             0x00, 0x00, 0x00, 0x00,
         ];
         let target = Target::from(target_bytes);
-        let difficulty = target_to_difficulty(target);
+        let difficulty = target_to_difficulty(&target);
 
         // Expected difficulty: 14484.162361
         let expected_difficulty = 14484.162361;
@@ -1169,7 +1415,7 @@ mod tests {
             0x00, 0x00, 0x00, 0x00,
         ];
         let max_target = Target::from(max_target_bytes);
-        let max_difficulty = target_to_difficulty(max_target);
+        let max_difficulty = target_to_difficulty(&max_target);
 
         let expected_max_difficulty = 1.0;
         let epsilon = 0.000001; // Small value for floating point comparison
@@ -1180,6 +1426,40 @@ mod tests {
             expected_max_difficulty,
             max_difficulty
         );
+
+        let zero_difficulty = target_to_difficulty(&Target::from([0; 32]));
+        assert!(zero_difficulty.is_finite());
+        assert_eq!(zero_difficulty, target_to_difficulty(&Target::new(1, 0)));
+    }
+
+    #[test]
+    fn test_difficulty_to_target() {
+        assert_eq!(difficulty_to_target(1.0), Target::from(DIFFICULTY_1_TARGET));
+        // 0x0000000000000000ffff000000000000000000000000000000000000000000000000
+        assert_eq!(difficulty_to_target(65536.0), Target::new(0, 0xffff << 64));
+        // 0x000000000004864c000000000000000000000000000000000000000000000000
+        let target = target_to_f64(difficulty_to_target(14484.162361));
+        let expected = target_to_f64(Target::new(0, 0x0004_864c_0000_0000_0000_0000));
+        assert!((target / expected - 1.0).abs() < 1e-9);
+        for difficulty in [0.0, -1.0, f64::NAN, 1e-70] {
+            assert_eq!(difficulty_to_target(difficulty), Target::from([0xff; 32]));
+        }
+    }
+
+    #[test]
+    fn test_difficulty_target_round_trip() {
+        for hashrate in [1.0e9, 1.0e12, 1.0e15, 1.0e18] {
+            let target: Target = hash_rate_to_target(hashrate, 6.0).unwrap().into();
+            let difficulty = target_to_difficulty(&target);
+            let round_trip = difficulty_to_target(difficulty);
+            let error = (target_to_f64(round_trip.clone()) / target_to_f64(target) - 1.0).abs();
+            assert!(
+                error < 1e-12,
+                "Target of difficulty {difficulty} is off by {error}"
+            );
+            let error = (target_to_difficulty(&round_trip) / difficulty - 1.0).abs();
+            assert!(error < 1e-12, "Difficulty {difficulty} is off by {error}");
+        }
     }
 
     #[test]
@@ -123,3 +123,18 @@ impl fmt::Display for SetCustomMiningJobError<'_> {
         )
     }
 }
+
+impl SetCustomMiningJobError<'_> {
+    pub fn invalid_channel_id_error_code() -> &'static str {
+        "invalid-channel-id"
+    }
+    pub fn invalid_mining_job_token_error_code() -> &'static str {
+        "invalid-mining-job-token"
+    }
+    pub fn invalid_prev_hash_error_code() -> &'static str {
+        "invalid-job-param-value-prev-hash"
+    }
+    pub fn invalid_coinbase_tx_outputs_error_code() -> &'static str {
+        "invalid-job-param-value-coinbase-tx-outputs"
+    }
+}